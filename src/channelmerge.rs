@@ -0,0 +1,245 @@
+use crate::rawrepodata::{OriginChannels, Signatures};
+use rattler_conda_types::{ChannelInfo, RepoData};
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+
+/// How the packages of a name are taken from several channels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePriority {
+    /// The packages of a name are taken from the first channel which has any, shadowing those of
+    /// the channels after it, as with the strict channel priority of conda.
+    Strict,
+    /// The packages of a name are taken from every channel.
+    Flexible,
+}
+
+/// The repodata of a subdir of one of the channels being merged.
+pub struct ChannelSubdir {
+    /// The URL of the channel, which its packages are attributed to unless the repodata attributes
+    /// them to another channel already.
+    pub channel: String,
+    pub repodata: RepoData,
+    pub signatures: Signatures,
+    pub origin_channels: OriginChannels,
+    /// The names of the packages of the channel in other subdirs, which shadow those of the
+    /// channels after it too, i.e. those of its noarch for an architecture, and those of its
+    /// architectures for noarch.
+    pub shadowing_names: HashSet<String>,
+}
+
+/// The repodata of a subdir merged from several channels.
+pub struct MergedSubdir {
+    pub repodata: RepoData,
+    pub signatures: Signatures,
+    /// The channel each package was taken from, if requested.
+    pub origin_channels: OriginChannels,
+    /// The number of packages left out for a name a channel before theirs has packages of.
+    pub shadowed: usize,
+    /// The number of packages left out for a filename a channel before theirs has already.
+    pub collisions: usize,
+}
+
+/// Merge the repodata of a subdir of several channels, highest priority first, into one. A package
+/// whose filename an earlier channel has already is left out, as are, with
+/// [`MergePriority::Strict`], those of a name an earlier channel has packages of. The merged
+/// repodata has no `base_url`, so that its output is published under the channel alias.
+#[must_use]
+pub fn merge_subdir(
+    subdir: &str,
+    channels: Vec<ChannelSubdir>,
+    priority: MergePriority,
+    with_origin_channels: bool,
+) -> MergedSubdir {
+    let mut repodata = RepoData {
+        info: Some(ChannelInfo {
+            subdir: subdir.to_string(),
+            base_url: None,
+        }),
+        packages: HashMap::default(),
+        conda_packages: HashMap::default(),
+        removed: HashSet::default(),
+        version: Some(2),
+    };
+    let mut signatures = Signatures::default();
+    let mut origins = Vec::new();
+    let mut shadowed = 0;
+    let mut collisions = 0;
+    // The names of the packages of the channels merged so far.
+    let mut taken_names: HashSet<String> = HashSet::new();
+    for channel in channels {
+        if let Some(base_url) = channel.repodata.base_url() {
+            warn!(
+                "warning: the {subdir} packages of {} are published under the channel alias rather \
+                than their base_url {base_url}",
+                channel.channel
+            );
+        }
+        let mut names = channel.shadowing_names;
+        let mut kept = HashSet::new();
+        for (packages, merged) in [
+            (channel.repodata.packages, &mut repodata.packages),
+            (
+                channel.repodata.conda_packages,
+                &mut repodata.conda_packages,
+            ),
+        ] {
+            for (filename, record) in packages {
+                let name = record.name.as_normalized();
+                if priority == MergePriority::Strict && taken_names.contains(name) {
+                    shadowed += 1;
+                    continue;
+                }
+                names.insert(name.to_string());
+                if merged.contains_key(&filename) {
+                    collisions += 1;
+                    continue;
+                }
+                if with_origin_channels {
+                    let origin = channel
+                        .origin_channels
+                        .get(&filename)
+                        .unwrap_or(&channel.channel);
+                    origins.push((filename.clone(), origin.to_string()));
+                }
+                kept.insert(filename.clone());
+                merged.insert(filename, record);
+            }
+        }
+        // A filename an earlier channel still publishes is not removed from the merged channel.
+        repodata
+            .removed
+            .extend(channel.repodata.removed.into_iter().filter(|filename| {
+                !repodata.packages.contains_key(filename)
+                    && !repodata.conda_packages.contains_key(filename)
+            }));
+        signatures.merge(channel.signatures, |filename| kept.contains(filename));
+        taken_names.extend(names);
+    }
+    MergedSubdir {
+        repodata,
+        signatures,
+        origin_channels: origins.into_iter().collect(),
+        shadowed,
+        collisions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channelmerge::{merge_subdir, ChannelSubdir, MergePriority};
+    use crate::rawrepodata::{OriginChannels, Signatures};
+    use rattler_conda_types::RepoData;
+
+    fn channel(url: &str, filenames: &[&str], shadowing_names: &[&str]) -> ChannelSubdir {
+        let packages: Vec<String> = filenames
+            .iter()
+            .map(|filename| {
+                let (name, rest) = filename.split_once('-').unwrap();
+                let (version, build) = rest.trim_end_matches(".conda").split_once('-').unwrap();
+                format!(
+                    r#""{filename}": {{"name": "{name}", "version": "{version}", "build": "{build}", "build_number": 0}}"#
+                )
+            })
+            .collect();
+        let repodata: RepoData = serde_json::from_str(&format!(
+            r#"{{"packages.conda": {{{}}}}}"#,
+            packages.join(",")
+        ))
+        .unwrap();
+        ChannelSubdir {
+            channel: url.to_string(),
+            repodata,
+            signatures: Signatures::default(),
+            origin_channels: OriginChannels::default(),
+            shadowing_names: shadowing_names.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn channels_merge_by_priority() {
+        let channels = || {
+            let mut channels = vec![
+                channel(
+                    "https://private.example.com/internal/",
+                    &["numpy-2.0.0-0.conda", "zlib-1.3.1-0.conda"],
+                    &["tzdata"],
+                ),
+                channel(
+                    "https://conda.anaconda.org/conda-forge/",
+                    &[
+                        "numpy-1.26.4-0.conda",
+                        "python-3.12.2-0.conda",
+                        "tzdata-2024a-0.conda",
+                        "zlib-1.3.1-0.conda",
+                    ],
+                    &[],
+                ),
+            ];
+            channels[1].repodata.removed = ["zlib-1.3.0-0.conda", "numpy-2.0.0-0.conda"]
+                .into_iter()
+                .map(ToString::to_string)
+                .collect();
+            channels
+        };
+        let filenames = |repodata: &RepoData| {
+            let mut filenames: Vec<String> = repodata.conda_packages.keys().cloned().collect();
+            filenames.sort_unstable();
+            filenames
+        };
+
+        let strict = merge_subdir("linux-64", channels(), MergePriority::Strict, true);
+        assert_eq!(
+            filenames(&strict.repodata),
+            [
+                "numpy-2.0.0-0.conda",
+                "python-3.12.2-0.conda",
+                "zlib-1.3.1-0.conda"
+            ]
+        );
+        assert_eq!((strict.shadowed, strict.collisions), (3, 0));
+        assert_eq!(
+            strict.origin_channels.get("zlib-1.3.1-0.conda"),
+            Some("https://private.example.com/internal/")
+        );
+        assert_eq!(
+            strict.origin_channels.get("python-3.12.2-0.conda"),
+            Some("https://conda.anaconda.org/conda-forge/")
+        );
+        assert_eq!(strict.repodata.base_url(), None);
+        assert_eq!(
+            strict.repodata.removed.into_iter().collect::<Vec<_>>(),
+            ["zlib-1.3.0-0.conda"]
+        );
+
+        let flexible = merge_subdir("linux-64", channels(), MergePriority::Flexible, false);
+        assert_eq!(
+            filenames(&flexible.repodata),
+            [
+                "numpy-1.26.4-0.conda",
+                "numpy-2.0.0-0.conda",
+                "python-3.12.2-0.conda",
+                "tzdata-2024a-0.conda",
+                "zlib-1.3.1-0.conda"
+            ]
+        );
+        assert_eq!((flexible.shadowed, flexible.collisions), (0, 1));
+        assert_eq!(flexible.origin_channels.iter().count(), 0);
+
+        // A private linux-64 foo shadows the noarch foo of conda-forge.
+        let noarch = merge_subdir(
+            "noarch",
+            vec![
+                channel("https://private.example.com/internal/", &[], &["foo"]),
+                channel(
+                    "https://conda.anaconda.org/conda-forge/",
+                    &["foo-1.0.0-0.conda", "six-1.16.0-0.conda"],
+                    &[],
+                ),
+            ],
+            MergePriority::Strict,
+            false,
+        );
+        assert_eq!(filenames(&noarch.repodata), ["six-1.16.0-0.conda"]);
+        assert_eq!(noarch.shadowed, 1);
+    }
+}
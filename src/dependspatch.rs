@@ -0,0 +1,129 @@
+use crate::matchspeccache::MatchspecCache;
+use crate::matchspecyaml::split_named_matchspec;
+use rattler_conda_types::{Matches, PackageRecord, RepoData};
+use serde::Deserialize;
+use std::path::Path;
+
+/// An edit of the depends of every record matching a selector, from a depends patch YAML file.
+/// The file is a list of these, i.e.
+///
+/// ```yaml
+/// - select: openssl >=3,<3.1
+///   remove: [libfoo]
+///   replace: ["ca-certificates >=2024"]
+///   add: ["libzlib >=1.3"]
+/// ```
+#[derive(Deserialize)]
+pub struct DependsPatch {
+    /// The records to patch, as a matchspec with a package name, i.e. `openssl >=3,<3.1`.
+    pub select: String,
+    /// Depends added to the records, as full matchspecs.
+    #[serde(default)]
+    pub add: Vec<String>,
+    /// Names of the depends removed from the records.
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Full matchspecs which replace the depends of the same name. Records without a depends of
+    /// that name are not changed.
+    #[serde(default)]
+    pub replace: Vec<String>,
+    /// Whether matching no records at all is expected, rather than an error.
+    #[serde(default)]
+    pub optional: bool,
+}
+
+impl DependsPatch {
+    /// Edit the depends of one record: first removals, then replacements, then additions.
+    pub fn apply(&self, record: &mut PackageRecord) {
+        let depends_name = |depend: &String| split_named_matchspec(depend).0.to_string();
+        record
+            .depends
+            .retain(|depend| !self.remove.contains(&depends_name(depend)));
+        for replacement in &self.replace {
+            let replacement_name = depends_name(replacement);
+            for depend in &mut record.depends {
+                if depends_name(depend) == replacement_name {
+                    depend.clone_from(replacement);
+                }
+            }
+        }
+        for addition in &self.add {
+            if !record.depends.contains(addition) {
+                record.depends.push(addition.clone());
+            }
+        }
+    }
+}
+
+pub fn read_depends_patches(
+    filename: &Path,
+) -> Result<Vec<DependsPatch>, Box<dyn std::error::Error>> {
+    Ok(serde_yaml::from_str(&std::fs::read_to_string(filename)?)?)
+}
+
+/// Apply every patch to the matching records of all of the repodatas, returning the number of
+/// records each patch matched.
+pub fn apply_depends_patches<'a>(
+    patches: &'a [DependsPatch],
+    matchspec_cache: &'a MatchspecCache<'a, 'a>,
+    repodatas: &mut [&mut RepoData],
+) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let mut counts = Vec::with_capacity(patches.len());
+    for patch in patches {
+        let (package_name, spec) = split_named_matchspec(&patch.select);
+        let spec = matchspec_cache
+            .get_or_insert(spec)
+            .map_err(|err| format!("select: {}: {err}", patch.select))?;
+        let mut count = 0;
+        for repodata in repodatas.iter_mut() {
+            for record in repodata
+                .packages
+                .values_mut()
+                .chain(repodata.conda_packages.values_mut())
+            {
+                if record.name.as_normalized() == package_name && spec.matches(&*record) {
+                    patch.apply(record);
+                    count += 1;
+                }
+            }
+        }
+        counts.push(count);
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dependspatch::DependsPatch;
+    use rattler_conda_types::{PackageName, PackageRecord, VersionWithSource};
+    use std::str::FromStr;
+
+    #[test]
+    fn remove_replace_add() {
+        let patches: Vec<DependsPatch> = serde_yaml::from_str(
+            r#"
+- select: openssl >=3
+  remove: [libfoo]
+  replace: ["ca-certificates >=2024"]
+  add: ["libzlib >=1.3"]
+"#,
+        )
+        .unwrap();
+        let mut record = PackageRecord::new(
+            PackageName::try_from("openssl").unwrap(),
+            VersionWithSource::from_str("3.3.1").unwrap(),
+            "h4bc722e_2".to_string(),
+        );
+        record.depends = vec![
+            "ca-certificates".to_string(),
+            "libfoo >=1".to_string(),
+            "libgcc-ng >=12".to_string(),
+        ];
+        patches[0].apply(&mut record);
+        assert_eq!(
+            record.depends,
+            ["ca-certificates >=2024", "libgcc-ng >=12", "libzlib >=1.3"]
+        );
+        assert!(!patches[0].optional);
+    }
+}
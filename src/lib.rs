@@ -1,5 +1,16 @@
+pub mod channelmerge;
+pub mod dependspatch;
+pub mod listfile;
+pub mod lockfile;
+pub mod logging;
 pub mod logs;
+pub mod manifest;
 pub mod matchspeccache;
 pub mod matchspecyaml;
+pub mod outputdiff;
 pub mod packagerelations;
+pub mod progress;
 pub mod rawrepodata;
+pub mod report;
+pub mod runconfig;
+pub mod shardedrepodata;
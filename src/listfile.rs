@@ -0,0 +1,199 @@
+use crate::matchspecyaml::parse_named_matchspec;
+use rattler_conda_types::{NamelessMatchSpec, ParseStrictness};
+use std::path::Path;
+
+/// Parse a list file: one entry per line. Surrounding whitespace is trimmed, and blank lines and
+/// lines starting with `#` are ignored.
+#[must_use]
+pub fn parse_list(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn read_list(filename: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(parse_list(&std::fs::read_to_string(filename)?))
+}
+
+/// An entry of a vulnerability denylist: a matchspec with a package name, i.e. `openssl ==3.0.0`
+/// or `openssl==3.0.0`, and optionally an identifier such as a CVE id.
+pub struct DenylistEntry {
+    /// The matchspec as written.
+    pub matchspec: String,
+    pub package_name: String,
+    pub spec: NamelessMatchSpec,
+    pub identifier: Option<String>,
+}
+
+/// Parse a vulnerability denylist: a list file whose entries are a matchspec, optionally followed
+/// by an identifier. The identifier is separated by a tab, or is the last word of the entry if it
+/// reads like `CVE-2022-3602` or `GHSA-v8gr-m533-ghj9`, which a build string would not. Fails with
+/// every matchspec which does not parse with `strictness`.
+pub fn parse_denylist(
+    contents: &str,
+    strictness: ParseStrictness,
+) -> Result<Vec<DenylistEntry>, Vec<String>> {
+    let mut entries = Vec::new();
+    let mut errors = Vec::new();
+    for line in parse_list(contents) {
+        let (matchspec, identifier) = split_identifier(&line);
+        match parse_named_matchspec(matchspec, strictness) {
+            Ok((package_name, spec)) => entries.push(DenylistEntry {
+                matchspec: matchspec.to_string(),
+                package_name,
+                spec,
+                identifier: identifier.map(str::to_string),
+            }),
+            Err(err) => errors.push(format!("{matchspec}: {err}")),
+        }
+    }
+    if errors.is_empty() {
+        Ok(entries)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Split the identifier off an entry of a vulnerability denylist, if it has one.
+fn split_identifier(line: &str) -> (&str, Option<&str>) {
+    if let Some((matchspec, identifier)) = line.split_once('\t') {
+        return (
+            matchspec.trim(),
+            Some(identifier.trim()).filter(|id| !id.is_empty()),
+        );
+    }
+    let is_identifier = |word: &str| {
+        word.split_once('-').is_some_and(|(prefix, rest)| {
+            !prefix.is_empty() && prefix.bytes().all(|b| b.is_ascii_uppercase()) && !rest.is_empty()
+        })
+    };
+    match line.rsplit_once(char::is_whitespace) {
+        Some((matchspec, identifier)) if is_identifier(identifier) => {
+            (matchspec.trim_end(), Some(identifier))
+        }
+        _ => (line, None),
+    }
+}
+
+/// Read a vulnerability denylist, failing with every matchspec which does not parse with
+/// `strictness`.
+pub fn read_denylist(
+    filename: &Path,
+    strictness: ParseStrictness,
+) -> Result<Vec<DenylistEntry>, Box<dyn std::error::Error>> {
+    parse_denylist(&std::fs::read_to_string(filename)?, strictness).map_err(|errors| {
+        errors
+            .iter()
+            .map(|err| format!("{}: {err}", filename.display()))
+            .collect::<Vec<String>>()
+            .join("\n")
+            .into()
+    })
+}
+
+/// Write a list file, one entry per line, unless it already holds exactly those. The entries are
+/// written to a sibling file first, which then replaces `filename`, so that a failed run never
+/// leaves a truncated list behind.
+pub fn write_list(
+    filename: &Path,
+    entries: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut contents = String::new();
+    for entry in entries {
+        contents.push_str(entry.as_ref());
+        contents.push('\n');
+    }
+    if std::fs::read_to_string(filename).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+    let mut partial = filename.as_os_str().to_owned();
+    partial.push(".partial");
+    std::fs::write(&partial, contents)?;
+    std::fs::rename(&partial, filename)?;
+    Ok(())
+}
+
+/// Read a list file of package name glob patterns, i.e. `qt*`.
+pub fn read_name_patterns(
+    filename: &Path,
+) -> Result<Vec<glob::Pattern>, Box<dyn std::error::Error>> {
+    read_list(filename)?
+        .iter()
+        .map(|pattern| {
+            glob::Pattern::new(pattern)
+                .map_err(|err| format!("{}: {pattern}: {err}", filename.display()).into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::listfile::{parse_denylist, parse_list, read_list, write_list};
+    use rattler_conda_types::ParseStrictness;
+
+    #[test]
+    fn comments_and_blank_lines() {
+        assert_eq!(
+            parse_list("# banned\nqt*\n\n  pyqt  \n#tk\n"),
+            ["qt*", "pyqt"]
+        );
+    }
+
+    #[test]
+    fn denylist_identifiers() {
+        let entries = parse_denylist(
+            "openssl ==3.0.0\tCVE-2022-3602\nzlib==1.2.11\nopenssl ==3.0.0 CVE-2022-3786\n\
+            numpy 1.22.0 GHSA-fpfv-jqm9-f5jm\nopenssl 3.0.0 h1234567_0\n",
+            ParseStrictness::Lenient,
+        )
+        .unwrap();
+        let split: Vec<(&str, &str, Option<&str>)> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.matchspec.as_str(),
+                    entry.package_name.as_str(),
+                    entry.identifier.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            split,
+            [
+                ("openssl ==3.0.0", "openssl", Some("CVE-2022-3602")),
+                ("zlib==1.2.11", "zlib", None),
+                ("openssl ==3.0.0", "openssl", Some("CVE-2022-3786")),
+                ("numpy 1.22.0", "numpy", Some("GHSA-fpfv-jqm9-f5jm")),
+                ("openssl 3.0.0 h1234567_0", "openssl", None),
+            ]
+        );
+        assert_eq!(
+            entries[4].spec.build.as_ref().unwrap().to_string(),
+            "h1234567_0"
+        );
+
+        let errors = parse_denylist("openssl >=>3\nzlib\n", ParseStrictness::Strict)
+            .err()
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("openssl >=>3: "));
+    }
+
+    #[test]
+    fn written_lists_read_back() {
+        let filename = std::env::temp_dir().join(format!("removed-{}.txt", std::process::id()));
+        write_list(
+            &filename,
+            ["linux-64/a-1.0-0.conda", "noarch/b-2.0-0.tar.bz2"],
+        )
+        .unwrap();
+        assert_eq!(
+            read_list(&filename).unwrap(),
+            ["linux-64/a-1.0-0.conda", "noarch/b-2.0-0.tar.bz2"]
+        );
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use serde::Deserialize;
+use std::path::Path;
+
+/// A conda package pinned by a lockfile.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub subdir: String,
+}
+
+impl LockedPackage {
+    /// The filenames the package may have in a repodata, one for each package format.
+    #[must_use]
+    pub fn filenames(&self) -> [String; 2] {
+        let stem = format!("{}-{}-{}", self.name, self.version, self.build);
+        [format!("{stem}.conda"), format!("{stem}.tar.bz2")]
+    }
+}
+
+/// The packages of a conda-lock (`package:`) or pixi (`packages:`) lockfile.
+#[derive(Deserialize)]
+struct LockFile {
+    #[serde(alias = "packages")]
+    package: Vec<LockEntry>,
+}
+
+/// A package of a lockfile. conda-lock describes conda packages with `manager: conda` and a
+/// `url`, and pixi with either `kind: conda` and a `url` or a `conda:` url. Fields which are
+/// missing are taken from the url.
+#[derive(Deserialize)]
+struct LockEntry {
+    name: Option<String>,
+    version: Option<String>,
+    build: Option<String>,
+    subdir: Option<String>,
+    manager: Option<String>,
+    kind: Option<String>,
+    url: Option<String>,
+    conda: Option<String>,
+}
+
+impl LockEntry {
+    fn is_conda(&self) -> bool {
+        self.conda.is_some()
+            || self.manager.as_deref() == Some("conda")
+            || self.kind.as_deref() == Some("conda")
+    }
+
+    fn into_locked_package(self) -> Result<LockedPackage, String> {
+        let url = self.conda.or(self.url).unwrap_or_default();
+        let mut segments = url.trim_end_matches('/').rsplit('/');
+        let filename = segments.next().unwrap_or_default();
+        let url_subdir = segments.next().filter(|_| !filename.is_empty());
+        let stem = filename
+            .strip_suffix(".conda")
+            .or_else(|| filename.strip_suffix(".tar.bz2"));
+        // Package names may contain dashes, but versions and builds may not.
+        let mut parts = stem.map(|stem| stem.rsplitn(3, '-')).into_iter().flatten();
+        let (url_build, url_version, url_name) = (parts.next(), parts.next(), parts.next());
+        let missing = |field: &str| format!("package {url:?} has no {field}");
+        Ok(LockedPackage {
+            name: self
+                .name
+                .or(url_name.map(str::to_string))
+                .ok_or_else(|| missing("name"))?,
+            version: self
+                .version
+                .or(url_version.map(str::to_string))
+                .ok_or_else(|| missing("version"))?,
+            build: self
+                .build
+                .or(url_build.map(str::to_string))
+                .ok_or_else(|| missing("build"))?,
+            // conda-lock's `platform` is that of the environment, which differs for noarch
+            // packages, so the url is the better source.
+            subdir: url_subdir
+                .map(str::to_string)
+                .or(self.subdir)
+                .ok_or_else(|| missing("subdir"))?,
+        })
+    }
+}
+
+/// Parse the conda packages of a conda-lock or pixi lockfile. Packages from pip are ignored.
+pub fn parse_lockfile(yaml: &str) -> Result<Vec<LockedPackage>, Box<dyn std::error::Error>> {
+    let file: LockFile = serde_yaml::from_str(yaml)?;
+    let mut result = Vec::with_capacity(file.package.len());
+    for entry in file.package {
+        if entry.is_conda() {
+            result.push(entry.into_locked_package()?);
+        }
+    }
+    Ok(result)
+}
+
+pub fn read_lockfile(filename: &Path) -> Result<Vec<LockedPackage>, Box<dyn std::error::Error>> {
+    parse_lockfile(&std::fs::read_to_string(filename)?)
+        .map_err(|err| format!("{}: {err}", filename.display()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lockfile::{parse_lockfile, LockedPackage};
+
+    fn locked(name: &str, version: &str, build: &str, subdir: &str) -> LockedPackage {
+        LockedPackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            build: build.to_string(),
+            subdir: subdir.to_string(),
+        }
+    }
+
+    #[test]
+    fn conda_lock() {
+        let packages = parse_lockfile(
+            r"
+version: 1
+metadata: {}
+package:
+- name: ca-certificates
+  version: 2024.7.4
+  manager: conda
+  platform: linux-64
+  url: https://conda.anaconda.org/conda-forge/linux-64/ca-certificates-2024.7.4-hbcca054_0.conda
+- name: tzdata
+  version: 2024a
+  manager: conda
+  platform: linux-64
+  url: https://conda.anaconda.org/conda-forge/noarch/tzdata-2024a-h0c530f3_0.conda
+- name: requests
+  version: 2.32.3
+  manager: pip
+  platform: linux-64
+  url: https://files.pythonhosted.org/packages/requests-2.32.3-py3-none-any.whl
+",
+        )
+        .unwrap();
+        assert_eq!(
+            packages,
+            [
+                locked("ca-certificates", "2024.7.4", "hbcca054_0", "linux-64"),
+                locked("tzdata", "2024a", "h0c530f3_0", "noarch"),
+            ]
+        );
+        assert_eq!(
+            packages[0].filenames(),
+            [
+                "ca-certificates-2024.7.4-hbcca054_0.conda",
+                "ca-certificates-2024.7.4-hbcca054_0.tar.bz2"
+            ]
+        );
+    }
+
+    #[test]
+    fn pixi_lock() {
+        let packages = parse_lockfile(
+            r"
+version: 5
+environments: {}
+packages:
+- kind: conda
+  name: _openmp_mutex
+  version: '4.5'
+  build: 2_gnu
+  subdir: linux-64
+  url: https://conda.anaconda.org/conda-forge/linux-64/_openmp_mutex-4.5-2_gnu.tar.bz2
+- kind: pypi
+  name: requests
+  version: 2.32.3
+  url: https://files.pythonhosted.org/packages/requests-2.32.3-py3-none-any.whl
+- conda: https://conda.anaconda.org/conda-forge/linux-64/libgcc-ng-14.1.0-h77fa898_0.conda
+",
+        )
+        .unwrap();
+        assert_eq!(
+            packages,
+            [
+                locked("_openmp_mutex", "4.5", "2_gnu", "linux-64"),
+                locked("libgcc-ng", "14.1.0", "h77fa898_0", "linux-64"),
+            ]
+        );
+    }
+}
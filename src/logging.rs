@@ -0,0 +1,176 @@
+use crate::progress::Progress;
+use std::fmt;
+use std::io;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Install the subscriber for the diagnostics of the whole run. Events of this crate are shown
+/// from INFO, DEBUG with one `-v` and TRACE with two, and those of its dependencies one level
+/// less verbosely. Text output is the bare message, as the summary tables have always been
+/// printed, while JSON output carries the fields and spans of every event.
+pub fn init_logging(verbosity: u8, json: bool, progress: &Progress) {
+    let (own_level, other_level) = match verbosity {
+        0 => (LevelFilter::INFO, LevelFilter::WARN),
+        1 => (LevelFilter::DEBUG, LevelFilter::INFO),
+        _ => (LevelFilter::TRACE, LevelFilter::DEBUG),
+    };
+    let filter = Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), own_level)
+        .with_default(other_level);
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::TRACE)
+        .with_writer(progress.clone());
+    // Only fails if a subscriber was already installed, which then keeps receiving the events.
+    let _ = if json {
+        builder
+            .json()
+            .with_span_list(true)
+            .finish()
+            .with(filter)
+            .try_init()
+    } else {
+        builder
+            .event_format(MessageOnly)
+            .finish()
+            .with(filter)
+            .try_init()
+    };
+}
+
+/// Formats an event as its message alone.
+struct MessageOnly;
+
+impl<S, N> FormatEvent<S, N> for MessageOnly
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut visitor = MessageVisitor {
+            writer: writer.by_ref(),
+            result: Ok(()),
+        };
+        event.record(&mut visitor);
+        visitor.result?;
+        writeln!(writer)
+    }
+}
+
+struct MessageVisitor<'a> {
+    writer: Writer<'a>,
+    result: fmt::Result,
+}
+
+impl Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.result = write!(self.writer, "{value:?}");
+        }
+    }
+}
+
+/// Buffers the output of one event, to print it whole above the progress bars once formatted.
+pub struct ProgressWriter {
+    progress: Progress,
+    to_stderr: bool,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ProgressWriter {
+    fn drop(&mut self) {
+        let output = String::from_utf8_lossy(&self.buffer);
+        let output = output.strip_suffix('\n').unwrap_or(&output);
+        if self.to_stderr {
+            self.progress.eprintln(output);
+        } else {
+            self.progress.println(output);
+        }
+    }
+}
+
+/// Warnings and errors go to stderr, everything else to stdout.
+impl<'a> MakeWriter<'a> for Progress {
+    type Writer = ProgressWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ProgressWriter {
+            progress: self.clone(),
+            to_stderr: false,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        ProgressWriter {
+            progress: self.clone(),
+            to_stderr: *meta.level() <= Level::WARN,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::MessageOnly;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, info_span};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn text_is_the_bare_message() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .event_format(MessageOnly)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = info_span!("architecture", architecture = "linux-64").entered();
+            info!("{:>15}: - {:>7}", "dev", 3);
+            info!(
+                rule = "dev",
+                filename = "a-1.0.dev0-0.conda",
+                "a-1.0.dev0-0.conda removed: dev"
+            );
+        });
+        assert_eq!(
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+            "            dev: -       3\na-1.0.dev0-0.conda removed: dev\n"
+        );
+    }
+}
@@ -1,8 +1,82 @@
-use rattler_conda_types::{BuildNumber, NamelessMatchSpec};
+use crate::matchspecyaml::ExactPin;
+use crate::packagerelations::{MetadataProblem, Prerelease};
+use chrono::{DateTime, TimeDelta, Utc};
+use rattler_conda_types::{
+    BuildNumber, NamelessMatchSpec, NoArchKind, PackageRecord, Version, VersionWithSource,
+};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
 
-pub trait Log<'a>: std::fmt::Display {
+/// The kind of rule which removed a package, by which explanations are told apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    UserSpec,
+    BuildPrune,
+    Feature,
+    Prerelease,
+    Architecture,
+    Unsatisfiable,
+    Other,
+}
+
+/// The fields of rattler types are written as they are displayed.
+fn display<S: Serializer>(
+    value: &impl std::fmt::Display,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn noarch_kind<S: Serializer>(value: &NoArchKind, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(match value {
+        NoArchKind::Generic => "generic",
+        NoArchKind::Python => "python",
+    })
+}
+
+fn seconds<S: Serializer>(value: &TimeDelta, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_i64(value.num_seconds())
+}
+
+#[allow(clippy::ref_option)]
+fn optional_seconds<S: Serializer>(
+    value: &Option<TimeDelta>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => seconds(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The names of the closure roots, rather than their matchspecs, which every line would repeat.
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn root_names<S: Serializer>(
+    value: &&HashMap<String, Vec<NamelessMatchSpec>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut names: Vec<&str> = value.keys().map(String::as_str).collect();
+    names.sort_unstable();
+    names.serialize(serializer)
+}
+
+/// A removal, which is printed by `--explain` and written by `--explain-to`.
+pub trait Log<'a>: std::fmt::Display + Serialize {
     fn filename(&self) -> &'a str;
     fn package_name(&self) -> &'a str;
+    fn category(&self) -> Category {
+        Category::Other
+    }
+    /// The name of the dependency whose removal took the package down, if any.
+    fn dependency_package_name(&self) -> Option<&'a str> {
+        None
+    }
+    /// The filename of the removed package which took the package down, if any.
+    fn cause_filename(&self) -> Option<&'a str> {
+        None
+    }
 }
 
 /// Log item for when a package is removed because of a dependency no longer being satsifiable.
@@ -14,32 +88,92 @@ pub struct RemovedUnsatisfiableLog<'a> {
     pub dependency_package_name: &'a str,
     pub matchspec: &'a NamelessMatchSpec,
     pub cause_filename: Option<&'a str>,
+    /// Set if the package only constrains the dependency rather than depending on it.
+    pub constraint: bool,
+    /// The user-declared virtual package which failed to satisfy the dependency, if any.
+    pub virtual_package: Option<&'a PackageRecord>,
 }
 
-impl<'a> std::fmt::Display for RemovedUnsatisfiableLog<'a> {
+/// The virtual package is written as its name and version, i.e. `__glibc=2.17`.
+impl Serialize for RemovedUnsatisfiableLog<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut log = serializer.serialize_struct("RemovedUnsatisfiableLog", 7)?;
+        log.serialize_field("filename", self.filename)?;
+        log.serialize_field("package_name", self.package_name)?;
+        log.serialize_field("dependency_package_name", self.dependency_package_name)?;
+        log.serialize_field("matchspec", &self.matchspec.to_string())?;
+        log.serialize_field("cause_filename", &self.cause_filename)?;
+        log.serialize_field("constraint", &self.constraint)?;
+        log.serialize_field(
+            "virtual_package",
+            &self
+                .virtual_package
+                .map(|record| format!("{}={}", record.name.as_source(), record.version)),
+        )?;
+        log.end()
+    }
+}
+
+impl std::fmt::Display for RemovedUnsatisfiableLog<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.constraint {
+            "constraint"
+        } else {
+            "dependency"
+        };
+        if let Some(virtual_package) = self.virtual_package {
+            return write!(
+                f,
+                "{} removed: {} {} {} unsatisfiable by declared {} {}",
+                self.filename,
+                kind,
+                self.dependency_package_name,
+                self.matchspec,
+                virtual_package.name.as_source(),
+                virtual_package.version
+            );
+        }
         match self.cause_filename {
             Some(cause_filename) => write!(
                 f,
-                "{} removed: dependency {} {} unsatisfiable after removal of {}",
-                self.filename, self.dependency_package_name, self.matchspec, cause_filename
+                "{} removed: {} {} {} unsatisfiable after removal of {}",
+                self.filename, kind, self.dependency_package_name, self.matchspec, cause_filename
             ),
             None => write!(
                 f,
-                "{} removed: dependency {} {} unsatisfiable, seemingly due to no fault of our own",
-                self.filename, self.dependency_package_name, self.matchspec
+                "{} removed: {} {} {} unsatisfiable, seemingly due to no fault of our own",
+                self.filename, kind, self.dependency_package_name, self.matchspec
             ),
         }
     }
 }
 
+#[derive(Serialize)]
 pub struct RemovedBecauseIncompatibleLog<'a> {
     pub filename: &'a str,
     pub package_name: &'a str,
     pub incompatible_with: &'a str,
 }
 
-impl<'a> std::fmt::Display for RemovedBecauseIncompatibleLog<'a> {
+impl<'a> Log<'a> for RemovedUnsatisfiableLog<'a> {
+    fn filename(&self) -> &'a str {
+        self.filename
+    }
+    fn package_name(&self) -> &'a str {
+        self.package_name
+    }
+    fn dependency_package_name(&self) -> Option<&'a str> {
+        Some(self.dependency_package_name)
+    }
+    fn cause_filename(&self) -> Option<&'a str> {
+        self.cause_filename
+    }
+    fn category(&self) -> Category {
+        Category::Unsatisfiable
+    }
+}
+
+impl std::fmt::Display for RemovedBecauseIncompatibleLog<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
@@ -49,49 +183,123 @@ impl<'a> std::fmt::Display for RemovedBecauseIncompatibleLog<'a> {
     }
 }
 
+#[derive(Serialize)]
 pub struct RemovedByUserLog<'a> {
     pub filename: &'a str,
     pub package_name: &'a str,
+    /// The matchspecs the package failed, as the user wrote them.
+    pub matchspecs: String,
+}
+
+impl std::fmt::Display for RemovedByUserLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.matchspecs.is_empty() {
+            return write!(f, "{} removed: failed user matchspec", self.filename);
+        }
+        write!(
+            f,
+            "{} removed: failed user matchspec {}",
+            self.filename, self.matchspecs
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByMinimumVersionLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    #[serde(serialize_with = "display")]
+    pub minimum_version: &'a NamelessMatchSpec,
 }
 
-impl<'a> std::fmt::Display for RemovedByUserLog<'a> {
+impl std::fmt::Display for RemovedByMinimumVersionLog<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} removed: failed user matchspec", self.filename)
+        write!(
+            f,
+            "{} removed: violates minimum version {} {}",
+            self.filename, self.package_name, self.minimum_version
+        )
     }
 }
 
+#[derive(Serialize)]
 pub struct RemovedBySupercedingBuildLog<'a> {
     pub filename: &'a str,
     pub package_name: &'a str,
     pub build_number: BuildNumber,
+    pub kept_filename: &'a str,
+}
+
+impl std::fmt::Display for RemovedBySupercedingBuildLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: superceded by build {}, {}",
+            self.filename, self.build_number, self.kept_filename
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByVariantPreferenceLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub score: usize,
+    pub winner: &'a str,
+    pub winner_score: usize,
+}
+
+impl std::fmt::Display for RemovedByVariantPreferenceLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: variant preference score {} is below {} with score {}",
+            self.filename, self.score, self.winner, self.winner_score
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByPlainRebuildLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub kept_filename: &'a str,
 }
 
-impl<'a> std::fmt::Display for RemovedBySupercedingBuildLog<'a> {
+impl std::fmt::Display for RemovedByPlainRebuildLog<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{} removed: superceded by build {}",
-            self.filename, self.build_number
+            "{} removed: identical rebuild {} supercedes it",
+            self.filename, self.kept_filename
         )
     }
 }
 
-pub struct RemovedByDevRcPolicyLog<'a> {
+#[derive(Serialize)]
+pub struct RemovedByPrereleasePolicyLog<'a> {
     pub filename: &'a str,
     pub package_name: &'a str,
+    pub prerelease: Prerelease,
 }
-impl<'a> std::fmt::Display for RemovedByDevRcPolicyLog<'a> {
+impl std::fmt::Display for RemovedByPrereleasePolicyLog<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} removed: dev/rc policy", self.filename)
+        write!(
+            f,
+            "{} removed: {} prerelease policy",
+            self.filename,
+            self.prerelease.as_str()
+        )
     }
 }
 
+#[derive(Serialize)]
 pub struct RemovedWithFeatureLog<'a> {
     pub filename: &'a str,
     pub package_name: &'a str,
     pub feature: &'a str,
 }
-impl<'a> std::fmt::Display for RemovedWithFeatureLog<'a> {
+impl std::fmt::Display for RemovedWithFeatureLog<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
@@ -101,6 +309,59 @@ impl<'a> std::fmt::Display for RemovedWithFeatureLog<'a> {
     }
 }
 
+#[derive(Serialize)]
+pub struct RemovedByNoarchTypeLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    #[serde(serialize_with = "noarch_kind")]
+    pub kind: NoArchKind,
+}
+impl std::fmt::Display for RemovedByNoarchTypeLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            NoArchKind::Generic => "generic",
+            NoArchKind::Python => "python",
+        };
+        write!(f, "{} removed: banned noarch type {}", self.filename, kind)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedBySubdirMismatchLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub field: &'static str,
+    pub value: &'a str,
+    pub subdir: &'a str,
+}
+impl std::fmt::Display for RemovedBySubdirMismatchLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: {} {} contradicts subdir {}",
+            self.filename, self.field, self.value, self.subdir
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByTrackFeaturesLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub track_features: &'a [String],
+}
+impl std::fmt::Display for RemovedByTrackFeaturesLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: tracks features {}",
+            self.filename,
+            self.track_features.join(", ")
+        )
+    }
+}
+
+#[derive(Serialize)]
 pub struct RemovedIncompatibleArchitectureLog<'a> {
     pub filename: &'a str,
     pub package_name: &'a str,
@@ -108,7 +369,7 @@ pub struct RemovedIncompatibleArchitectureLog<'a> {
     pub actual_architecture: &'a str,
 }
 
-impl<'a> std::fmt::Display for RemovedIncompatibleArchitectureLog<'a> {
+impl std::fmt::Display for RemovedIncompatibleArchitectureLog<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
@@ -118,16 +379,803 @@ impl<'a> std::fmt::Display for RemovedIncompatibleArchitectureLog<'a> {
     }
 }
 
-macro_rules! impl_Log {
-    (for $($t:ty),+) => {
-        $(impl<'a> Log<'a> for $t {
-            fn filename(&self) -> &'a str {
-                self.filename
-            }
-            fn package_name(&self) -> &'a str {
-                self.package_name
-            }
-        })*
+#[derive(Serialize)]
+pub struct RemovedByLicenseLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub license: &'a str,
+}
+
+impl std::fmt::Display for RemovedByLicenseLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: banned license {}",
+            self.filename, self.license
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByTimestampLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+impl std::fmt::Display for RemovedByTimestampLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.timestamp {
+            Some(timestamp) => write!(
+                f,
+                "{} removed: timestamp {} is after the cutoff",
+                self.filename,
+                timestamp.to_rfc3339()
+            ),
+            None => write!(f, "{} removed: has no timestamp", self.filename),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByRecencyWindowLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    /// None if the package has no timestamp
+    #[serde(rename = "age_seconds", serialize_with = "optional_seconds")]
+    pub age: Option<TimeDelta>,
+    #[serde(rename = "window_seconds", serialize_with = "seconds")]
+    pub window: TimeDelta,
+}
+
+impl std::fmt::Display for RemovedByRecencyWindowLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.age {
+            Some(age) => write!(
+                f,
+                "{} removed: {} old, outside the {} window",
+                self.filename,
+                human_duration(age),
+                human_duration(self.window)
+            ),
+            None => write!(
+                f,
+                "{} removed: has no timestamp, so cannot be within the {} window",
+                self.filename,
+                human_duration(self.window)
+            ),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByVersionPruneLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub keep_versions: usize,
+    #[serde(serialize_with = "display")]
+    pub oldest_kept_version: &'a VersionWithSource,
+}
+
+impl std::fmt::Display for RemovedByVersionPruneLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: superceded by the {} newest versions ({} and newer)",
+            self.filename, self.keep_versions, self.oldest_kept_version
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedBySeriesPruneLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    #[serde(serialize_with = "display")]
+    pub series: Version,
+    /// Whether the series itself is kept, and only this version is too old within it.
+    pub series_kept: bool,
+    pub keep_series: usize,
+    pub keep_per_series: usize,
+}
+
+impl std::fmt::Display for RemovedBySeriesPruneLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.series_kept {
+            write!(
+                f,
+                "{} removed: not among the {} newest versions of series {}",
+                self.filename, self.keep_per_series, self.series
+            )
+        } else {
+            write!(
+                f,
+                "{} removed: series {} is not among the {} newest series",
+                self.filename, self.series, self.keep_series
+            )
+        }
+    }
+}
+
+/// Format a duration in whole days, or in hours when shorter than a day, i.e. `90d`.
+fn human_duration(duration: TimeDelta) -> String {
+    if duration.num_days() > 0 {
+        format!("{}d", duration.num_days())
+    } else {
+        format!("{}h", duration.num_hours())
+    }
+}
+
+/// Format a byte count with a binary unit suffix, i.e. `2.5 GiB`.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByNameListLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    /// The denylist pattern which matched the package name, or None if the package name did not
+    /// match any pattern of the allowlist.
+    pub denied_by: Option<&'a str>,
+}
+
+impl std::fmt::Display for RemovedByNameListLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.denied_by {
+            Some(pattern) => write!(
+                f,
+                "{} removed: name {} matches denylist pattern {}",
+                self.filename, self.package_name, pattern
+            ),
+            None => write!(
+                f,
+                "{} removed: name {} not in allowlist",
+                self.filename, self.package_name
+            ),
+        }
+    }
+}
+
+/// A package removed by `--ban-package`, with the pattern its name matched.
+#[derive(Serialize)]
+pub struct RemovedByNameBanLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub pattern: &'a str,
+}
+
+impl std::fmt::Display for RemovedByNameBanLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: package {} banned by {}",
+            self.filename, self.package_name, self.pattern
+        )
     }
 }
-impl_Log!(for RemovedWithFeatureLog<'a>, RemovedByDevRcPolicyLog<'a>, RemovedUnsatisfiableLog<'a>, RemovedBecauseIncompatibleLog<'a>, RemovedBySupercedingBuildLog<'a>, RemovedByUserLog<'a>, RemovedIncompatibleArchitectureLog<'a>);
+
+#[derive(Serialize)]
+pub struct RemovedByFilenameBanLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+}
+
+impl std::fmt::Display for RemovedByFilenameBanLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} removed: explicitly banned filename", self.filename)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedBadMetadataLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    #[serde(serialize_with = "display")]
+    pub problem: MetadataProblem<'a>,
+}
+
+impl std::fmt::Display for RemovedBadMetadataLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} removed: {}", self.filename, self.problem)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByRunExportLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub run_export: &'a str,
+    pub banned_matchspec: &'a str,
+}
+
+impl std::fmt::Display for RemovedByRunExportLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: run export {} matches banned {}",
+            self.filename, self.run_export, self.banned_matchspec
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByLocalVersionLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    #[serde(serialize_with = "display")]
+    pub version: &'a VersionWithSource,
+}
+
+impl std::fmt::Display for RemovedByLocalVersionLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: version {} has a local version segment",
+            self.filename, self.version
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByEpochLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    #[serde(serialize_with = "display")]
+    pub version: &'a VersionWithSource,
+}
+
+impl std::fmt::Display for RemovedByEpochLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: version {} has a nonzero epoch",
+            self.filename, self.version
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedBySelfCycleLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub matchspec: &'a str,
+}
+
+impl std::fmt::Display for RemovedBySelfCycleLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: depends on its own name with unsatisfiable {}",
+            self.filename, self.matchspec
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByVulnerabilityLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub matchspec: &'a str,
+    pub identifier: Option<&'a str>,
+}
+
+impl std::fmt::Display for RemovedByVulnerabilityLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.identifier {
+            Some(identifier) => write!(
+                f,
+                "{} removed: vulnerable ({}), denied by {}",
+                self.filename, identifier, self.matchspec
+            ),
+            None => write!(
+                f,
+                "{} removed: vulnerable, denied by {}",
+                self.filename, self.matchspec
+            ),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByPinConflictLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub pinned_package_name: &'a str,
+    #[serde(serialize_with = "display")]
+    pub matchspec: &'a NamelessMatchSpec,
+    #[serde(serialize_with = "display")]
+    pub pin: &'a NamelessMatchSpec,
+}
+
+impl std::fmt::Display for RemovedByPinConflictLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: dependency {} {} conflicts with pin {} {}",
+            self.filename,
+            self.pinned_package_name,
+            self.matchspec,
+            self.pinned_package_name,
+            self.pin
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByOriginChannelLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub channel: &'a str,
+    pub pattern: &'a str,
+}
+
+impl std::fmt::Display for RemovedByOriginChannelLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: from channel {}, banned by {}",
+            self.filename, self.channel, self.pattern
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedUnsignedLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+}
+
+impl std::fmt::Display for RemovedUnsignedLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} removed: no package signature", self.filename)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByChannelLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+}
+
+impl std::fmt::Display for RemovedByChannelLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: listed in channel removed set",
+            self.filename
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByPreRemovalLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+}
+
+impl std::fmt::Display for RemovedByPreRemovalLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} removed: removed by an earlier run", self.filename)
+    }
+}
+
+/// A noarch package left out of the noarch repodata, which is decided across the architectures.
+#[derive(Serialize)]
+pub struct RemovedFromNoarchLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    /// Whether a rule removes it regardless of the architecture, rather than every architecture
+    /// having removed it.
+    pub unconditional: bool,
+}
+
+impl std::fmt::Display for RemovedFromNoarchLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.unconditional {
+            write!(
+                f,
+                "{} removed from noarch: removed regardless of architecture",
+                self.filename
+            )
+        } else {
+            write!(
+                f,
+                "{} removed from noarch: removed for every architecture",
+                self.filename
+            )
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByDanglingDependLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub dependency_name: &'a str,
+}
+
+impl std::fmt::Display for RemovedByDanglingDependLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: depends on {} which does not exist in this channel",
+            self.filename, self.dependency_name
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByMissingLicenseLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+}
+
+impl std::fmt::Display for RemovedByMissingLicenseLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} removed: no license declared", self.filename)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedBySizeLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub size: u64,
+    pub max_size: u64,
+}
+
+impl std::fmt::Display for RemovedBySizeLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: size {} exceeds limit of {}",
+            self.filename,
+            human_size(self.size),
+            human_size(self.max_size)
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByChecksumPolicyLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    /// Which digest(s) the package was required to have
+    pub missing: &'static str,
+}
+
+impl std::fmt::Display for RemovedByChecksumPolicyLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} removed: no {} checksum", self.filename, self.missing)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByCudaVersionLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    /// The depends entry or build string which conflicted
+    pub constraint: &'a str,
+    #[serde(serialize_with = "display")]
+    pub cuda_version: &'a Version,
+}
+
+impl std::fmt::Display for RemovedByCudaVersionLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: {} conflicts with CUDA {}",
+            self.filename, self.constraint, self.cuda_version
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByBlasPolicyLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub implementation: &'static str,
+}
+
+impl std::fmt::Display for RemovedByBlasPolicyLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: blas policy excludes {}",
+            self.filename, self.implementation
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByExactPinLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    #[serde(serialize_with = "display")]
+    pub pin: &'a ExactPin,
+}
+
+impl std::fmt::Display for RemovedByExactPinLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: collapsed by exact pin {} {}",
+            self.filename, self.package_name, self.pin
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByMutexLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub build: &'a str,
+    /// The build string of a build which was selected instead
+    pub winner: &'a str,
+    pub selection: &'a str,
+}
+
+impl std::fmt::Display for RemovedByMutexLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: mutex {} variant {} lost to {} selected by {}",
+            self.filename, self.package_name, self.build, self.winner, self.selection
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByComputeFlavorLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub preferred: &'static str,
+    /// A build of the preferred flavor of the same version, which is kept
+    pub superseded_by: &'a str,
+}
+
+impl std::fmt::Display for RemovedByComputeFlavorLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: {} builds preferred, superseded by {}",
+            self.filename, self.preferred, self.superseded_by
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByPythonAbiLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    /// `python_abi` when removing `python`, and the other way around
+    pub counterpart: &'static str,
+    pub implementation: &'static str,
+    #[serde(serialize_with = "display")]
+    pub minor: Version,
+}
+
+impl std::fmt::Display for RemovedByPythonAbiLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: no {} remains for {} {}",
+            self.filename, self.counterpart, self.implementation, self.minor
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByPythonVersionLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    /// The depends entry or build string which shows which python the build targets
+    pub target: &'a str,
+}
+
+impl std::fmt::Display for RemovedByPythonVersionLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: targets unselected python ({})",
+            self.filename, self.target
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByPypyPolicyLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    /// The depends entry, build string or package name which identified the package as `PyPy`
+    pub evidence: &'a str,
+}
+
+impl std::fmt::Display for RemovedByPypyPolicyLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: pypy policy ({})",
+            self.filename, self.evidence
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByBuildStringLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub pattern: &'a str,
+}
+
+impl std::fmt::Display for RemovedByBuildStringLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: build string matches banned pattern {}",
+            self.filename, self.pattern
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedByDependerBanLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub dependency_package_name: &'a str,
+}
+
+impl std::fmt::Display for RemovedByDependerBanLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: depends on banned package {}",
+            self.filename, self.dependency_package_name
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedOutsideClosureLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    #[serde(serialize_with = "root_names")]
+    pub roots: &'a HashMap<String, Vec<NamelessMatchSpec>>,
+}
+
+impl std::fmt::Display for RemovedOutsideClosureLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut roots: Vec<&str> = self.roots.keys().map(String::as_str).collect();
+        roots.sort_unstable();
+        write!(
+            f,
+            "{} removed: not in closure of {}",
+            self.filename,
+            roots.join(", ")
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedOrphanLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+}
+
+impl std::fmt::Display for RemovedOrphanLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: orphaned: no remaining dependers and not requested",
+            self.filename
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct RemovedAsDuplicateFormatLog<'a> {
+    pub filename: &'a str,
+    pub package_name: &'a str,
+    pub conda_filename: &'a str,
+}
+
+impl std::fmt::Display for RemovedAsDuplicateFormatLog<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} removed: superseded by .conda equivalent {}",
+            self.filename, self.conda_filename
+        )
+    }
+}
+
+macro_rules! impl_Log {
+    (for $($t:ty),+) => {
+        $(impl<'a> Log<'a> for $t {
+            fn filename(&self) -> &'a str {
+                self.filename
+            }
+            fn package_name(&self) -> &'a str {
+                self.package_name
+            }
+        })*
+    };
+    ($category:ident for $t:ty) => {
+        impl<'a> Log<'a> for $t {
+            fn filename(&self) -> &'a str {
+                self.filename
+            }
+            fn package_name(&self) -> &'a str {
+                self.package_name
+            }
+            fn category(&self) -> Category {
+                Category::$category
+            }
+        }
+    };
+}
+impl_Log!(UserSpec for RemovedByUserLog<'a>);
+impl_Log!(BuildPrune for RemovedBySupercedingBuildLog<'a>);
+impl_Log!(Feature for RemovedWithFeatureLog<'a>);
+impl_Log!(Prerelease for RemovedByPrereleasePolicyLog<'a>);
+impl_Log!(Architecture for RemovedIncompatibleArchitectureLog<'a>);
+impl_Log!(for
+    RemovedBecauseIncompatibleLog<'a>,
+    RemovedByLicenseLog<'a>,
+    RemovedByTimestampLog<'a>,
+    RemovedByVersionPruneLog<'a>,
+    RemovedByMinimumVersionLog<'a>,
+    RemovedBySizeLog<'a>,
+    RemovedByChecksumPolicyLog<'a>,
+    RemovedByCudaVersionLog<'a>,
+    RemovedByBlasPolicyLog<'a>,
+    RemovedByPythonVersionLog<'a>,
+    RemovedByPypyPolicyLog<'a>,
+    RemovedByBuildStringLog<'a>,
+    RemovedByDependerBanLog<'a>,
+    RemovedOutsideClosureLog<'a>,
+    RemovedOrphanLog<'a>,
+    RemovedAsDuplicateFormatLog<'a>,
+    RemovedByLocalVersionLog<'a>,
+    RemovedByEpochLog<'a>,
+    RemovedByNameListLog<'a>,
+    RemovedByNameBanLog<'a>,
+    RemovedByFilenameBanLog<'a>,
+    RemovedBadMetadataLog<'a>,
+    RemovedByRunExportLog<'a>,
+    RemovedByTrackFeaturesLog<'a>,
+    RemovedByPlainRebuildLog<'a>,
+    RemovedByRecencyWindowLog<'a>,
+    RemovedByNoarchTypeLog<'a>,
+    RemovedBySubdirMismatchLog<'a>,
+    RemovedByMissingLicenseLog<'a>,
+    RemovedBySelfCycleLog<'a>,
+    RemovedBySeriesPruneLog<'a>,
+    RemovedByDanglingDependLog<'a>,
+    RemovedByChannelLog<'a>,
+    RemovedByPreRemovalLog<'a>,
+    RemovedFromNoarchLog<'a>,
+    RemovedByVulnerabilityLog<'a>,
+    RemovedUnsignedLog<'a>,
+    RemovedByOriginChannelLog<'a>,
+    RemovedByPinConflictLog<'a>,
+    RemovedByMutexLog<'a>,
+    RemovedByComputeFlavorLog<'a>,
+    RemovedByPythonAbiLog<'a>,
+    RemovedByExactPinLog<'a>,
+    RemovedByVariantPreferenceLog<'a>
+);
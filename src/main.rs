@@ -1,12 +1,46 @@
+use conda_curation::channelmerge::{merge_subdir, ChannelSubdir, MergePriority};
+use conda_curation::dependspatch::{apply_depends_patches, read_depends_patches, DependsPatch};
+use conda_curation::listfile::{
+    read_denylist, read_list, read_name_patterns, write_list, DenylistEntry,
+};
+use conda_curation::lockfile::{read_lockfile, LockedPackage};
+use conda_curation::logging::init_logging;
+use conda_curation::logs::{human_size, Category, RemovedFromNoarchLog};
+use conda_curation::manifest::{
+    info_stamp, remove_manifest, CurationManifest, SubdirCounts, UpstreamRepodata,
+};
 use conda_curation::matchspeccache::MatchspecCache;
-use conda_curation::matchspecyaml::get_user_matchspecs;
-use conda_curation::packagerelations::PackageRelations;
+use conda_curation::matchspecyaml::{
+    format_user_matchspec, get_environment_file, get_user_matchspecs, parse_argument_matchspecs,
+    parse_named_matchspec, parse_user_matchspecs, split_named_matchspec, MatchspecsYaml,
+};
+use conda_curation::outputdiff::diff_output_directories;
+use conda_curation::packagerelations::{
+    unknown_user_spec_names, ComputeFlavor, PackageRelations, Prerelease, BLAS_IMPLEMENTATIONS,
+};
+use conda_curation::progress::Progress;
 use conda_curation::rawrepodata;
-use conda_curation::rawrepodata::filtered_repodata_to_file;
+use conda_curation::rawrepodata::{
+    filtered_repodata_to_file, CacheMode, ChannelAliases, OriginChannels, OutputFormat,
+    RepodataSource, RunExportsIndex, Signatures, SubdirCache, WrittenRepodata,
+};
+use conda_curation::report::{
+    most_removed, ArchitectureReport, ExplanationRecord, PhaseReport, RemovalStatistics,
+    RemovalTally, ResourceReport, RoundRecord, RoundReport, RunReport,
+};
+use conda_curation::runconfig::{effective_config, merge_arguments, read_run_config};
 
-use rattler_conda_types::RepoData;
-use std::collections::HashSet;
-use std::time::Instant;
+use rattler_conda_types::package::RunExportsJson;
+use rattler_conda_types::{
+    Matches, NamelessMatchSpec, NoArchKind, PackageName, PackageRecord, ParseStrictness, Platform,
+    RepoData, Version, VersionWithSource,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
 
@@ -14,8 +48,12 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-use clap::Parser;
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, Utc};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use indicatif::ProgressBar;
 use rayon::prelude::*;
+use tracing::{debug, error, info, info_span, warn};
 
 const ARCHITECTURES: &[&str] = &[
     //"freebsd-64",
@@ -51,188 +89,3844 @@ fn architectures_parser(value: &str) -> Result<String, &'static str> {
     }
 }
 
+fn named_matchspec_parser(value: &str) -> Result<String, String> {
+    match parse_named_matchspec(value, ParseStrictness::Lenient) {
+        Ok(_) => Ok(value.to_string()),
+        Err(err) => Err(format!("Invalid matchspec: {err}")),
+    }
+}
+
+fn glob_parser(value: &str) -> Result<String, String> {
+    match glob::Pattern::new(value) {
+        Ok(_) => Ok(value.to_string()),
+        Err(err) => Err(format!("Invalid pattern: {err}")),
+    }
+}
+
+/// Split a `--ban-build-regex` argument into its optional package name scope and its regular
+/// expression, i.e. `numpy:.*_debug.*`.
+fn split_build_regex(value: &str) -> (Option<&str>, &str) {
+    match value.split_once(':') {
+        Some((package_name, pattern))
+            if !package_name.is_empty()
+                && package_name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') =>
+        {
+            (Some(package_name), pattern)
+        }
+        _ => (None, value),
+    }
+}
+
+fn build_regex_parser(value: &str) -> Result<String, String> {
+    let (_, pattern) = split_build_regex(value);
+    match regex::Regex::new(pattern) {
+        Ok(_) => Ok(value.to_string()),
+        Err(err) => Err(format!("Invalid regular expression: {err}")),
+    }
+}
+
+/// Accepts either an ISO 8601 date (`2023-06-01`), an RFC 3339 timestamp, or seconds since the
+/// Unix epoch.
+fn timestamp_parser(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(seconds) = value.parse::<i64>() {
+        return DateTime::from_timestamp(seconds, 0).ok_or_else(|| "timestamp out of range".into());
+    }
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => Ok(date.and_time(NaiveTime::MIN).and_utc()),
+        Err(_) => Err("expected a date (YYYY-MM-DD), RFC 3339 timestamp or epoch seconds".into()),
+    }
+}
+
+/// Accepts a number of hours, days or weeks, i.e. `36h`, `90d` or `12w`.
+fn age_parser(value: &str) -> Result<TimeDelta, String> {
+    let value = value.trim();
+    let Some(suffix) = value.chars().last() else {
+        return Err("expected an age, i.e. 90d".into());
+    };
+    let amount = value[..value.len() - suffix.len_utf8()]
+        .parse::<i64>()
+        .map_err(|err| format!("invalid age: {err}"))?;
+    let age = match suffix {
+        'h' => TimeDelta::try_hours(amount),
+        'd' => TimeDelta::try_days(amount),
+        'w' => TimeDelta::try_weeks(amount),
+        _ => return Err(format!("unknown age suffix {suffix}, expected h, d or w")),
+    };
+    age.ok_or_else(|| "age too large".to_string())
+}
+
+/// Parse a `--base-url`, which ends in a slash like the channel aliases.
+fn base_url_parser(value: &str) -> Result<String, String> {
+    url::Url::parse(value).map_err(|err| err.to_string())?;
+    if value.ends_with('/') {
+        Ok(value.to_string())
+    } else {
+        Ok(format!("{value}/"))
+    }
+}
+
+fn non_empty_parser(value: &str) -> Result<String, &'static str> {
+    if value.is_empty() {
+        Err("cannot be an empty string")
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+const ARCHITECTURES_VARIABLE: &str = "CONDA_CURATION_ARCHITECTURES";
+const CHANNEL_ALIAS_VARIABLE: &str = "CONDA_CURATION_CHANNEL_ALIAS";
+
+/// Split the comma separated list of an environment variable, checking every entry with `parse`.
+fn parse_env_list<E: std::fmt::Display>(
+    variable: &str,
+    value: &str,
+    parse: impl Fn(&str) -> Result<String, E>,
+) -> Result<Vec<String>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .enumerate()
+        .map(|(index, entry)| {
+            parse(entry).map_err(|err| {
+                format!(
+                    "{variable}={value:?}: entry {} {entry:?} is invalid: {err}",
+                    index + 1
+                )
+            })
+        })
+        .collect()
+}
+
+/// The list of an environment variable, if it is set.
+fn env_list<E: std::fmt::Display>(
+    variable: &str,
+    parse: impl Fn(&str) -> Result<String, E>,
+) -> Result<Option<Vec<String>>, Failure> {
+    let Some(value) = std::env::var_os(variable) else {
+        return Ok(None);
+    };
+    let value = value
+        .into_string()
+        .map_err(|_| Failure::Input(format!("{variable} is not valid unicode")))?;
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    parse_env_list(variable, &value, parse)
+        .map(Some)
+        .map_err(Failure::Input)
+}
+
+/// Accepts a number of seconds, minutes, hours or days, i.e. `90s`, `30m`, `6h` or `1d`.
+fn interval_parser(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let Some(suffix) = value.chars().last() else {
+        return Err("expected an interval, i.e. 6h".into());
+    };
+    let amount = value[..value.len() - suffix.len_utf8()]
+        .parse::<u64>()
+        .map_err(|err| format!("invalid interval: {err}"))?;
+    let seconds = match suffix {
+        's' => Some(amount),
+        'm' => amount.checked_mul(60),
+        'h' => amount.checked_mul(60 * 60),
+        'd' => amount.checked_mul(24 * 60 * 60),
+        _ => {
+            return Err(format!(
+                "unknown interval suffix {suffix}, expected s, m, h or d"
+            ))
+        }
+    };
+    match seconds {
+        Some(0) => Err("the interval must not be zero".into()),
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => Err("interval too large".into()),
+    }
+}
+
+/// Accepts a number of bytes, optionally followed by a binary unit suffix (`K`, `M`, `G`, `T`).
+fn size_parser(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let multiplier: u64 = match suffix.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(format!("unknown size suffix {suffix}")),
+            };
+            (&value[..index], multiplier)
+        }
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .map_err(|err| format!("invalid size: {err}"))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| "size too large".to_string())
+}
+
+/// Accepts a share, either as a percentage (`60%`) or as a fraction (`0.6`).
+fn share_parser(value: &str) -> Result<f64, String> {
+    let value = value.trim();
+    let share = match value.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|percent| percent / 100.0),
+        None => value.parse::<f64>(),
+    }
+    .map_err(|err| format!("invalid share: {err}"))?;
+    if (0.0..=1.0).contains(&share) {
+        Ok(share)
+    } else {
+        Err(format!("{value} is not between 0% and 100%"))
+    }
+}
+
+/// Accepts a virtual package declaration such as `__glibc=2.28`, and turns it into a record that
+/// stands in for the virtual package when evaluating depends.
+fn virtual_package_parser(value: &str) -> Result<PackageRecord, String> {
+    let Some((name, version)) = value.split_once('=') else {
+        return Err("expected NAME=VERSION, i.e. __glibc=2.28".into());
+    };
+    if !name.starts_with("__") {
+        return Err(format!(
+            "{name} is not a virtual package (must start with __)"
+        ));
+    }
+    let name = PackageName::try_from(name).map_err(|err| format!("invalid name: {err}"))?;
+    let version =
+        VersionWithSource::from_str(version).map_err(|err| format!("invalid version: {err}"))?;
+    Ok(PackageRecord::new(name, version, "0".to_string()))
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TimestampMissing {
+    Keep,
+    Remove,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NoarchType {
+    Generic,
+    Python,
+}
+
+impl From<NoarchType> for NoArchKind {
+    fn from(value: NoarchType) -> Self {
+        match value {
+            NoarchType::Generic => NoArchKind::Generic,
+            NoarchType::Python => NoArchKind::Python,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Flavor {
+    Cpu,
+    Gpu,
+}
+
+impl From<Flavor> for ComputeFlavor {
+    fn from(value: Flavor) -> Self {
+        match value {
+            Flavor::Cpu => ComputeFlavor::Cpu,
+            Flavor::Gpu => ComputeFlavor::Gpu,
+        }
+    }
+}
+
+/// The built-in rules which `--skip-rule` may disable.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Rule {
+    /// the removal of builds superseded by a higher build number of the same version
+    BuildPrune,
+    /// the removal of packages with a banned feature
+    Features,
+    /// the removal of prereleases, as banned by --keep-dev, --keep-rc and the like
+    DevRc,
+    /// the removal of packages of another architecture than the subdir they are in
+    IncompatArch,
+    /// the removal of packages failing the user matchspecs
+    UserMatchspecs,
+    /// the removal of packages whose dependencies are no longer satisfiable, without which the
+    /// output may be unsolvable
+    Unresolveable,
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.to_possible_value().unwrap().get_name())
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ChannelPriority {
+    Strict,
+    Flexible,
+}
+
+impl From<ChannelPriority> for MergePriority {
+    fn from(value: ChannelPriority) -> Self {
+        match value {
+            ChannelPriority::Strict => MergePriority::Strict,
+            ChannelPriority::Flexible => MergePriority::Flexible,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BadMetadata {
+    Keep,
+    Remove,
+}
+
+const DEFAULT_CHANNEL_ALIAS: &str = "https://conda.anaconda.org/conda-forge/";
+
+const EXIT_CODES_HELP: &str = "Exit codes:
+  0  success
+  1  an input could not be loaded or is invalid
+  2  invalid command line
+  3  a user matchspec matches no package (with --fail-on-empty-spec or
+     --fail-on-unknown-spec)
+  4  a --must-compatible-with anchor matches no package
+  5  too much of an architecture was removed (with --fail-on-removal-over)
+  6  writing the output failed
+  7  a --lockfile package is missing from the output";
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExplainGroupBy {
+    Rule,
+    Package,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RoundFormat {
+    Text,
+    Json,
+}
+
+const DEFAULT_SUBCOMMAND_HELP: &str = "Without a subcommand, the options of `curate` are \
+accepted and it is run, as before there were subcommands. This is deprecated: name the `curate` \
+subcommand instead.";
+
+/// The whole command line: a subcommand, or the options of `curate` without naming it, as before
+/// there were subcommands.
+#[derive(Parser)]
+#[command(
+    author = "Aaron Opfer",
+    about = "Apply various filtering rules to remove packages from a Conda Channel in order to speed up downloads and solutions and/or enforce policy.",
+    before_help = DEFAULT_SUBCOMMAND_HELP,
+    after_help = EXIT_CODES_HELP,
+    args_conflicts_with_subcommands = true
+)]
+struct Invocation {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    curate: Cli,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// filter the repodata of a channel (the default, though leaving it out is deprecated)
+    Curate(Box<Cli>),
+    /// download the repodata of the channel into the cache, for later runs with --offline
+    Fetch(FetchArgs),
+    /// print which subdirs the channel publishes, with the size and last modification of their
+    /// repodata, probing the --architecture subdirs, or else every known subdir, and those of its
+    /// channeldata.json
+    ListArchitectures(ListArchitecturesArgs),
+    /// compare the repodata written to two output directories, printing the packages added,
+    /// removed and changed in each subdir
+    Diff(DiffArgs),
+    /// print the completions of this command for a shell
+    #[command(hide = true)]
+    Completions { shell: clap_complete::Shell },
+    /// print the man page of this command
+    Manpage,
+    /// filter like `curate` without writing anything, and print why a package was removed from
+    /// each architecture, following the removals which left it unsatisfiable to their root cause
+    #[command(visible_alias = "explain")]
+    Why(Box<WhyArgs>),
+}
+
+#[derive(clap::Args)]
+#[allow(clippy::doc_markdown)]
+struct FetchArgs {
+    #[command(flatten)]
+    channel: ChannelArgs,
+    /// also download the run_exports.json of each subdir, which --ban-run-export needs
+    #[arg(long)]
+    run_exports: bool,
+    /// fail when the channel does not publish one of the architectures, rather than leaving it
+    /// out with a warning
+    #[arg(long = "strict-architectures")]
+    strict_architectures: bool,
+    /// do not draw progress bars
+    #[arg(short = 'q', long)]
+    quiet: bool,
+    /// log more: DEBUG with -v and TRACE with -vv
+    #[arg(short = 'v', long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(clap::Args)]
+struct ListArchitecturesArgs {
+    #[command(flatten)]
+    channel: ChannelArgs,
+    /// list the subdirs in the cache instead of asking the channel
+    #[arg(
+        long = "offline",
+        action = clap::ArgAction::SetTrue,
+        env = "CONDA_CURATION_OFFLINE",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    is_offline: bool,
+    /// log more: DEBUG with -v and TRACE with -vv
+    #[arg(short = 'v', long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(clap::Args)]
+struct DiffArgs {
+    /// the output directory of the earlier run
+    old: std::path::PathBuf,
+    /// the output directory of the later run
+    new: std::path::PathBuf,
+}
+
+/// What to do, once the command line is parsed.
+enum Task {
+    Curate(Box<Cli>),
+    Fetch(FetchArgs),
+    ListArchitectures(ListArchitecturesArgs),
+    Diff(DiffArgs),
+}
+
+#[derive(clap::Args)]
+struct WhyArgs {
+    /// the filename of a package, or a package name for every removed build of it
+    target: String,
+    #[command(flatten)]
+    curate: Cli,
+}
+
+/// The options of every subcommand which fetches from the channel.
+#[derive(clap::Args)]
+#[allow(clippy::struct_excessive_bools, clippy::doc_markdown)]
+struct ChannelArgs {
+    /// Base URL for downloading repodata, unless --channel is given, and the `base_url` of the
+    /// output unless --base-url is given. A channel on disk may be given as a `file://` URL or a
+    /// path, and is read in place. A URL for one subdir only may be given as SUBDIR=CHANNEL_URL
+    /// (i.e. `linux-64=https://linux.example.com/conda-forge/`). May be repeated, or given as a
+    /// comma separated list by CONDA_CURATION_CHANNEL_ALIAS.
+    #[arg(
+        long = "channel-alias",
+        default_value = DEFAULT_CHANNEL_ALIAS,
+        value_name = "CHANNEL_URL"
+    )]
+    channel_alias: Vec<String>,
+    /// The --channel-alias URL of each subdir.
+    #[arg(skip)]
+    channel_aliases: ChannelAliases,
+    /// download the repodata from this channel, a URL or a directory, rather than from
+    /// --channel-alias, which remains the `base_url` of the output unless --base-url is given.
+    /// May be repeated, highest priority first, to merge the channels into one repodata per
+    /// subdir.
+    #[arg(long = "channel", value_name = "CHANNEL_URL")]
+    channels: Vec<String>,
+    /// The URL of each --channel, for every subdir.
+    #[arg(skip)]
+    channel_sources: Vec<ChannelAliases>,
+    /// how the packages of a name are taken from several --channel: `strict` takes them from the
+    /// first channel with any, counting its noarch packages toward each architecture, where
+    /// `flexible` takes them from every channel. A filename in several channels is always taken
+    /// from the first.
+    #[arg(long, value_enum, default_value = "strict")]
+    channel_priority: ChannelPriority,
+    /// Which architectures to render index information for. If none are specified, will default to
+    /// those of CONDA_CURATION_ARCHITECTURES, a comma separated list, or else all architectures.
+    #[arg(short = 'a', long = "architecture", value_parser = architectures_parser)]
+    architectures: Vec<String>,
+    /// leave this architecture out of the default architectures (i.e. `win-64`). May be repeated,
+    /// but not combined with an --architecture of the same name.
+    #[arg(
+        long = "exclude-architecture",
+        value_name = "ARCH",
+        value_parser = architectures_parser
+    )]
+    exclude_architectures: Vec<String>,
+    /// cache the downloaded repodata in this directory rather than the default cache of rattler
+    #[arg(long, env = "CONDA_CURATION_CACHE_DIR", value_name = "DIR")]
+    cache_dir: Option<std::path::PathBuf>,
+    /// download the repodata again even if the cache holds a copy the channel says is fresh, i.e.
+    /// when the channel republished it without changing its caching headers
+    #[arg(long)]
+    refresh: bool,
+    /// fail when the repodata of a subdir comes from a cached copy older than this (i.e. `12h`),
+    /// as it does offline or while the channel says the copy is fresh
+    #[arg(long, value_name = "DURATION", value_parser = interval_parser)]
+    max_cache_age: Option<Duration>,
+    /// only warn about repodata older than --max-cache-age
+    #[arg(long, requires = "max_cache_age")]
+    allow_stale: bool,
+    /// fetch the repodata.json of every subdir, even where the channel publishes sharded
+    /// repodata (CEP-16), which is otherwise preferred
+    #[arg(long)]
+    no_shards: bool,
+    /// download cached repodata.json again in full, rather than updating it with the JLAP patches
+    /// of the channel where it publishes them; a cache whose patches do not apply is always
+    /// downloaded in full
+    #[arg(long)]
+    no_jlap: bool,
+}
+
+impl ChannelArgs {
+    /// Take the lists not given by `matches` from their environment variables.
+    fn read_environment(&mut self, matches: &clap::ArgMatches) -> Result<(), Failure> {
+        if matches.value_source("architectures").is_none() {
+            if let Some(architectures) = env_list(ARCHITECTURES_VARIABLE, architectures_parser)? {
+                self.architectures = architectures;
+            }
+        }
+        if matches.value_source("channel_alias") != Some(ValueSource::CommandLine) {
+            if let Some(channel_aliases) = env_list(CHANNEL_ALIAS_VARIABLE, non_empty_parser)? {
+                self.channel_alias = channel_aliases;
+            }
+        }
+        Ok(())
+    }
+
+    /// Default to every architecture but the excluded ones, and parse the channel aliases, each of
+    /// which must be for one of the architectures or noarch.
+    fn resolve(&mut self) -> Result<(), Failure> {
+        if self.architectures.is_empty() {
+            self.architectures.extend(
+                ARCHITECTURES
+                    .iter()
+                    .filter(|arch| {
+                        !self
+                            .exclude_architectures
+                            .iter()
+                            .any(|excluded| excluded == *arch)
+                    })
+                    .map(|arch| (*arch).to_string()),
+            );
+            if self.architectures.is_empty() {
+                return Err(Failure::Input(
+                    "--exclude-architecture excludes every architecture".to_string(),
+                ));
+            }
+        } else if let Some(excluded) = self
+            .exclude_architectures
+            .iter()
+            .find(|excluded| self.architectures.contains(excluded))
+        {
+            return Err(Failure::Input(format!(
+                "--exclude-architecture {excluded} contradicts --architecture {excluded}"
+            )));
+        }
+        self.channel_aliases = ChannelAliases::parse(&self.channel_alias, DEFAULT_CHANNEL_ALIAS)
+            .map_err(|err| Failure::Input(format!("invalid --channel-alias: {err}")))?;
+        if let Some(subdir) = self.channel_aliases.mapped_subdirs().find(|subdir| {
+            *subdir != "noarch" && !self.architectures.iter().any(|arch| arch == subdir)
+        }) {
+            return Err(Failure::Input(format!(
+                "--channel-alias given for {subdir}, which is not one of the architectures"
+            )));
+        }
+        self.channel_sources = self
+            .channels
+            .iter()
+            .map(|channel| {
+                ChannelAliases::parse(&[], channel)
+                    .map_err(|err| Failure::Input(format!("invalid --channel: {err}")))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// How the cached repodata is used.
+    fn cache_mode(&self, is_offline: bool) -> CacheMode {
+        if is_offline {
+            CacheMode::Offline
+        } else if self.refresh {
+            CacheMode::Refresh
+        } else {
+            CacheMode::Normal
+        }
+    }
+
+    /// Where the repodata is fetched from, and how it is cached.
+    fn source(&self, is_offline: bool) -> RepodataSource<'_> {
+        RepodataSource {
+            channel_aliases: &self.channel_aliases,
+            cache_dir: self.cache_dir.as_deref(),
+            cache_mode: self.cache_mode(is_offline),
+            use_shards: !self.no_shards,
+            use_jlap: !self.no_jlap,
+        }
+    }
+
+    /// Where the repodata of each --channel is fetched from, highest priority first, or else that
+    /// of the --channel-alias.
+    fn sources(&self, is_offline: bool) -> Vec<RepodataSource<'_>> {
+        if self.channel_sources.is_empty() {
+            return vec![self.source(is_offline)];
+        }
+        self.channel_sources
+            .iter()
+            .map(|channel_aliases| RepodataSource {
+                channel_aliases,
+                ..self.source(is_offline)
+            })
+            .collect()
+    }
+
+    /// Fail, or only warn with --allow-stale, when the repodata of a subdir was older than
+    /// --max-cache-age.
+    fn check_cache_ages(&self, caches: &[SubdirCache]) -> Result<(), Failure> {
+        let Some(max_cache_age) = self.max_cache_age else {
+            return Ok(());
+        };
+        let stale: Vec<String> = caches
+            .iter()
+            .filter(|cache| cache.age > max_cache_age)
+            .map(|cache| {
+                format!(
+                    "{} ({:.1}h old)",
+                    cache.subdir,
+                    cache.age.as_secs_f64() / 3600.0
+                )
+            })
+            .collect();
+        if stale.is_empty() {
+            return Ok(());
+        }
+        let message = format!(
+            "cached repodata older than --max-cache-age: {}",
+            stale.join(", ")
+        );
+        if self.allow_stale {
+            warn!("warning: {message}");
+            Ok(())
+        } else {
+            Err(Failure::Input(message))
+        }
+    }
+
+    /// Print the architectures of the run, and those excluded from it.
+    fn log_architectures(&self) {
+        if self.exclude_architectures.is_empty() {
+            info!("architectures: {}", self.architectures.join(", "));
+        } else {
+            info!(
+                "architectures: {} (excluded: {})",
+                self.architectures.join(", "),
+                self.exclude_architectures.join(", ")
+            );
+        }
+    }
+}
+
 #[derive(Parser)]
 #[command(
     author = "Aaron Opfer",
-    about = "Apply various filtering rules to remove packages from a Conda Channel in order to speed up downloads and solutions and/or enforce policy."
+    about = "Apply various filtering rules to remove packages from a Conda Channel in order to speed up downloads and solutions and/or enforce policy.",
+    after_help = EXIT_CODES_HELP
 )]
 #[allow(clippy::struct_excessive_bools, clippy::doc_markdown)]
 struct Cli {
     /// remove packages with this feature
     #[arg(short = 'F', long = "ban-feature", value_name = "FEATURE")]
     ban_features: Vec<String>,
-    /// remove packages that aren't compatible with any variant of PACKAGE_NAME
+    /// remove noarch packages of this kind. May be repeated.
+    #[arg(long = "ban-noarch-type", value_name = "KIND")]
+    ban_noarch_types: Vec<NoarchType>,
+    /// remove packages which depend on a name that no package in the channel provides
+    #[arg(long)]
+    remove_dangling_depends: bool,
+    /// after filtering, report the cycles in the dependency graph of the remaining packages
+    #[arg(long)]
+    analyze_cycles: bool,
+    /// with --analyze-cycles, first remove packages which depend on their own name with an
+    /// unsatisfiable spec
+    #[arg(long, requires = "analyze_cycles")]
+    break_self_cycles: bool,
+    /// remove packages whose platform or arch contradict the subdir they are published under, on
+    /// top of those whose subdir field does. The number of such packages is printed regardless.
+    #[arg(
+        long = "enforce-subdir-consistency",
+        conflicts_with = "keep_subdir_mismatches"
+    )]
+    enforce_subdir_consistency: bool,
+    /// remove every package which has any track_features
+    #[arg(long)]
+    ban_all_track_features: bool,
+    /// remove packages that aren't compatible with any variant of PACKAGE_NAME. A matchspec
+    /// (i.e. `python=3.11`) restricts this to the variants matching it.
     #[arg(
         short = 'C',
         long = "must-compatible-with",
-        value_name = "PACKAGE_NAME"
+        value_name = "MATCHSPEC",
+        value_parser = named_matchspec_parser
     )]
     must_compatible: Vec<String>,
+    /// require packages to be compatible with every --must-compatible-with at the same time,
+    /// rather than with each of them individually
+    #[arg(long = "must-compatible-all", requires = "must_compatible")]
+    must_compatible_all: bool,
+    /// how many dependencies away from a --must-compatible-with package compatibility is checked
+    #[arg(
+        long = "must-compatible-max-depth",
+        value_name = "N",
+        default_value_t = 64
+    )]
+    must_compatible_max_depth: usize,
+    /// remove packages whose license matches this glob pattern (i.e. `GPL*`)
+    #[arg(long = "ban-license", value_name = "PATTERN", value_parser = glob_parser)]
+    ban_licenses: Vec<String>,
+    /// remove packages which do not declare a license, except those listed in the
+    /// `allow_missing_license:` section of the matchspecs YAML
+    #[arg(long)]
+    require_license: bool,
+    /// remove packages uploaded after this date (YYYY-MM-DD, RFC 3339 or epoch seconds)
+    #[arg(long = "max-timestamp", value_name = "TIMESTAMP", value_parser = timestamp_parser)]
+    max_timestamp: Option<DateTime<Utc>>,
+    /// remove packages older than this (i.e. `90d`, `12w` or `36h`), except for the newest
+    /// version of each package
+    #[arg(long = "only-newer-than", value_name = "AGE", value_parser = age_parser)]
+    only_newer_than: Option<TimeDelta>,
+    /// what timestamp filters should do with packages that have no timestamp
+    #[arg(long = "timestamp-missing", value_enum, default_value = "keep")]
+    timestamp_missing: TimestampMissing,
+    /// remove packages larger than this size (i.e. `500M`, `2G`)
+    #[arg(long = "max-package-size", value_name = "BYTES", value_parser = size_parser)]
+    max_package_size: Option<u64>,
+    /// remove packages which do not have a sha256 checksum
+    #[arg(long = "require-sha256")]
+    require_sha256: bool,
+    /// remove packages which have neither a sha256 nor an md5 checksum
+    #[arg(long = "require-any-digest")]
+    require_any_digest: bool,
+    /// remove builds made for a CUDA version other than this one. CPU-only builds are kept.
+    #[arg(long = "cuda-version", value_name = "VERSION")]
+    cuda_version: Option<Version>,
+    /// remove BLAS implementations other than this one
+    #[arg(
+        long = "blas-impl",
+        value_name = "IMPLEMENTATION",
+        value_parser = clap::builder::PossibleValuesParser::new(
+            BLAS_IMPLEMENTATIONS.iter().map(|(implementation, _)| *implementation)
+        )
+    )]
+    blas_impl: Option<String>,
+    /// where a package version has both CPU-only and GPU builds, keep only those of this flavor
+    #[arg(long = "compute-flavor", value_name = "FLAVOR")]
+    compute_flavor: Option<Flavor>,
+    /// remove architecture-specific builds targeting CPython minor versions other than these
+    #[arg(
+        long = "python-versions",
+        value_name = "VERSIONS",
+        value_delimiter = ','
+    )]
+    python_versions: Vec<Version>,
+    /// remove PyPy and every package built for it
+    #[arg(long = "no-pypy")]
+    ban_pypy: bool,
+    /// remove packages whose build string matches this regular expression. Prefix with
+    /// `PACKAGE_NAME:` to only apply it to one package (i.e. `numpy:.*_debug.*`).
+    #[arg(long = "ban-build-regex", value_name = "REGEX", value_parser = build_regex_parser)]
+    ban_build_regexes: Vec<String>,
+    /// remove every package which depends on PACKAGE_NAME
+    #[arg(long = "ban-dependers", value_name = "PACKAGE_NAME")]
+    ban_dependers: Vec<String>,
+    /// remove every package outside the dependency closure of the `closure:` section of the
+    /// matchspecs YAML, or of the packages named by the user matchspecs if there is no such section
+    #[arg(long = "closure-only")]
+    closure_only: bool,
+    /// remove packages which nothing depends upon and which no user matchspec asks for, repeatedly
+    #[arg(long = "remove-orphans")]
+    remove_orphans: bool,
+    /// never remove packages matching this glob pattern as orphans
+    #[arg(long = "keep-orphan", value_name = "PATTERN", value_parser = glob_parser)]
+    keep_orphans: Vec<String>,
+    /// remove .tar.bz2 packages for which an equivalent .conda package exists
+    #[arg(long = "dedupe-formats")]
+    dedupe_formats: bool,
+    /// keep only the N newest versions of each package name
+    #[arg(
+        long = "keep-versions",
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    keep_versions: Option<usize>,
     /// don't remove development (dev) packages
     #[arg(long = "keep-dev", action=clap::ArgAction::SetFalse)]
     ban_dev: bool,
+    /// remove rebuilds without a variant hash (i.e. build `0`) which are superceded by a later
+    /// rebuild of the same version with identical depends
+    #[arg(long)]
+    prune_plain_rebuilds: bool,
+    /// keep only the builds of a package version whose build string contains the earliest listed
+    /// of these substrings, i.e. `--variant-preference openblas --variant-preference gnu`
+    #[arg(long = "variant-preference", value_name = "SUBSTRING")]
+    variant_preferences: Vec<String>,
     /// don't remove release candidate (rc) packages
     #[arg(long = "keep-rc", action=clap::ArgAction::SetFalse)]
     ban_rc: bool,
-    /// Base URL for downloading repodata
+    /// don't remove alpha (a, alpha) packages
+    #[arg(long = "keep-alpha", action=clap::ArgAction::SetFalse)]
+    ban_alpha: bool,
+    /// don't remove beta (b, beta, preview) packages
+    #[arg(long = "keep-beta", action=clap::ArgAction::SetFalse)]
+    ban_beta: bool,
+    /// remove packages whose version has a local version segment (i.e. `1.2.3+local`)
+    #[arg(long)]
+    ban_local_versions: bool,
+    /// remove packages whose version has a nonzero epoch (i.e. `1!2.0`), except those listed in
+    /// the `allow_epochs:` section of the matchspecs YAML
+    #[arg(long)]
+    ban_epochs: bool,
+    /// also remove packages whose constrains exclude every remaining provider of a package, or
+    /// which constrain a virtual package that is impossible on the architecture
+    #[arg(long)]
+    enforce_constrains: bool,
+    /// declare the version of a virtual package available on every machine (i.e.
+    /// `__glibc=2.28`). Packages depending on a newer version are removed. May be repeated.
+    #[arg(long = "virtual-package", value_name = "NAME=VERSION", value_parser = virtual_package_parser)]
+    virtual_packages: Vec<PackageRecord>,
+    /// file of package name patterns to remove, one per line (i.e. `qt*`)
+    #[arg(long, value_name = "FILE")]
+    deny_names: Option<std::path::PathBuf>,
+    /// file of package name patterns, one per line. Packages whose name matches none of them are
+    /// removed.
+    #[arg(long, value_name = "FILE")]
+    allow_names: Option<std::path::PathBuf>,
+    /// remove every build of the packages whose name matches this glob pattern (i.e. `qt-*`),
+    /// before any other rule, along with what depends on them. May be repeated.
+    #[arg(long = "ban-package", value_name = "NAME", value_parser = glob_parser)]
+    ban_packages: Vec<String>,
+    /// file of exact package filenames to remove, one per line (i.e.
+    /// `numpy-1.26.4-py311h64a7726_0.conda`)
+    #[arg(long, value_name = "FILE")]
+    ban_filenames: Option<std::path::PathBuf>,
+    /// filter the noarch packages on their own, without fetching any architecture, and write only
+    /// noarch/repodata.json. Depends on names which no noarch package provides are taken as
+    /// satisfied by the architectures
     #[arg(
-        long = "channel-alias",
-        default_value = "https://conda.anaconda.org/conda-forge/",
-        value_name = "CHANNEL_URL"
+        long,
+        conflicts_with_all = ["architectures", "exclude_architectures", "remove_dangling_depends"]
     )]
-    channel_alias: String,
+    noarch_only: bool,
+    /// file of the packages an earlier run removed, as written by --removed-list, to remove
+    /// before any rule so that what depends on them is removed too. Filenames prefixed with a
+    /// subdir (`linux-64/...`) are removed from that subdir only
+    #[arg(long, value_name = "FILE")]
+    pre_removed: Option<std::path::PathBuf>,
+    /// file of vulnerable releases to remove, one matchspec per line (i.e. `openssl ==3.0.0`),
+    /// optionally followed by an identifier such as a CVE id (i.e. `CVE-2022-3602`)
+    #[arg(long = "cve-denylist", value_name = "FILE")]
+    cve_denylist: Option<std::path::PathBuf>,
+    /// what to do with packages whose depends or constrains cannot be parsed. Kept packages are
+    /// treated as if the unparseable entries were absent.
+    #[arg(long = "bad-metadata", value_enum, default_value = "remove")]
+    bad_metadata: BadMetadata,
+    /// keep packages with an empty name or build string, or a version with empty segments or
+    /// not starting with a number, which are otherwise removed
+    #[arg(long = "keep-malformed")]
+    keep_malformed: bool,
+    /// remove packages whose weak or strong run exports match this matchspec (i.e. `openssl <3`),
+    /// using the `run_exports.json` of the channel. May be repeated.
+    #[arg(long = "ban-run-export", value_name = "MATCHSPEC", value_parser = named_matchspec_parser)]
+    ban_run_exports: Vec<String>,
+    /// parse the matchspecs given by the user (the matchspecs YAML, environment files, the CVE
+    /// denylist, --must-compatible-with and --ban-run-export) strictly, reporting every one that
+    /// does not parse. The dependencies in the repodata are always parsed leniently.
+    #[arg(long = "strict-matchspecs")]
+    strict_matchspecs: bool,
+    #[command(flatten)]
+    channel: ChannelArgs,
     /// Use cached repodata and do not make network calls
-    #[arg(long = "offline", action=clap::ArgAction::SetTrue)]
+    #[arg(
+        long = "offline",
+        action = clap::ArgAction::SetTrue,
+        env = "CONDA_CURATION_OFFLINE",
+        conflicts_with = "refresh",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
     is_offline: bool,
+    /// do not draw progress bars, which are otherwise drawn on stderr when stdout is a terminal,
+    /// nor print the table of the packages removed by each round
+    #[arg(short = 'q', long)]
+    quiet: bool,
+    /// how to print the packages removed by each round: a table row in the log, or a JSON object
+    /// per line carrying the architecture, label, removed count and seconds of the round
+    #[arg(long, value_enum, default_value_t = RoundFormat::Text)]
+    round_format: RoundFormat,
+    /// write the rounds of --round-format json to this file rather than stdout
+    #[arg(long, value_name = "PATH")]
+    round_output: Option<std::path::PathBuf>,
+    /// log more: DEBUG with -v and TRACE with -vv, which also raises the logging of dependencies
+    #[arg(short = 'v', long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// how to format the log: the plain messages, or a JSON object per line carrying the fields
+    /// of each message, such as the rule, filename and package of --explain messages
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// how many threads to filter with, instead of one per core. With 1, the run is sequential
+    #[arg(
+        long,
+        env = "CONDA_CURATION_THREADS",
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    threads: Option<usize>,
+    /// keep running, curating again every INTERVAL (i.e. `30m` or `6h`) with freshly fetched
+    /// repodata and rewriting only the outputs which changed. A failed cycle is retried at the
+    /// next tick, and SIGTERM stops once the cycle in flight is done.
+    #[arg(long, value_name = "INTERVAL", value_parser = interval_parser)]
+    watch: Option<Duration>,
+    /// disable a built-in rule. May be repeated.
+    #[arg(long = "skip-rule", value_name = "RULE", value_enum)]
+    skip_rules: Vec<Rule>,
     /// Emit the reasons why packages are being removed.
-    #[arg(short = 'e', long = "explain")]
+    #[arg(
+        short = 'e',
+        long = "explain",
+        env = "CONDA_CURATION_EXPLAIN",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
     explain: bool,
+    /// explain only the removals of packages whose name matches this glob pattern, along with
+    /// those a removed dependency matching it took down, by name or filename. Implies --explain.
+    /// May be repeated.
+    #[arg(long = "explain-package", value_name = "NAME", value_parser = glob_parser)]
+    explain_packages: Vec<String>,
+    /// print the explanations of each architecture once it is filtered, grouped by the rule
+    /// which removed the package or by the name of the package, with a count for each group
+    #[arg(long, value_enum, value_name = "GROUP")]
+    explain_group_by: Option<ExplainGroupBy>,
+    /// write every removal to this file as a JSON object on a line of its own, with the
+    /// filename, package name, architecture, rule and the fields of the rule, whether or not
+    /// --explain prints it. With --explain-package, only the removals it would print
+    #[arg(long, value_name = "PATH")]
+    explain_to: Option<std::path::PathBuf>,
     /// Write repodata.json files to the specified directory
-    #[arg(short = 'o', long = "output-dir", default_value = "out")]
+    #[arg(
+        short = 'o',
+        long = "output-dir",
+        env = "CONDA_CURATION_OUTPUT_DIR",
+        default_value = "out"
+    )]
     output_directory: std::path::PathBuf,
-    /// Which architectures to render index information for. If none are specified, will default to
-    /// all architectures.
-    #[arg(short = 'a', long = "architecture", value_parser = architectures_parser)]
-    architectures: Vec<String>,
-    matchspecs_yaml: std::path::PathBuf,
+    /// the `base_url` of every output repodata instead of the --channel-alias, such as where a
+    /// channel read from disk is served. A `base_url` of the upstream repodata is still kept.
+    #[arg(long, value_name = "URL", value_parser = base_url_parser)]
+    base_url: Option<String>,
+    /// fail when the channel does not publish one of the architectures, rather than leaving it
+    /// out of the run with a warning
+    #[arg(long = "strict-architectures")]
+    strict_architectures: bool,
+    /// keep packages whose subdir field names another subdir than the directory they are
+    /// published under, rather than removing them. A `noarch` subdir is allowed in the directory
+    /// of an architecture
+    #[arg(long)]
+    keep_subdir_mismatches: bool,
+    /// remove packages attributed to a channel matching this glob pattern, by the `channel`
+    /// field of their record in aggregated channels. May be repeated.
+    #[arg(long = "ban-origin-channel", value_name = "NAME", value_parser = glob_parser)]
+    ban_origin_channels: Vec<String>,
+    /// remove packages without a signature, when the channel publishes package signatures
+    #[arg(long)]
+    require_signatures: bool,
+    /// keep packages which the input repodata lists as removed, for channels where that list is
+    /// stale
+    #[arg(long)]
+    resurrect_removed: bool,
+    /// YAML file of edits to the depends of selected packages, applied before any filtering and
+    /// reflected in the output repodata
+    #[arg(long = "patch-depends", value_name = "FILE")]
+    patch_depends: Option<std::path::PathBuf>,
+    /// keep only the packages matching the conda dependencies of this environment.yml, as if
+    /// they were listed in the matchspecs YAML. May be repeated.
+    #[arg(long = "environment-file", value_name = "FILE")]
+    environment_files: Vec<std::path::PathBuf>,
+    /// keep only the packages of this name matching this matchspec, i.e. "python >=3.10", as
+    /// if it were listed in the matchspecs YAML. May be repeated.
+    #[arg(long = "matchspec", value_name = "MATCHSPEC")]
+    matchspecs: Vec<String>,
+    /// never remove the conda packages pinned by this conda-lock or pixi lockfile, and fail if
+    /// any of them is missing from the output. May be repeated.
+    #[arg(long = "lockfile", value_name = "FILE")]
+    lockfiles: Vec<std::path::PathBuf>,
+    /// YAML file setting any of these options, by field name (i.e. `channel_alias:`) or long
+    /// flag (i.e. `channel-alias:`), and optionally the matchspecs inline under `matchspecs:`.
+    /// Options given on the command line take precedence.
+    #[arg(long, value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
+    /// select a profile of the --config file, whose options replace those of its `base:`
+    #[arg(long, value_name = "NAME", requires = "config")]
+    profile: Option<String>,
+    /// print the configuration merged from the command line and --config, and exit
+    #[arg(long)]
+    show_config: bool,
+    /// do not write a zstd compressed repodata.json.zst alongside each repodata.json, and remove
+    /// any left by an earlier run
+    #[arg(long)]
+    no_zst: bool,
+    /// the zstd compression level of the repodata.json.zst, from 1 (fastest) to 22 (smallest)
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        default_value_t = 16,
+        value_parser = clap::value_parser!(i32).range(1..=22),
+        conflicts_with = "no_zst"
+    )]
+    zst_level: i32,
+    /// also write a bzip2 compressed repodata.json.bz2 alongside each repodata.json, for old
+    /// clients which look for no other, and remove any left by an earlier run without it
+    #[arg(long)]
+    bz2: bool,
+    /// also write current_repodata.json into each subdir, with the newest version of each
+    /// remaining package name and the packages they need, as conda-index does, and remove any
+    /// left by an earlier run without it
+    #[arg(long)]
+    write_current_repodata: bool,
+    /// add a `curation` marker, with the version of conda_curation and a hash of its options, to
+    /// the `info` of every output repodata, for channels which tolerate extra keys there
+    #[arg(long)]
+    stamp_info: bool,
+    /// write a JSON report of the run to this file: the package counts of each architecture
+    /// before and after, the removals and wall time of every round, and the most removed names
+    #[arg(long = "report-json", value_name = "PATH")]
+    report_json: Option<std::path::PathBuf>,
+    /// filter without writing any repodata, and print the removals of each architecture by rule,
+    /// the ten package names with the most removals and the removals of each package format,
+    /// which --report-json also carries
+    #[arg(long, conflicts_with_all = ["removed_list", "explain_to"])]
+    stats_only: bool,
+    /// write the filenames of every removed package, across all architectures and noarch, to
+    /// this file, one per line. It is replaced only once complete.
+    #[arg(long = "removed-list", value_name = "PATH")]
+    removed_list: Option<std::path::PathBuf>,
+    /// prefix the filenames of the --removed-list with their subdir (i.e.
+    /// `linux-64/numpy-2.0.0-py312h1234567_0.conda`)
+    #[arg(long = "removed-list-subdirs", requires = "removed_list")]
+    removed_list_subdirs: bool,
+    /// how many of the package names with the most removals to list in the --report-json
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    report_top: usize,
+    /// after filtering each architecture, print the N package names with the most removed
+    /// packages, by rule, with their share of all the removals
+    #[arg(long, value_name = "N")]
+    top_removed: Option<usize>,
+    /// fail with exit code 3 when a matchspec of the matchspecs YAML matches no package in the
+    /// source repodata of any requested architecture, rather than only warning
+    #[arg(long)]
+    fail_on_empty_spec: bool,
+    /// fail with exit code 3 when a package name of the matchspecs YAML is not the name of any
+    /// package of any architecture or of noarch, rather than only warning with the closest names
+    #[arg(long)]
+    fail_on_unknown_spec: bool,
+    /// fail with exit code 5, before writing any output, when more than this share of the
+    /// packages of an architecture, noarch included, or of the noarch output is removed (i.e.
+    /// `60%` or `0.6`)
+    #[arg(long, value_name = "SHARE", value_parser = share_parser)]
+    fail_on_removal_over: Option<f64>,
+    /// The matchspecs given inline in the --config file.
+    #[arg(skip)]
+    inline_matchspecs: Option<serde_yaml::Value>,
+    /// Every option of the run, as printed by --show-config.
+    #[arg(skip)]
+    effective_config: serde_yaml::Mapping,
+    /// The marker of --stamp-info.
+    #[arg(skip)]
+    info_stamp: Option<serde_json::Value>,
+    /// The package asked about by the `why` subcommand.
+    #[arg(skip)]
+    why: Option<String>,
+    /// the matchspecs YAML, or `-` to read it from stdin. Without it, and without matchspecs in
+    /// the --config file, no package is removed by user matchspecs.
+    matchspecs_yaml: Option<std::path::PathBuf>,
+}
+
+impl Cli {
+    /// How the matchspecs given by the user are parsed.
+    fn matchspec_strictness(&self) -> ParseStrictness {
+        if self.strict_matchspecs {
+            ParseStrictness::Strict
+        } else {
+            ParseStrictness::Lenient
+        }
+    }
+
+    /// The `base_url` of the output repodata of a subdir, unless its upstream repodata has one.
+    fn base_url(&self, subdir: &str) -> &str {
+        self.base_url
+            .as_deref()
+            .unwrap_or_else(|| self.channel.channel_aliases.for_subdir(subdir))
+    }
+
+    /// Warn when the output would be published under a channel alias on disk, which clients
+    /// elsewhere cannot download packages from.
+    fn warn_local_base_url(&self, architectures: &[String]) {
+        if self.base_url.is_some() || self.stats_only {
+            return;
+        }
+        let subdirs = architectures.iter().map(String::as_str).chain(["noarch"]);
+        if let Some(url) = subdirs
+            .map(|subdir| self.channel.channel_aliases.for_subdir(subdir))
+            .find(|url| url.starts_with("file:"))
+        {
+            warn!("warning: the output is published under {url}, give --base-url to change it");
+        }
+    }
+
+    /// How the output repodata is written.
+    fn output_format(&self) -> OutputFormat<'_> {
+        OutputFormat {
+            stamp: self.info_stamp.as_ref(),
+            zst_level: (!self.no_zst).then_some(self.zst_level),
+            bz2: self.bz2,
+            current: false,
+        }
+    }
+
+    /// How the `current_repodata.json` is written: like the `repodata.json`, but never compressed.
+    fn current_output_format(&self) -> OutputFormat<'_> {
+        OutputFormat {
+            zst_level: None,
+            bz2: false,
+            current: true,
+            ..self.output_format()
+        }
+    }
+}
+
+/// Filtering inputs which are derived from the command line once and then shared by the filtering
+/// of every architecture.
+struct Policy<'a> {
+    user_matchspecs: &'a MatchspecsYaml,
+    banned_features: HashSet<&'a str>,
+    banned_licenses: Vec<glob::Pattern>,
+    banned_origin_channels: Vec<glob::Pattern>,
+    banned_builds: Vec<(Option<String>, regex::Regex)>,
+    closure_roots: Option<HashMap<String, Vec<NamelessMatchSpec>>>,
+    kept_orphans: Vec<glob::Pattern>,
+    banned_prereleases: Vec<Prerelease>,
+    denied_names: Vec<glob::Pattern>,
+    banned_packages: Vec<glob::Pattern>,
+    banned_filenames: Vec<String>,
+    /// The lines of --pre-removed.
+    pre_removed: Vec<String>,
+    allowed_names: Option<Vec<glob::Pattern>>,
+    /// The `--must-compatible-with` anchors joined, naming them in logs of `--must-compatible-all`.
+    must_compatible_label: String,
+    depends_patches: Vec<DependsPatch>,
+    vulnerability_denylist: Vec<DenylistEntry>,
+    locked_packages: Vec<LockedPackage>,
+    explain: Explain,
+    explain_format: ExplainFormat,
+    /// Whether to keep the reason of every removal, to answer `why`.
+    retain_reasons: bool,
+    explain_to: Option<Arc<Mutex<ExplanationFile>>>,
+    /// When the run started, for rules relative to the current time.
+    now: DateTime<Utc>,
+}
+
+impl<'a> Policy<'a> {
+    fn new(args: &'a Cli, user_matchspecs: &'a MatchspecsYaml) -> Result<Self, Failure> {
+        Ok(Policy {
+            user_matchspecs,
+            banned_features: args.ban_features.iter().map(String::as_str).collect(),
+            must_compatible_label: args.must_compatible.join(" and "),
+            banned_licenses: args
+                .ban_licenses
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern).unwrap())
+                .collect(),
+            banned_origin_channels: args
+                .ban_origin_channels
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern).unwrap())
+                .collect(),
+            banned_builds: args
+                .ban_build_regexes
+                .iter()
+                .map(|value| {
+                    let (package_name, pattern) = split_build_regex(value);
+                    (
+                        package_name.map(str::to_string),
+                        regex::Regex::new(pattern).unwrap(),
+                    )
+                })
+                .collect(),
+            closure_roots: args.closure_only.then(|| match &user_matchspecs.closure {
+                Some(closure) => closure.clone(),
+                // An entry without any matchspecs bans a package, so it cannot be a root.
+                None => user_matchspecs
+                    .matchspecs
+                    .iter()
+                    .filter(|(_, specs)| !specs.is_empty())
+                    .map(|(package_name, specs)| (package_name.clone(), specs.clone()))
+                    .collect(),
+            }),
+            kept_orphans: args
+                .keep_orphans
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern).unwrap())
+                .collect(),
+            explain: Explain::new(args),
+            explain_format: ExplainFormat::new(args),
+            retain_reasons: args.why.is_some(),
+            explain_to: ExplanationFile::open(args)?,
+            banned_prereleases: [
+                (args.ban_dev, Prerelease::Dev),
+                (args.ban_rc, Prerelease::Rc),
+                (args.ban_alpha, Prerelease::Alpha),
+                (args.ban_beta, Prerelease::Beta),
+            ]
+            .into_iter()
+            .filter_map(|(banned, prerelease)| banned.then_some(prerelease))
+            .collect(),
+            denied_names: args
+                .deny_names
+                .as_deref()
+                .map(read_name_patterns)
+                .transpose()
+                .map_err(Failure::input("failed to load --deny-names file"))?
+                .unwrap_or_default(),
+            banned_packages: args
+                .ban_packages
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern).unwrap())
+                .collect(),
+            banned_filenames: read_optional_list(args.ban_filenames.as_deref(), "--ban-filenames")?,
+            pre_removed: read_optional_list(args.pre_removed.as_deref(), "--pre-removed")?,
+            vulnerability_denylist: args
+                .cve_denylist
+                .as_deref()
+                .map(|filename| read_denylist(filename, args.matchspec_strictness()))
+                .transpose()
+                .map_err(Failure::input("failed to load --cve-denylist file"))?
+                .unwrap_or_default(),
+            locked_packages: args
+                .lockfiles
+                .iter()
+                .map(|filename| read_lockfile(filename))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(Failure::input("failed to load --lockfile file"))?
+                .into_iter()
+                .flatten()
+                .collect(),
+            depends_patches: args
+                .patch_depends
+                .as_deref()
+                .map(read_depends_patches)
+                .transpose()
+                .map_err(Failure::input("failed to load --patch-depends file"))?
+                .unwrap_or_default(),
+            now: Utc::now(),
+            allowed_names: args
+                .allow_names
+                .as_deref()
+                .map(read_name_patterns)
+                .transpose()
+                .map_err(Failure::input("failed to load --allow-names file"))?,
+        })
+    }
+}
+
+/// The lines of the list file given as `argument`, if any.
+fn read_optional_list(
+    filename: Option<&std::path::Path>,
+    argument: &str,
+) -> Result<Vec<String>, Failure> {
+    filename
+        .map(read_list)
+        .transpose()
+        .map_err(Failure::input(format!("failed to load {argument} file")))
+        .map(Option::unwrap_or_default)
+}
+
+/// Why a run failed, with a one line diagnostic. Each kind exits with its own code, as listed by
+/// `EXIT_CODES_HELP`.
+enum Failure {
+    Input(String),
+    EmptySpecs(String),
+    MissingAnchor(String),
+    RemovalOver(String),
+    Output(String),
+    LockedMissing(String),
+}
+
+impl Failure {
+    /// Describe an error loading or validating the input named `what`.
+    fn input<E: std::fmt::Display>(what: impl std::fmt::Display) -> impl FnOnce(E) -> Failure {
+        move |err| Failure::Input(format!("{what}: {err}"))
+    }
+
+    /// Describe an error writing the output named `what`.
+    fn output<E: std::fmt::Display>(what: impl std::fmt::Display) -> impl FnOnce(E) -> Failure {
+        move |err| Failure::Output(format!("{what}: {err}"))
+    }
+
+    fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Failure::Input(_) => 1,
+            Failure::EmptySpecs(_) => 3,
+            Failure::MissingAnchor(_) => 4,
+            Failure::RemovalOver(_) => 5,
+            Failure::Output(_) => 6,
+            Failure::LockedMissing(_) => 7,
+        })
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Failure::Input(message)
+            | Failure::EmptySpecs(message)
+            | Failure::MissingAnchor(message)
+            | Failure::RemovalOver(message)
+            | Failure::Output(message)
+            | Failure::LockedMissing(message) => f.write_str(message),
+        }
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let mut args = Cli::parse();
-    if !args.channel_alias.ends_with('/') {
-        args.channel_alias += "/";
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("error: {failure}");
+            failure.exit_code()
+        }
+    }
+}
+
+/// Set up the progress bars, the logging and the thread pool of the run.
+fn init_run(args: &Cli) -> Result<Progress, Failure> {
+    let progress = Progress::new(args.quiet);
+    init_logging(
+        args.verbose,
+        matches!(args.log_format, LogFormat::Json),
+        &progress,
+    );
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(Failure::input("failed to start the thread pool"))?;
+    }
+    if args.skip_rules.contains(&Rule::Unresolveable) {
+        warn!(
+            "warning: --skip-rule unresolveable keeps packages whose dependencies were removed, \
+             the output may be unsolvable"
+        );
+    }
+    Ok(progress)
+}
+
+async fn run() -> Result<(), Failure> {
+    let args = match parse_args()? {
+        Task::Curate(args) => *args,
+        Task::Fetch(args) => return fetch(&args).await,
+        Task::ListArchitectures(args) => return list_architectures(&args).await,
+        Task::Diff(args) => return diff(&args),
+    };
+    let progress = init_run(&args)?;
+
+    if args.why.is_none() && !args.stats_only {
+        std::fs::create_dir_all(&args.output_directory)
+            .map_err(Failure::output("failed to create output directory"))?;
+    }
+
+    let user_matchspecs = load_user_matchspecs(&args)?;
+    let rounds = RoundSink::new(&args, &progress)?;
+    match args.watch {
+        Some(interval) if args.why.is_none() => {
+            watch(interval, &args, &user_matchspecs, &progress, &rounds).await
+        }
+        _ => curate(&args, &user_matchspecs, &progress, &rounds).await,
+    }
+}
+
+/// Download the repodata of every architecture into the cache, without filtering it.
+async fn fetch(args: &FetchArgs) -> Result<(), Failure> {
+    let progress = Progress::new(args.quiet);
+    init_logging(args.verbose, false, &progress);
+    args.channel.log_architectures();
+    for source in args.channel.sources(false) {
+        let filenames = rawrepodata::fetch_repodata(
+            &source,
+            &args.channel.architectures,
+            args.run_exports,
+            args.strict_architectures,
+            &progress,
+        )
+        .await
+        .map_err(Failure::input("failed to download repodata"))?;
+        args.channel.check_cache_ages(&filenames.caches)?;
+        info!(
+            "cached the repodata of {} and noarch",
+            filenames.architectures.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Print the subdirs the channel publishes, one per line with the size and last modification of
+/// its repodata.
+#[allow(clippy::cast_precision_loss)]
+async fn list_architectures(args: &ListArchitecturesArgs) -> Result<(), Failure> {
+    init_logging(args.verbose, false, &Progress::new(true));
+    let mut subdirs = args.channel.architectures.clone();
+    subdirs.push("noarch".to_string());
+    let published = rawrepodata::list_subdirs(
+        &args.channel.channel_aliases,
+        &subdirs,
+        args.is_offline,
+        args.channel.cache_dir.as_deref(),
+    )
+    .await
+    .map_err(Failure::input("failed to list the subdirs of the channel"))?;
+    if published.is_empty() {
+        println!("no subdirs published");
+    }
+    for subdir in published {
+        let size = subdir.size.map_or_else(
+            || "unknown size".to_string(),
+            |size| format!("{:.1} MiB", size as f64 / 1024.0 / 1024.0),
+        );
+        let last_modified = subdir.last_modified.map_or_else(
+            || "unknown".to_string(),
+            |time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        );
+        println!("{:<20} {size:>12}  {last_modified}", subdir.subdir);
+    }
+    Ok(())
+}
+
+/// Print how the repodata of two output directories differs, a summary line for each subdir
+/// followed by its packages: `+` added, `-` removed and `~` changed.
+fn diff(args: &DiffArgs) -> Result<(), Failure> {
+    let diffs = diff_output_directories(&args.old, &args.new)
+        .map_err(Failure::input("failed to compare the output directories"))?;
+    if diffs.is_empty() {
+        println!("no differences");
+    }
+    for diff in diffs {
+        println!(
+            "{}: {} added, {} removed, {} changed",
+            diff.subdir,
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        );
+        for (sign, filenames) in [
+            ("+", &diff.added),
+            ("-", &diff.removed),
+            ("~", &diff.changed),
+        ] {
+            for filename in filenames {
+                println!("{sign} {}/{filename}", diff.subdir);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Curate every `interval` until asked to terminate, by SIGTERM or Ctrl-C, which lets the cycle
+/// in flight finish first. A failed cycle, such as one whose downloads failed, is logged and
+/// retried at the next tick.
+async fn watch(
+    interval: Duration,
+    args: &Cli,
+    user_matchspecs: &MatchspecsYaml,
+    progress: &Progress,
+    rounds: &RoundSink,
+) -> Result<(), Failure> {
+    let mut termination =
+        Termination::new().map_err(Failure::input("failed to listen for termination"))?;
+    loop {
+        let start = Instant::now();
+        if let Err(failure) = curate(args, user_matchspecs, progress, rounds).await {
+            error!("error: {failure}");
+        }
+        let wait = interval.saturating_sub(start.elapsed());
+        info!("next curation in {}s", wait.as_secs());
+        tokio::select! {
+            () = tokio::time::sleep(wait) => {}
+            () = termination.requested() => {
+                info!("terminating");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Listens for the requests to terminate a `--watch`.
+struct Termination {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Termination {
+    /// Start listening, so that a request made while a cycle runs is kept until asked for.
+    fn new() -> std::io::Result<Self> {
+        Ok(Termination {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?,
+        })
+    }
+
+    async fn requested(&mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = self.terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Fetch, filter and write the repodata of every architecture once.
+async fn curate(
+    args: &Cli,
+    user_matchspecs: &MatchspecsYaml,
+    progress: &Progress,
+    rounds: &RoundSink,
+) -> Result<(), Failure> {
+    let policy = Policy::new(args, user_matchspecs)?;
+    let matchspec_cache = MatchspecCache::with_capacity(1024 * 192);
+    let mut resources = ResourceReport::default();
+
+    let start = Instant::now();
+    let fetched = fetch_repodata(args, progress).await?;
+    let caches: Vec<SubdirCache> = fetched
+        .iter()
+        .flat_map(|filenames| filenames.caches.iter().cloned())
+        .collect();
+    resources.record("fetch", start.elapsed(), resident_bytes());
+
+    let start = Instant::now();
+    let LoadedRepodata {
+        architectures,
+        noarch: mut repodata_noarch,
+        noarch_extras,
+        noarch_run_exports,
+        mut repodatas,
+        extras,
+        run_exports,
+    } = load_channels(args, fetched, progress)?;
+    patch_depends(
+        &policy.depends_patches,
+        &matchspec_cache,
+        &mut repodatas,
+        &mut repodata_noarch,
+    )?;
+    resources.record("parse", start.elapsed(), resident_bytes());
+
+    check_exact_pins(user_matchspecs, repodatas.iter().chain([&repodata_noarch]))?;
+    warn_unused_entries(&architectures, &policy, &repodatas, &repodata_noarch);
+    check_user_specs(
+        args,
+        &architectures,
+        user_matchspecs,
+        &repodatas,
+        &repodata_noarch,
+    )?;
+
+    let empty_repodata = empty_repodata();
+    let subdirs = subdir_metadatas(
+        &architectures,
+        &repodatas,
+        &extras,
+        &run_exports,
+        SubdirMetadata {
+            repodata_noarch: &repodata_noarch,
+            repodata_arch: &empty_repodata,
+            run_exports: noarch_run_exports.iter().collect(),
+            extras: [&noarch_extras, &noarch_extras],
+        },
+    );
+
+    if let Some(target) = &args.why {
+        return explain_why(target, &subdirs, args, &matchspec_cache, &policy, progress);
+    }
+    args.warn_local_base_url(&architectures);
+    let mut manifest = start_manifest(args, policy.now, &caches)?;
+    let curated = curate_subdirs(
+        &subdirs,
+        args,
+        &matchspec_cache,
+        &policy,
+        progress,
+        rounds,
+        &mut resources,
+    )?;
+    if !args.noarch_only {
+        explain_noarch_removals(&policy, &repodata_noarch, &curated.noarch_removals);
+    }
+    ExplanationFile::close(policy.explain_to.as_deref())?;
+    if let Some(manifest) = &mut manifest {
+        let start = Instant::now();
+        let noarch_counts = write_noarch_and_removed_list(
+            args,
+            &repodata_noarch,
+            &noarch_extras.signatures,
+            &curated.noarch_removals,
+            curated.removed_lists,
+            curated.noarch_current.as_ref(),
+        )?;
+        manifest.subdirs.push(noarch_counts);
+        resources.record("write", start.elapsed(), resident_bytes());
+    }
+    conclude(
+        args,
+        &curated_locked_packages(&policy.locked_packages, &architectures),
+        curated.reports,
+        resources,
+        manifest,
+    )
+}
+
+/// Begin the manifest of the run, with the hashes of its input files, and remove that of an
+/// earlier run, which no longer describes the output directory. There is none with --stats-only,
+/// which writes nothing.
+fn start_manifest(
+    args: &Cli,
+    started: DateTime<Utc>,
+    caches: &[SubdirCache],
+) -> Result<Option<CurationManifest>, Failure> {
+    if args.stats_only {
+        return Ok(None);
+    }
+    remove_manifest(&args.output_directory).map_err(Failure::output(
+        "failed to remove the manifest of an earlier run",
+    ))?;
+    let mut manifest = CurationManifest::new(started, args.effective_config.clone());
+    let inputs = args
+        .config
+        .iter()
+        .chain(
+            args.matchspecs_yaml
+                .iter()
+                .filter(|path| path.as_os_str() != "-"),
+        )
+        .chain(&args.environment_files)
+        .chain(&args.lockfiles)
+        .chain(
+            [
+                &args.patch_depends,
+                &args.deny_names,
+                &args.allow_names,
+                &args.ban_filenames,
+                &args.pre_removed,
+                &args.cve_denylist,
+            ]
+            .into_iter()
+            .flatten(),
+        );
+    for filename in inputs {
+        manifest
+            .add_input(filename)
+            .map_err(Failure::input(format!(
+                "failed to hash {}",
+                filename.display()
+            )))?;
+    }
+    manifest.upstream = caches.iter().map(UpstreamRepodata::from).collect();
+    Ok(Some(manifest))
+}
+
+/// Print the resources used, write the report and check the outcome of the run, then write the
+/// manifest, if any, last.
+fn conclude(
+    args: &Cli,
+    locked_packages: &[&LockedPackage],
+    reports: Vec<ArchitectureReport>,
+    resources: ResourceReport,
+    manifest: Option<CurationManifest>,
+) -> Result<(), Failure> {
+    if !args.quiet {
+        log_resources(&resources);
+    }
+    if args.stats_only {
+        log_statistics(&reports);
+    }
+    let report = RunReport::new(reports, resources);
+    if let Some(report_json) = &args.report_json {
+        report
+            .write(report_json)
+            .map_err(Failure::output("failed to write --report-json"))?;
+    }
+    if !args.stats_only {
+        verify_locked_output(&args.output_directory, locked_packages)?;
+    }
+    let Some(mut manifest) = manifest else {
+        return Ok(());
+    };
+    manifest.subdirs.extend(
+        report
+            .architectures
+            .iter()
+            // With --noarch-only, noarch is counted as written.
+            .filter(|architecture| architecture.architecture != "noarch")
+            .map(|architecture| SubdirCounts {
+                subdir: architecture.architecture.clone(),
+                packages_before: architecture.packages_before,
+                packages_after: architecture.packages_after,
+            }),
+    );
+    manifest
+        .write(&args.output_directory)
+        .map_err(Failure::output("failed to write the manifest"))
+}
+
+/// A repodata without any package, which stands in for the architecture of `--noarch-only`.
+fn empty_repodata() -> RepoData {
+    RepoData {
+        info: None,
+        packages: HashMap::default(),
+        conda_packages: HashMap::default(),
+        removed: HashSet::default(),
+        version: None,
+    }
+}
+
+/// Fetch the repodata of the architectures the channel, or each --channel, publishes, failing if
+/// none is published. With several --channel, an architecture only needs one of them to publish
+/// it, even with --strict-architectures.
+async fn fetch_repodata(
+    args: &Cli,
+    progress: &Progress,
+) -> Result<Vec<rawrepodata::RepodataFilenames>, Failure> {
+    let architectures: &[String] = if args.noarch_only {
+        &[]
+    } else {
+        args.channel.log_architectures();
+        &args.channel.architectures
+    };
+    let sources = args.channel.sources(args.is_offline);
+    let merging = sources.len() > 1;
+    let mut fetched = Vec::with_capacity(sources.len());
+    for source in &sources {
+        let filenames = rawrepodata::fetch_repodata(
+            source,
+            architectures,
+            !args.ban_run_exports.is_empty(),
+            args.strict_architectures && !merging,
+            progress,
+        )
+        .await
+        .map_err(Failure::input("failed to download repodata"))?;
+        args.channel.check_cache_ages(&filenames.caches)?;
+        fetched.push(filenames);
+    }
+    let mut missing = architectures.iter().filter(|architecture| {
+        !fetched
+            .iter()
+            .any(|filenames| filenames.architectures.contains(architecture))
+    });
+    if merging && args.strict_architectures {
+        if let Some(architecture) = missing.next() {
+            return Err(Failure::Input(format!(
+                "no --channel publishes {architecture}"
+            )));
+        }
+    }
+    if fetched
+        .iter()
+        .all(|filenames| filenames.architectures.is_empty())
+        && !args.noarch_only
+    {
+        return Err(Failure::Input(
+            "the channel publishes none of the requested architectures".to_string(),
+        ));
+    }
+    Ok(fetched)
+}
+
+/// The noarch filenames removed for every architecture, and those which must be removed
+/// regardless.
+type NoarchRemovals<'a> = (HashSet<&'a str>, HashSet<&'a str>);
+
+/// The outcome of filtering every architecture.
+struct CuratedSubdirs<'a> {
+    noarch_removals: NoarchRemovals<'a>,
+    reports: Vec<ArchitectureReport>,
+    /// The filenames removed from the repodata of each architecture, by architecture.
+    removed_lists: Vec<(&'a str, Vec<&'a str>)>,
+    /// The noarch filenames current for any architecture, with --write-current-repodata.
+    noarch_current: Option<HashSet<&'a str>>,
+}
+
+/// Filter the repodata of every architecture, then write it unless too much of a subdir was
+/// removed, returning the noarch filenames removed for every architecture, those which must be
+/// removed regardless, and the reports of the architectures.
+fn curate_subdirs<'a>(
+    subdirs: &[(&'a str, SubdirMetadata<'a>)],
+    args: &'a Cli,
+    matchspec_cache: &'a MatchspecCache<'a, 'a>,
+    policy: &'a Policy<'a>,
+    progress: &Progress,
+    rounds: &RoundSink,
+    resources: &mut ResourceReport,
+) -> Result<CuratedSubdirs<'a>, Failure> {
+    let curated = subdirs
+        .iter()
+        .map(|(architecture, metadata)| {
+            let curated = curate_subdir(
+                architecture,
+                args,
+                matchspec_cache,
+                policy,
+                metadata,
+                progress,
+                rounds,
+            )?;
+            resources.record(
+                &format!("filter {architecture}"),
+                curated.filter_time,
+                curated.filter_resident_bytes,
+            );
+            check_max_removal(args.fail_on_removal_over, &curated.report)?;
+            Ok(curated)
+        })
+        .collect::<Result<Vec<_>, Failure>>()?;
+    // A noarch package is left out of the noarch output once every architecture removed it.
+    let mut common_filtered_fns: Option<HashSet<&str>> = None;
+    let mut unconditional_fns = HashSet::new();
+    let mut noarch_current: Option<HashSet<&str>> = None;
+    for curated in &curated {
+        match &mut common_filtered_fns {
+            Some(common) => common.retain(|filename| curated.removed.contains(filename)),
+            None => common_filtered_fns = Some(curated.removed.clone()),
+        }
+        unconditional_fns.extend(&curated.unconditional);
+        if let Some(current) = &curated.current {
+            noarch_current
+                .get_or_insert_with(HashSet::new)
+                .extend(current);
+        }
+    }
+    let common_filtered_fns = common_filtered_fns.unwrap_or_default();
+    if let Some(limit) = args.fail_on_removal_over {
+        check_max_noarch_removal(
+            limit,
+            subdirs[0].1.repodata_noarch,
+            &common_filtered_fns,
+            &unconditional_fns,
+        )?;
+    }
+    // Nothing is written until every subdir passed --fail-on-removal-over.
+    let mut reports = Vec::with_capacity(curated.len());
+    let mut removed_lists = Vec::with_capacity(curated.len());
+    let mut writes = Vec::with_capacity(curated.len());
+    for (curated, (architecture, metadata)) in curated.into_iter().zip(subdirs) {
+        reports.push(curated.report);
+        if args.noarch_only {
+            // noarch is the only subdir, and is written on its own.
+            continue;
+        }
+        if !args.stats_only {
+            writes.push((*architecture, metadata, curated.removed, curated.current));
+        }
+        removed_lists.push((*architecture, curated.removed_from_subdir));
+    }
+    if !writes.is_empty() {
+        // The architectures are serialized and compressed in parallel.
+        let start = Instant::now();
+        let written: Vec<Result<WrittenRepodata, Failure>> = writes
+            .par_iter()
+            .map(|(architecture, metadata, removed, current)| {
+                let write = |predicate: &dyn Fn(&str) -> bool, format| {
+                    filtered_repodata_to_file(
+                        metadata.repodata_arch,
+                        &args.output_directory,
+                        predicate,
+                        architecture,
+                        args.base_url(architecture),
+                        &metadata.extras[0].signatures,
+                        format,
+                    )
+                    .map_err(Failure::output(format!(
+                        "failed to write the repodata of {architecture}"
+                    )))
+                };
+                let written = write(&|pkfn| !removed.contains(pkfn), args.output_format())?;
+                match current {
+                    Some(current) => {
+                        write(&|pkfn| current.contains(pkfn), args.current_output_format())?;
+                    }
+                    None => remove_current_repodata(&args.output_directory, architecture)?,
+                }
+                Ok(written)
+            })
+            .collect();
+        for ((architecture, ..), written) in writes.iter().zip(written) {
+            log_written(architecture, written?);
+        }
+        resources.record("write", start.elapsed(), resident_bytes());
+    }
+    Ok(CuratedSubdirs {
+        noarch_removals: (common_filtered_fns, unconditional_fns),
+        reports,
+        removed_lists,
+        noarch_current,
+    })
+}
+
+/// Write the noarch repodata once every architecture is written, then the --removed-list.
+fn write_noarch_and_removed_list<'a>(
+    args: &Cli,
+    repodata_noarch: &'a RepoData,
+    noarch_signatures: &'a Signatures,
+    (common_filtered_fns, unconditional_fns): &NoarchRemovals,
+    mut removed_lists: Vec<(&str, Vec<&'a str>)>,
+    noarch_current: Option<&HashSet<&str>>,
+) -> Result<SubdirCounts, Failure> {
+    let noarch_removed = write_noarch_repodata(
+        args,
+        repodata_noarch,
+        noarch_signatures,
+        common_filtered_fns,
+        unconditional_fns,
+    )?;
+    match noarch_current {
+        Some(current) => {
+            filtered_repodata_to_file(
+                repodata_noarch,
+                &args.output_directory,
+                |pkfn| current.contains(pkfn) && !unconditional_fns.contains(pkfn),
+                "noarch",
+                args.base_url("noarch"),
+                noarch_signatures,
+                args.current_output_format(),
+            )
+            .map_err(Failure::output("failed to write the noarch repodata"))?;
+        }
+        None => remove_current_repodata(&args.output_directory, "noarch")?,
+    }
+    let packages_before = repodata_noarch.packages.len() + repodata_noarch.conda_packages.len();
+    let counts = SubdirCounts {
+        subdir: "noarch".to_string(),
+        packages_before,
+        packages_after: packages_before - noarch_removed.len(),
+    };
+    if let Some(removed_list) = &args.removed_list {
+        removed_lists.push(("noarch", noarch_removed));
+        write_removed_list(removed_list, args.removed_list_subdirs, &removed_lists)?;
+    }
+    Ok(counts)
+}
+
+/// Write the filenames removed from every subdir, one per line, optionally prefixed with the
+/// subdir.
+fn write_removed_list(
+    filename: &std::path::Path,
+    with_subdirs: bool,
+    removed_lists: &[(&str, Vec<&str>)],
+) -> Result<(), Failure> {
+    let lines = removed_lists.iter().flat_map(|(subdir, removed)| {
+        removed.iter().map(move |filename| {
+            if with_subdirs {
+                format!("{subdir}/{filename}")
+            } else {
+                (*filename).to_string()
+            }
+        })
+    });
+    write_list(filename, lines).map_err(Failure::output("failed to write --removed-list"))
+}
+
+/// Abort before anything is written when more than `limit` of the packages of an architecture
+/// were removed, naming the rounds which removed the most.
+fn check_max_removal(limit: Option<f64>, report: &ArchitectureReport) -> Result<(), Failure> {
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    if report.removed_share() <= limit {
+        return Ok(());
+    }
+    let contributions: Vec<String> = report
+        .removed_by_rule()
+        .iter()
+        .filter(|rule| rule.removed > 0)
+        .take(5)
+        .map(|rule| format!("{} {}", rule.rule, rule.removed))
+        .collect();
+    Err(Failure::RemovalOver(format!(
+        "{:.1}% of the packages of {} removed, over --fail-on-removal-over {:.1}%, nothing was \
+         written; most removed by: {}",
+        report.removed_share() * 100.0,
+        report.architecture,
+        limit * 100.0,
+        contributions.join(", ")
+    )))
+}
+
+/// Abort before anything is written when more than `limit` of the noarch packages would be
+/// removed from the noarch output.
+#[allow(clippy::cast_precision_loss)]
+fn check_max_noarch_removal(
+    limit: f64,
+    repodata_noarch: &RepoData,
+    common_filtered_fns: &HashSet<&str>,
+    unconditional_fns: &HashSet<&str>,
+) -> Result<(), Failure> {
+    let total = repodata_noarch.packages.len() + repodata_noarch.conda_packages.len();
+    let removed = repodata_noarch
+        .packages
+        .keys()
+        .chain(repodata_noarch.conda_packages.keys())
+        .filter(|filename| {
+            common_filtered_fns.contains(filename.as_str())
+                || unconditional_fns.contains(filename.as_str())
+        })
+        .count();
+    if total == 0 {
+        return Ok(());
+    }
+    let share = removed as f64 / total as f64;
+    if share <= limit {
+        return Ok(());
+    }
+    Err(Failure::RemovalOver(format!(
+        "{:.1}% of the noarch packages removed, over --fail-on-removal-over {:.1}%, nothing was \
+         written",
+        share * 100.0,
+        limit * 100.0
+    )))
+}
+
+/// The packages of the `--lockfile`s in the subdirs curated by the run, i.e. noarch and the
+/// architectures fetched. Those of other subdirs are neither checked nor warned about.
+fn curated_locked_packages<'a>(
+    locked_packages: &'a [LockedPackage],
+    architectures: &[String],
+) -> Vec<&'a LockedPackage> {
+    locked_packages
+        .iter()
+        .filter(|package| package.subdir == "noarch" || architectures.contains(&package.subdir))
+        .collect()
+}
+
+/// Fail unless every package of the `--lockfile`s made it into the output repodata.
+fn verify_locked_output(
+    output_directory: &std::path::Path,
+    locked_packages: &[&LockedPackage],
+) -> Result<(), Failure> {
+    let mut outputs: HashMap<&str, Option<RepoData>> = HashMap::new();
+    let missing: Vec<String> = locked_packages
+        .iter()
+        .filter(|package| {
+            let output = outputs.entry(&package.subdir).or_insert_with(|| {
+                RepoData::from_path(output_directory.join(&package.subdir).join("repodata.json"))
+                    .ok()
+            });
+            !output.as_ref().is_some_and(|output| {
+                package.filenames().iter().any(|filename| {
+                    output.packages.contains_key(filename)
+                        || output.conda_packages.contains_key(filename)
+                })
+            })
+        })
+        .map(|package| format!("{}/{}", package.subdir, package.filenames()[0]))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Failure::LockedMissing(format!(
+            "locked packages missing from the output: {}",
+            missing.join(", ")
+        )))
+    }
+}
+
+/// The outcome of filtering one architecture.
+struct CuratedSubdir<'a> {
+    /// The filenames of the removed packages, noarch ones included.
+    removed: HashSet<&'a str>,
+    /// The filenames of the noarch packages which must be removed regardless.
+    unconditional: HashSet<&'a str>,
+    /// The filenames removed from the repodata of the architecture itself, sorted.
+    removed_from_subdir: Vec<&'a str>,
+    /// The filenames of the packages of `current_repodata.json`, noarch ones included.
+    current: Option<HashSet<&'a str>>,
+    report: ArchitectureReport,
+    filter_time: Duration,
+    /// The memory resident once filtered.
+    filter_resident_bytes: Option<u64>,
+}
+
+/// What to filter: each architecture along with noarch, or noarch alone when no architecture
+/// was fetched, as with --noarch-only. `noarch` is the metadata of noarch on its own, on top of
+/// an empty architecture.
+fn subdir_metadatas<'a>(
+    architectures: &'a [String],
+    repodatas: &'a [RepoData],
+    extras: &'a [RepodataExtras],
+    run_exports: &'a [Option<RunExportsIndex>],
+    noarch: SubdirMetadata<'a>,
+) -> Vec<(&'a str, SubdirMetadata<'a>)> {
+    if architectures.is_empty() {
+        return vec![("noarch", noarch)];
+    }
+    architectures
+        .iter()
+        .zip(repodatas.iter().zip(extras))
+        .zip(run_exports)
+        .map(
+            |((architecture, (repodata_arch, extras_arch)), run_exports_arch)| {
+                let metadata = SubdirMetadata {
+                    repodata_noarch: noarch.repodata_noarch,
+                    repodata_arch,
+                    run_exports: run_exports_arch
+                        .iter()
+                        .chain(noarch.run_exports.iter().copied())
+                        .collect(),
+                    extras: [extras_arch, noarch.extras[1]],
+                };
+                (architecture.as_str(), metadata)
+            },
+        )
+        .collect()
+}
+
+/// Explain the noarch packages left out of the noarch repodata, which no round of a single
+/// architecture decides.
+fn explain_noarch_removals<'a>(
+    policy: &Policy,
+    repodata_noarch: &'a RepoData,
+    (common_filtered_fns, unconditional_fns): &NoarchRemovals,
+) {
+    if matches!(policy.explain, Explain::Nothing) && policy.explain_to.is_none() {
+        return;
+    }
+    let logs: Vec<RemovedFromNoarchLog<'a>> = rawrepodata::sorted_iter(&[repodata_noarch])
+        .into_iter()
+        .filter_map(|(filename, record)| {
+            let unconditional = unconditional_fns.contains(filename.as_str());
+            (unconditional || common_filtered_fns.contains(filename.as_str())).then(|| {
+                RemovedFromNoarchLog {
+                    filename,
+                    package_name: record.name.as_source(),
+                    unconditional,
+                }
+            })
+        })
+        .collect();
+    let mut removals = Removals::new(
+        "noarch",
+        logs.len(),
+        &Progress::default(),
+        &RoundSink::Hidden,
+        policy,
+        &[],
+    );
+    removals.round("noarch", || logs);
+    removals.finish_progress();
+}
+
+/// Filter the repodata of one architecture.
+fn curate_subdir<'a>(
+    architecture: &'a str,
+    args: &'a Cli,
+    matchspec_cache: &'a MatchspecCache<'a, 'a>,
+    policy: &'a Policy<'a>,
+    metadata: &SubdirMetadata<'a>,
+    progress: &Progress,
+    rounds: &RoundSink,
+) -> Result<CuratedSubdir<'a>, Failure> {
+    let _span = info_span!("architecture", architecture).entered();
+    info!("{architecture}-----");
+    let start = Instant::now();
+    let removals = filter_repodata(
+        architecture,
+        args,
+        matchspec_cache,
+        policy,
+        metadata,
+        progress,
+        rounds,
+    )?;
+    let filter_time = start.elapsed();
+    let filter_resident_bytes = resident_bytes();
+    let tally = &removals.output.tally;
+    if let Some(count) = args.top_removed {
+        log_top_removed(architecture, tally, count);
+    }
+    let mut report = ArchitectureReport {
+        architecture: architecture.to_string(),
+        packages_before: removals.package_count,
+        packages_after: removals.package_count - removals.filenames.len(),
+        most_removed: tally.most_removed(args.report_top),
+        rounds: removals.rounds,
+        statistics: None,
+    };
+    if args.stats_only {
+        report.statistics = Some(removal_statistics(
+            &report,
+            [metadata.repodata_arch, metadata.repodata_noarch],
+            &removals.filenames,
+        ));
+    }
+    let mut removed_from_subdir: Vec<&'a str> = metadata
+        .repodata_arch
+        .packages
+        .keys()
+        .chain(metadata.repodata_arch.conda_packages.keys())
+        .map(String::as_str)
+        .filter(|filename| removals.filenames.contains(filename))
+        .collect();
+    removed_from_subdir.sort_unstable();
+    Ok(CuratedSubdir {
+        removed: removals.filenames,
+        unconditional: removals.unconditional,
+        removed_from_subdir,
+        current: removals.current,
+        report,
+        filter_time,
+        filter_resident_bytes,
+    })
+}
+
+/// Print the `count` package names with the most packages removed from an architecture, with
+/// the rules which removed them and their share of all its removals.
+#[allow(clippy::cast_precision_loss)]
+fn log_top_removed(architecture: &str, tally: &RemovalTally, count: usize) {
+    let share = |removed: usize| {
+        if tally.total() == 0 {
+            0.0
+        } else {
+            removed as f64 * 100.0 / tally.total() as f64
+        }
+    };
+    info!("top {count} removed {architecture}-----");
+    let names = tally.most_removed(count);
+    for name in &names {
+        let by_rule: Vec<String> = name
+            .by_rule
+            .iter()
+            .map(|rule| format!("{} {}", rule.rule, rule.removed))
+            .collect();
+        info!(
+            "{:>20}: {:>7} {:>5.1}%  {}",
+            name.name,
+            name.removed,
+            share(name.removed),
+            by_rule.join(", ")
+        );
+    }
+    let removed = names.iter().map(|name| name.removed).sum();
+    info!(
+        "{:>20}: {:>7} {:>5.1}% of {}",
+        format!("top {}", names.len()),
+        removed,
+        share(removed),
+        tally.total()
+    );
+}
+
+/// The statistics of `--stats-only` of the packages removed from an architecture and noarch.
+fn removal_statistics(
+    report: &ArchitectureReport,
+    repodatas: [&RepoData; 2],
+    removed: &HashSet<&str>,
+) -> RemovalStatistics {
+    let is_removed = |filename: &&String| removed.contains(filename.as_str());
+    let removed_names = repodatas
+        .iter()
+        .flat_map(|repodata| repodata.packages.iter().chain(&repodata.conda_packages))
+        .filter(|(filename, _)| is_removed(filename))
+        .map(|(_, record)| record.name.as_normalized());
+    RemovalStatistics {
+        removed_by_rule: report.removed_by_rule(),
+        most_removed: most_removed(removed_names, 10),
+        removed_tar_bz2: repodatas
+            .iter()
+            .map(|repodata| repodata.packages.keys().filter(is_removed).count())
+            .sum(),
+        removed_conda: repodatas
+            .iter()
+            .map(|repodata| repodata.conda_packages.keys().filter(is_removed).count())
+            .sum(),
+    }
+}
+
+/// Print the statistics of `--stats-only` of every architecture.
+fn log_statistics(reports: &[ArchitectureReport]) {
+    for report in reports {
+        let Some(statistics) = &report.statistics else {
+            continue;
+        };
+        info!("statistics {}-----", report.architecture);
+        info!(
+            "{:>20}: {:>7} of {} ({:.1}%)",
+            "removed",
+            report.packages_before - report.packages_after,
+            report.packages_before,
+            report.removed_share() * 100.0
+        );
+        for rule in &statistics.removed_by_rule {
+            if rule.removed > 0 {
+                info!("{:>20}: {:>7}", rule.rule, rule.removed);
+            }
+        }
+        info!("{:>20}: {:>7}", ".tar.bz2", statistics.removed_tar_bz2);
+        info!("{:>20}: {:>7}", ".conda", statistics.removed_conda);
+        let names: Vec<String> = statistics
+            .most_removed
+            .iter()
+            .map(|name| format!("{} {}", name.name, name.removed))
+            .collect();
+        info!("{:>20}: {}", "most removed", names.join(", "));
+    }
+}
+
+/// Filter every architecture without writing anything, and print why `target`, a filename or a
+/// package name, was removed from each as a tree of the removals which caused it.
+fn explain_why<'a>(
+    target: &str,
+    subdirs: &[(&'a str, SubdirMetadata<'a>)],
+    args: &'a Cli,
+    matchspec_cache: &'a MatchspecCache<'a, 'a>,
+    policy: &'a Policy<'a>,
+    progress: &Progress,
+) -> Result<(), Failure> {
+    let mut found = false;
+    for (architecture, metadata) in subdirs {
+        let mut filenames: Vec<&str> = [metadata.repodata_arch, metadata.repodata_noarch]
+            .into_iter()
+            .flat_map(|repodata| repodata.packages.iter().chain(&repodata.conda_packages))
+            .filter(|(filename, record)| {
+                *filename == target || record.name.as_normalized() == target
+            })
+            .map(|(filename, _)| filename.as_str())
+            .collect();
+        if filenames.is_empty() {
+            continue;
+        }
+        found = true;
+        filenames.sort_unstable();
+        let removals = {
+            let _span = info_span!("architecture", architecture = *architecture).entered();
+            filter_repodata(
+                architecture,
+                args,
+                matchspec_cache,
+                policy,
+                metadata,
+                progress,
+                &RoundSink::Hidden,
+            )?
+        };
+        let reasons = removals.output.reasons.as_ref().unwrap();
+        for filename in filenames {
+            progress.println(format!("{architecture}: {filename}"));
+            for line in removal_chain(filename, reasons) {
+                progress.println(line);
+            }
+        }
+    }
+    if found {
+        Ok(())
+    } else {
+        Err(Failure::Input(format!(
+            "{target} is neither a filename nor a package name of any architecture"
+        )))
+    }
+}
+
+/// The reason `filename` was removed, followed by the reason of the removal which left it
+/// unsatisfiable, if any, and so on, each indented one level more than the last.
+fn removal_chain(filename: &str, reasons: &HashMap<String, Reason>) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut seen = HashSet::new();
+    let mut next = Some(filename);
+    while let Some(filename) = next.take() {
+        let indent = "  ".repeat(lines.len() + 1);
+        let Some(reason) = reasons.get(filename) else {
+            lines.push(format!("{indent}{filename} is kept"));
+            break;
+        };
+        if !seen.insert(filename) {
+            lines.push(format!("{indent}{filename} (cycle)"));
+            break;
+        }
+        lines.push(format!("{indent}[{}] {}", reason.rule, reason.message));
+        next = reason.cause_filename.as_deref();
+    }
+    lines
+}
+
+/// The memory resident now as counted by jemalloc, or else the most ever resident as counted by
+/// Linux. Either is cheap enough to read after every phase.
+fn resident_bytes() -> Option<u64> {
+    #[cfg(not(target_env = "msvc"))]
+    {
+        // The statistics of jemalloc are only refreshed by advancing its epoch.
+        if tikv_jemalloc_ctl::epoch::advance().is_ok() {
+            if let Ok(resident) = tikv_jemalloc_ctl::stats::resident::read() {
+                return u64::try_from(resident).ok();
+            }
+        }
+    }
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Print the time taken by each phase of the run and the peak resident memory.
+#[allow(clippy::cast_precision_loss)]
+fn log_resources(resources: &ResourceReport) {
+    info!("resources-----");
+    for PhaseReport { phase, seconds } in &resources.phases {
+        info!("{phase:>20}: {seconds:>10.3}s");
+    }
+    if let Some(bytes) = resources.peak_resident_bytes {
+        info!(
+            "{:>20}: {:>10.1} MiB",
+            "peak resident",
+            bytes as f64 / (1024.0 * 1024.0)
+        );
+    }
+}
+
+/// Remove the `current_repodata.json` an earlier run with --write-current-repodata left in a
+/// subdir, since conda would read it instead of the `repodata.json`.
+fn remove_current_repodata(
+    output_directory: &std::path::Path,
+    subdir: &str,
+) -> Result<(), Failure> {
+    let filename = output_directory.join(subdir).join("current_repodata.json");
+    match std::fs::remove_file(&filename) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(Failure::Output(format!(
+            "failed to remove {}: {err}",
+            filename.display()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Print the sizes of the files of the repodata of a subdir, as written.
+fn log_written(subdir: &str, written: WrittenRepodata) {
+    let files: Vec<String> = [
+        ("repodata.json", Some(written.json_size)),
+        ("repodata.json.zst", written.zst_size),
+        ("repodata.json.bz2", written.bz2_size),
+    ]
+    .into_iter()
+    .filter_map(|(filename, size)| Some(format!("{filename} {}", human_size(size?))))
+    .collect();
+    info!("{subdir} written: {}", files.join(", "));
+}
+
+/// Write the noarch repodata, without the packages removed for every architecture and those
+/// which must be removed regardless, returning the filenames of the removed packages, sorted.
+fn write_noarch_repodata<'a>(
+    args: &Cli,
+    repodata_noarch: &'a RepoData,
+    noarch_signatures: &'a Signatures,
+    common_filtered_fns: &HashSet<&str>,
+    unconditional_fns: &HashSet<&str>,
+) -> Result<Vec<&'a str>, Failure> {
+    let mut removed = Vec::new();
+    let written = filtered_repodata_to_file(
+        repodata_noarch,
+        &args.output_directory,
+        |pkfn| {
+            if common_filtered_fns.contains(pkfn) || unconditional_fns.contains(pkfn) {
+                removed.push(pkfn);
+                false
+            } else {
+                true
+            }
+        },
+        "noarch",
+        args.base_url("noarch"),
+        noarch_signatures,
+        args.output_format(),
+    )
+    .map_err(Failure::output("failed to write the noarch repodata"))?;
+    log_written("noarch", written);
+    info!(
+        "Noarch packages removed: {} of {}",
+        removed.len(),
+        repodata_noarch.packages.len() + repodata_noarch.conda_packages.len()
+    );
+    removed.sort_unstable();
+    Ok(removed)
+}
+
+/// Perform a round, explaining and tallying what it removed, and return its measurement for the
+/// caller to record.
+#[inline]
+fn perform_round<'a, F, S, L>(
+    label: S,
+    action: F,
+    removed_filenames: &mut HashSet<&'a str>,
+    removed_package_names: &mut HashSet<&'a str>,
+    output: &mut RoundOutput,
+) -> RoundReport
+where
+    S: std::fmt::Display,
+    L: conda_curation::logs::Log<'a>,
+    F: FnOnce() -> Vec<L>,
+{
+    let label = label.to_string();
+    let _span = info_span!("round", rule = %label).entered();
+    if let Some(bar) = &output.bar {
+        bar.set_message(label.clone());
+    }
+    let start = Instant::now();
+    let mut removal_count = 0;
+    for log_entry in action() {
+        if removed_filenames.insert(log_entry.filename()) {
+            removal_count += 1;
+            if output.explain.wants(&log_entry) {
+                output.explain_removal(&label, &log_entry);
+            }
+            if let Some(explain_to) = &output.explain_to {
+                if output.explain.records(&log_entry) {
+                    explain_to.lock().unwrap().write(&ExplanationRecord {
+                        architecture: &output.architecture,
+                        rule: &label,
+                        removal: &log_entry,
+                    });
+                }
+            }
+            if let Some(reasons) = &mut output.reasons {
+                reasons.insert(
+                    log_entry.filename().to_string(),
+                    Reason {
+                        rule: label.clone(),
+                        message: log_entry.to_string(),
+                        cause_filename: log_entry.cause_filename().map(str::to_string),
+                    },
+                );
+            }
+            removed_package_names.insert(log_entry.package_name());
+            output.tally.add(log_entry.package_name(), &label);
+        }
+    }
+    let report = RoundReport {
+        label,
+        removed: removal_count,
+        seconds: start.elapsed().as_secs_f64(),
+    };
+    if let Some(bar) = &output.bar {
+        bar.set_position(removed_filenames.len() as u64);
+    }
+    report
+}
+
+/// How rounds report what they removed: the bar counting the packages of the architecture removed
+/// so far, when progress bars are drawn, and the record of each round.
+struct RoundOutput {
+    bar: Option<ProgressBar>,
+    architecture: String,
+    rounds: RoundSink,
+    explain: Explain,
+    explain_format: ExplainFormat,
+    /// The explanations held back until the architecture is filtered, with --explain-group-by.
+    explanations: Vec<Explanation>,
+    /// The reason of every removal by filename, when kept to answer `why`.
+    reasons: Option<HashMap<String, Reason>>,
+    explain_to: Option<Arc<Mutex<ExplanationFile>>>,
+    /// Every removal by package name and rule, for `--top-removed` and the report.
+    tally: RemovalTally,
+}
+
+/// The file of `--explain-to`, written as the removals are made. The first error writing it is
+/// kept, and fails the run once filtering is done.
+struct ExplanationFile {
+    writer: std::io::BufWriter<std::fs::File>,
+    error: Option<std::io::Error>,
+}
+
+impl ExplanationFile {
+    fn open(args: &Cli) -> Result<Option<Arc<Mutex<Self>>>, Failure> {
+        let Some(filename) = &args.explain_to else {
+            return Ok(None);
+        };
+        let file = std::fs::File::create(filename)
+            .map_err(Failure::output("failed to create --explain-to file"))?;
+        Ok(Some(Arc::new(Mutex::new(ExplanationFile {
+            writer: std::io::BufWriter::new(file),
+            error: None,
+        }))))
+    }
+
+    /// Flush the file, failing with the first error writing it.
+    fn close(file: Option<&Mutex<Self>>) -> Result<(), Failure> {
+        let Some(file) = file else {
+            return Ok(());
+        };
+        let mut file = file.lock().unwrap();
+        match file.error.take() {
+            Some(err) => Err(err),
+            None => file.writer.flush(),
+        }
+        .map_err(Failure::output("failed to write --explain-to"))
+    }
+
+    fn write(&mut self, record: &impl serde::Serialize) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = serde_json::to_writer(&mut self.writer, record)
+            .map_err(std::io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"));
+        if let Err(err) = result {
+            self.error = Some(err);
+        }
+    }
+}
+
+/// Why a package was removed.
+struct Reason {
+    rule: String,
+    message: String,
+    /// The removed package which left it unsatisfiable, if any.
+    cause_filename: Option<String>,
+}
+
+impl RoundOutput {
+    fn explain_removal<'a>(&mut self, rule: &str, log_entry: &impl conda_curation::logs::Log<'a>) {
+        let explanation = Explanation {
+            rule: rule.to_string(),
+            filename: log_entry.filename().to_string(),
+            package: log_entry.package_name().to_string(),
+            message: self
+                .explain_format
+                .paint(log_entry.category(), log_entry.to_string()),
+        };
+        if self.explain_format.group_by.is_some() {
+            self.explanations.push(explanation);
+        } else {
+            explanation.log("");
+        }
+    }
+
+    /// Print the explanations held back, grouped, with the number of packages in each group.
+    fn log_explanations(&mut self) {
+        let Some(group_by) = self.explain_format.group_by else {
+            return;
+        };
+        let group_of = |explanation: &Explanation| match group_by {
+            ExplainGroupBy::Rule => explanation.rule.clone(),
+            ExplainGroupBy::Package => explanation.package.clone(),
+        };
+        // Rules are grouped in the order they were applied and packages by name.
+        let mut groups: Vec<(String, Vec<Explanation>)> = Vec::new();
+        for explanation in self.explanations.drain(..) {
+            let group = group_of(&explanation);
+            match groups.iter_mut().find(|(other, _)| *other == group) {
+                Some((_, explanations)) => explanations.push(explanation),
+                None => groups.push((group, vec![explanation])),
+            }
+        }
+        if matches!(group_by, ExplainGroupBy::Package) {
+            groups.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        for (group, explanations) in groups {
+            info!("{group} ({} removed)", explanations.len());
+            for explanation in explanations {
+                explanation.log("  ");
+            }
+        }
+    }
+}
+
+/// The explanation of one removal.
+struct Explanation {
+    rule: String,
+    filename: String,
+    package: String,
+    message: String,
+}
+
+impl Explanation {
+    fn log(&self, indent: &str) {
+        info!(
+            rule = %self.rule,
+            filename = self.filename,
+            package = self.package,
+            "{indent}{}",
+            self.message
+        );
+    }
+}
+
+/// How explanations are printed: colored by the category of their rule when printed as text to a
+/// terminal, unless `NO_COLOR` is set, and optionally grouped.
+#[derive(Clone, Copy)]
+struct ExplainFormat {
+    color: bool,
+    group_by: Option<ExplainGroupBy>,
+}
+
+impl ExplainFormat {
+    fn new(args: &Cli) -> Self {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        ExplainFormat {
+            color: matches!(args.log_format, LogFormat::Text)
+                && !no_color
+                && std::io::stdout().is_terminal(),
+            group_by: args.explain_group_by,
+        }
+    }
+
+    fn paint(self, category: Category, message: String) -> String {
+        let code = match category {
+            Category::UserSpec => "35",
+            Category::BuildPrune => "34",
+            Category::Feature => "36",
+            Category::Prerelease => "33",
+            Category::Architecture => "32",
+            Category::Unsatisfiable => "31",
+            Category::Other => return message,
+        };
+        if self.color {
+            format!("\x1b[{code}m{message}\x1b[0m")
+        } else {
+            message
+        }
+    }
+}
+
+/// Which removals are explained.
+#[derive(Clone)]
+enum Explain {
+    Nothing,
+    All,
+    /// Those of packages matching any of the patterns, or taken down by the removal of one.
+    Packages(Vec<glob::Pattern>),
+}
+
+impl Explain {
+    fn new(args: &Cli) -> Self {
+        if !args.explain_packages.is_empty() {
+            Explain::Packages(
+                args.explain_packages
+                    .iter()
+                    .map(|pattern| glob::Pattern::new(pattern).unwrap())
+                    .collect(),
+            )
+        } else if args.explain {
+            Explain::All
+        } else {
+            Explain::Nothing
+        }
+    }
+
+    /// Whether to write the removal to `--explain-to`: every removal, unless only some packages
+    /// are explained.
+    fn records<'a>(&self, log_entry: &impl conda_curation::logs::Log<'a>) -> bool {
+        !matches!(self, Explain::Packages(_)) || self.wants(log_entry)
+    }
+
+    fn wants<'a>(&self, log_entry: &impl conda_curation::logs::Log<'a>) -> bool {
+        match self {
+            Explain::Nothing => false,
+            Explain::All => true,
+            Explain::Packages(patterns) => [
+                Some(log_entry.package_name()),
+                log_entry.dependency_package_name(),
+                log_entry.cause_filename(),
+            ]
+            .into_iter()
+            .flatten()
+            .any(|name| patterns.iter().any(|pattern| pattern.matches(name))),
+        }
+    }
+}
+
+/// Where the packages removed by each round are printed.
+#[derive(Clone)]
+enum RoundSink {
+    /// A row of the table in the log, as always.
+    Table,
+    /// Nowhere, with `--quiet`.
+    Hidden,
+    /// A JSON object per line on stdout, above the progress bars.
+    JsonStdout(Progress),
+    /// A JSON object per line in the `--round-output` file.
+    JsonFile(Arc<Mutex<std::fs::File>>),
+}
+
+impl RoundSink {
+    fn new(args: &Cli, progress: &Progress) -> Result<Self, Failure> {
+        Ok(match (args.round_format, &args.round_output) {
+            (RoundFormat::Json, Some(filename)) => RoundSink::JsonFile(Arc::new(Mutex::new(
+                std::fs::File::create(filename)
+                    .map_err(Failure::output("failed to create --round-output"))?,
+            ))),
+            (RoundFormat::Json, None) => RoundSink::JsonStdout(progress.clone()),
+            (RoundFormat::Text, _) if args.quiet => RoundSink::Hidden,
+            (RoundFormat::Text, _) => RoundSink::Table,
+        })
+    }
+
+    fn record(&self, architecture: &str, round: &RoundReport) {
+        let RoundReport {
+            label,
+            removed,
+            seconds,
+        } = round;
+        let line = || {
+            serde_json::to_string(&RoundRecord {
+                architecture,
+                round,
+            })
+            .expect("a round serializes")
+        };
+        match self {
+            RoundSink::Table => info!("{label:>15}: - {removed:>7} ({seconds:>2.7}s)"),
+            RoundSink::Hidden => {}
+            RoundSink::JsonStdout(progress) => progress.println(line()),
+            RoundSink::JsonFile(file) => {
+                if let Err(err) = writeln!(file.lock().unwrap(), "{}", line()) {
+                    warn!("warning: failed to write --round-output: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// The packages removed so far while filtering a single architecture.
+struct Removals<'a> {
+    filenames: HashSet<&'a str>,
+    /// Names of the packages removed by rounds, used to seed later unresolveable analysis.
+    package_names: HashSet<&'a str>,
+    /// Filenames of noarch packages which must be removed from the noarch output, even if they
+    /// are kept for other architectures.
+    unconditional: HashSet<&'a str>,
+    /// The number of packages of the architecture and noarch, before any removal.
+    package_count: usize,
+    rounds: Vec<RoundReport>,
+    output: RoundOutput,
+    /// The rules disabled by `--skip-rule`.
+    skipped_rules: Vec<Rule>,
+    /// The filenames of the packages of `current_repodata.json`, with --write-current-repodata.
+    current: Option<HashSet<&'a str>>,
+}
+
+impl<'a> Removals<'a> {
+    fn new(
+        architecture: &str,
+        package_count: usize,
+        progress: &Progress,
+        rounds: &RoundSink,
+        policy: &Policy,
+        skipped_rules: &[Rule],
+    ) -> Self {
+        Removals {
+            filenames: HashSet::new(),
+            package_names: HashSet::new(),
+            unconditional: HashSet::new(),
+            package_count,
+            rounds: Vec::new(),
+            current: None,
+            output: RoundOutput {
+                bar: progress.counter(architecture, package_count as u64),
+                architecture: architecture.to_string(),
+                rounds: rounds.clone(),
+                explain: policy.explain.clone(),
+                explain_format: policy.explain_format,
+                explanations: Vec::new(),
+                reasons: policy.retain_reasons.then(HashMap::new),
+                explain_to: policy.explain_to.clone(),
+                tally: RemovalTally::default(),
+            },
+            skipped_rules: skipped_rules.to_vec(),
+        }
+    }
+
+    /// Print what remains of the architecture once every round was performed.
+    fn log_summary(&self) {
+        // We want to round up the floating point value that we calculate.
+        // Integer division rounds down. So, we'll calculate the percentage
+        // of packages we removed, and then subtract 1 from it instead.
+        let total_removed_count = self.filenames.len();
+        let remaining_count = self.package_count - total_removed_count;
+        let percent = 100 - (total_removed_count * 100 / self.package_count);
+        info!("=============================================");
+        info!("      Remaining:   {remaining_count:>7} ({percent}% of original)");
+        if !self.skipped_rules.is_empty() {
+            let skipped: Vec<String> = self.skipped_rules.iter().map(Rule::to_string).collect();
+            info!("  Skipped rules:   {}", skipped.join(", "));
+        }
+    }
+
+    fn skips(&self, rule: Rule) -> bool {
+        self.skipped_rules.contains(&rule)
+    }
+
+    /// Perform the round of a built-in rule, unless it is skipped.
+    #[inline]
+    fn rule<F, S, L>(&mut self, rule: Rule, label: S, action: F)
+    where
+        S: std::fmt::Display,
+        L: conda_curation::logs::Log<'a>,
+        F: FnOnce() -> Vec<L>,
+    {
+        if !self.skips(rule) {
+            self.round(label, action);
+        }
+    }
+
+    /// Stop counting the removals, once no more rounds will be performed, and print the
+    /// explanations held back.
+    fn finish_progress(&mut self) {
+        if let Some(bar) = self.output.bar.take() {
+            bar.finish_and_clear();
+        }
+        self.output.log_explanations();
+    }
+
+    #[inline]
+    fn round<F, S, L>(&mut self, label: S, action: F)
+    where
+        S: std::fmt::Display,
+        L: conda_curation::logs::Log<'a>,
+        F: FnOnce() -> Vec<L>,
+    {
+        let report = perform_round(
+            label,
+            action,
+            &mut self.filenames,
+            &mut self.package_names,
+            &mut self.output,
+        );
+        self.record_round(report);
+    }
+
+    /// Record the measurement of a round, in the rounds output and in the report.
+    fn record_round(&mut self, report: RoundReport) {
+        self.output
+            .rounds
+            .record(&self.output.architecture, &report);
+        self.rounds.push(report);
+    }
+}
+
+/// Apply the `--patch-depends` patches to the records of every subdir, ahead of any analysis.
+fn patch_depends<'a>(
+    patches: &'a [DependsPatch],
+    matchspec_cache: &'a MatchspecCache<'a, 'a>,
+    repodatas: &mut [RepoData],
+    repodata_noarch: &mut RepoData,
+) -> Result<(), Failure> {
+    if patches.is_empty() {
+        return Ok(());
+    }
+    let mut all_repodatas: Vec<&mut RepoData> =
+        repodatas.iter_mut().chain([repodata_noarch]).collect();
+    let counts = apply_depends_patches(patches, matchspec_cache, &mut all_repodatas)
+        .map_err(Failure::input("failed to apply --patch-depends file"))?;
+    for (patch, count) in patches.iter().zip(counts) {
+        info!("patched {count:>7} records selected by {}", patch.select);
+        if count == 0 && !patch.optional {
+            return Err(Failure::Input(format!(
+                "--patch-depends: {} selects no records, mark it `optional: true` if this is \
+                 expected",
+                patch.select
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// What a repodata holds beyond what rattler models.
+struct RepodataExtras {
+    signatures: Signatures,
+    origin_channels: OriginChannels,
+}
+
+/// Protect the packages of the `--lockfile`s for this architecture and noarch from every rule.
+fn lock_packages(
+    relations: &mut PackageRelations,
+    architecture: &str,
+    locked_packages: &[LockedPackage],
+) {
+    if locked_packages.is_empty() {
+        return;
+    }
+    let locked_count: usize = locked_packages
+        .iter()
+        .filter(|package| package.subdir == architecture || package.subdir == "noarch")
+        .map(|package| relations.lock(&package.name, &package.version, &package.build))
+        .sum();
+    info!("  locked:          {locked_count:>7}");
+}
+
+/// Print the completions or the man page of this command.
+fn print_documentation(command: &Command) -> Result<(), Failure> {
+    match command {
+        Command::Completions { shell } => {
+            let mut completions = Vec::new();
+            clap_complete::generate(
+                *shell,
+                &mut Invocation::command(),
+                env!("CARGO_BIN_NAME"),
+                &mut completions,
+            );
+            std::io::stdout()
+                .write_all(&completions)
+                .map_err(Failure::output("failed to write the completions"))
+        }
+        Command::Manpage => clap_mangen::Man::new(Invocation::command())
+            .render(&mut std::io::stdout())
+            .map_err(Failure::output("failed to write the man page")),
+        Command::Curate(_)
+        | Command::Fetch(_)
+        | Command::ListArchitectures(_)
+        | Command::Diff(_)
+        | Command::Why(_) => Ok(()),
+    }
+}
+
+/// Parse the command line, merged with the `--config` file if one is given, and fill in the
+/// defaults which depend on other options. With `--show-config`, print the merged configuration
+/// and exit.
+fn parse_args() -> Result<Task, Failure> {
+    let invocation_matches = Invocation::command().get_matches();
+    let invocation =
+        Invocation::from_arg_matches(&invocation_matches).unwrap_or_else(|err| err.exit());
+    let mut why = None;
+    let (args, matches) = match invocation.command {
+        None => (invocation.curate, invocation_matches),
+        Some(Command::Curate(args)) => (
+            *args,
+            invocation_matches
+                .subcommand_matches("curate")
+                .unwrap()
+                .clone(),
+        ),
+        Some(Command::Why(why_args)) => {
+            why = Some(why_args.target);
+            (
+                why_args.curate,
+                invocation_matches
+                    .subcommand_matches("why")
+                    .unwrap()
+                    .clone(),
+            )
+        }
+        Some(Command::Fetch(mut fetch_args)) => {
+            fetch_args
+                .channel
+                .read_environment(invocation_matches.subcommand_matches("fetch").unwrap())?;
+            fetch_args.channel.resolve()?;
+            return Ok(Task::Fetch(fetch_args));
+        }
+        Some(Command::ListArchitectures(mut list_args)) => {
+            let channel = &mut list_args.channel;
+            channel.read_environment(
+                invocation_matches
+                    .subcommand_matches("list-architectures")
+                    .unwrap(),
+            )?;
+            if channel.architectures.is_empty() {
+                channel.architectures = Platform::all()
+                    .filter(|platform| !matches!(platform, Platform::NoArch | Platform::Unknown))
+                    .map(|platform| platform.to_string())
+                    .filter(|subdir| !channel.exclude_architectures.contains(subdir))
+                    .collect();
+            }
+            channel.resolve()?;
+            return Ok(Task::ListArchitectures(list_args));
+        }
+        Some(Command::Diff(diff_args)) => return Ok(Task::Diff(diff_args)),
+        Some(command @ (Command::Completions { .. } | Command::Manpage)) => {
+            print_documentation(&command)?;
+            std::process::exit(0);
+        }
+    };
+    parse_curate_args(args, matches, why).map(|args| Task::Curate(Box::new(args)))
+}
+
+/// Apply the config file and the environment to the options of `curate` or `why`.
+fn parse_curate_args(
+    mut args: Cli,
+    mut matches: clap::ArgMatches,
+    why: Option<String>,
+) -> Result<Cli, Failure> {
+    let command = Cli::command();
+    let mut inline_matchspecs = None;
+    if let Some(filename) = matches.get_one::<std::path::PathBuf>("config").cloned() {
+        let load = || -> Result<_, Box<dyn std::error::Error>> {
+            let mut config = read_run_config(&filename)?;
+            if let Some(profile) = matches.get_one::<String>("profile") {
+                config.select_profile(profile)?;
+            }
+            let arguments = merge_arguments(&command, &matches, &config)?;
+            Ok((config.matchspecs, arguments))
+        };
+        let (matchspecs, arguments) = load().map_err(Failure::input(format!(
+            "failed to load config {}",
+            filename.display()
+        )))?;
+        inline_matchspecs = matchspecs;
+        matches = command.clone().get_matches_from(arguments);
+        args = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    }
+    if args.matchspecs_yaml.is_none() {
+        args.inline_matchspecs = inline_matchspecs;
+    }
+    args.why = why;
+    args.channel.read_environment(&matches)?;
+    let mut config = effective_config(&command, &matches);
+    config.remove("config");
+    config.remove("show_config");
+    config.remove("profile");
+    for (key, values) in [
+        ("architectures", &args.channel.architectures),
+        ("channel_alias", &args.channel.channel_alias),
+    ] {
+        config.insert(key.into(), values.iter().map(String::as_str).collect());
+    }
+    if let Some(matchspecs) = &args.inline_matchspecs {
+        config.insert("matchspecs".into(), matchspecs.clone());
+    }
+    if args.show_config {
+        print!("{}", serde_yaml::to_string(&config).unwrap());
+        std::process::exit(0);
+    }
+    args.info_stamp = args.stamp_info.then(|| info_stamp(&config));
+    args.effective_config = config;
+    args.channel.resolve()?;
+    if args.strict_matchspecs {
+        check_strict_arguments(&args)?;
+    }
+    Ok(args)
+}
+
+/// Reparse the matchspecs given as arguments, which were accepted leniently, strictly.
+fn check_strict_arguments(args: &Cli) -> Result<(), Failure> {
+    let errors: Vec<String> = args
+        .must_compatible
+        .iter()
+        .map(|matchspec| ("--must-compatible-with", matchspec))
+        .chain(
+            args.ban_run_exports
+                .iter()
+                .map(|matchspec| ("--ban-run-export", matchspec)),
+        )
+        .filter_map(|(argument, matchspec)| {
+            parse_named_matchspec(matchspec, ParseStrictness::Strict)
+                .err()
+                .map(|err| format!("{argument}: {matchspec}: {err}"))
+        })
+        .collect();
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(Failure::Input(format!(
+        "invalid matchspecs with --strict-matchspecs:\n{}",
+        errors.join("\n")
+    )))
+}
+
+/// Load the matchspecs YAML, merged with the dependencies of every `--environment-file` and with
+/// every `--matchspec`.
+fn load_user_matchspecs(args: &Cli) -> Result<MatchspecsYaml, Failure> {
+    let mut user_matchspecs = match (&args.matchspecs_yaml, &args.inline_matchspecs) {
+        (Some(filename), _) => get_user_matchspecs(filename, args.matchspec_strictness()),
+        (None, Some(matchspecs)) => serde_yaml::to_string(matchspecs)
+            .map_err(Into::into)
+            .and_then(|yaml| parse_user_matchspecs(&yaml, args.matchspec_strictness())),
+        (None, None) => {
+            if args.environment_files.is_empty() && args.matchspecs.is_empty() {
+                info!("no matchspecs YAML given, skipping the user matchspecs");
+            }
+            Ok(MatchspecsYaml::default())
+        }
+    }
+    .map_err(Failure::input("failed to load user-provided matchspecs"))?;
+    for environment_file in &args.environment_files {
+        let environment = get_environment_file(environment_file, args.matchspec_strictness())
+            .map_err(Failure::input(format!(
+                "failed to load environment file {}",
+                environment_file.display()
+            )))?;
+        user_matchspecs.merge_matchspecs(environment);
+    }
+    let arguments = parse_argument_matchspecs(&args.matchspecs, args.matchspec_strictness())
+        .map_err(Failure::input("failed to load the --matchspec matchspecs"))?;
+    user_matchspecs.merge_matchspecs(arguments);
+    Ok(user_matchspecs)
+}
+
+/// The parsed repodata of every subdir, of the channel or merged from every --channel.
+struct LoadedRepodata {
+    /// The architectures fetched, in the order requested. The other fields of the architectures
+    /// are aligned with them.
+    architectures: Vec<String>,
+    noarch: RepoData,
+    noarch_extras: RepodataExtras,
+    noarch_run_exports: Option<RunExportsIndex>,
+    repodatas: Vec<RepoData>,
+    extras: Vec<RepodataExtras>,
+    run_exports: Vec<Option<RunExportsIndex>>,
+}
+
+/// Parse the repodata fetched from every channel, merging that of several --channel into one
+/// repodata per subdir by their priority.
+fn load_channels(
+    args: &Cli,
+    fetched: Vec<rawrepodata::RepodataFilenames>,
+    progress: &Progress,
+) -> Result<LoadedRepodata, Failure> {
+    let with_origin_channels = !args.ban_origin_channels.is_empty();
+    let mut channels = fetched
+        .into_iter()
+        .map(|filenames| load_channel(filenames, with_origin_channels, progress))
+        .collect::<Result<Vec<_>, Failure>>()?;
+    if channels.len() == 1 {
+        return Ok(channels.pop().unwrap());
+    }
+    Ok(merge_channels(args, channels, with_origin_channels))
+}
+
+/// Parse the repodata fetched from a channel, along with what it holds beyond what rattler
+/// models and its run exports.
+fn load_channel(
+    filenames: rawrepodata::RepodataFilenames,
+    with_origin_channels: bool,
+    progress: &Progress,
+) -> Result<LoadedRepodata, Failure> {
+    let (noarch, noarch_signatures) = parse_repodata(&filenames.noarch, "noarch", progress)?;
+    let (repodatas, extras) = load_repodatas(
+        &filenames.architectures,
+        &filenames.arches,
+        with_origin_channels,
+        progress,
+    )?;
+    Ok(LoadedRepodata {
+        noarch,
+        noarch_extras: load_extras(&filenames.noarch, noarch_signatures, with_origin_channels),
+        noarch_run_exports: load_run_exports(filenames.noarch_run_exports),
+        repodatas,
+        extras,
+        run_exports: filenames
+            .arches_run_exports
+            .into_iter()
+            .map(load_run_exports)
+            .collect(),
+        architectures: filenames.architectures,
+    })
+}
+
+/// The names of the packages of `repodatas`.
+fn package_names<'a>(repodatas: impl IntoIterator<Item = &'a RepoData>) -> HashSet<String> {
+    repodatas
+        .into_iter()
+        .flat_map(|repodata| {
+            repodata
+                .packages
+                .values()
+                .chain(repodata.conda_packages.values())
+        })
+        .map(|record| record.name.as_normalized().to_string())
+        .collect()
+}
+
+/// Merge the repodata of every --channel, highest priority first, into one per subdir. The
+/// packages are attributed to the channel they were taken from, for --ban-origin-channel.
+fn merge_channels(
+    args: &Cli,
+    channels: Vec<LoadedRepodata>,
+    with_origin_channels: bool,
+) -> LoadedRepodata {
+    let priority = MergePriority::from(args.channel.channel_priority);
+    let architectures: Vec<String> = args
+        .channel
+        .architectures
+        .iter()
+        .filter(|architecture| {
+            channels
+                .iter()
+                .any(|channel| channel.architectures.contains(architecture))
+        })
+        .cloned()
+        .collect();
+    let mut by_subdir: HashMap<String, Vec<(ChannelSubdir, Option<RunExportsIndex>)>> =
+        HashMap::new();
+    for (channel, channel_aliases) in channels.into_iter().zip(&args.channel.channel_sources) {
+        // The noarch packages of a channel shadow those of the architectures of the channels
+        // after it too, and its architecture packages shadow their noarch ones.
+        let noarch_names = package_names([&channel.noarch]);
+        let architecture_names = package_names(&channel.repodatas);
+        let subdirs = channel
+            .architectures
+            .into_iter()
+            .zip(channel.repodatas)
+            .zip(channel.extras)
+            .zip(channel.run_exports)
+            .map(|(((subdir, repodata), extras), run_exports)| {
+                (subdir, repodata, extras, run_exports, noarch_names.clone())
+            })
+            .chain([(
+                "noarch".to_string(),
+                channel.noarch,
+                channel.noarch_extras,
+                channel.noarch_run_exports,
+                architecture_names,
+            )]);
+        for (subdir, repodata, extras, run_exports, shadowing_names) in subdirs {
+            let channel_subdir = ChannelSubdir {
+                channel: channel_aliases.for_subdir(&subdir).to_string(),
+                repodata,
+                signatures: extras.signatures,
+                origin_channels: extras.origin_channels,
+                shadowing_names,
+            };
+            by_subdir
+                .entry(subdir)
+                .or_default()
+                .push((channel_subdir, run_exports));
+        }
+    }
+    let mut merge = |subdir: &str| {
+        let (channel_subdirs, run_exports): (Vec<_>, Vec<_>) = by_subdir
+            .remove(subdir)
+            .unwrap_or_default()
+            .into_iter()
+            .unzip();
+        let merged = merge_subdir(subdir, channel_subdirs, priority, with_origin_channels);
+        info!(
+            "{subdir} merged from {} channels: {} packages, {} shadowed by a channel of higher \
+            priority, {} filenames taken from the first of several channels",
+            run_exports.len(),
+            merged.repodata.packages.len() + merged.repodata.conda_packages.len(),
+            merged.shadowed,
+            merged.collisions
+        );
+        let run_exports = run_exports
+            .into_iter()
+            .flatten()
+            .reduce(|mut index, other| {
+                index.merge(other);
+                index
+            });
+        let extras = RepodataExtras {
+            signatures: merged.signatures,
+            origin_channels: merged.origin_channels,
+        };
+        (merged.repodata, extras, run_exports)
+    };
+    let (noarch, noarch_extras, noarch_run_exports) = merge("noarch");
+    let mut repodatas = Vec::with_capacity(architectures.len());
+    let mut extras = Vec::with_capacity(architectures.len());
+    let mut run_exports = Vec::with_capacity(architectures.len());
+    for architecture in &architectures {
+        let (repodata, repodata_extras, index) = merge(architecture);
+        repodatas.push(repodata);
+        extras.push(repodata_extras);
+        run_exports.push(index);
+    }
+    LoadedRepodata {
+        architectures,
+        noarch,
+        noarch_extras,
+        noarch_run_exports,
+        repodatas,
+        extras,
+        run_exports,
+    }
+}
+
+/// Load the repodata of every architecture, and what they hold beyond what rattler models.
+fn load_repodatas(
+    architectures: &[String],
+    repodata_fns: &[std::path::PathBuf],
+    with_origin_channels: bool,
+    progress: &Progress,
+) -> Result<(Vec<RepoData>, Vec<RepodataExtras>), Failure> {
+    let loaded = architectures
+        .par_iter()
+        .zip(repodata_fns)
+        .map(|(architecture, repodata_fn)| {
+            let (repodata, signatures) = parse_repodata(repodata_fn, architecture, progress)?;
+            let extras = load_extras(repodata_fn, signatures, with_origin_channels);
+            Ok((repodata, extras))
+        })
+        .collect::<Result<Vec<_>, Failure>>()?;
+    Ok(loaded.into_iter().unzip())
+}
+
+/// Parse the repodata of a subdir and its package signatures, with a spinner while it does.
+fn parse_repodata(
+    filename: &std::path::Path,
+    subdir: &str,
+    progress: &Progress,
+) -> Result<(RepoData, Signatures), Failure> {
+    let spinner = progress.spinner(format!("parsing {subdir} repodata"));
+    let start = Instant::now();
+    let repodata = rawrepodata::read_repodata(filename).map_err(Failure::input(format!(
+        "failed to load the repodata of {subdir}"
+    )));
+    if let Some(spinner) = spinner {
+        spinner.finish_and_clear();
+    }
+    debug!(
+        "parsed {subdir} repodata {} in {:.3}s",
+        filename.display(),
+        start.elapsed().as_secs_f64()
+    );
+    repodata
+}
+
+/// Gather what a repodata holds beyond what rattler models: its package signatures, and the
+/// channels its packages are attributed to if requested. Attributions which cannot be read are
+/// ignored with a warning.
+fn load_extras(
+    filename: &std::path::Path,
+    signatures: Signatures,
+    with_origin_channels: bool,
+) -> RepodataExtras {
+    let origin_channels = if with_origin_channels {
+        OriginChannels::from_path(filename).unwrap_or_else(|err| {
+            warn!(
+                "warning: failed to load channel attributions from {}: {err}",
+                filename.display()
+            );
+            OriginChannels::default()
+        })
+    } else {
+        OriginChannels::default()
+    };
+    RepodataExtras {
+        signatures,
+        origin_channels,
+    }
+}
+
+/// Load a `run_exports.json`, if one was fetched. Unreadable files are ignored with a warning.
+fn load_run_exports(filename: Option<std::path::PathBuf>) -> Option<RunExportsIndex> {
+    let filename = filename?;
+    match RunExportsIndex::from_path(&filename) {
+        Ok(index) => Some(index),
+        Err(err) => {
+            warn!("warning: failed to load {}: {err}", filename.display());
+            None
+        }
+    }
+}
+
+/// Fail on exact pins whose artifact is in none of the repodatas, since every build of the pinned
+/// package would be kept instead of the one which was asked for.
+fn check_exact_pins<'a>(
+    user_matchspecs: &MatchspecsYaml,
+    repodatas: impl Iterator<Item = &'a RepoData>,
+) -> Result<(), Failure> {
+    let pins = &user_matchspecs.pin_exact;
+    if pins.is_empty() {
+        return Ok(());
     }
-    if args.architectures.is_empty() {
-        args.architectures
-            .extend(ARCHITECTURES.iter().map(|arch| (*arch).to_string()));
+    let mut found: HashSet<&str> = HashSet::new();
+    for (filename, record) in repodatas.flat_map(|repodata| {
+        repodata
+            .packages
+            .iter()
+            .chain(repodata.conda_packages.iter())
+    }) {
+        if let Some((package_name, pin)) = pins.get_key_value(record.name.as_source()) {
+            if pin.matches(filename, record) {
+                found.insert(package_name);
+            }
+        }
     }
-    let args = args; // read-only for now on.
-
-    std::fs::create_dir_all(&args.output_directory).expect("Failed to create output directory");
-
-    let banned_features: HashSet<&str> = args.ban_features.iter().map(String::as_str).collect();
-    let user_matchspecs = get_user_matchspecs(&args.matchspecs_yaml)
-        .expect("Failed to load user-provided matchspecs file");
-    let matchspec_cache = MatchspecCache::with_capacity(1024 * 192);
+    let missing: Vec<String> = pins
+        .iter()
+        .filter(|(package_name, _)| !found.contains(package_name.as_str()))
+        .map(|(package_name, pin)| format!("{package_name} {pin}"))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Failure::Input(format!(
+            "exact pins not in the source repodata of any requested architecture: {}",
+            missing.join(", ")
+        )))
+    }
+}
 
-    let rawrepodata::RepodataFilenames {
-        noarch: noarch_repodata_fn,
-        arches: repodata_fns,
-    } = rawrepodata::fetch_repodata(&args.channel_alias, &args.architectures, args.is_offline)
-        .await
-        .expect("Failed to download repodata");
+/// Check the matchspecs YAML against the source repodata of every subdir at once, for unknown
+/// package names and for matchspecs which match no package.
+fn check_user_specs(
+    args: &Cli,
+    architectures: &[String],
+    user_matchspecs: &MatchspecsYaml,
+    repodatas: &[RepoData],
+    repodata_noarch: &RepoData,
+) -> Result<(), Failure> {
+    check_unknown_user_specs(
+        args,
+        architectures,
+        user_matchspecs,
+        repodatas,
+        repodata_noarch,
+    )?;
+    check_empty_specs(
+        args,
+        architectures,
+        user_matchspecs,
+        repodatas,
+        repodata_noarch,
+    )
+}
 
-    let repodata_noarch =
-        RepoData::from_path(noarch_repodata_fn).expect("Failed to load noarch repodata");
+/// Warn about the package names of the matchspecs YAML which no package of any subdir has, with
+/// the closest names which do exist, as they are likely typos. A name only published for some of
+/// the architectures is not reported. With `--fail-on-unknown-spec`, fail instead.
+fn check_unknown_user_specs(
+    args: &Cli,
+    architectures: &[String],
+    user_matchspecs: &MatchspecsYaml,
+    repodatas: &[RepoData],
+    repodata_noarch: &RepoData,
+) -> Result<(), Failure> {
+    let matchspecs: Vec<_> = if architectures.is_empty() {
+        vec![user_matchspecs.matchspecs_for("noarch")]
+    } else {
+        architectures
+            .iter()
+            .map(|architecture| user_matchspecs.matchspecs_for(architecture))
+            .collect()
+    };
+    let unknown = unknown_user_spec_names(
+        matchspecs
+            .iter()
+            .flat_map(|matchspecs| matchspecs.keys().map(String::as_str)),
+        repodatas.iter().chain([repodata_noarch]),
+    );
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    if args.fail_on_unknown_spec {
+        let unknown: Vec<String> = unknown.iter().map(ToString::to_string).collect();
+        return Err(Failure::EmptySpecs(format!(
+            "user matchspecs for unknown packages: {}",
+            unknown.join("; ")
+        )));
+    }
+    for unmatched in unknown {
+        warn!("warning: user matchspecs: {unmatched}");
+    }
+    Ok(())
+}
 
-    let repodatas: Vec<RepoData> = repodata_fns
-        .into_par_iter()
-        .map(|repodata_fn| RepoData::from_path(repodata_fn).expect("Failed to load repodata"))
+/// Warn about the matchspecs of the matchspecs YAML which match no package in the source
+/// repodata of any architecture, as they are likely typos. With `--fail-on-empty-spec`, fail
+/// instead.
+fn check_empty_specs(
+    args: &Cli,
+    architectures: &[String],
+    user_matchspecs: &MatchspecsYaml,
+    repodatas: &[RepoData],
+    repodata_noarch: &RepoData,
+) -> Result<(), Failure> {
+    // Whether each matchspec matched a package for any architecture so far.
+    let mut matched: BTreeMap<String, bool> = BTreeMap::new();
+    for (architecture, repodata_arch) in architectures.iter().zip(repodatas) {
+        let matchspecs = user_matchspecs.matchspecs_for(architecture);
+        let mut records: HashMap<&str, Vec<&PackageRecord>> = HashMap::new();
+        for record in [repodata_arch, repodata_noarch]
+            .into_iter()
+            .flat_map(|repodata| {
+                repodata
+                    .packages
+                    .values()
+                    .chain(repodata.conda_packages.values())
+            })
+        {
+            if matchspecs.contains_key(record.name.as_normalized()) {
+                records
+                    .entry(record.name.as_normalized())
+                    .or_default()
+                    .push(record);
+            }
+        }
+        for (package_name, specs) in matchspecs.iter() {
+            let candidates = records
+                .get(package_name.as_str())
+                .map_or(&[][..], Vec::as_slice);
+            for spec in specs {
+                let entry = matched
+                    .entry(format!("{package_name} {}", format_user_matchspec(spec)))
+                    .or_default();
+                *entry = *entry || candidates.iter().any(|record| spec.matches(*record));
+            }
+        }
+    }
+    let unmatched: Vec<String> = matched
+        .into_iter()
+        .filter_map(|(matchspec, matched)| (!matched).then_some(matchspec))
         .collect();
+    if unmatched.is_empty() {
+        return Ok(());
+    }
+    let message = format!(
+        "user matchspecs matching no package in any repodata: {}",
+        unmatched.join(", ")
+    );
+    if args.fail_on_empty_spec {
+        return Err(Failure::EmptySpecs(message));
+    }
+    warn!("{message}");
+    Ok(())
+}
 
-    let pairs: Vec<(&RepoData, &String)> =
-        repodatas.iter().zip(args.architectures.iter()).collect();
+/// Report the entries of the user's lists which do not apply to any package at all.
+fn warn_unused_entries<'a>(
+    architectures: &[String],
+    policy: &'a Policy<'a>,
+    repodatas: &'a [RepoData],
+    repodata_noarch: &'a RepoData,
+) {
+    warn_missing_locked(
+        &curated_locked_packages(&policy.locked_packages, architectures),
+        architectures,
+        repodatas,
+        repodata_noarch,
+    );
+    warn_unknown_filenames(
+        &policy.banned_filenames,
+        repodatas.iter().chain([repodata_noarch]),
+    );
+    warn_unknown_pre_removed(
+        &policy.pre_removed,
+        architectures,
+        repodatas,
+        repodata_noarch,
+    );
+    warn_unmatched_name_patterns(
+        &policy.banned_packages,
+        "--ban-package patterns",
+        repodatas.iter().chain([repodata_noarch]),
+    );
+    warn_unmatched_name_patterns(
+        &policy.user_matchspecs.prerelease_exceptions,
+        "prerelease exceptions",
+        repodatas.iter().chain([repodata_noarch]),
+    );
+    warn_unmatched_denylist(
+        &policy.vulnerability_denylist,
+        repodatas.iter().chain([repodata_noarch]),
+    );
+}
 
-    let common_filtered_fns: HashSet<&str> = pairs
-        .into_iter()
-        .map(|(repodata_arch, architecture)| {
-            println!("{architecture}-----");
-            let removed_filenames = filter_repodata(
-                architecture,
-                &args,
-                &matchspec_cache,
-                &user_matchspecs,
-                &banned_features,
-                &repodata_noarch,
-                repodata_arch,
+fn warn_missing_locked(
+    locked_packages: &[&LockedPackage],
+    architectures: &[String],
+    repodatas: &[RepoData],
+    repodata_noarch: &RepoData,
+) {
+    for package in locked_packages {
+        let repodata = if package.subdir == "noarch" {
+            Some(repodata_noarch)
+        } else {
+            architectures
+                .iter()
+                .position(|architecture| *architecture == package.subdir)
+                .map(|index| &repodatas[index])
+        };
+        let present = repodata.is_some_and(|repodata| {
+            package.filenames().iter().any(|filename| {
+                repodata.packages.contains_key(filename)
+                    || repodata.conda_packages.contains_key(filename)
+            })
+        });
+        if !present {
+            warn!(
+                "WARNING: locked package {}/{} is not in the source repodata",
+                package.subdir,
+                package.filenames()[0]
             );
-            filtered_repodata_to_file(
-                repodata_arch,
-                &args.output_directory,
-                |pkfn| !removed_filenames.contains(pkfn),
-                architecture,
-                &args.channel_alias,
-            )
-            .expect("Error writing repodata to file");
-            removed_filenames
+        }
+    }
+}
+
+/// Warn about banned filenames which are not in any of the repodatas, as they are likely typos.
+fn warn_unknown_filenames<'a>(filenames: &[String], repodatas: impl Iterator<Item = &'a RepoData>) {
+    let mut unknown: HashSet<&str> = filenames.iter().map(String::as_str).collect();
+    for repodata in repodatas {
+        unknown.retain(|filename| {
+            !repodata.packages.contains_key(*filename)
+                && !repodata.conda_packages.contains_key(*filename)
+        });
+    }
+    if !unknown.is_empty() {
+        let mut unknown: Vec<&str> = unknown.into_iter().collect();
+        unknown.sort_unstable();
+        warn!(
+            "banned filenames not found in any repodata: {}",
+            unknown.join(", ")
+        );
+    }
+}
+
+/// The filenames of --pre-removed to remove from an architecture: those without a subdir, and
+/// those of the architecture or noarch.
+fn pre_removed_filenames<'p>(
+    pre_removed: &'p [String],
+    architecture: &'p str,
+) -> impl Iterator<Item = &'p str> {
+    pre_removed
+        .iter()
+        .filter_map(move |line| match line.split_once('/') {
+            Some((subdir, filename)) => {
+                (subdir == architecture || subdir == "noarch").then_some(filename)
+            }
+            None => Some(line.as_str()),
         })
-        .reduce(|left, right| {
-            left.intersection(&right)
-                .copied()
-                .collect::<HashSet<&str>>()
+}
+
+/// Warn about the --pre-removed filenames which are not in the source repodata, as the earlier
+/// run was likely of another channel or snapshot. Those of subdirs outside of this run are not
+/// checked.
+fn warn_unknown_pre_removed(
+    pre_removed: &[String],
+    architectures: &[String],
+    repodatas: &[RepoData],
+    repodata_noarch: &RepoData,
+) {
+    let contains = |repodata: &RepoData, filename: &str| {
+        repodata.packages.contains_key(filename) || repodata.conda_packages.contains_key(filename)
+    };
+    let unknown: Vec<&str> = pre_removed
+        .iter()
+        .filter(|line| match line.split_once('/') {
+            Some(("noarch", filename)) => !contains(repodata_noarch, filename),
+            Some((subdir, filename)) => architectures
+                .iter()
+                .position(|architecture| architecture == subdir)
+                .is_some_and(|index| !contains(&repodatas[index], filename)),
+            None => !repodatas
+                .iter()
+                .chain([repodata_noarch])
+                .any(|repodata| contains(repodata, line)),
         })
-        .unwrap();
-    // Rayon Version
-    //.reduce(HashSet::<&str>::new, |mut acc, fns| {
-    //    acc.extend(fns);
-    //    acc
-    //})
-    //.into_iter()
-    //.collect();
-    let mut removed = std::collections::HashSet::new();
-    filtered_repodata_to_file(
-        &repodata_noarch,
-        &args.output_directory,
-        |pkfn| {
-            if common_filtered_fns.contains(pkfn) {
-                removed.insert(pkfn);
-                false
-            } else {
-                true
-            }
-        },
-        "noarch",
-        &args.channel_alias,
-    )
-    .expect("Failed writing noarch repodata to file");
-    println!(
-        "Noarch packages removed: {} of {}",
-        removed.len(),
-        repodata_noarch.packages.len() + repodata_noarch.conda_packages.len()
-    );
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        warn!(
+            "warning: {} --pre-removed filenames not in the source repodata, i.e. {}",
+            unknown.len(),
+            unknown[..unknown.len().min(5)].join(", ")
+        );
+    }
 }
 
-#[inline]
-fn perform_round<'a, F, S, L>(
-    label: S,
-    action: F,
-    removed_filenames: &mut HashSet<&'a str>,
-    removed_package_names: &mut HashSet<&'a str>,
-    explain: bool,
-) where
-    S: std::fmt::Display,
-    L: conda_curation::logs::Log<'a>,
-    F: FnOnce() -> Vec<L>,
-{
-    let start = Instant::now();
-    let mut removal_count = 0;
-    for log_entry in action() {
-        if removed_filenames.insert(log_entry.filename()) {
-            removal_count += 1;
-            if explain {
-                println!("{log_entry}");
+/// Warn about name patterns, i.e. prerelease exceptions, which match no package name in any of the
+/// repodatas, as they are likely typos.
+fn warn_unmatched_name_patterns<'a>(
+    patterns: &[glob::Pattern],
+    what: &str,
+    repodatas: impl Iterator<Item = &'a RepoData>,
+) {
+    if patterns.is_empty() {
+        return;
+    }
+    let mut unmatched: Vec<&glob::Pattern> = patterns.iter().collect();
+    for repodata in repodatas {
+        for record in repodata
+            .packages
+            .values()
+            .chain(repodata.conda_packages.values())
+        {
+            unmatched.retain(|pattern| !pattern.matches(record.name.as_source()));
+        }
+    }
+    if !unmatched.is_empty() {
+        let unmatched: Vec<&str> = unmatched.iter().map(|pattern| pattern.as_str()).collect();
+        warn!(
+            "{what} matching no package in any repodata: {}",
+            unmatched.join(", ")
+        );
+    }
+}
+
+/// Report the denylist entries which match no package of any repodata, so that the denylist can
+/// be pruned.
+fn warn_unmatched_denylist<'a>(
+    denylist: &'a [DenylistEntry],
+    repodatas: impl Iterator<Item = &'a RepoData>,
+) {
+    if denylist.is_empty() {
+        return;
+    }
+    let mut unmatched: HashMap<&str, Vec<(&str, &NamelessMatchSpec)>> = HashMap::new();
+    for entry in denylist {
+        unmatched
+            .entry(&entry.package_name)
+            .or_default()
+            .push((&entry.matchspec, &entry.spec));
+    }
+    for repodata in repodatas {
+        for record in repodata
+            .packages
+            .values()
+            .chain(repodata.conda_packages.values())
+        {
+            if let Some(entries) = unmatched.get_mut(record.name.as_normalized()) {
+                entries.retain(|(_, spec)| !spec.matches(record));
             }
-            removed_package_names.insert(log_entry.package_name());
         }
     }
-    let duration = start.elapsed().as_secs_f64();
-    println!("{label:>15}: - {removal_count:>7} ({duration:>2.7}s)");
+    let mut unmatched: Vec<&str> = unmatched
+        .into_values()
+        .flatten()
+        .map(|(matchspec, _)| matchspec)
+        .collect();
+    if !unmatched.is_empty() {
+        unmatched.sort_unstable();
+        warn!(
+            "--cve-denylist entries matching no package in any repodata: {}",
+            unmatched.join(", ")
+        );
+    }
 }
 
-fn filter_repodata<'a>(
-    architecture: &'a str,
+fn load_relations<'a>(
+    architecture: &str,
     args: &'a Cli,
     matchspec_cache: &'a MatchspecCache<'a, 'a>,
-    user_matchspecs: &'a std::collections::HashMap<
-        String,
-        Vec<rattler_conda_types::NamelessMatchSpec>,
-    >,
-    banned_features: &HashSet<&str>,
     repodata_noarch: &'a RepoData,
     repodata_arch: &'a RepoData,
-) -> HashSet<&'a str> {
-    let mut relations = PackageRelations::new();
+) -> PackageRelations<'a> {
+    let mut relations = PackageRelations::new()
+        .with_enforced_constrains(args.enforce_constrains)
+        .with_virtual_packages(&args.virtual_packages)
+        .with_external_providers(args.noarch_only);
 
     for (package_filename, package_record) in
         rawrepodata::sorted_iter(&[repodata_arch, repodata_noarch])
@@ -241,106 +3935,481 @@ fn filter_repodata<'a>(
     }
     relations.shrink_to_fit();
     let (package_count, package_name_count, edges) = relations.stats();
-    println!(
+    info!(
         "  package count:   {package_count:>7} ({package_name_count} unique names, {edges} edges)"
     );
+    let subdir_mismatch_count = relations
+        .subdir_mismatch_count(&[(architecture, repodata_arch), ("noarch", repodata_noarch)]);
+    info!("  subdir mismatch: {subdir_mismatch_count:>7} (platform, arch or subdir)");
+    let dangling = relations.dangling_dependencies();
+    if !dangling.is_empty() {
+        info!(
+            "  dangling names:  {:>7} (depended on, never provided)",
+            dangling.len()
+        );
+        for (package_name, depender_count) in &dangling {
+            info!("    {package_name} ({depender_count} dependers)");
+        }
+    }
+    let missing_license_count = relations.missing_license_count();
+    info!("  no license:      {missing_license_count:>7}");
+    let bad_metadata_count = relations.bad_metadata_count();
+    if bad_metadata_count > 0 {
+        info!(
+            "  bad metadata:    {bad_metadata_count:>7} (malformed records, or unparseable depends or constrains)"
+        );
+    }
+    relations
+}
 
-    let mut removed_filenames = HashSet::new();
-    let mut next_round = HashSet::new();
-    perform_round(
-        "user matchspecs",
-        || relations.apply_user_matchspecs(user_matchspecs),
-        &mut removed_filenames,
-        &mut next_round,
-        args.explain,
-    );
-    perform_round(
-        "old builds",
-        || relations.apply_build_prune(),
-        &mut removed_filenames,
-        &mut next_round,
-        args.explain,
+/// Remove packages incompatible with the `--must-compatible-with` anchors, either one at a time
+/// or jointly.
+fn apply_must_compatible<'a>(
+    relations: &mut PackageRelations<'a>,
+    removals: &mut Removals<'a>,
+    architecture: &str,
+    args: &'a Cli,
+    matchspec_cache: &'a MatchspecCache<'a, 'a>,
+    policy: &'a Policy<'a>,
+) -> Result<(), Failure> {
+    let anchors: Vec<(&str, Option<&NamelessMatchSpec>)> = args
+        .must_compatible
+        .iter()
+        .map(|matchspec| {
+            let (package_name, spec) = split_named_matchspec(matchspec);
+            let spec = (!spec.is_empty())
+                .then(|| matchspec_cache.get_or_insert(spec))
+                .transpose()
+                .map_err(|err| {
+                    Failure::Input(format!(
+                        "invalid --must-compatible-with matchspec {matchspec}: {err}"
+                    ))
+                })?;
+            match spec {
+                Some(spec) if !relations.matches_any(package_name, spec) => {
+                    Err(Failure::MissingAnchor(format!(
+                        "--must-compatible-with {matchspec} matches no package for {architecture}"
+                    )))
+                }
+                _ => Ok((package_name, spec)),
+            }
+        })
+        .collect::<Result<_, _>>()?;
+    if args.must_compatible_all && !anchors.is_empty() {
+        // Anchor variants left unresolveable by another anchor no longer constrain anything, so
+        // repeat until nothing more is removed.
+        loop {
+            let removed_count = removals.filenames.len();
+            removals.round(format!("compat {}", policy.must_compatible_label), || {
+                relations.apply_must_compatible_all(&anchors, &policy.must_compatible_label)
+            });
+            unresolveable(relations, removals, true);
+            if removals.filenames.len() == removed_count {
+                return Ok(());
+            }
+        }
+    }
+    for (matchspec, (package_name, spec)) in args.must_compatible.iter().zip(anchors) {
+        removals.round(format!("compat {matchspec}"), || {
+            relations.apply_must_compatible(package_name, spec, args.must_compatible_max_depth)
+        });
+        unresolveable(relations, removals, true);
+    }
+    Ok(())
+}
+
+/// Remove the packages an earlier run removed, then those the channel lists as removed.
+fn apply_removed_lists<'a>(
+    relations: &mut PackageRelations<'a>,
+    removals: &mut Removals<'a>,
+    architecture: &'a str,
+    args: &'a Cli,
+    policy: &'a Policy<'a>,
+    repodatas: [&'a RepoData; 2],
+) {
+    removals.round("package ban", || {
+        relations.apply_name_ban(&policy.banned_packages)
+    });
+    if !policy.pre_removed.is_empty() {
+        removals.round("pre-removed", || {
+            relations.apply_pre_removal(pre_removed_filenames(&policy.pre_removed, architecture))
+        });
+    }
+    if !args.resurrect_removed {
+        removals.round("channel removed", || {
+            relations.apply_channel_removed(
+                repodatas
+                    .iter()
+                    .flat_map(|repodata| &repodata.removed)
+                    .map(String::as_str),
+            )
+        });
+    }
+}
+
+/// Apply the rules about missing, malformed or contradictory package metadata.
+fn apply_metadata_policies<'a>(
+    relations: &mut PackageRelations<'a>,
+    removals: &mut Removals<'a>,
+    args: &'a Cli,
+    policy: &'a Policy<'a>,
+) {
+    removals.round("dangling depends", || {
+        relations.apply_dangling_depends_removal(args.remove_dangling_depends)
+    });
+    removals.round("bad metadata", || {
+        relations.apply_bad_metadata_removal(
+            matches!(args.bad_metadata, BadMetadata::Remove),
+            !args.keep_malformed,
+        )
+    });
+    removals.round("licenses", || {
+        relations.apply_license_ban(&policy.banned_licenses)
+    });
+    removals.round("missing licenses", || {
+        relations.apply_license_requirement(
+            args.require_license,
+            &policy.user_matchspecs.allow_missing_license,
+        )
+    });
+    removals.round("checksums", || {
+        relations.apply_checksum_policy(args.require_sha256, args.require_any_digest)
+    });
+}
+
+/// Remove the builds of variants other than the selected ones: CUDA versions, BLAS
+/// implementations, compute flavors, mutexes, exactly pinned packages and python
+/// implementations and versions.
+fn apply_variant_policies<'a>(
+    relations: &mut PackageRelations<'a>,
+    removals: &mut Removals<'a>,
+    args: &'a Cli,
+    policy: &'a Policy<'a>,
+) {
+    removals.round("cuda", || {
+        relations.apply_cuda_version(args.cuda_version.as_ref())
+    });
+    removals.round("blas", || {
+        relations.apply_blas_policy(args.blas_impl.as_deref())
+    });
+    removals.round("compute flavor", || {
+        relations.apply_compute_flavor(args.compute_flavor.map(Into::into))
+    });
+    removals.round("mutexes", || {
+        relations.apply_mutex_selection(&policy.user_matchspecs.mutex)
+    });
+    removals.round("exact pins", || {
+        relations.apply_exact_pins(&policy.user_matchspecs.pin_exact)
+    });
+    removals.round("python versions", || {
+        relations.apply_python_versions(&args.python_versions)
+    });
+    removals.round("pypy", || relations.apply_pypy_ban(args.ban_pypy));
+}
+
+/// Remove the builds of a package version other than the latest or preferred ones.
+fn apply_build_policies<'a>(
+    relations: &mut PackageRelations<'a>,
+    removals: &mut Removals<'a>,
+    args: &'a Cli,
+) {
+    removals.rule(Rule::BuildPrune, "old builds", || {
+        relations.apply_build_prune()
+    });
+    removals.round("plain rebuilds", || {
+        relations.apply_plain_rebuild_prune(args.prune_plain_rebuilds)
+    });
+    removals.round("formats", || {
+        relations.apply_format_dedupe(args.dedupe_formats)
+    });
+    removals.round("variant preference", || {
+        relations.apply_variant_preference(&args.variant_preferences)
+    });
+}
+
+/// Apply every policy rule, ahead of the search for unresolveable packages.
+fn apply_policies<'a>(
+    relations: &mut PackageRelations<'a>,
+    removals: &mut Removals<'a>,
+    architecture: &'a str,
+    args: &'a Cli,
+    policy: &'a Policy<'a>,
+    run_exports: Option<&HashMap<&'a str, &'a RunExportsJson>>,
+) {
+    apply_metadata_policies(relations, removals, args, policy);
+    let user_matchspecs = policy.user_matchspecs.matchspecs_for(architecture);
+    if !user_matchspecs.is_empty() && !removals.skips(Rule::UserMatchspecs) {
+        removals.round("user matchspecs", || {
+            relations.apply_user_matchspecs(&user_matchspecs)
+        });
+        warn_unmatched_user_specs(relations, architecture, &user_matchspecs);
+    }
+    removals.round("name lists", || {
+        relations.apply_name_lists(&policy.denied_names, policy.allowed_names.as_deref())
+    });
+    removals.round("filenames", || {
+        relations.apply_filename_ban(&policy.banned_filenames)
+    });
+    removals.round("vulnerabilities", || {
+        relations.apply_vulnerability_denylist(&policy.vulnerability_denylist)
+    });
+    removals.round("min versions", || {
+        relations.apply_minimum_versions(&policy.user_matchspecs.minimum_versions)
+    });
+    removals.round("pins", || {
+        relations.apply_pin_conflicts(&policy.user_matchspecs.pins)
+    });
+    apply_build_policies(relations, removals, args);
+    removals.rule(Rule::Features, "features", || {
+        relations.apply_feature_removal(&policy.banned_features)
+    });
+    let mut banned_noarch = Vec::new();
+    removals.round("noarch types", || {
+        let banned: Vec<NoArchKind> = args
+            .ban_noarch_types
+            .iter()
+            .map(|&kind| kind.into())
+            .collect();
+        let result = relations.apply_noarch_type_ban(&banned);
+        banned_noarch.extend(result.iter().map(|log| log.filename));
+        result
+    });
+    removals.unconditional.extend(banned_noarch);
+    removals.round("track features", || {
+        relations.apply_track_features_ban(args.ban_all_track_features)
+    });
+    removals.round("timestamp", || {
+        relations.apply_timestamp_cutoff(
+            args.max_timestamp,
+            matches!(args.timestamp_missing, TimestampMissing::Remove),
+        )
+    });
+    removals.round("recency", || {
+        relations.apply_recency_window(
+            args.only_newer_than,
+            policy.now,
+            matches!(args.timestamp_missing, TimestampMissing::Remove),
+        )
+    });
+    removals.round("size", || relations.apply_size_limit(args.max_package_size));
+    apply_variant_policies(relations, removals, args, policy);
+    removals.round("build strings", || {
+        relations.apply_build_string_ban(&policy.banned_builds)
+    });
+    removals.round("run exports", || {
+        relations.apply_run_exports_ban(run_exports, &args.ban_run_exports)
+    });
+    removals.round("dependers", || {
+        relations.apply_depender_ban(&args.ban_dependers)
+    });
+    removals.rule(Rule::DevRc, "prereleases", || {
+        relations.apply_prerelease_ban(
+            &policy.banned_prereleases,
+            &policy.user_matchspecs.prerelease_exceptions,
+        )
+    });
+    removals.round("local versions", || {
+        relations.apply_local_version_ban(args.ban_local_versions)
+    });
+    removals.round("epochs", || {
+        relations.apply_epoch_ban(args.ban_epochs, &policy.user_matchspecs.allow_epochs)
+    });
+    removals.rule(Rule::IncompatArch, "incompat arch", || {
+        relations.apply_incompatible_architecture(architecture)
+    });
+    removals.round("old versions", || {
+        relations.apply_version_prune(
+            args.keep_versions,
+            &policy.user_matchspecs.keep_versions_for(architecture),
+        )
+    });
+    removals.round("version series", || {
+        relations.apply_series_prune(&policy.user_matchspecs.series_prune)
+    });
+    removals.round("python abi", || relations.apply_python_abi_coupling());
+}
+
+/// Warn about the package names of the user matchspecs whose matchspecs left no package of the
+/// name, as the constraint is then likely impossible.
+fn warn_unmatched_user_specs(
+    relations: &PackageRelations,
+    architecture: &str,
+    user_matchspecs: &HashMap<String, Vec<NamelessMatchSpec>>,
+) {
+    for unmatched in relations.unmatched_user_matchspecs(user_matchspecs) {
+        warn!("warning: user matchspecs for {architecture}: {unmatched}");
+    }
+}
+
+/// The metadata an architecture is filtered with: that of its own subdir, and that of noarch.
+struct SubdirMetadata<'a> {
+    repodata_noarch: &'a RepoData,
+    repodata_arch: &'a RepoData,
+    run_exports: Vec<&'a RunExportsIndex>,
+    /// The extras of the subdir itself first, then those of noarch.
+    extras: [&'a RepodataExtras; 2],
+}
+
+fn filter_repodata<'a>(
+    architecture: &'a str,
+    args: &'a Cli,
+    matchspec_cache: &'a MatchspecCache<'a, 'a>,
+    policy: &'a Policy<'a>,
+    metadata: &SubdirMetadata<'a>,
+    progress: &Progress,
+    rounds: &RoundSink,
+) -> Result<Removals<'a>, Failure> {
+    let SubdirMetadata {
+        repodata_noarch,
+        repodata_arch,
+        ref run_exports,
+        extras,
+    } = *metadata;
+    let signatures = extras.map(|extras| &extras.signatures);
+    let mut relations = load_relations(
+        architecture,
+        args,
+        matchspec_cache,
+        repodata_noarch,
+        repodata_arch,
     );
-    perform_round(
-        "features",
-        || relations.apply_feature_removal(banned_features),
-        &mut removed_filenames,
-        &mut next_round,
-        args.explain,
+    lock_packages(&mut relations, architecture, &policy.locked_packages);
+    let run_exports: Option<HashMap<&str, &RunExportsJson>> = (!run_exports.is_empty())
+        .then(|| run_exports.iter().flat_map(|index| index.iter()).collect());
+    let publishes_signatures = signatures.iter().any(|signatures| !signatures.is_empty());
+    if publishes_signatures || args.require_signatures {
+        let unsigned_count = relations.unsigned_count(&signatures);
+        info!("  unsigned:        {unsigned_count:>7}");
+    }
+    if args.require_signatures && !publishes_signatures {
+        warn!("warning: --require-signatures ignored, no signatures published for {architecture}");
+    }
+    let (package_count, _, _) = relations.stats();
+
+    let mut removals = Removals::new(
+        architecture,
+        package_count,
+        progress,
+        rounds,
+        policy,
+        &args.skip_rules,
     );
-    perform_round(
-        "dev & rc",
-        || relations.apply_dev_rc_ban(args.ban_dev, args.ban_rc),
-        &mut removed_filenames,
-        &mut next_round,
-        args.explain,
+    apply_removed_lists(
+        &mut relations,
+        &mut removals,
+        architecture,
+        args,
+        policy,
+        [repodata_arch, repodata_noarch],
     );
-    perform_round(
-        "incompat arch",
-        || relations.apply_incompatible_architecture(architecture),
-        &mut removed_filenames,
-        &mut next_round,
-        args.explain,
+    removals.round("subdir consistency", || {
+        relations.apply_subdir_consistency(
+            &[(architecture, repodata_arch), ("noarch", repodata_noarch)],
+            !args.keep_subdir_mismatches,
+            args.enforce_subdir_consistency,
+        )
+    });
+    removals.round("origin channels", || {
+        relations.apply_origin_channel_ban(
+            &policy.banned_origin_channels,
+            &extras.map(|extras| &extras.origin_channels),
+        )
+    });
+    removals.round("signatures", || {
+        relations.apply_signature_requirement(
+            args.require_signatures && publishes_signatures,
+            &signatures,
+        )
+    });
+    apply_policies(
+        &mut relations,
+        &mut removals,
+        architecture,
+        args,
+        policy,
+        run_exports.as_ref(),
     );
-    unresolveable(&mut relations, &mut removed_filenames, None, args.explain);
+    unresolveable(&mut relations, &mut removals, false);
 
-    for package_name in &args.must_compatible {
-        perform_round(
-            format!("compat {package_name}"),
-            || relations.apply_must_compatible(package_name),
-            &mut removed_filenames,
-            &mut next_round,
-            args.explain,
-        );
-        unresolveable(
-            &mut relations,
-            &mut removed_filenames,
-            Some(&next_round),
-            args.explain,
-        );
+    apply_must_compatible(
+        &mut relations,
+        &mut removals,
+        architecture,
+        args,
+        matchspec_cache,
+        policy,
+    )?;
+    removals.round("closure", || {
+        relations.apply_closure(policy.closure_roots.as_ref())
+    });
+    removals.round("orphans", || {
+        relations.apply_orphan_removal(
+            args.remove_orphans,
+            &policy.user_matchspecs.matchspecs_for(architecture),
+            &policy.kept_orphans,
+        )
+    });
+    if args.analyze_cycles {
+        analyze_cycles(&mut relations, &mut removals, args);
+    }
+    if args.write_current_repodata {
+        removals.current = Some(relations.current_filenames());
     }
+    removals.finish_progress();
+    removals.log_summary();
+    Ok(removals)
+}
 
-    // We want to round up the floating point value that we calculate.
-    // Integer division rounds down. So, we'll calculate the percentage
-    // of packages we removed, and then subtract 1 from it instead.
-    let total_removed_count = removed_filenames.len();
-    let remaining_count = package_count - total_removed_count;
-    let percent = 100 - (total_removed_count * 100 / package_count);
-    println!("=============================================");
-    println!("      Remaining:   {remaining_count:>7} ({percent}% of original)");
-    removed_filenames
+/// Report the dependency cycles among the remaining packages, after optionally removing packages
+/// which depend on their own name with an unsatisfiable spec.
+fn analyze_cycles<'a>(
+    relations: &mut PackageRelations<'a>,
+    removals: &mut Removals<'a>,
+    args: &Cli,
+) {
+    removals.round("self cycles", || {
+        relations.apply_self_cycle_removal(args.break_self_cycles)
+    });
+    unresolveable(relations, removals, true);
+    let cycles = relations.detect_cycles();
+    info!("  dependency cycles: {:>5}", cycles.len());
+    for cycle in &cycles {
+        info!("    {cycle}");
+    }
 }
 
 /// Find packages which definitely have no possible solution and remove them. This operation is
 /// recursive, i.e. once some packages are removed for being unsolveable, this may make additional
 /// packages unsolveable, and this operation will handle this appropriately.
-/// If the `test_set` is None, then all packages in the entire repodata will be tested. Otherwise,
-/// if `test_set` is provided, analysis will begin at packages that depend on the affected package
-/// set.
+/// If `after_removals` is false, then all packages in the entire repodata will be tested.
+/// Otherwise, analysis will begin at packages that depend on the package names removed by
+/// earlier rounds.
 fn unresolveable<'a>(
     relations: &mut PackageRelations<'a>,
-    removed_filenames: &mut HashSet<&'a str>,
-    test_set: Option<&HashSet<&'a str>>,
-    explain: bool,
+    removals: &mut Removals<'a>,
+    after_removals: bool,
 ) {
+    if removals.skips(Rule::Unresolveable) {
+        return;
+    }
     let mut round = 0;
 
     let mut next_round: HashSet<&'a str>;
 
     // Are we analyzing the entire repodata or just a subset?
-    match test_set {
-        None => {
-            next_round = HashSet::new();
-            round += 1;
-            perform_round(
-                format!("No Sln Round {round}"),
-                || relations.find_all_unresolveables(),
-                removed_filenames,
-                &mut next_round,
-                explain,
-            );
-        }
-        Some(test_set) => next_round = test_set.clone(),
+    if after_removals {
+        next_round = removals.package_names.clone();
+    } else {
+        next_round = HashSet::new();
+        round += 1;
+        let report = perform_round(
+            format!("No Sln Round {round}"),
+            || relations.find_all_unresolveables(),
+            &mut removals.filenames,
+            &mut next_round,
+            &mut removals.output,
+        );
+        removals.record_round(report);
     }
 
     // Keep attempting to remove packages until a round fails to remove any packages at all.
@@ -348,15 +4417,500 @@ fn unresolveable<'a>(
         round += 1;
         let this_round = next_round.clone();
         next_round.clear();
-        perform_round(
+        let report = perform_round(
             format!("No Sln Round {round}"),
             || relations.find_unresolveables(this_round.into_iter().collect()),
-            removed_filenames,
+            &mut removals.filenames,
             &mut next_round,
-            explain,
+            &mut removals.output,
         );
+        removals.record_round(report);
         if next_round.is_empty() {
             break;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        age_parser, architectures_parser, check_max_removal, curated_locked_packages,
+        interval_parser, parse_env_list, pre_removed_filenames, removal_chain, share_parser,
+        size_parser, split_build_regex, timestamp_parser, verify_locked_output,
+        virtual_package_parser, Command, Explain, ExplainFormat, Failure, Invocation, Reason, Rule,
+        ARCHITECTURES, ARCHITECTURES_VARIABLE,
+    };
+    use clap::{CommandFactory, Parser};
+    use conda_curation::lockfile::LockedPackage;
+    use conda_curation::logs::Category;
+    use conda_curation::logs::RemovedUnsatisfiableLog;
+    use conda_curation::rawrepodata::{CacheMode, CacheState, SubdirCache};
+    use conda_curation::report::{ArchitectureReport, RoundReport};
+    use rattler_conda_types::{NamelessMatchSpec, ParseStrictness};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    /// Parsing reads the `CONDA_CURATION_*` variables, which a test sets, so every parse holds
+    /// this lock.
+    static ENVIRONMENT: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn try_parse_from<T: Into<std::ffi::OsString> + Clone>(
+        arguments: impl IntoIterator<Item = T>,
+    ) -> Result<Invocation, clap::Error> {
+        let _environment = ENVIRONMENT
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        Invocation::try_parse_from(arguments)
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(size_parser("1234"), Ok(1234));
+        assert_eq!(size_parser("500M"), Ok(500 * 1024 * 1024));
+        assert_eq!(size_parser("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(size_parser("2X").is_err());
+        assert!(size_parser("M").is_err());
+    }
+
+    #[test]
+    fn parse_shares() {
+        assert_eq!(share_parser("60%"), Ok(0.6));
+        assert_eq!(share_parser("0.25"), Ok(0.25));
+        assert_eq!(share_parser("100%"), Ok(1.0));
+        assert!(share_parser("150%").is_err());
+        assert!(share_parser("-1").is_err());
+        assert!(share_parser("most").is_err());
+    }
+
+    #[test]
+    fn max_removal_names_the_largest_rules() {
+        let round = |label: &str, removed| RoundReport {
+            label: label.to_string(),
+            removed,
+            seconds: 0.0,
+        };
+        let report = ArchitectureReport {
+            architecture: "linux-64".to_string(),
+            packages_before: 100,
+            packages_after: 3,
+            statistics: None,
+            rounds: vec![
+                round("old builds", 2),
+                round("user matchspecs", 80),
+                round("No Sln Round 1", 10),
+                round("No Sln Round 2", 5),
+                round("features", 0),
+            ],
+            most_removed: Vec::new(),
+        };
+        assert!(check_max_removal(None, &report).is_ok());
+        assert!(check_max_removal(Some(0.97), &report).is_ok());
+        let Err(Failure::RemovalOver(message)) = check_max_removal(Some(0.5), &report) else {
+            panic!("expected a removal failure");
+        };
+        assert_eq!(
+            message,
+            "97.0% of the packages of linux-64 removed, over --fail-on-removal-over 50.0%, nothing \
+             was written; most removed by: user matchspecs 80, unresolveable 15, old builds 2"
+        );
+    }
+
+    #[test]
+    fn parse_build_regexes() {
+        assert_eq!(
+            split_build_regex("numpy:.*_debug.*"),
+            (Some("numpy"), ".*_debug.*")
+        );
+        assert_eq!(split_build_regex(".*cuda92.*"), (None, ".*cuda92.*"));
+        assert_eq!(split_build_regex("(?:cuda92)"), (None, "(?:cuda92)"));
+    }
+
+    #[test]
+    fn parse_timestamps() {
+        let expected = chrono::DateTime::from_timestamp(1_685_577_600, 0).unwrap();
+        assert_eq!(timestamp_parser("2023-06-01"), Ok(expected));
+        assert_eq!(timestamp_parser("1685577600"), Ok(expected));
+        assert_eq!(timestamp_parser("2023-06-01T00:00:00Z"), Ok(expected));
+        assert!(timestamp_parser("June 1st").is_err());
+    }
+
+    #[test]
+    fn parse_virtual_packages() {
+        let record = virtual_package_parser("__glibc=2.28").unwrap();
+        assert_eq!(record.name.as_source(), "__glibc");
+        assert_eq!(record.version.to_string(), "2.28");
+        assert!(virtual_package_parser("glibc=2.28").is_err());
+        assert!(virtual_package_parser("__glibc").is_err());
+    }
+
+    #[test]
+    fn parse_ages() {
+        assert_eq!(age_parser("90d"), Ok(chrono::TimeDelta::days(90)));
+        assert_eq!(age_parser("12w"), Ok(chrono::TimeDelta::weeks(12)));
+        assert_eq!(age_parser("36h"), Ok(chrono::TimeDelta::hours(36)));
+        assert!(age_parser("90").is_err());
+        assert!(age_parser("d").is_err());
+    }
+
+    #[test]
+    fn subcommands_are_optional() {
+        Invocation::command().debug_assert();
+        let invocation =
+            try_parse_from(["conda_curation", "-a", "linux-64", "specs.yaml"]).unwrap();
+        assert!(invocation.command.is_none());
+        assert_eq!(invocation.curate.channel.architectures, ["linux-64"]);
+        let invocation = try_parse_from(["conda_curation", "curate", "specs.yaml"]).unwrap();
+        assert!(matches!(invocation.command, Some(Command::Curate(_))));
+        let invocation = try_parse_from(["conda_curation", "completions", "fish"]).unwrap();
+        assert!(matches!(
+            invocation.command,
+            Some(Command::Completions {
+                shell: clap_complete::Shell::Fish
+            })
+        ));
+        let invocation = try_parse_from([
+            "conda_curation",
+            "why",
+            "scipy-1.11.4-py311h64a7726_0.conda",
+            "specs.yaml",
+            "-a",
+            "linux-64",
+        ])
+        .unwrap();
+        let Some(Command::Why(why)) = invocation.command else {
+            panic!("expected the why subcommand");
+        };
+        assert_eq!(why.target, "scipy-1.11.4-py311h64a7726_0.conda");
+        assert_eq!(why.curate.matchspecs_yaml, Some("specs.yaml".into()));
+        assert_eq!(why.curate.channel.architectures, ["linux-64"]);
+        let invocation =
+            try_parse_from(["conda_curation", "explain", "scipy", "-a", "linux-64"]).unwrap();
+        assert!(matches!(invocation.command, Some(Command::Why(_))));
+        let invocation = try_parse_from([
+            "conda_curation",
+            "fetch",
+            "-a",
+            "linux-64",
+            "--cache-dir",
+            "/var/cache/conda",
+        ])
+        .unwrap();
+        let Some(Command::Fetch(fetch)) = invocation.command else {
+            panic!("expected the fetch subcommand");
+        };
+        assert_eq!(fetch.channel.architectures, ["linux-64"]);
+        assert_eq!(fetch.channel.cache_dir, Some("/var/cache/conda".into()));
+        let invocation = try_parse_from(["conda_curation", "diff", "old", "new"]).unwrap();
+        assert!(matches!(invocation.command, Some(Command::Diff(_))));
+    }
+
+    #[test]
+    fn excluded_architectures() {
+        let resolve = |arguments: &[&str]| {
+            let mut invocation =
+                try_parse_from(["conda_curation"].iter().chain(arguments)).unwrap();
+            invocation
+                .curate
+                .channel
+                .resolve()
+                .map(|()| invocation.curate.channel)
+        };
+        let channel = resolve(&[
+            "--exclude-architecture",
+            "win-64",
+            "--exclude-architecture",
+            "zos-z",
+        ])
+        .ok()
+        .unwrap();
+        assert!(!channel.architectures.iter().any(|arch| arch == "win-64"));
+        assert_eq!(channel.architectures.len(), ARCHITECTURES.len() - 1);
+        assert!(resolve(&["-a", "osx-64", "--exclude-architecture", "win-64"]).is_ok());
+        assert!(resolve(&["-a", "win-64", "--exclude-architecture", "win-64"]).is_err());
+    }
+
+    #[test]
+    fn cache_ages() {
+        let channel = |arguments: &[&str]| {
+            try_parse_from(["conda_curation"].iter().chain(arguments))
+                .map(|invocation| invocation.curate.channel)
+        };
+        let ages = [
+            SubdirCache {
+                subdir: "linux-64".to_string(),
+                url: "https://conda.anaconda.org/conda-forge/linux-64/".to_string(),
+                state: CacheState::Cached,
+                age: Duration::from_secs(3 * 3600),
+            },
+            SubdirCache {
+                subdir: "noarch".to_string(),
+                url: "https://conda.anaconda.org/conda-forge/noarch/".to_string(),
+                state: CacheState::Fetched,
+                age: Duration::ZERO,
+            },
+        ];
+        assert!(channel(&[]).unwrap().check_cache_ages(&ages).is_ok());
+        let Err(Failure::Input(message)) = channel(&["--max-cache-age", "2h"])
+            .unwrap()
+            .check_cache_ages(&ages)
+        else {
+            panic!("expected stale repodata to fail");
+        };
+        assert_eq!(
+            message,
+            "cached repodata older than --max-cache-age: linux-64 (3.0h old)"
+        );
+        assert!(channel(&["--max-cache-age", "2h", "--allow-stale"])
+            .unwrap()
+            .check_cache_ages(&ages)
+            .is_ok());
+        assert!(channel(&["--max-cache-age", "4h"])
+            .unwrap()
+            .check_cache_ages(&ages)
+            .is_ok());
+        assert!(channel(&["--allow-stale"]).is_err());
+        assert!(channel(&["--offline", "--refresh"]).is_err());
+        assert_eq!(
+            channel(&["--refresh"]).unwrap().cache_mode(false),
+            CacheMode::Refresh
+        );
+    }
+
+    #[test]
+    fn pre_removed_by_subdir() {
+        let pre_removed = [
+            "linux-64/a-1.0-0.conda".to_string(),
+            "osx-64/b-1.0-0.conda".to_string(),
+            "noarch/c-1.0-0.conda".to_string(),
+            "d-1.0-0.tar.bz2".to_string(),
+        ];
+        assert_eq!(
+            pre_removed_filenames(&pre_removed, "linux-64").collect::<Vec<_>>(),
+            ["a-1.0-0.conda", "c-1.0-0.conda", "d-1.0-0.tar.bz2"]
+        );
+    }
+
+    #[test]
+    fn parse_env_lists() {
+        assert_eq!(
+            parse_env_list(
+                ARCHITECTURES_VARIABLE,
+                "linux-64, osx-arm64",
+                architectures_parser
+            ),
+            Ok(vec!["linux-64".to_string(), "osx-arm64".to_string()])
+        );
+        assert_eq!(
+            parse_env_list(
+                ARCHITECTURES_VARIABLE,
+                "linux-64,,osx-arm64",
+                architectures_parser
+            ),
+            Err(
+                "CONDA_CURATION_ARCHITECTURES=\"linux-64,,osx-arm64\": entry 2 \"\" is invalid: \
+                 cannot be an empty string"
+                    .to_string()
+            )
+        );
+        assert!(parse_env_list(
+            ARCHITECTURES_VARIABLE,
+            "linux-64,noarch",
+            architectures_parser
+        )
+        .unwrap_err()
+        .contains("entry 2 \"noarch\""));
+    }
+
+    #[test]
+    fn locked_packages_checked_in_curated_subdirs() {
+        let directory = std::env::temp_dir().join(format!("lockedoutput-{}", std::process::id()));
+        for (subdir, filename) in [
+            ("linux-64", "python-3.12.2-0.conda"),
+            ("noarch", "six-1.16.0-0.conda"),
+        ] {
+            let path = directory.join(subdir);
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(
+                path.join("repodata.json"),
+                format!(r#"{{"packages.conda": {{"{filename}": {{"name": "x", "version": "1", "build": "0", "build_number": 0}}}}}}"#),
+            )
+            .unwrap();
+        }
+        let locked = |name: &str, version: &str, subdir: &str| LockedPackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            build: "0".to_string(),
+            subdir: subdir.to_string(),
+        };
+        let mut locked_packages = vec![
+            locked("python", "3.12.2", "linux-64"),
+            locked("six", "1.16.0", "noarch"),
+            locked("python", "3.12.2", "osx-arm64"),
+        ];
+        let architectures = ["linux-64".to_string()];
+        let curated = curated_locked_packages(&locked_packages, &architectures);
+        assert_eq!(curated.len(), 2);
+        assert!(verify_locked_output(&directory, &curated).is_ok());
+        assert_eq!(
+            curated_locked_packages(&locked_packages, &[]).len(),
+            1,
+            "only noarch is curated with --noarch-only"
+        );
+
+        locked_packages.push(locked("zlib", "1.3.1", "linux-64"));
+        let curated = curated_locked_packages(&locked_packages, &architectures);
+        assert!(matches!(
+            verify_locked_output(&directory, &curated),
+            Err(Failure::LockedMissing(message)) if message.ends_with("linux-64/zlib-1.3.1-0.conda")
+        ));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn command_line_takes_precedence_over_environment() {
+        let _environment = ENVIRONMENT
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        std::env::set_var("CONDA_CURATION_OUTPUT_DIR", "/srv/channel");
+        std::env::set_var("CONDA_CURATION_OFFLINE", "yes");
+        let invocation = Invocation::try_parse_from(["conda_curation"]).unwrap();
+        assert_eq!(
+            invocation.curate.output_directory,
+            std::path::Path::new("/srv/channel")
+        );
+        assert!(invocation.curate.is_offline);
+        let invocation =
+            Invocation::try_parse_from(["conda_curation", "curate", "-o", "out", "--offline"])
+                .unwrap();
+        let Some(Command::Curate(args)) = invocation.command else {
+            panic!("expected the curate subcommand");
+        };
+        assert_eq!(args.output_directory, std::path::Path::new("out"));
+        assert!(args.is_offline);
+        std::env::set_var("CONDA_CURATION_OFFLINE", "0");
+        assert!(
+            !Invocation::try_parse_from(["conda_curation"])
+                .unwrap()
+                .curate
+                .is_offline
+        );
+        std::env::set_var("CONDA_CURATION_OFFLINE", "maybe");
+        assert!(Invocation::try_parse_from(["conda_curation"]).is_err());
+        std::env::remove_var("CONDA_CURATION_OUTPUT_DIR");
+        std::env::remove_var("CONDA_CURATION_OFFLINE");
+    }
+
+    #[test]
+    fn explain_packages() {
+        let matchspec = NamelessMatchSpec::from_str(">=1.2", ParseStrictness::Lenient).unwrap();
+        let unsatisfiable = RemovedUnsatisfiableLog {
+            filename: "pyxyz-1.0-py312_0.conda",
+            package_name: "pyxyz",
+            dependency_package_name: "libxyz",
+            matchspec: &matchspec,
+            cause_filename: Some("libxyz-1.2-h0_0.conda"),
+            constraint: false,
+            virtual_package: None,
+        };
+        let explain = |pattern: &str| Explain::Packages(vec![glob::Pattern::new(pattern).unwrap()]);
+        assert!(explain("py*").wants(&unsatisfiable));
+        assert!(explain("libxyz").wants(&unsatisfiable));
+        assert!(explain("libxyz-1.2-*").wants(&unsatisfiable));
+        assert!(!explain("numpy").wants(&unsatisfiable));
+        assert!(Explain::All.wants(&unsatisfiable));
+        assert!(!Explain::Nothing.wants(&unsatisfiable));
+    }
+
+    #[test]
+    fn explain_colors() {
+        let mut format = ExplainFormat {
+            color: false,
+            group_by: None,
+        };
+        assert_eq!(
+            format.paint(Category::Unsatisfiable, "a removed".to_string()),
+            "a removed"
+        );
+        format.color = true;
+        assert_eq!(
+            format.paint(Category::Unsatisfiable, "a removed".to_string()),
+            "\x1b[31ma removed\x1b[0m"
+        );
+        assert_eq!(
+            format.paint(Category::Other, "a removed".to_string()),
+            "a removed"
+        );
+    }
+
+    #[test]
+    fn removal_chains() {
+        let reason = |rule: &str, message: &str, cause: Option<&str>| Reason {
+            rule: rule.to_string(),
+            message: message.to_string(),
+            cause_filename: cause.map(str::to_string),
+        };
+        let reasons = HashMap::from([
+            (
+                "scipy-1.11.4-0.conda".to_string(),
+                reason(
+                    "No Sln Round 2",
+                    "scipy-1.11.4-0.conda removed: dependency libxyz >=2 unsatisfiable after \
+                     removal of libxyz-2.0-0.conda",
+                    Some("libxyz-2.0-0.conda"),
+                ),
+            ),
+            (
+                "libxyz-2.0-0.conda".to_string(),
+                reason(
+                    "old builds",
+                    "libxyz-2.0-0.conda removed: superceded by build 1, libxyz-2.0-1.conda",
+                    None,
+                ),
+            ),
+        ]);
+        assert_eq!(
+            removal_chain("scipy-1.11.4-0.conda", &reasons),
+            [
+                "  [No Sln Round 2] scipy-1.11.4-0.conda removed: dependency libxyz >=2 \
+                 unsatisfiable after removal of libxyz-2.0-0.conda",
+                "    [old builds] libxyz-2.0-0.conda removed: superceded by build 1, \
+                 libxyz-2.0-1.conda",
+            ]
+        );
+        assert_eq!(
+            removal_chain("numpy-2.0-0.conda", &reasons),
+            ["  numpy-2.0-0.conda is kept"]
+        );
+    }
+
+    #[test]
+    fn rule_names() {
+        let invocation = try_parse_from([
+            "conda_curation",
+            "--skip-rule",
+            "build-prune",
+            "--skip-rule",
+            "dev-rc",
+        ])
+        .unwrap();
+        let skipped: Vec<String> = invocation
+            .curate
+            .skip_rules
+            .iter()
+            .map(Rule::to_string)
+            .collect();
+        assert_eq!(skipped, ["build-prune", "dev-rc"]);
+        assert!(try_parse_from(["conda_curation", "--skip-rule", "old-builds"]).is_err());
+    }
+
+    #[test]
+    fn parse_intervals() {
+        assert_eq!(interval_parser("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(interval_parser("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(interval_parser("6h"), Ok(Duration::from_secs(6 * 60 * 60)));
+        assert_eq!(interval_parser("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert!(interval_parser("0h").is_err());
+        assert!(interval_parser("6").is_err());
+        assert!(interval_parser("h").is_err());
+    }
+}
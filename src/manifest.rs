@@ -0,0 +1,179 @@
+use crate::rawrepodata::{CacheState, SubdirCache};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The file of the output directory the manifest is written to.
+pub const MANIFEST_FILENAME: &str = "curation-manifest.json";
+
+/// The version of the schema of the manifest, increased whenever a field is removed or changes
+/// meaning.
+pub const MANIFEST_SCHEMA_VERSION: u32 = 1;
+
+/// How an output directory was produced, written once everything else of the run was, so that
+/// the output of a run which failed part way has no manifest.
+#[derive(Serialize)]
+pub struct CurationManifest {
+    pub schema_version: u32,
+    pub version: &'static str,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    /// Every option of the run, from the command line, the --config file and the environment,
+    /// keyed by field name.
+    pub config: serde_yaml::Mapping,
+    /// The SHA-256 of each input file, by path.
+    pub inputs: BTreeMap<String, String>,
+    pub upstream: Vec<UpstreamRepodata>,
+    pub subdirs: Vec<SubdirCounts>,
+}
+
+/// The repodata of a subdir as fetched from the channel.
+#[derive(Serialize)]
+pub struct UpstreamRepodata {
+    pub subdir: String,
+    pub url: String,
+    pub cache: CacheState,
+    pub cache_age_seconds: u64,
+}
+
+impl From<&SubdirCache> for UpstreamRepodata {
+    fn from(cache: &SubdirCache) -> Self {
+        UpstreamRepodata {
+            subdir: cache.subdir.clone(),
+            url: cache.url.clone(),
+            cache: cache.state,
+            cache_age_seconds: cache.age.as_secs(),
+        }
+    }
+}
+
+/// The packages of a subdir before and after filtering. Those of an architecture include the
+/// noarch packages, as in the report of `--report-json`.
+#[derive(Serialize)]
+pub struct SubdirCounts {
+    pub subdir: String,
+    pub packages_before: usize,
+    pub packages_after: usize,
+}
+
+impl CurationManifest {
+    #[must_use]
+    pub fn new(started: DateTime<Utc>, config: serde_yaml::Mapping) -> Self {
+        CurationManifest {
+            schema_version: MANIFEST_SCHEMA_VERSION,
+            version: env!("CARGO_PKG_VERSION"),
+            started,
+            finished: None,
+            config,
+            inputs: BTreeMap::new(),
+            upstream: Vec::new(),
+            subdirs: Vec::new(),
+        }
+    }
+
+    /// Record the SHA-256 of an input file.
+    pub fn add_input(&mut self, filename: &Path) -> Result<(), std::io::Error> {
+        let digest = rattler_digest::compute_file_digest::<rattler_digest::Sha256>(filename)?;
+        self.inputs
+            .insert(filename.display().to_string(), format!("{digest:x}"));
+        Ok(())
+    }
+
+    /// Write the manifest into the output directory, finished now. It is written to a sibling file
+    /// first, so that it is never seen incomplete.
+    pub fn write(mut self, output_directory: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.finished = Some(Utc::now());
+        self.subdirs
+            .sort_unstable_by(|a, b| a.subdir.cmp(&b.subdir));
+        let mut json = serde_json::to_string_pretty(&self)?;
+        json.push('\n');
+        let filename = output_directory.join(MANIFEST_FILENAME);
+        let partial = output_directory.join(format!("{MANIFEST_FILENAME}.partial"));
+        std::fs::write(&partial, json)?;
+        std::fs::rename(&partial, filename)?;
+        Ok(())
+    }
+}
+
+/// Remove the manifest of an earlier run from the output directory, before anything of this run is
+/// written to it.
+pub fn remove_manifest(output_directory: &Path) -> Result<(), std::io::Error> {
+    match std::fs::remove_file(output_directory.join(MANIFEST_FILENAME)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// The marker `--stamp-info` adds to the `info` of every repodata: the version of the tool and
+/// the SHA-256 of its options, which stays the same for as long as the options do.
+#[must_use]
+pub fn info_stamp(config: &serde_yaml::Mapping) -> serde_json::Value {
+    let config = serde_json::to_vec(config).unwrap_or_default();
+    let digest = rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(config);
+    serde_json::json!({
+        "tool": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "config_sha256": format!("{digest:x}"),
+        "manifest": format!("../{MANIFEST_FILENAME}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::{
+        info_stamp, remove_manifest, CurationManifest, SubdirCounts, UpstreamRepodata,
+        MANIFEST_FILENAME,
+    };
+    use crate::rawrepodata::{CacheState, SubdirCache};
+    use std::time::Duration;
+
+    #[test]
+    fn manifest_is_written_last() {
+        let directory = std::env::temp_dir().join(format!("manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("specs.yaml");
+        std::fs::write(&input, "python: [\">=3.10\"]\n").unwrap();
+
+        let mut config = serde_yaml::Mapping::new();
+        config.insert("keep_dev".into(), false.into());
+        let mut manifest = CurationManifest::new(chrono::Utc::now(), config.clone());
+        manifest.add_input(&input).unwrap();
+        manifest.upstream.push(UpstreamRepodata::from(&SubdirCache {
+            subdir: "linux-64".to_string(),
+            url: "https://conda.anaconda.org/conda-forge/linux-64/".to_string(),
+            state: CacheState::Confirmed,
+            age: Duration::ZERO,
+        }));
+        manifest.subdirs.push(SubdirCounts {
+            subdir: "linux-64".to_string(),
+            packages_before: 10,
+            packages_after: 7,
+        });
+        manifest.write(&directory).unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(directory.join(MANIFEST_FILENAME)).unwrap())
+                .unwrap();
+        remove_manifest(&directory).unwrap();
+        remove_manifest(&directory).unwrap();
+        let remaining: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(remaining, ["specs.yaml"]);
+        assert_eq!(written["schema_version"], 1);
+        assert_eq!(written["config"]["keep_dev"], false);
+        assert_eq!(
+            written["inputs"][input.display().to_string()],
+            "420dda62cb8b6b959341328afe201860b577eaaf83390b199e43b62217f540d7"
+        );
+        assert_eq!(written["upstream"][0]["cache"], "confirmed");
+        assert_eq!(written["subdirs"][0]["packages_after"], 7);
+        assert!(written["finished"].is_string());
+        assert_eq!(info_stamp(&config), info_stamp(&config));
+        assert_eq!(info_stamp(&config)["tool"], "conda_curation");
+    }
+}
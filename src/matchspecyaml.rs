@@ -1,29 +1,908 @@
-use rattler_conda_types::{NamelessMatchSpec, ParseStrictness};
+use rattler_conda_types::{
+    BuildNumberSpec, MatchSpec, NamelessMatchSpec, PackageRecord, ParseStrictness, Platform,
+};
+use serde::Deserialize;
 use serde_yaml;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 
+/// A package's entry in the matchspecs YAML. Either a plain list of matchspecs, or a mapping
+/// which may also carry per-package settings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PackageEntry {
+    Matchspecs(Vec<String>),
+    Detailed {
+        matchspecs: Option<Vec<String>>,
+        keep_versions: Option<usize>,
+    },
+}
+
+/// A package's entry in the `series_prune:` section of the matchspecs YAML.
+#[derive(Deserialize)]
+struct SeriesPruneEntry {
+    series: String,
+    keep_series: usize,
+    keep_per_series: usize,
+}
+
+/// How many versions of a package to keep, grouped into series by their leading version segments.
+pub struct SeriesPrune {
+    /// The number of leading version segments which make up a series, i.e. 2 for `major.minor`.
+    pub depth: usize,
+    /// The number of newest series to keep.
+    pub keep_series: usize,
+    /// The number of newest versions to keep within each kept series.
+    pub keep_per_series: usize,
+}
+
+/// The top level of the matchspecs YAML: reserved sections, and package entries or architecture
+/// sections for every other key.
+#[derive(Deserialize)]
+struct MatchspecsFile {
+    #[serde(default)]
+    minimum_versions: HashMap<String, String>,
+    closure: Option<Vec<String>>,
+    #[serde(default)]
+    allow_epochs: Vec<String>,
+    #[serde(default)]
+    allow_missing_license: Vec<String>,
+    #[serde(default)]
+    prerelease_exceptions: Vec<String>,
+    #[serde(default)]
+    series_prune: HashMap<String, SeriesPruneEntry>,
+    #[serde(default)]
+    pins: Vec<String>,
+    #[serde(default)]
+    mutex: HashMap<String, String>,
+    #[serde(default)]
+    pin_exact: HashMap<String, String>,
+    #[serde(flatten)]
+    packages: HashMap<String, serde_yaml::Value>,
+}
+
+/// The single artifact of a package to keep, from the `pin_exact:` section of the matchspecs YAML.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExactPin {
+    /// A full filename, i.e. `openssl-3.2.1-hd590300_0.conda`.
+    Filename(String),
+    /// A version and build string, i.e. `3.2.1 hd590300_0`, in either package format.
+    Build { version: String, build: String },
+}
+
+impl ExactPin {
+    #[must_use]
+    pub fn matches(&self, filename: &str, record: &PackageRecord) -> bool {
+        match self {
+            ExactPin::Filename(pinned) => filename == pinned,
+            ExactPin::Build { version, build } => {
+                record.version.as_str() == *version && record.build == *build
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ExactPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExactPin::Filename(filename) => write!(f, "{filename}"),
+            ExactPin::Build { version, build } => write!(f, "{version} {build}"),
+        }
+    }
+}
+
+/// The parts of a conda environment.yml which matter here.
+#[derive(Deserialize)]
+struct EnvironmentFile {
+    #[serde(default)]
+    dependencies: Vec<EnvironmentDependency>,
+}
+
+/// An entry of the `dependencies:` of an environment.yml. Either a conda matchspec, or a nested
+/// section such as `pip:`, which is ignored.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EnvironmentDependency {
+    Conda(String),
+    Other(serde::de::IgnoredAny),
+}
+
+/// Everything the user configured in the matchspecs YAML.
+#[derive(Default)]
+pub struct MatchspecsYaml {
+    /// Packages of these names are kept only if they match at least one of the matchspecs.
+    pub matchspecs: HashMap<String, Vec<NamelessMatchSpec>>,
+    /// Per-package overrides of the number of newest versions to keep.
+    pub keep_versions: HashMap<String, usize>,
+    /// Packages of these names are removed if their version is below the floor.
+    pub minimum_versions: HashMap<String, NamelessMatchSpec>,
+    /// Roots of the dependency closure for `--closure-only`, if given explicitly. A root with no
+    /// matchspecs accepts any package of that name.
+    pub closure: Option<HashMap<String, Vec<NamelessMatchSpec>>>,
+    /// Packages of these names are exempt from `--ban-epochs`.
+    pub allow_epochs: HashSet<String>,
+    /// Packages of these names are exempt from `--require-license`.
+    pub allow_missing_license: HashSet<String>,
+    /// Packages whose names match these patterns are exempt from the dev, rc, alpha and beta bans.
+    pub prerelease_exceptions: Vec<glob::Pattern>,
+    /// Per-package retention of the newest versions of the newest version series.
+    pub series_prune: HashMap<String, SeriesPrune>,
+    /// Global pins. Packages whose depends cannot be satisfied alongside these are removed.
+    pub pins: HashMap<String, NamelessMatchSpec>,
+    /// Mutex packages, i.e. `_openmp_mutex`, and a glob pattern selecting the build string or
+    /// track feature of the variant to keep.
+    pub mutex: HashMap<String, glob::Pattern>,
+    /// Packages collapsed to a single artifact. Every other build of these is removed.
+    pub pin_exact: HashMap<String, ExactPin>,
+    /// Package entries which apply to a single architecture only, by architecture name.
+    pub architectures: HashMap<String, ArchitectureSection>,
+}
+
+/// The package entries of an architecture's section of the matchspecs YAML, i.e. `win-64:`.
+#[derive(Default)]
+pub struct ArchitectureSection {
+    pub matchspecs: HashMap<String, Vec<NamelessMatchSpec>>,
+    pub keep_versions: HashMap<String, usize>,
+}
+
+impl MatchspecsYaml {
+    /// The matchspecs which apply to an architecture. A package listed in the architecture's
+    /// section takes its matchspecs from there instead of from the default ones.
+    #[must_use]
+    pub fn matchspecs_for(
+        &self,
+        architecture: &str,
+    ) -> Cow<'_, HashMap<String, Vec<NamelessMatchSpec>>> {
+        match self.architectures.get(architecture) {
+            Some(section) if !section.matchspecs.is_empty() => {
+                let mut merged = self.matchspecs.clone();
+                merged.extend(
+                    section
+                        .matchspecs
+                        .iter()
+                        .map(|(package_name, specs)| (package_name.clone(), specs.clone())),
+                );
+                Cow::Owned(merged)
+            }
+            _ => Cow::Borrowed(&self.matchspecs),
+        }
+    }
+
+    /// The `keep_versions` overrides which apply to an architecture, with the same precedence as
+    /// [`MatchspecsYaml::matchspecs_for`].
+    #[must_use]
+    pub fn keep_versions_for(&self, architecture: &str) -> Cow<'_, HashMap<String, usize>> {
+        match self.architectures.get(architecture) {
+            Some(section) if !section.keep_versions.is_empty() => {
+                let mut merged = self.keep_versions.clone();
+                merged.extend(
+                    section
+                        .keep_versions
+                        .iter()
+                        .map(|(package_name, keep)| (package_name.clone(), *keep)),
+                );
+                Cow::Owned(merged)
+            }
+            _ => Cow::Borrowed(&self.keep_versions),
+        }
+    }
+
+    /// Add matchspecs from another source, i.e. an environment.yml or `--matchspec`. A package is
+    /// then kept if it matches any of its matchspecs from either source.
+    pub fn merge_matchspecs(&mut self, matchspecs: HashMap<String, Vec<NamelessMatchSpec>>) {
+        for (package_name, specs) in matchspecs {
+            self.matchspecs
+                .entry(package_name)
+                .or_default()
+                .extend(specs);
+        }
+    }
+}
+
+/// Parse a full matchspec which includes a package name, i.e. `python >=3.10`, into the name and
+/// the rest of the matchspec.
+pub fn parse_named_matchspec(
+    matchspec: &str,
+    strictness: ParseStrictness,
+) -> Result<(String, NamelessMatchSpec), Box<dyn std::error::Error>> {
+    let (name, nameless) = MatchSpec::from_str(matchspec, strictness)?.into_nameless();
+    match name {
+        Some(name) => Ok((name.as_source().to_string(), nameless)),
+        None => Err(format!("matchspec {matchspec} has no package name").into()),
+    }
+}
+
+/// Parse a user matchspec without a package name. Besides conda's own syntax, i.e.
+/// `1.2.*[build_number='>=3']`, the build number may be constrained with a separate
+/// `build_number` comparator, i.e. `1.2.* build_number>=3`, which conda would take for a build
+/// string.
+pub fn parse_user_matchspec(
+    matchspec: &str,
+    strictness: ParseStrictness,
+) -> Result<NamelessMatchSpec, Box<dyn std::error::Error>> {
+    let mut build_number = None;
+    let mut rest = Vec::new();
+    for token in matchspec.split_whitespace() {
+        match token.strip_prefix("build_number") {
+            Some(comparator) if build_number.is_none() => {
+                build_number = Some(BuildNumberSpec::from_str(comparator)?);
+            }
+            Some(_) => return Err("build_number is constrained more than once".into()),
+            None => rest.push(token),
+        }
+    }
+    let mut result = NamelessMatchSpec::from_str(&rest.join(" "), strictness)?;
+    if build_number.is_some() {
+        if result.build_number.is_some() {
+            return Err("build_number is constrained more than once".into());
+        }
+        result.build_number = build_number;
+    }
+    Ok(result)
+}
+
+/// Format a user matchspec the way [`parse_user_matchspec`] accepts it. Unlike conda's own
+/// formatting, this keeps the build number constraint.
+#[must_use]
+pub fn format_user_matchspec(matchspec: &NamelessMatchSpec) -> String {
+    match &matchspec.build_number {
+        Some(build_number) => format!("{matchspec} build_number{build_number}"),
+        None => matchspec.to_string(),
+    }
+}
+
+/// Split a full matchspec into its package name and the rest of the matchspec as written, i.e.
+/// `python=3.11` or `python >=3.11`. The rest is empty for a bare package name.
+#[must_use]
+pub fn split_named_matchspec(matchspec: &str) -> (&str, &str) {
+    let end = matchspec
+        .find(|c: char| c.is_whitespace() || "=<>!~[".contains(c))
+        .unwrap_or(matchspec.len());
+    (&matchspec[..end], matchspec[end..].trim_start())
+}
+
+fn parse_exact_pin(package_name: &str, pin: &str) -> Result<ExactPin, Box<dyn std::error::Error>> {
+    let stem = pin
+        .strip_suffix(".conda")
+        .or_else(|| pin.strip_suffix(".tar.bz2"));
+    if let Some(stem) = stem {
+        if !stem.starts_with(&format!("{package_name}-")) {
+            return Err(format!(
+                "pin_exact: {package_name}: {pin} is not a {package_name} package"
+            )
+            .into());
+        }
+        return Ok(ExactPin::Filename(pin.to_string()));
+    }
+    match pin.split_whitespace().collect::<Vec<_>>()[..] {
+        [version, build] => Ok(ExactPin::Build {
+            version: version.to_string(),
+            build: build.to_string(),
+        }),
+        _ => Err(format!(
+            "pin_exact: {package_name}: {pin} must be a filename, or a version and build string"
+        )
+        .into()),
+    }
+}
+
+fn parse_series_prune(
+    package_name: &str,
+    entry: &SeriesPruneEntry,
+) -> Result<SeriesPrune, Box<dyn std::error::Error>> {
+    let depth = match entry.series.as_str() {
+        "major" => 1,
+        "major.minor" => 2,
+        "major.minor.patch" => 3,
+        series => {
+            return Err(format!(
+                "series_prune: {package_name}: series {series} must be major, major.minor or major.minor.patch"
+            )
+            .into())
+        }
+    };
+    if entry.keep_series == 0 || entry.keep_per_series == 0 {
+        return Err(format!(
+            "series_prune: {package_name}: keep_series and keep_per_series must be at least 1"
+        )
+        .into());
+    }
+    Ok(SeriesPrune {
+        depth,
+        keep_series: entry.keep_series,
+        keep_per_series: entry.keep_per_series,
+    })
+}
+
+/// Parse the package entries of the `default:` or an architecture's section.
+fn insert_package_entries(
+    section_name: &str,
+    value: serde_yaml::Value,
+    section: &mut ArchitectureSection,
+    specs: &mut SpecParser,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let entries: HashMap<String, PackageEntry> =
+        serde_yaml::from_value(value).map_err(|err| format!("{section_name}: {err}"))?;
+    for (package_name, entry) in entries {
+        let context = format!("{section_name}: {package_name}");
+        insert_package_entry(
+            &context,
+            package_name,
+            entry,
+            &mut section.matchspecs,
+            &mut section.keep_versions,
+            specs,
+        )?;
+    }
+    Ok(())
+}
+
+fn insert_package_entry(
+    context: &str,
+    package_name: String,
+    entry: PackageEntry,
+    matchspecs: &mut HashMap<String, Vec<NamelessMatchSpec>>,
+    keep_versions: &mut HashMap<String, usize>,
+    specs: &mut SpecParser,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let entry_matchspecs = match entry {
+        PackageEntry::Matchspecs(entry_matchspecs) => Some(entry_matchspecs),
+        PackageEntry::Detailed {
+            matchspecs: entry_matchspecs,
+            keep_versions: entry_keep_versions,
+        } => {
+            if let Some(entry_keep_versions) = entry_keep_versions {
+                if entry_keep_versions == 0 {
+                    return Err(format!("{context}: keep_versions must be at least 1").into());
+                }
+                keep_versions.insert(package_name.clone(), entry_keep_versions);
+            }
+            entry_matchspecs
+        }
+    };
+    if let Some(entry_matchspecs) = entry_matchspecs {
+        matchspecs.insert(
+            package_name,
+            entry_matchspecs
+                .iter()
+                .filter_map(|matchspec| {
+                    specs.record(
+                        context,
+                        matchspec,
+                        parse_user_matchspec(matchspec, specs.strictness),
+                    )
+                })
+                .collect(),
+        );
+    }
+    Ok(())
+}
+
+/// Parses the matchspecs of a file with the same strictness, collecting every one which fails
+/// so that they are all reported together rather than one per run.
+struct SpecParser {
+    strictness: ParseStrictness,
+    errors: Vec<String>,
+}
+
+impl SpecParser {
+    fn new(strictness: ParseStrictness) -> Self {
+        SpecParser {
+            strictness,
+            errors: Vec::new(),
+        }
+    }
+
+    /// The parsed matchspec, or none if it failed to parse, as given for `context`.
+    fn record<T>(
+        &mut self,
+        context: &str,
+        matchspec: &str,
+        parsed: Result<T, Box<dyn std::error::Error>>,
+    ) -> Option<T> {
+        parsed
+            .map_err(|err| self.errors.push(format!("{context}: {matchspec}: {err}")))
+            .ok()
+    }
+
+    /// Fail with every matchspec which failed to parse, one per line.
+    fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        self.errors.sort_unstable();
+        Err(self.errors.join("\n").into())
+    }
+}
+
+/// Read and parse a matchspecs YAML, from stdin if `filename` is `-`.
 pub fn get_user_matchspecs(
     filename: &std::path::PathBuf,
+    strictness: ParseStrictness,
+) -> Result<MatchspecsYaml, Box<dyn std::error::Error>> {
+    let yaml = if filename.as_os_str() == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(filename)?
+    };
+    parse_user_matchspecs(&yaml, strictness)
+}
+
+pub fn get_environment_file(
+    filename: &std::path::Path,
+    strictness: ParseStrictness,
 ) -> Result<HashMap<String, Vec<NamelessMatchSpec>>, Box<dyn std::error::Error>> {
-    let matchspecs: HashMap<String, Vec<String>> =
-        serde_yaml::from_str(&std::fs::read_to_string(filename)?)?;
-
-    Ok(matchspecs
-        .into_iter()
-        .map(|(package_name, values)| {
-            (
-                package_name,
-                values
-                    .into_iter()
-                    .map(|matchspec_string| {
-                        NamelessMatchSpec::from_str(
-                            matchspec_string.as_str(),
-                            ParseStrictness::Lenient,
-                        )
-                        .expect("parse failure in user matchspec")
-                    })
-                    .collect(),
-            )
-        })
-        .collect())
+    parse_environment_file(&std::fs::read_to_string(filename)?, strictness)
+}
+
+/// Parse the conda `dependencies:` of an environment.yml into matchspecs by package name. Channel
+/// prefixes such as `conda-forge::` are dropped, and an entry without a version accepts any
+/// package of that name.
+pub fn parse_environment_file(
+    yaml: &str,
+    strictness: ParseStrictness,
+) -> Result<HashMap<String, Vec<NamelessMatchSpec>>, Box<dyn std::error::Error>> {
+    let file: EnvironmentFile = serde_yaml::from_str(yaml)?;
+    let mut specs = SpecParser::new(strictness);
+    let mut result: HashMap<String, Vec<NamelessMatchSpec>> = HashMap::new();
+    for dependency in file.dependencies {
+        let EnvironmentDependency::Conda(matchspec) = dependency else {
+            continue;
+        };
+        let without_channel = matchspec
+            .split_once("::")
+            .map_or(matchspec.as_str(), |(_, rest)| rest);
+        let parsed = parse_named_matchspec(without_channel, strictness);
+        if let Some((package_name, nameless)) = specs.record("dependencies", &matchspec, parsed) {
+            result.entry(package_name).or_default().push(nameless);
+        }
+    }
+    specs.finish()?;
+    Ok(result)
+}
+
+/// Parse the full matchspecs of `--matchspec` by package name. The part after the name is parsed
+/// as it would be in the matchspecs YAML, and every matchspec which fails is reported.
+pub fn parse_argument_matchspecs(
+    matchspecs: &[String],
+    strictness: ParseStrictness,
+) -> Result<HashMap<String, Vec<NamelessMatchSpec>>, Box<dyn std::error::Error>> {
+    let mut specs = SpecParser::new(strictness);
+    let mut result: HashMap<String, Vec<NamelessMatchSpec>> = HashMap::new();
+    for matchspec in matchspecs {
+        let (package_name, rest) = split_named_matchspec(matchspec);
+        let parsed = if package_name.is_empty() {
+            Err(format!("matchspec {matchspec} has no package name").into())
+        } else {
+            parse_user_matchspec(rest, strictness)
+        };
+        if let Some(nameless) = specs.record("--matchspec", matchspec, parsed) {
+            result
+                .entry(package_name.to_string())
+                .or_default()
+                .push(nameless);
+        }
+    }
+    specs.finish()?;
+    Ok(result)
+}
+
+/// The roots of the closure by package name, with the matchspecs restricting each, if any.
+fn parse_closure_roots(
+    closure: &[String],
+    specs: &mut SpecParser,
+) -> HashMap<String, Vec<NamelessMatchSpec>> {
+    let mut roots: HashMap<String, Vec<NamelessMatchSpec>> = HashMap::new();
+    for matchspec in closure {
+        let parsed = parse_named_matchspec(matchspec, specs.strictness);
+        let Some((package_name, nameless)) = specs.record("closure", matchspec, parsed) else {
+            continue;
+        };
+        let root_specs = roots.entry(package_name).or_default();
+        if nameless != NamelessMatchSpec::default() {
+            root_specs.push(nameless);
+        }
+    }
+    roots
+}
+
+/// Parse a matchspecs YAML. Every matchspec which fails to parse with `strictness` is reported,
+/// with the package name it is given for.
+pub fn parse_user_matchspecs(
+    yaml: &str,
+    strictness: ParseStrictness,
+) -> Result<MatchspecsYaml, Box<dyn std::error::Error>> {
+    let file: MatchspecsFile = serde_yaml::from_str(yaml)?;
+    let mut specs = SpecParser::new(strictness);
+
+    let mut result = MatchspecsYaml {
+        allow_epochs: file.allow_epochs.into_iter().collect(),
+        allow_missing_license: file.allow_missing_license.into_iter().collect(),
+        ..MatchspecsYaml::default()
+    };
+    for (package_name, minimum_version) in file.minimum_versions {
+        let matchspec = format!(">={minimum_version}");
+        let parsed = NamelessMatchSpec::from_str(&matchspec, strictness).map_err(Into::into);
+        let context = format!("minimum_versions: {package_name}");
+        if let Some(matchspec) = specs.record(&context, &minimum_version, parsed) {
+            result.minimum_versions.insert(package_name, matchspec);
+        }
+    }
+    for (package_name, entry) in file.series_prune {
+        let series_prune = parse_series_prune(&package_name, &entry)?;
+        result.series_prune.insert(package_name, series_prune);
+    }
+    for matchspec in file.pins {
+        let parsed = parse_named_matchspec(&matchspec, strictness);
+        let Some((package_name, nameless)) = specs.record("pins", &matchspec, parsed) else {
+            continue;
+        };
+        if result.pins.contains_key(&package_name) {
+            return Err(format!("pins: {package_name} is pinned more than once").into());
+        }
+        result.pins.insert(package_name, nameless);
+    }
+    for pattern in file.prerelease_exceptions {
+        let pattern = glob::Pattern::new(&pattern)
+            .map_err(|err| format!("prerelease_exceptions: {pattern}: {err}"))?;
+        result.prerelease_exceptions.push(pattern);
+    }
+    for (package_name, selection) in file.mutex {
+        let pattern = glob::Pattern::new(&selection)
+            .map_err(|err| format!("mutex: {package_name}: {err}"))?;
+        result.mutex.insert(package_name, pattern);
+    }
+    for (package_name, pin) in file.pin_exact {
+        let pin = parse_exact_pin(&package_name, &pin)?;
+        result.pin_exact.insert(package_name, pin);
+    }
+    result.closure = file
+        .closure
+        .map(|closure| parse_closure_roots(&closure, &mut specs));
+    let mut default_section = None;
+    for (key, value) in file.packages {
+        let is_architecture =
+            Platform::from_str(&key).is_ok_and(|platform| platform != Platform::NoArch);
+        if key == "default" {
+            default_section = Some(value);
+        } else if is_architecture {
+            let mut section = ArchitectureSection::default();
+            insert_package_entries(&key, value, &mut section, &mut specs)?;
+            result.architectures.insert(key, section);
+        } else {
+            let entry = serde_yaml::from_value(value).map_err(|err| format!("{key}: {err}"))?;
+            insert_package_entry(
+                &key,
+                key.clone(),
+                entry,
+                &mut result.matchspecs,
+                &mut result.keep_versions,
+                &mut specs,
+            )?;
+        }
+    }
+    if let Some(value) = default_section {
+        let mut section = ArchitectureSection::default();
+        insert_package_entries("default", value, &mut section, &mut specs)?;
+        for package_name in section
+            .matchspecs
+            .keys()
+            .chain(section.keep_versions.keys())
+        {
+            if result.matchspecs.contains_key(package_name)
+                || result.keep_versions.contains_key(package_name)
+            {
+                return Err(format!(
+                    "default: {package_name} is also given outside of the default section"
+                )
+                .into());
+            }
+        }
+        result.matchspecs.extend(section.matchspecs);
+        result.keep_versions.extend(section.keep_versions);
+    }
+    specs.finish()?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matchspecyaml::{
+        format_user_matchspec, parse_argument_matchspecs, parse_environment_file,
+        parse_user_matchspec, parse_user_matchspecs, split_named_matchspec, ExactPin,
+    };
+    use rattler_conda_types::ParseStrictness;
+
+    #[test]
+    fn flat_and_detailed_entries() {
+        let parsed = parse_user_matchspecs(
+            r#"
+python:
+  - ">=3.11"
+openssl:
+  keep_versions: 3
+zlib:
+  matchspecs: [">=1.3"]
+  keep_versions: 1
+empty: []
+minimum_versions:
+  openssl: "3.0"
+closure:
+  - python >=3.11
+  - numpy
+allow_epochs: [openssl]
+allow_missing_license: [gcc_impl_linux-64]
+prerelease_exceptions: [black, apache-*]
+series_prune:
+  python: {series: major.minor, keep_series: 4, keep_per_series: 3}
+pins:
+  - openssl >=3
+  - libstdcxx-ng >=12
+mutex:
+  _openmp_mutex: "*gnu*"
+"#,
+            ParseStrictness::Lenient,
+        )
+        .unwrap();
+        assert_eq!(parsed.matchspecs["python"].len(), 1);
+        assert!(!parsed.matchspecs.contains_key("openssl"));
+        assert_eq!(parsed.matchspecs["zlib"].len(), 1);
+        assert!(parsed.matchspecs["empty"].is_empty());
+        assert_eq!(parsed.keep_versions["openssl"], 3);
+        assert_eq!(parsed.keep_versions["zlib"], 1);
+        assert!(!parsed.matchspecs.contains_key("minimum_versions"));
+        assert_eq!(parsed.minimum_versions["openssl"].to_string(), ">=3.0");
+        let closure = parsed.closure.unwrap();
+        assert_eq!(closure["python"].len(), 1);
+        assert!(closure["numpy"].is_empty());
+        assert!(parsed.allow_epochs.contains("openssl"));
+        assert!(!parsed.matchspecs.contains_key("allow_epochs"));
+        assert!(parsed.allow_missing_license.contains("gcc_impl_linux-64"));
+        assert!(!parsed.matchspecs.contains_key("allow_missing_license"));
+        assert_eq!(parsed.prerelease_exceptions[1].as_str(), "apache-*");
+        assert!(!parsed.matchspecs.contains_key("prerelease_exceptions"));
+        assert_eq!(parsed.series_prune["python"].depth, 2);
+        assert_eq!(parsed.series_prune["python"].keep_series, 4);
+        assert_eq!(parsed.series_prune["python"].keep_per_series, 3);
+        assert!(!parsed.matchspecs.contains_key("series_prune"));
+        assert_eq!(parsed.pins["openssl"].to_string(), ">=3");
+        assert_eq!(parsed.pins.len(), 2);
+        assert!(!parsed.matchspecs.contains_key("pins"));
+        assert_eq!(parsed.mutex["_openmp_mutex"].as_str(), "*gnu*");
+        assert!(!parsed.matchspecs.contains_key("mutex"));
+    }
+
+    #[test]
+    fn split_named_matchspecs() {
+        assert_eq!(split_named_matchspec("python"), ("python", ""));
+        assert_eq!(split_named_matchspec("python=3.11"), ("python", "=3.11"));
+        assert!(
+            <rattler_conda_types::NamelessMatchSpec as std::str::FromStr>::from_str("=3.11")
+                .is_ok()
+        );
+        assert_eq!(split_named_matchspec("python >=3.11"), ("python", ">=3.11"));
+        assert_eq!(
+            split_named_matchspec("numpy[version='>=2']"),
+            ("numpy", "[version='>=2']")
+        );
+    }
+
+    #[test]
+    fn environment_file() {
+        let mut parsed =
+            parse_user_matchspecs("python: [\">=3.12\"]", ParseStrictness::Lenient).unwrap();
+        let environment = parse_environment_file(
+            r"
+name: analysis
+channels: [conda-forge]
+dependencies:
+  - python=3.11
+  - conda-forge::numpy=1.26
+  - pandas
+  - pip
+  - pip:
+      - requests==2.32.3
+",
+            ParseStrictness::Lenient,
+        )
+        .unwrap();
+        assert_eq!(environment.len(), 4);
+        assert_eq!(environment["numpy"][0].to_string(), "1.26.*");
+        assert!(environment["pandas"][0].version.is_none());
+        assert!(!environment.contains_key("requests"));
+        parsed.merge_matchspecs(environment);
+        assert_eq!(parsed.matchspecs["python"].len(), 2);
+        assert_eq!(parsed.matchspecs["numpy"].len(), 1);
+    }
+
+    #[test]
+    fn argument_matchspecs() {
+        let mut parsed =
+            parse_user_matchspecs("python: [\">=3.12\"]", ParseStrictness::Lenient).unwrap();
+        let arguments = parse_argument_matchspecs(
+            &[
+                "python >=3.10,<3.11".to_string(),
+                "numpy 1.26.* build_number>=2".to_string(),
+                "zlib".to_string(),
+            ],
+            ParseStrictness::Lenient,
+        )
+        .unwrap();
+        assert_eq!(arguments["numpy"][0].to_string(), "1.26.*");
+        assert!(arguments["numpy"][0].build_number.is_some());
+        assert!(arguments["zlib"][0].version.is_none());
+        parsed.merge_matchspecs(arguments);
+        assert_eq!(parsed.matchspecs["python"].len(), 2);
+        let err = parse_argument_matchspecs(
+            &[
+                "python >=3.10".to_string(),
+                "numpy >=1.2.3.a.b.c!".to_string(),
+            ],
+            ParseStrictness::Strict,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("--matchspec: numpy >=1.2.3.a.b.c!: "));
+    }
+
+    #[test]
+    fn zero_keep_versions_is_an_error() {
+        assert!(
+            parse_user_matchspecs("openssl: {keep_versions: 0}", ParseStrictness::Lenient).is_err()
+        );
+    }
+
+    #[test]
+    fn unknown_series_is_an_error() {
+        assert!(parse_user_matchspecs(
+            "series_prune: {python: {series: minor, keep_series: 1, keep_per_series: 1}}",
+            ParseStrictness::Lenient
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn architecture_sections() {
+        let parsed = parse_user_matchspecs(
+            r#"
+default:
+  python: [">=3.11"]
+  vs2015_runtime: [">=14.40"]
+  openssl:
+    keep_versions: 3
+win-64:
+  vs2015_runtime: ["14.29.*"]
+  openssl:
+    keep_versions: 1
+linux-64:
+  libgcc-ng: [">=12"]
+zlib: [">=1.3"]
+"#,
+            ParseStrictness::Lenient,
+        )
+        .unwrap();
+        assert!(!parsed.matchspecs.contains_key("default"));
+        assert!(!parsed.matchspecs.contains_key("win-64"));
+        assert_eq!(parsed.matchspecs.len(), 3);
+
+        let win = parsed.matchspecs_for("win-64");
+        assert_eq!(win["vs2015_runtime"].len(), 1);
+        assert_eq!(win["vs2015_runtime"][0].to_string(), "14.29.*");
+        assert_eq!(win["python"][0].to_string(), ">=3.11");
+        assert_eq!(win["zlib"][0].to_string(), ">=1.3");
+        assert!(!win.contains_key("libgcc-ng"));
+        assert_eq!(parsed.keep_versions_for("win-64")["openssl"], 1);
+
+        let linux = parsed.matchspecs_for("linux-64");
+        assert_eq!(linux["vs2015_runtime"][0].to_string(), ">=14.40");
+        assert_eq!(linux["libgcc-ng"][0].to_string(), ">=12");
+        assert_eq!(parsed.keep_versions_for("linux-64")["openssl"], 3);
+
+        let osx = parsed.matchspecs_for("osx-arm64");
+        assert!(matches!(osx, std::borrow::Cow::Borrowed(_)));
+        assert_eq!(osx.len(), 3);
+    }
+
+    #[test]
+    fn architecture_section_errors() {
+        assert!(parse_user_matchspecs(
+            "python: []\ndefault: {python: []}",
+            ParseStrictness::Lenient
+        )
+        .is_err());
+        assert!(parse_user_matchspecs(
+            "win-64: {openssl: {keep_versions: 0}}",
+            ParseStrictness::Lenient
+        )
+        .is_err());
+        assert!(parse_user_matchspecs("win-64: [\">=1\"]", ParseStrictness::Lenient).is_err());
+    }
+
+    #[test]
+    fn strict_reports_every_error() {
+        let yaml = r#"
+python: [">=3.8.*"]
+numpy: ["==1.26.*", ">=1.20"]
+pins: [openssl >=3.0.*]
+"#;
+        assert!(parse_user_matchspecs(yaml, ParseStrictness::Lenient).is_ok());
+        let Err(err) = parse_user_matchspecs(yaml, ParseStrictness::Strict) else {
+            panic!("strict parsing accepted lenient matchspecs");
+        };
+        let err = err.to_string();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("numpy: ==1.26.*: "));
+        assert!(lines[1].starts_with("pins: openssl >=3.0.*: "));
+        assert!(lines[2].starts_with("python: >=3.8.*: "));
+    }
+
+    #[test]
+    fn build_number_constraints() {
+        let parsed = parse_user_matchspecs(
+            r#"
+libfoo: ["1.2.* build_number>=3", "1.3.*[build_number='>=1']", "build_number>2"]
+"#,
+            ParseStrictness::Lenient,
+        )
+        .unwrap();
+        let libfoo = &parsed.matchspecs["libfoo"];
+        assert_eq!(libfoo[0].version.as_ref().unwrap().to_string(), "1.2.*");
+        assert!(libfoo[0].build.is_none());
+        assert_eq!(libfoo[0].build_number.as_ref().unwrap().to_string(), ">=3");
+        assert_eq!(libfoo[1].build_number.as_ref().unwrap().to_string(), ">=1");
+        assert!(libfoo[2].version.is_none());
+        assert_eq!(format_user_matchspec(&libfoo[0]), "1.2.* build_number>=3");
+        assert_eq!(format_user_matchspec(&libfoo[2]), "* build_number>2");
+        assert!(parse_user_matchspec("1.2.* *_3", ParseStrictness::Lenient)
+            .unwrap()
+            .build
+            .is_some());
+        assert!(parse_user_matchspec("1.2.* build_number>=x", ParseStrictness::Lenient).is_err());
+        assert!(parse_user_matchspec(
+            "1.2.*[build_number=1] build_number>=3",
+            ParseStrictness::Lenient
+        )
+        .is_err());
+        assert!(parse_user_matchspecs(
+            "libfoo: [\"1.2.* build_number>=x\"]",
+            ParseStrictness::Lenient
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn exact_pins() {
+        let parsed = parse_user_matchspecs(
+            r"
+pin_exact:
+  openssl: openssl-3.2.1-hd590300_0.conda
+  ca-certificates: 2024.7.4 hbcca054_0
+",
+            ParseStrictness::Lenient,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.pin_exact["openssl"],
+            ExactPin::Filename("openssl-3.2.1-hd590300_0.conda".to_string())
+        );
+        assert_eq!(
+            parsed.pin_exact["ca-certificates"],
+            ExactPin::Build {
+                version: "2024.7.4".to_string(),
+                build: "hbcca054_0".to_string()
+            }
+        );
+        assert!(!parsed.matchspecs.contains_key("pin_exact"));
+        assert!(
+            parse_user_matchspecs("pin_exact: {openssl: 3.2.1}", ParseStrictness::Lenient).is_err()
+        );
+        assert!(parse_user_matchspecs(
+            "pin_exact: {openssl: zlib-1.3-h0_0.conda}",
+            ParseStrictness::Lenient
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,171 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// How the repodata of one subdir differs between two output directories, by package filename.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SubdirDiff {
+    pub subdir: String,
+    /// The packages only in the later directory.
+    pub added: Vec<String>,
+    /// The packages only in the earlier directory.
+    pub removed: Vec<String>,
+    /// The packages in both whose records differ, such as by a patched `depends`.
+    pub changed: Vec<String>,
+}
+
+impl SubdirDiff {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// The records of a repodata.json, of both package formats, read as they are written.
+#[derive(Deserialize)]
+struct Records {
+    #[serde(default)]
+    packages: BTreeMap<String, Value>,
+    #[serde(default, rename = "packages.conda")]
+    conda_packages: BTreeMap<String, Value>,
+}
+
+/// Compare the repodata of every subdir in either of two output directories, leaving out the
+/// subdirs which are the same. A subdir missing from one directory has no packages there.
+pub fn diff_output_directories(
+    old: &Path,
+    new: &Path,
+) -> Result<Vec<SubdirDiff>, Box<dyn std::error::Error>> {
+    let mut subdirs = read_subdirs(old)?;
+    subdirs.extend(read_subdirs(new)?);
+    let mut diffs = Vec::new();
+    for subdir in subdirs {
+        let diff = diff_records(
+            subdir.clone(),
+            &read_records(old, &subdir)?,
+            &read_records(new, &subdir)?,
+        );
+        if !diff.is_empty() {
+            diffs.push(diff);
+        }
+    }
+    Ok(diffs)
+}
+
+/// The subdirs of an output directory, which are those with a repodata.json.
+fn read_subdirs(directory: &Path) -> Result<BTreeSet<String>, Box<dyn std::error::Error>> {
+    let mut subdirs = BTreeSet::new();
+    for entry in
+        std::fs::read_dir(directory).map_err(|err| format!("{}: {err}", directory.display()))?
+    {
+        let entry = entry?;
+        if entry.path().join("repodata.json").is_file() {
+            if let Some(subdir) = entry.file_name().to_str() {
+                subdirs.insert(subdir.to_string());
+            }
+        }
+    }
+    Ok(subdirs)
+}
+
+fn read_records(
+    directory: &Path,
+    subdir: &str,
+) -> Result<BTreeMap<String, Value>, Box<dyn std::error::Error>> {
+    let filename = directory.join(subdir).join("repodata.json");
+    if !filename.is_file() {
+        return Ok(BTreeMap::new());
+    }
+    let contents =
+        std::fs::read(&filename).map_err(|err| format!("{}: {err}", filename.display()))?;
+    let records: Records = serde_json::from_slice(&contents)
+        .map_err(|err| format!("{}: {err}", filename.display()))?;
+    let mut all = records.packages;
+    all.extend(records.conda_packages);
+    Ok(all)
+}
+
+fn diff_records(
+    subdir: String,
+    old: &BTreeMap<String, Value>,
+    new: &BTreeMap<String, Value>,
+) -> SubdirDiff {
+    let mut diff = SubdirDiff {
+        subdir,
+        ..SubdirDiff::default()
+    };
+    for (filename, record) in old {
+        match new.get(filename) {
+            None => diff.removed.push(filename.clone()),
+            Some(new_record) if new_record != record => diff.changed.push(filename.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.added = new
+        .keys()
+        .filter(|filename| !old.contains_key(*filename))
+        .cloned()
+        .collect();
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::outputdiff::{diff_output_directories, SubdirDiff};
+
+    #[test]
+    fn output_directories_differ() {
+        let root = std::env::temp_dir().join(format!("outputdiff-{}", std::process::id()));
+        let write = |directory: &str, subdir: &str, repodata: &str| {
+            let path = root.join(directory).join(subdir);
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(path.join("repodata.json"), repodata).unwrap();
+        };
+        write(
+            "old",
+            "linux-64",
+            r#"{"packages": {"a-1.0-0.tar.bz2": {"depends": []}},
+                "packages.conda": {"b-1.0-0.conda": {"depends": []}, "c-1.0-0.conda": {}}}"#,
+        );
+        write(
+            "new",
+            "linux-64",
+            r#"{"packages.conda": {"b-1.0-0.conda": {"depends": ["zlib"]},
+                "c-1.0-0.conda": {}, "d-1.0-0.conda": {}}}"#,
+        );
+        write(
+            "old",
+            "noarch",
+            r#"{"packages.conda": {"e-1.0-0.conda": {}}}"#,
+        );
+        write(
+            "new",
+            "noarch",
+            r#"{"packages.conda": {"e-1.0-0.conda": {}}}"#,
+        );
+        write(
+            "new",
+            "osx-64",
+            r#"{"packages.conda": {"f-1.0-0.conda": {}}}"#,
+        );
+        let diffs = diff_output_directories(&root.join("old"), &root.join("new")).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            diffs,
+            [
+                SubdirDiff {
+                    subdir: "linux-64".to_string(),
+                    added: vec!["d-1.0-0.conda".to_string()],
+                    removed: vec!["a-1.0-0.tar.bz2".to_string()],
+                    changed: vec!["b-1.0-0.conda".to_string()],
+                },
+                SubdirDiff {
+                    subdir: "osx-64".to_string(),
+                    added: vec!["f-1.0-0.conda".to_string()],
+                    ..SubdirDiff::default()
+                },
+            ]
+        );
+    }
+}
@@ -1,19 +1,50 @@
+use crate::listfile::DenylistEntry;
 use crate::logs::{
-    RemovedBecauseIncompatibleLog, RemovedByDevRcPolicyLog, RemovedBySupercedingBuildLog,
-    RemovedByUserLog, RemovedIncompatibleArchitectureLog, RemovedUnsatisfiableLog,
-    RemovedWithFeatureLog,
+    Log, RemovedAsDuplicateFormatLog, RemovedBadMetadataLog, RemovedBecauseIncompatibleLog,
+    RemovedByBlasPolicyLog, RemovedByBuildStringLog, RemovedByChannelLog,
+    RemovedByChecksumPolicyLog, RemovedByComputeFlavorLog, RemovedByCudaVersionLog,
+    RemovedByDanglingDependLog, RemovedByDependerBanLog, RemovedByEpochLog, RemovedByExactPinLog,
+    RemovedByFilenameBanLog, RemovedByLicenseLog, RemovedByLocalVersionLog,
+    RemovedByMinimumVersionLog, RemovedByMissingLicenseLog, RemovedByMutexLog, RemovedByNameBanLog,
+    RemovedByNameListLog, RemovedByNoarchTypeLog, RemovedByOriginChannelLog,
+    RemovedByPinConflictLog, RemovedByPlainRebuildLog, RemovedByPreRemovalLog,
+    RemovedByPrereleasePolicyLog, RemovedByPypyPolicyLog, RemovedByPythonAbiLog,
+    RemovedByPythonVersionLog, RemovedByRecencyWindowLog, RemovedByRunExportLog,
+    RemovedBySelfCycleLog, RemovedBySeriesPruneLog, RemovedBySizeLog, RemovedBySubdirMismatchLog,
+    RemovedBySupercedingBuildLog, RemovedByTimestampLog, RemovedByTrackFeaturesLog,
+    RemovedByUserLog, RemovedByVariantPreferenceLog, RemovedByVersionPruneLog,
+    RemovedByVulnerabilityLog, RemovedIncompatibleArchitectureLog, RemovedOrphanLog,
+    RemovedOutsideClosureLog, RemovedUnsatisfiableLog, RemovedUnsignedLog, RemovedWithFeatureLog,
 };
 use crate::matchspeccache::MatchspecCache;
+use crate::matchspecyaml::{format_user_matchspec, parse_named_matchspec, ExactPin, SeriesPrune};
+use crate::rawrepodata::{OriginChannels, Signatures};
 use bitvec::vec::BitVec;
+use chrono::{DateTime, TimeDelta, Utc};
 use itertools::Itertools;
+use rattler_conda_types::package::RunExportsJson;
+use rattler_conda_types::version_spec::{
+    EqualityOperator, LogicalOperator, RangeOperator, StrictRangeOperator,
+};
 use rattler_conda_types::Matches;
-use rattler_conda_types::{NamelessMatchSpec, PackageRecord};
+use rattler_conda_types::{
+    Component, NamelessMatchSpec, NoArchKind, PackageRecord, ParseStrictness, Platform, RepoData,
+    Version, VersionSpec, VersionWithSource,
+};
 use rayon::prelude::*;
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ops::Range;
+use std::str::FromStr;
+use tracing::warn;
 
 /// Provided some architecture subdir name, return the virtual packages that are unsatisfiable.
-fn get_virtual_package_bans(architecture: &str) -> &'static [&'static str] {
+/// macOS has no CUDA, but `__cuda` is only banned there when constrains are enforced, for the
+/// packages which constrain it.
+fn get_virtual_package_bans(
+    architecture: &str,
+    enforce_constrains: bool,
+) -> &'static [&'static str] {
     let mut iter = architecture.splitn(2, '-');
     let os = iter.next();
     if os.is_none() {
@@ -21,23 +52,231 @@ fn get_virtual_package_bans(architecture: &str) -> &'static [&'static str] {
     }
     let os = os.unwrap();
     match os {
+        "osx" if enforce_constrains => &["__linux", "__win", "__glibc", "__cuda"],
         "osx" | "freebsd" => &["__linux", "__win", "__glibc"],
         "linux" => &["__osx", "__win"],
         "win" => &["__linux", "__unix", "__glibc", "__osx"],
+        "noarch" => &[],
         _ => {
-            eprintln!("subdir {architecture} virtual bans not understood");
+            warn!("subdir {architecture} virtual bans not understood");
             &[]
         }
     }
 }
 
+/// Packages whose depends pin the CUDA version a build was made for.
+const CUDA_PACKAGES: &[&str] = &["cuda-version", "cudatoolkit", "__cuda"];
+
+/// Could a build whose build string names CUDA `build_digits` (i.e. `118` from `cuda118`) run
+/// against the CUDA `major`.`minor` the user selected? Builds for older minor versions of the same
+/// major version are compatible.
+fn cuda_build_string_compatible(build_digits: &str, major: &str, minor: Option<&str>) -> bool {
+    let Some(build_minor) = build_digits.strip_prefix(major) else {
+        return false;
+    };
+    match (
+        minor.and_then(|minor| minor.parse::<u64>().ok()),
+        build_minor.parse::<u64>().ok(),
+    ) {
+        (Some(minor), Some(build_minor)) => build_minor <= minor,
+        _ => true,
+    }
+}
+
+/// BLAS implementations selectable through conda-forge's blas mutex, and the packages which
+/// provide each implementation.
+pub const BLAS_IMPLEMENTATIONS: &[(&str, &[&str])] = &[
+    ("openblas", &["openblas", "libopenblas"]),
+    ("mkl", &["mkl"]),
+    ("blis", &["blis", "libblis"]),
+];
+
+/// Packages whose build string names the BLAS implementation they are built against, i.e.
+/// `libblas-3.9.0-20_linux64_openblas`.
+const BLAS_MUTEX_PACKAGES: &[&str] = &[
+    "blas",
+    "blas-devel",
+    "libblas",
+    "libcblas",
+    "liblapack",
+    "liblapacke",
+];
+
+/// Whether a build runs on the CPU only or on a GPU, for packages which ship both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComputeFlavor {
+    Cpu,
+    Gpu,
+}
+
+impl ComputeFlavor {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ComputeFlavor::Cpu => "cpu",
+            ComputeFlavor::Gpu => "gpu",
+        }
+    }
+}
+
+/// The compute flavor of a build, from a `cpu`, `gpu` or `cuda<version>` fragment of its build
+/// string, i.e. `cpu_mkl_py311h1234567_100` or `cuda120_py311h1234567_300`, or from depends on
+/// CUDA packages. Builds without any of these don't take part in the cpu/gpu split.
+fn compute_flavor(record: &PackageRecord) -> Option<ComputeFlavor> {
+    let mut fragments = record.build.split('_');
+    if fragments.clone().any(|fragment| {
+        fragment == "gpu"
+            || fragment
+                .strip_prefix("cuda")
+                .is_some_and(|digits| digits.chars().all(|c| c.is_ascii_digit()))
+    }) {
+        return Some(ComputeFlavor::Gpu);
+    }
+    if fragments.any(|fragment| fragment == "cpu") {
+        return Some(ComputeFlavor::Cpu);
+    }
+    record
+        .depends
+        .iter()
+        .any(|depend| {
+            let (dependency_name, _) = dependsstr_to_name_and_spec(depend);
+            CUDA_PACKAGES.contains(&dependency_name)
+                || dependency_name.starts_with("cuda-")
+                || dependency_name == "cudnn"
+        })
+        .then_some(ComputeFlavor::Gpu)
+}
+
+/// The `major.minor` of a version, i.e. `3.11` from `3.11.9`.
+fn minor_version(version: &Version) -> Version {
+    if version.segment_count() >= 2 {
+        version.with_segments(..2)
+    } else {
+        None
+    }
+    .unwrap_or_else(|| version.clone())
+}
+
+/// The python implementation a `python` or `python_abi` build is for, i.e. `pypy` for
+/// `python_abi 3.9 4_pypy39_pp73`.
+fn python_implementation(record: &PackageRecord) -> &'static str {
+    if record.build.contains("pypy") {
+        "pypy"
+    } else {
+        "cpython"
+    }
+}
+
+/// Removing orphans can orphan their dependencies in turn. Give up after this many passes.
+const ORPHAN_ROUND_LIMIT: usize = 64;
+
+/// Classes of prerelease versions which can be banned by policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Prerelease {
+    Dev,
+    Rc,
+    Alpha,
+    Beta,
+}
+
+impl Prerelease {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Prerelease::Dev => "dev",
+            Prerelease::Rc => "rc",
+            Prerelease::Alpha => "alpha",
+            Prerelease::Beta => "beta",
+        }
+    }
+}
+
+/// Why the metadata of a package cannot be trusted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataProblem<'a> {
+    /// A depends or constrains entry which could not be parsed.
+    UnparseableMatchspec(&'a str),
+    EmptyName,
+    EmptyBuild,
+    /// A version which rattler accepts, but which has an empty segment (i.e. `1.0-`) or does not
+    /// start with a number (i.e. `latest`), and so sorts unlike what its publisher intended.
+    MalformedVersion(&'a str),
+}
+
+impl std::fmt::Display for MetadataProblem<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetadataProblem::UnparseableMatchspec(matchspec) => {
+                write!(f, "unparseable matchspec {matchspec:?}")
+            }
+            MetadataProblem::EmptyName => write!(f, "empty package name"),
+            MetadataProblem::EmptyBuild => write!(f, "empty build string"),
+            MetadataProblem::MalformedVersion(version) => {
+                write!(f, "malformed version {version:?}")
+            }
+        }
+    }
+}
+
+/// Find what is wrong with the name, version or build of a package, if anything.
+fn malformed_record(package_record: &PackageRecord) -> Option<MetadataProblem<'_>> {
+    if package_record.name.as_source().is_empty() {
+        return Some(MetadataProblem::EmptyName);
+    }
+    if package_record.build.is_empty() {
+        return Some(MetadataProblem::EmptyBuild);
+    }
+    // Versions built by rattler rather than read from a repodata have no source to check.
+    let Cow::Borrowed(version) = package_record.version.as_str() else {
+        return None;
+    };
+    let without_epoch = version.split_once('!').map_or(version, |(_, rest)| rest);
+    let malformed = !without_epoch.starts_with(|c: char| c.is_ascii_digit())
+        || without_epoch.split(['.', '-', '_', '+']).any(str::is_empty);
+    malformed.then_some(MetadataProblem::MalformedVersion(version))
+}
+
+/// Find every prerelease class a version belongs to. A bare `a` or `b` only marks an alpha or
+/// beta when a number follows it (`1.0a1`), so that versions such as `2024a` are not mistaken
+/// for prereleases. `preview` is treated as a beta.
+fn prereleases(version: &Version) -> impl Iterator<Item = Prerelease> + '_ {
+    version.segments().flat_map(|segment| {
+        let components: Vec<&Component> = segment.components().collect();
+        let mut found = Vec::new();
+        for (index, component) in components.iter().enumerate() {
+            if component.is_dev() {
+                found.push(Prerelease::Dev);
+                continue;
+            }
+            let Some(iden) = component.as_string() else {
+                continue;
+            };
+            let followed_by_number = components
+                .get(index + 1)
+                .is_some_and(|next| next.is_numeric());
+            match iden {
+                iden if iden.starts_with("rc") => found.push(Prerelease::Rc),
+                "alpha" => found.push(Prerelease::Alpha),
+                "a" if followed_by_number => found.push(Prerelease::Alpha),
+                "beta" | "preview" => found.push(Prerelease::Beta),
+                "b" if followed_by_number => found.push(Prerelease::Beta),
+                _ => {}
+            }
+        }
+        found
+    })
+}
+
 struct DependencyKey<'a> {
     name: &'a str,
     matchspec: &'a str,
 }
 
 enum Evaluation<'a> {
-    RemoveAndLog(DependencyKey<'a>, Option<PkgIdx>),
+    /// The dependency is unsatisfiable. The bool is set if the weak dependers must be removed as
+    /// well, because providers remain but none of them satisfy the constraint.
+    RemoveAndLog(DependencyKey<'a>, Option<PkgIdx>, bool),
     UpdateSolution(DependencyKey<'a>, PkgIdxOffset),
 }
 
@@ -89,9 +328,294 @@ impl PkgIdxOffset {
     }
 }
 
+/// Extract the variant hashes (`h` followed by 7 hex digits, ending an underscore-separated
+/// token) from a build string, i.e. `h0f8d45d` from `py39h0f8d45d_100`.
+fn variant_hashes(build: &str) -> Vec<&str> {
+    build
+        .split('_')
+        .filter_map(|token| {
+            let hash = token.get(token.len().checked_sub(8)?..)?;
+            let is_hash = hash.starts_with('h')
+                && hash[1..]
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+            is_hash.then_some(hash)
+        })
+        .collect()
+}
+
+/// A set of package names which all depend on each other, directly or indirectly, through the
+/// remaining packages.
+pub struct DependencyCycle<'a> {
+    /// Each package name in the cycle, with the filename of its newest remaining package.
+    pub members: Vec<(&'a str, &'a str)>,
+}
+
+impl std::fmt::Display for DependencyCycle<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cycle of {} names:", self.members.len())?;
+        for (package_name, filename) in &self.members {
+            write!(f, " {package_name} ({filename})")?;
+        }
+        Ok(())
+    }
+}
+
+/// A package name of the user matchspecs which no remaining package satisfies.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UnmatchedUserSpec<'a, 'b> {
+    /// No package has the name at all, so it is likely a typo of one of the suggested names.
+    UnknownName {
+        package_name: &'b str,
+        suggestions: Vec<&'a str>,
+    },
+    /// Packages of the name exist, but none of them remains, so the matchspecs are likely
+    /// impossible.
+    NoneRemaining { package_name: &'b str },
+}
+
+impl std::fmt::Display for UnmatchedUserSpec<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnmatchedUserSpec::UnknownName {
+                package_name,
+                suggestions,
+            } => {
+                write!(f, "{package_name}: no such package")?;
+                if !suggestions.is_empty() {
+                    write!(f, ", did you mean {}?", suggestions.join(", "))?;
+                }
+                Ok(())
+            }
+            UnmatchedUserSpec::NoneRemaining { package_name } => {
+                write!(f, "{package_name}: no package matches the user matchspecs")
+            }
+        }
+    }
+}
+
+/// The package names of the user matchspecs which no package of any of `repodatas` has, sorted,
+/// each with the existing names closest to it. The subdirs are looked at together, as a name only
+/// published for some architectures is not a typo.
+#[must_use]
+pub fn unknown_user_spec_names<'a, 'b>(
+    package_names: impl IntoIterator<Item = &'b str>,
+    repodatas: impl IntoIterator<Item = &'a RepoData>,
+) -> Vec<UnmatchedUserSpec<'a, 'b>> {
+    let known: HashSet<&'a str> = repodatas
+        .into_iter()
+        .flat_map(|repodata| {
+            repodata
+                .packages
+                .values()
+                .chain(repodata.conda_packages.values())
+        })
+        .map(|record| record.name.as_normalized())
+        .collect();
+    let mut unknown: Vec<&'b str> = package_names
+        .into_iter()
+        .filter(|package_name| !known.contains(package_name))
+        .collect();
+    unknown.sort_unstable();
+    unknown.dedup();
+    unknown
+        .into_iter()
+        .map(|package_name| UnmatchedUserSpec::UnknownName {
+            package_name,
+            suggestions: similar_package_names(package_name, known.iter().copied()),
+        })
+        .collect()
+}
+
+/// Up to three of `candidates` closest to `package_name` by edit distance, closest first, among
+/// those close enough to be a plausible typo of it.
+fn similar_package_names<'a>(
+    package_name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Vec<&'a str> {
+    let max_distance = (package_name.len() / 3).max(1);
+    let mut candidates: Vec<(usize, &'a str)> = candidates
+        .into_iter()
+        .map(|candidate| {
+            (
+                strsim::damerau_levenshtein(package_name, candidate),
+                candidate,
+            )
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    candidates.sort_unstable();
+    candidates
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// The strongly connected components of a graph given as the edges out of each node, by
+/// Tarjan's algorithm. This is iterative, as dependency chains can be long.
+fn strongly_connected_components(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    const UNVISITED: usize = usize::MAX;
+    let mut order = vec![UNVISITED; edges.len()];
+    let mut lowlink = vec![0; edges.len()];
+    let mut on_stack = vec![false; edges.len()];
+    let mut stack = Vec::new();
+    let mut counter = 0;
+    let mut components = Vec::new();
+    for root in 0..edges.len() {
+        if order[root] != UNVISITED {
+            continue;
+        }
+        // Each frame is a node and the position of the next of its edges to follow.
+        let mut call_stack: Vec<(usize, usize)> = Vec::new();
+        let mut visit = Some(root);
+        loop {
+            if let Some(node) = visit.take() {
+                order[node] = counter;
+                lowlink[node] = counter;
+                counter += 1;
+                stack.push(node);
+                on_stack[node] = true;
+                call_stack.push((node, 0));
+            }
+            let Some(frame) = call_stack.last_mut() else {
+                break;
+            };
+            let node = frame.0;
+            if let Some(&child) = edges[node].get(frame.1) {
+                frame.1 += 1;
+                if order[child] == UNVISITED {
+                    visit = Some(child);
+                } else if on_stack[child] {
+                    lowlink[node] = lowlink[node].min(order[child]);
+                }
+                continue;
+            }
+            call_stack.pop();
+            if let Some(&(parent, _)) = call_stack.last() {
+                lowlink[parent] = lowlink[parent].min(lowlink[node]);
+            }
+            if lowlink[node] == order[node] {
+                let mut component = Vec::new();
+                loop {
+                    let member = stack.pop().unwrap();
+                    on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+        }
+    }
+    components
+}
+
+/// Whether a record has no license, or only a blank one.
+fn lacks_license(record: &PackageRecord) -> bool {
+    record
+        .license
+        .as_deref()
+        .map_or(true, |license| license.trim().is_empty())
+}
+
+/// The first of `platform`, `arch` and `subdir` of a record which contradicts the directory it is
+/// published under, as the field name and its value. Absent fields are never a contradiction,
+/// nor are the platform and arch of noarch records, nor is a `noarch` subdir in the directory of
+/// an architecture. A noarch directory only holds records of the `noarch` subdir.
+fn subdir_mismatch<'r>(
+    record: &'r PackageRecord,
+    directory: &str,
+    platform: Option<Platform>,
+) -> Option<(&'static str, &'r str)> {
+    if !record.subdir.is_empty()
+        && record.subdir != directory
+        && (record.subdir != "noarch" || directory == "noarch")
+    {
+        return Some(("subdir", &record.subdir));
+    }
+    if record.subdir == "noarch" || !record.noarch.is_none() {
+        return None;
+    }
+    let platform = platform?;
+    if let (Some(value), Some(expected)) = (&record.platform, platform.only_platform()) {
+        if value != expected {
+            return Some(("platform", value));
+        }
+    }
+    if let (Some(value), Some(expected)) = (&record.arch, platform.arch()) {
+        if value != expected.as_str() {
+            return Some(("arch", value));
+        }
+    }
+    None
+}
+
+/// The version a run export pins, i.e. `1.2.13` from `>=1.2.13,<2.0a0`, `3.0` from `3.0.*` or
+/// `2.1` from `==2.1`.
+fn pinned_version(spec: &VersionSpec) -> Option<&Version> {
+    match spec {
+        VersionSpec::Range(RangeOperator::GreaterEquals | RangeOperator::Greater, version)
+        | VersionSpec::Exact(EqualityOperator::Equals, version) => Some(version),
+        VersionSpec::StrictRange(StrictRangeOperator::StartsWith, version) => Some(&version.0),
+        VersionSpec::Group(LogicalOperator::And, specs) => specs.iter().find_map(pinned_version),
+        _ => None,
+    }
+}
+
+/// Whether no version can satisfy both specs. Only the common comparator forms are understood,
+/// so this errs towards `false`: upper bounds below lower bounds, exact versions outside the
+/// other spec, and disjoint `.*` prefixes.
+fn version_specs_disjoint(a: &VersionSpec, b: &VersionSpec) -> bool {
+    match (a, b) {
+        (VersionSpec::Exact(EqualityOperator::Equals, version), other)
+        | (other, VersionSpec::Exact(EqualityOperator::Equals, version)) => !other.matches(version),
+        (VersionSpec::Group(LogicalOperator::And, specs), other)
+        | (other, VersionSpec::Group(LogicalOperator::And, specs)) => {
+            specs.iter().any(|spec| version_specs_disjoint(spec, other))
+        }
+        (VersionSpec::Group(LogicalOperator::Or, specs), other)
+        | (other, VersionSpec::Group(LogicalOperator::Or, specs)) => {
+            specs.iter().all(|spec| version_specs_disjoint(spec, other))
+        }
+        _ => bounds_disjoint(a, b) || bounds_disjoint(b, a),
+    }
+}
+
+/// Whether everything `lower` admits is above everything `upper` admits.
+fn bounds_disjoint(lower: &VersionSpec, upper: &VersionSpec) -> bool {
+    let (lower, lower_inclusive) = match lower {
+        VersionSpec::Range(RangeOperator::GreaterEquals, version) => (version, true),
+        VersionSpec::Range(RangeOperator::Greater, version) => (version, false),
+        VersionSpec::StrictRange(StrictRangeOperator::StartsWith, prefix) => {
+            return match upper {
+                // Versions starting with one prefix cannot start with another unrelated prefix.
+                VersionSpec::StrictRange(StrictRangeOperator::StartsWith, other) => {
+                    !prefix.0.starts_with(&other.0) && !other.0.starts_with(&prefix.0)
+                }
+                VersionSpec::Range(RangeOperator::Less, version) => *version <= prefix.0,
+                VersionSpec::Range(RangeOperator::LessEquals, version) => *version < prefix.0,
+                _ => false,
+            };
+        }
+        _ => return false,
+    };
+    match upper {
+        VersionSpec::Range(RangeOperator::Less, version) => version <= lower,
+        VersionSpec::Range(RangeOperator::LessEquals, version) => {
+            version < lower || (version == lower && !lower_inclusive)
+        }
+        VersionSpec::StrictRange(StrictRangeOperator::StartsWith, prefix) => {
+            prefix.0 < *lower && !lower.starts_with(&prefix.0)
+        }
+        _ => false,
+    }
+}
+
 #[must_use]
 fn dependsstr_to_name_and_spec(depend: &str) -> (&str, &str) {
-    let dependency_name = depend.split_whitespace().next().unwrap();
+    let dependency_name = depend.split_whitespace().next().unwrap_or_default();
     let dependency_spec = if dependency_name.len() == depend.len() {
         ""
     } else {
@@ -121,6 +645,9 @@ struct PackageDependency<'a> {
     last_successful_resolution: Option<PkgIdxOffset>,
     /// What packages contain this dependency?
     dependers: Vec<PkgIdx>,
+    /// What packages contain this matchspec as a constraint? These edges are weak: the package
+    /// need not be installed at all, so a missing provider is not a problem.
+    weak_dependers: Vec<PkgIdx>,
 }
 
 struct PackageMetadata<'a> {
@@ -130,6 +657,11 @@ struct PackageMetadata<'a> {
 
 pub struct PackageRelations<'a> {
     removed: BitVec,
+    // Packages removed only because an equivalent package in another format exists. These are
+    // never blamed for a dependency becoming unsatisfiable.
+    duplicates: BitVec,
+    // Packages pinned by a lockfile. No rule removes these.
+    locked: BitVec,
     package_dependencies: HashMap<&'a str, HashMap<&'a str, PackageDependency<'a>>>,
     // Sorted by filename. Implies also sorted by packagename.
     // this allows us to use a range system to define packages.
@@ -137,12 +669,21 @@ pub struct PackageRelations<'a> {
     filename_to_metadata: HashMap<&'a str, PkgIdx>,
     // Package Name -> (Start Index, End Index)
     package_name_to_providers: HashMap<&'a str, (PkgIdx, PkgIdxOffset)>,
+    // Whether constrains are indexed as weak edges.
+    enforce_constrains: bool,
+    // Packages with depends or constrains which could not be parsed, and the offending string.
+    bad_metadata: Vec<(PkgIdx, MetadataProblem<'a>)>,
+    // Virtual packages declared by the user. Each record stands in as the only provider of its
+    // virtual package.
+    virtual_packages: HashMap<&'a str, &'a PackageRecord>,
+    // Whether names no inserted package provides are provided elsewhere, and so satisfiable.
+    external_providers: bool,
     // TODO
     // Lazy-populated when a matchspec that matches on build hash is found.
     //package_name_build_to_providers: HashMap<(&'a str, &'a str), Vec<bool>>,
 }
 
-impl<'a> Default for PackageRelations<'a> {
+impl Default for PackageRelations<'_> {
     fn default() -> Self {
         Self::new()
     }
@@ -155,13 +696,47 @@ impl<'a> PackageRelations<'a> {
         const PROVIDERS_CAPACITY: usize = 32 * 1024;
         PackageRelations {
             removed: bitvec::vec::BitVec::with_capacity(VERSIONS_CAPACITY),
+            duplicates: bitvec::vec::BitVec::with_capacity(VERSIONS_CAPACITY),
+            locked: bitvec::vec::BitVec::with_capacity(VERSIONS_CAPACITY),
             package_dependencies: HashMap::with_capacity(PROVIDERS_CAPACITY),
             package_metadatas: Vec::with_capacity(VERSIONS_CAPACITY),
             filename_to_metadata: HashMap::with_capacity(VERSIONS_CAPACITY),
             package_name_to_providers: HashMap::with_capacity(PROVIDERS_CAPACITY),
+            enforce_constrains: false,
+            virtual_packages: HashMap::new(),
+            external_providers: false,
+            bad_metadata: Vec::new(),
         }
     }
 
+    /// Evaluate depends on these virtual packages against the given records, rather than
+    /// assuming that they are satisfiable.
+    #[must_use]
+    pub fn with_virtual_packages(mut self, records: &'a [PackageRecord]) -> Self {
+        self.virtual_packages = records
+            .iter()
+            .map(|record| (record.name.as_normalized(), record))
+            .collect();
+        self
+    }
+
+    /// Also index the constrains of packages inserted from now on, so that packages are removed
+    /// when a constraint excludes every remaining provider of a package, or constrains a virtual
+    /// package which is impossible on the architecture.
+    #[must_use]
+    pub fn with_enforced_constrains(mut self, enforce_constrains: bool) -> Self {
+        self.enforce_constrains = enforce_constrains;
+        self
+    }
+
+    /// Take depends on names which no inserted package provides as satisfiable, as when noarch
+    /// packages are filtered without the architectures which provide them.
+    #[must_use]
+    pub fn with_external_providers(mut self, external_providers: bool) -> Self {
+        self.external_providers = external_providers;
+        self
+    }
+
     #[must_use]
     pub fn stats(&self) -> (usize, usize, usize) {
         let edges = self.package_dependencies.values().map(HashMap::len).sum();
@@ -184,6 +759,8 @@ impl<'a> PackageRelations<'a> {
             package_record,
         });
         self.removed.push(false);
+        self.duplicates.push(false);
+        self.locked.push(false);
         let index = PkgIdx {
             index: u32::try_from(self.package_metadatas.len() - 1).expect("too many packages"),
         };
@@ -198,12 +775,28 @@ impl<'a> PackageRelations<'a> {
             value.1.offset += 1;
         }
         self.filename_to_metadata.insert(filename, index);
+        if let Some(problem) = malformed_record(package_record) {
+            self.bad_metadata.push((index, problem));
+        }
 
-        for depend in &package_record.depends {
+        let constrains: &[String] = if self.enforce_constrains {
+            &package_record.constrains
+        } else {
+            &[]
+        };
+        let depends = package_record.depends.iter().map(|depend| (depend, false));
+        let constrains = constrains.iter().map(|constrain| (constrain, true));
+        for (depend, weak) in depends.chain(constrains) {
             let (dependency_name, dependency_spec) = dependsstr_to_name_and_spec(depend);
-            let matchspec = matchspec_cache
-                .get_or_insert(dependency_spec)
-                .expect(depend);
+            // An unparseable matchspec is left out of the graph, as if it were absent.
+            let matchspec = match matchspec_cache.get_or_insert(dependency_spec) {
+                Ok(matchspec) if !dependency_name.is_empty() => matchspec,
+                _ => {
+                    self.bad_metadata
+                        .push((index, MetadataProblem::UnparseableMatchspec(depend)));
+                    continue;
+                }
+            };
 
             let dependency = self
                 .package_dependencies
@@ -215,13 +808,225 @@ impl<'a> PackageRelations<'a> {
                     matchspec,
                     last_successful_resolution: None,
                     dependers: Vec::new(),
+                    weak_dependers: Vec::new(),
                 });
-            dependency.dependers.push(index);
+            if weak {
+                dependency.weak_dependers.push(index);
+            } else {
+                dependency.dependers.push(index);
+            }
+        }
+    }
+
+    /// The number of packages with malformed names, versions or builds, or with depends or
+    /// constrains which could not be parsed.
+    #[must_use]
+    pub fn bad_metadata_count(&self) -> usize {
+        self.bad_metadata
+            .iter()
+            .map(|(index, _)| index.index())
+            .unique()
+            .count()
+    }
+
+    /// Remove packages with depends or constrains which could not be parsed if
+    /// `remove_unparseable`, and those with a malformed name, version or build if
+    /// `remove_malformed`. Otherwise, these are kept, and the unparseable entries are ignored.
+    pub fn apply_bad_metadata_removal(
+        &mut self,
+        remove_unparseable: bool,
+        remove_malformed: bool,
+    ) -> Vec<RemovedBadMetadataLog<'a>> {
+        let mut result = Vec::new();
+        for (index, problem) in &self.bad_metadata {
+            let remove = match problem {
+                MetadataProblem::UnparseableMatchspec(_) => remove_unparseable,
+                _ => remove_malformed,
+            };
+            if !remove || self.removed[index.index()] || self.locked[index.index()] {
+                continue;
+            }
+            self.removed.set(index.index(), true);
+            let package = &self.package_metadatas[index.index()];
+            result.push(RemovedBadMetadataLog {
+                filename: package.filename,
+                package_name: package.package_record.name.as_source(),
+                problem: *problem,
+            });
+        }
+        result
+    }
+
+    /// The packages whose platform, arch or subdir contradict the directory they are published
+    /// under, given as each directory's name and repodata.
+    fn subdir_mismatches<'d>(
+        &'d self,
+        directories: &'d [(&'a str, &'a RepoData)],
+    ) -> impl Iterator<Item = RemovedBySubdirMismatchLog<'a>> + 'd {
+        directories.iter().flat_map(move |(directory, repodata)| {
+            let platform = Platform::from_str(directory).ok();
+            repodata
+                .packages
+                .iter()
+                .chain(&repodata.conda_packages)
+                .filter_map(move |(filename, record)| {
+                    let (field, value) = subdir_mismatch(record, directory, platform)?;
+                    let index = self.filename_to_metadata.get(filename.as_str())?;
+                    Some(RemovedBySubdirMismatchLog {
+                        filename: self.package_metadatas[index.index()].filename,
+                        package_name: record.name.as_source(),
+                        field,
+                        value,
+                        subdir: directory,
+                    })
+                })
+        })
+    }
+
+    /// The number of packages whose platform, arch or subdir contradict the directory they are
+    /// published under.
+    #[must_use]
+    pub fn subdir_mismatch_count(&self, directories: &[(&'a str, &'a RepoData)]) -> usize {
+        self.subdir_mismatches(directories).count()
+    }
+
+    /// Remove packages whose subdir field contradicts the directory they are published under if
+    /// `remove_subdir`, and those whose platform or arch does if `remove_platform`.
+    pub fn apply_subdir_consistency(
+        &mut self,
+        directories: &[(&'a str, &'a RepoData)],
+        remove_subdir: bool,
+        remove_platform: bool,
+    ) -> Vec<RemovedBySubdirMismatchLog<'a>> {
+        if !remove_subdir && !remove_platform {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedBySubdirMismatchLog<'a>> = self
+            .subdir_mismatches(directories)
+            .filter(|log| {
+                if log.field == "subdir" {
+                    remove_subdir
+                } else {
+                    remove_platform
+                }
+            })
+            .filter(|log| !self.removed[self.filename_to_metadata[log.filename].index()])
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Find the cycles in the dependency graph of the remaining packages, at the level of package
+    /// names. A name depends on another if any of its remaining packages depends on it, and only
+    /// cycles of more than one name are reported.
+    #[must_use]
+    pub fn detect_cycles(&self) -> Vec<DependencyCycle<'a>> {
+        let mut nodes: Vec<(&'a str, &'a str)> = self
+            .package_name_to_providers
+            .keys()
+            .filter_map(|package_name| {
+                let newest = self
+                    .mkrange(package_name)
+                    .rev()
+                    .find(|index| !self.removed[*index])?;
+                Some((*package_name, self.package_metadatas[newest].filename))
+            })
+            .collect();
+        nodes.sort_unstable();
+        let node_indices: HashMap<&str, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(node, (package_name, _))| (*package_name, node))
+            .collect();
+        let edges: Vec<Vec<usize>> = nodes
+            .iter()
+            .map(|(package_name, _)| {
+                self.mkrange(package_name)
+                    .filter(|index| !self.removed[*index])
+                    .flat_map(|index| self.get_dependencies(index))
+                    .filter_map(|(dependency_name, _)| node_indices.get(dependency_name).copied())
+                    .unique()
+                    .collect()
+            })
+            .collect();
+        strongly_connected_components(&edges)
+            .into_iter()
+            .filter(|component| component.len() > 1)
+            .map(|component| DependencyCycle {
+                members: component
+                    .into_iter()
+                    .sorted_unstable()
+                    .map(|node| nodes[node])
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Remove packages which depend on their own name with a spec that no remaining package of
+    /// that name satisfies.
+    pub fn apply_self_cycle_removal(&mut self, enabled: bool) -> Vec<RemovedBySelfCycleLog<'a>> {
+        if !enabled {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedBySelfCycleLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .enumerate()
+            .filter(|(index, _)| !self.removed[*index])
+            .filter_map(|(_, package)| {
+                let package_name = package.package_record.name.as_source();
+                let matchspec = package.package_record.depends.iter().find(|depend| {
+                    let Some((dependency_name, dependency)) = self.get_dependency(depend) else {
+                        return false;
+                    };
+                    dependency_name == package_name
+                        && !self.mkrange(package_name).any(|index| {
+                            !self.removed[index]
+                                && dependency
+                                    .matchspec
+                                    .matches(self.package_metadatas[index].package_record)
+                        })
+                })?;
+                Some(RemovedBySelfCycleLog {
+                    filename: package.filename,
+                    package_name,
+                    matchspec,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Protect the packages with this name, version and build from every rule, returning how
+    /// many there are. There may be one of each package format.
+    pub fn lock(&mut self, package_name: &str, version: &str, build: &str) -> usize {
+        let mut count = 0;
+        for index in self.mkrange(package_name) {
+            let record = self.package_metadatas[index].package_record;
+            if record.version.as_str() == version && record.build == build {
+                self.locked.set(index, true);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Mark the packages of these logs as removed, except locked packages, whose logs are dropped.
+    fn mark_removed<L: Log<'a>>(&mut self, result: &mut Vec<L>) {
+        result.retain(|log| !self.locked[self.filename_to_metadata[log.filename()].index()]);
+        for res in result.iter() {
+            self.removed
+                .set(self.filename_to_metadata[res.filename()].index(), true);
         }
     }
 
     pub fn shrink_to_fit(&mut self) {
         self.removed.shrink_to_fit();
+        self.duplicates.shrink_to_fit();
+        self.locked.shrink_to_fit();
         self.package_metadatas.shrink_to_fit();
         self.filename_to_metadata.shrink_to_fit();
         self.package_name_to_providers.shrink_to_fit();
@@ -231,64 +1036,224 @@ impl<'a> PackageRelations<'a> {
         }
     }
 
+    /// Remove builds superceded by a build with a higher build number of the same variant, or by a
+    /// newer upload of the same build number. Builds are the same variant if they share a package
+    /// name, version, variant hashes and depends. Builds with the same build string as the kept
+    /// build only differ in format, and are kept too.
+    /// Builds without a variant hash are never pruned, nor are builds which a remaining package
+    /// pins with a build string or build number that no build surviving the prune satisfies.
     pub fn apply_build_prune(&mut self) -> Vec<RemovedBySupercedingBuildLog<'a>> {
         let mut result = Vec::new();
-        let pattern = regex::Regex::new(r".*h[\da-zA-Z]{7}.+\d").unwrap();
         for (_, packages) in &self.package_metadatas[..]
             .iter()
-            .filter(|pkg| {
-                let build = &pkg.package_record.build;
-                pattern.is_match(build)
-            })
+            .enumerate()
+            .filter(|(index, _)| !self.removed[*index])
+            .map(|(_, pkg)| pkg)
             .chunk_by(|pkg| {
                 let r = &pkg.package_record;
-                let buildnumstr = r.build_number.to_string();
-                let mut build: &str = &r.build;
-                if build.ends_with(&buildnumstr) {
-                    build = &build[0..(build.len() - buildnumstr.len())];
-                }
-                (r.name.as_source(), &r.version, build)
+                (r.name.as_source(), &r.version)
             })
         {
-            let packages: Vec<&PackageMetadata> = packages.collect();
-            if packages.len() < 2 {
-                continue;
+            let mut variants: HashMap<(Vec<&str>, Vec<&str>), Vec<&PackageMetadata>> =
+                HashMap::new();
+            for pkg in packages {
+                let r = &pkg.package_record;
+                let hashes = variant_hashes(&r.build);
+                if hashes.is_empty() {
+                    continue;
+                }
+                let mut depends: Vec<&str> = r.depends.iter().map(String::as_str).collect();
+                depends.sort_unstable();
+                variants.entry((hashes, depends)).or_default().push(pkg);
             }
-            let big = packages[packages.len() - 1].package_record.build_number;
-            for pkg in &packages[..packages.len() - 1] {
-                if pkg.package_record.build_number < big {
-                    result.push(RemovedBySupercedingBuildLog {
-                        filename: pkg.filename,
-                        package_name: packages[0].package_record.name.as_source(),
-                        build_number: big,
-                    });
+            for packages in variants.values() {
+                // The newest upload breaks ties between builds of the same build number, and the
+                // filename ties between builds without timestamps.
+                let kept = packages
+                    .iter()
+                    .max_by_key(|pkg| {
+                        (
+                            pkg.package_record.build_number,
+                            pkg.package_record.timestamp,
+                            pkg.filename,
+                        )
+                    })
+                    .unwrap();
+                for pkg in packages {
+                    if pkg.package_record.build != kept.package_record.build {
+                        result.push(RemovedBySupercedingBuildLog {
+                            filename: pkg.filename,
+                            package_name: pkg.package_record.name.as_source(),
+                            build_number: kept.package_record.build_number,
+                            kept_filename: kept.filename,
+                        });
+                    }
                 }
             }
         }
-        for res in &result {
-            self.removed
-                .set(self.filename_to_metadata[res.filename].index(), true);
+        // Pruning a pinned build would only take its dependers with it.
+        let pruned: HashSet<usize> = result
+            .iter()
+            .map(|log| self.filename_to_metadata[log.filename].index())
+            .collect();
+        result.retain(|log| {
+            let index = self.filename_to_metadata[log.filename].index();
+            !self.is_build_pinned(log.package_name, index, &pruned)
+        });
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Whether a remaining package depends on this build with a build string or build number
+    /// constraint which no other remaining build outside of `pruned` satisfies.
+    fn is_build_pinned(&self, package_name: &str, index: usize, pruned: &HashSet<usize>) -> bool {
+        let Some(dependencies) = self.package_dependencies.get(package_name) else {
+            return false;
+        };
+        let record = self.package_metadatas[index].package_record;
+        dependencies.values().any(|dependency| {
+            let matchspec = dependency.matchspec;
+            (matchspec.build.is_some() || matchspec.build_number.is_some())
+                && matchspec.matches(record)
+                && dependency
+                    .dependers
+                    .iter()
+                    .any(|depender| !self.removed[depender.index()])
+                && !self.mkrange(package_name).any(|other| {
+                    other != index
+                        && !self.removed[other]
+                        && !pruned.contains(&other)
+                        && matchspec.matches(self.package_metadatas[other].package_record)
+                })
+        })
+    }
+
+    /// Remove the builds of a package version which score below the others by `preferences`, a
+    /// list of build string substrings with the most preferred first. A build scores higher the
+    /// earlier the first of the substrings it contains is listed, and zero if it contains none.
+    /// Versions whose builds all score the same are left alone.
+    pub fn apply_variant_preference(
+        &mut self,
+        preferences: &[String],
+    ) -> Vec<RemovedByVariantPreferenceLog<'a>> {
+        let mut result = Vec::new();
+        if preferences.is_empty() {
+            return result;
+        }
+        let score = |build: &str| {
+            preferences
+                .iter()
+                .position(|preference| build.contains(preference.as_str()))
+                .map_or(0, |position| preferences.len() - position)
+        };
+        for (_, packages) in &self.package_metadatas[..]
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !self.removed[*index])
+            .map(|(_, pkg)| pkg)
+            .chunk_by(|pkg| {
+                let r = &pkg.package_record;
+                (r.name.as_source(), &r.version)
+            })
+        {
+            let scored: Vec<(&PackageMetadata, usize)> = packages
+                .map(|pkg| (pkg, score(&pkg.package_record.build)))
+                .collect();
+            let Some(&(winner, winner_score)) = scored.iter().max_by_key(|(_, score)| *score)
+            else {
+                continue;
+            };
+            result.extend(
+                scored
+                    .iter()
+                    .filter(|(_, score)| *score < winner_score)
+                    .map(|(pkg, score)| RemovedByVariantPreferenceLog {
+                        filename: pkg.filename,
+                        package_name: pkg.package_record.name.as_source(),
+                        score: *score,
+                        winner: winner.filename,
+                        winner_score,
+                    }),
+            );
         }
+        self.mark_removed(&mut result);
         result
     }
 
-    pub fn apply_feature_removal(
+    /// Remove rebuilds without a variant hash which have been superceded by a rebuild of the same
+    /// version with identical depends. The build with the highest build number is kept, with the
+    /// newest timestamp breaking ties. Builds with the same build string as the kept build are
+    /// kept too, since they only differ in format.
+    pub fn apply_plain_rebuild_prune(
         &mut self,
-        features: &HashSet<&str>,
-    ) -> Vec<RemovedWithFeatureLog<'a>> {
-        if features.is_empty() {
-            let res = Vec::with_capacity(0);
-            return res;
+        enabled: bool,
+    ) -> Vec<RemovedByPlainRebuildLog<'a>> {
+        let mut result = Vec::new();
+        if !enabled {
+            return result;
         }
-        let result: Vec<RemovedWithFeatureLog<'a>> = self
-            .package_metadatas
-            .par_iter()
-            .filter_map(|package| {
-                if let Some(feature) = package.package_record.features.as_ref() {
-                    if features.contains(feature.as_str()) {
-                        return Some(RemovedWithFeatureLog {
-                            filename: package.filename,
-                            package_name: package.package_record.name.as_source(),
+        for (_, packages) in &self.package_metadatas[..]
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !self.removed[*index])
+            .map(|(_, pkg)| pkg)
+            .chunk_by(|pkg| {
+                let r = &pkg.package_record;
+                (r.name.as_source(), &r.version)
+            })
+        {
+            let mut rebuilds: HashMap<Vec<&str>, Vec<&PackageMetadata>> = HashMap::new();
+            for pkg in packages {
+                let r = &pkg.package_record;
+                if !variant_hashes(&r.build).is_empty() {
+                    continue;
+                }
+                let mut depends: Vec<&str> = r.depends.iter().map(String::as_str).collect();
+                depends.sort_unstable();
+                rebuilds.entry(depends).or_default().push(pkg);
+            }
+            for packages in rebuilds.values() {
+                let kept = packages
+                    .iter()
+                    .max_by_key(|pkg| {
+                        (
+                            pkg.package_record.build_number,
+                            pkg.package_record.timestamp,
+                        )
+                    })
+                    .unwrap();
+                for pkg in packages {
+                    if pkg.package_record.build != kept.package_record.build {
+                        result.push(RemovedByPlainRebuildLog {
+                            filename: pkg.filename,
+                            package_name: pkg.package_record.name.as_source(),
+                            kept_filename: kept.filename,
+                        });
+                    }
+                }
+            }
+        }
+        self.mark_removed(&mut result);
+        result
+    }
+
+    pub fn apply_feature_removal(
+        &mut self,
+        features: &HashSet<&str>,
+    ) -> Vec<RemovedWithFeatureLog<'a>> {
+        if features.is_empty() {
+            let res = Vec::with_capacity(0);
+            return res;
+        }
+        let mut result: Vec<RemovedWithFeatureLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                if let Some(feature) = package.package_record.features.as_ref() {
+                    if features.contains(feature.as_str()) {
+                        return Some(RemovedWithFeatureLog {
+                            filename: package.filename,
+                            package_name: package.package_record.name.as_source(),
                             feature,
                         });
                     }
@@ -305,416 +1270,4410 @@ impl<'a> PackageRelations<'a> {
                 None
             })
             .collect();
-        for res in &result {
-            self.removed
-                .set(self.filename_to_metadata[res.filename].index(), true);
-        }
+        self.mark_removed(&mut result);
         result
     }
 
-    pub fn apply_dev_rc_ban(
+    /// Remove noarch packages of the banned noarch kinds.
+    pub fn apply_noarch_type_ban(
         &mut self,
-        ban_dev: bool,
-        ban_rc: bool,
-    ) -> Vec<RemovedByDevRcPolicyLog<'a>> {
-        if !(ban_dev || ban_rc) {
+        banned: &[NoArchKind],
+    ) -> Vec<RemovedByNoarchTypeLog<'a>> {
+        if banned.is_empty() {
             let result = Vec::with_capacity(0);
             return result;
         }
-        let result: Vec<RemovedByDevRcPolicyLog<'a>> = self
+        let mut result: Vec<RemovedByNoarchTypeLog<'a>> = self
             .package_metadatas
             .par_iter()
             .filter_map(|package| {
-                if package
-                    .package_record
-                    .version
-                    .segments()
-                    .flat_map(|segment| segment.components())
-                    .any(|component| {
-                        (ban_dev && component.is_dev())
-                            || (ban_rc
-                                && component
-                                    .as_string()
-                                    .is_some_and(|the_str| the_str.starts_with("rc")))
-                    })
-                {
-                    Some(RemovedByDevRcPolicyLog {
+                let kind = package.package_record.noarch.kind()?;
+                if banned.contains(&kind) {
+                    Some(RemovedByNoarchTypeLog {
                         filename: package.filename,
                         package_name: package.package_record.name.as_source(),
+                        kind,
                     })
                 } else {
                     None
                 }
             })
             .collect();
-        for res in &result {
-            self.removed
-                .set(self.filename_to_metadata[res.filename].index(), true);
-        }
+        self.mark_removed(&mut result);
         result
     }
 
-    pub fn apply_incompatible_architecture(
+    /// Remove every package which tracks any feature at all. Solvers deprioritize these packages,
+    /// so they are rarely wanted.
+    pub fn apply_track_features_ban(
         &mut self,
-        architecture: &'a str,
-    ) -> Vec<RemovedIncompatibleArchitectureLog<'a>> {
-        let result: Vec<RemovedIncompatibleArchitectureLog<'a>> =
-            (*get_virtual_package_bans(architecture))
-                .into_par_iter()
-                .copied()
-                .filter_map(|depending_on| {
-                    self.package_dependencies
-                        .get(depending_on)
-                        .map(|d| (depending_on, d))
-                })
-                .flat_map(|(dependency_name, dependencies)| {
-                    dependencies
-                        .par_iter()
-                        .flat_map(|(_, dependency)| dependency.dependers.par_iter())
-                        .map(|pkgindex| {
-                            let package = &self.package_metadatas[pkgindex.index()];
-                            RemovedIncompatibleArchitectureLog {
-                                filename: package.filename,
-                                package_name: package.package_record.name.as_source(),
-                                virtual_package: dependency_name,
-                                actual_architecture: architecture,
-                            }
-                        })
-                })
-                .collect();
-        // Mark the packages as removed
-        for res in &result {
-            self.removed
-                .set(self.filename_to_metadata[res.filename].index(), true);
+        enabled: bool,
+    ) -> Vec<RemovedByTrackFeaturesLog<'a>> {
+        if !enabled {
+            let result = Vec::with_capacity(0);
+            return result;
         }
-        // Mark the dependencies as unresolveable
-        for virtual_package_name in get_virtual_package_bans(architecture) {
-            if let Some(matchspec_map) = self.package_dependencies.get_mut(virtual_package_name) {
-                for dependency in matchspec_map.values_mut() {
-                    dependency.unsatisfiable = true;
+        let mut result: Vec<RemovedByTrackFeaturesLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let track_features = &package.package_record.track_features;
+                if track_features.iter().all(String::is_empty) {
+                    return None;
                 }
-            }
-        }
+                Some(RemovedByTrackFeaturesLog {
+                    filename: package.filename,
+                    package_name: package.package_record.name.as_source(),
+                    track_features,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
         result
     }
 
-    pub fn apply_user_matchspecs(
+    /// Remove the packages whose versions are of a banned prerelease kind, except those whose
+    /// names match one of the `exceptions`.
+    pub fn apply_prerelease_ban(
         &mut self,
-        user_matchspecs: &HashMap<String, Vec<NamelessMatchSpec>>,
-    ) -> Vec<RemovedByUserLog<'a>> {
+        banned: &[Prerelease],
+        exceptions: &[glob::Pattern],
+    ) -> Vec<RemovedByPrereleasePolicyLog<'a>> {
+        if banned.is_empty() {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedByPrereleasePolicyLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let package_name = package.package_record.name.as_source();
+                if exceptions
+                    .iter()
+                    .any(|exception| exception.matches(package_name))
+                {
+                    return None;
+                }
+                let prerelease = prereleases(&package.package_record.version)
+                    .find(|prerelease| banned.contains(prerelease))?;
+                Some(RemovedByPrereleasePolicyLog {
+                    filename: package.filename,
+                    package_name: package.package_record.name.as_source(),
+                    prerelease,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove the packages with exactly these filenames. Filenames which are not present are
+    /// ignored.
+    pub fn apply_filename_ban(&mut self, filenames: &[String]) -> Vec<RemovedByFilenameBanLog<'a>> {
         let mut result = Vec::new();
-        for (package_name, specs) in user_matchspecs {
-            let spec_arg: Vec<&NamelessMatchSpec> = specs.iter().collect();
-            result.append(&mut (self.apply_matchspecs(package_name, &spec_arg)));
+        for filename in filenames {
+            let Some(index) = self.filename_to_metadata.get(filename.as_str()) else {
+                continue;
+            };
+            if self.removed[index.index()] || self.locked[index.index()] {
+                continue;
+            }
+            self.removed.set(index.index(), true);
+            let package = &self.package_metadatas[index.index()];
+            result.push(RemovedByFilenameBanLog {
+                filename: package.filename,
+                package_name: package.package_record.name.as_source(),
+            });
         }
         result
     }
 
-    fn apply_matchspecs(
+    /// Remove the packages matching any entry of a vulnerability denylist.
+    pub fn apply_vulnerability_denylist(
         &mut self,
-        package_name: &str,
-        specs: &[&NamelessMatchSpec],
-    ) -> Vec<RemovedByUserLog<'a>> {
+        denylist: &'a [DenylistEntry],
+    ) -> Vec<RemovedByVulnerabilityLog<'a>> {
         let mut result = Vec::new();
-        if let Some((start, offset)) = self.package_name_to_providers.get(package_name) {
-            for index in start.range_to(*offset) {
-                if self.removed[index] {
+        for entry in denylist {
+            for index in self.mkrange(&entry.package_name) {
+                let package = &self.package_metadatas[index];
+                if self.removed[index]
+                    || self.locked[index]
+                    || !entry.spec.matches(package.package_record)
+                {
                     continue;
                 }
-                let md = &mut self.package_metadatas[index];
-                let mut passes = false;
-
-                // Determine if this package should no longer be here
-                for spec in specs {
-                    if spec.matches(md.package_record) {
-                        passes = true;
-                        break;
-                    }
-                }
+                self.removed.set(index, true);
+                result.push(RemovedByVulnerabilityLog {
+                    filename: package.filename,
+                    package_name: package.package_record.name.as_source(),
+                    matchspec: &entry.matchspec,
+                    identifier: entry.identifier.as_deref(),
+                });
+            }
+        }
+        result
+    }
 
-                if !passes {
-                    self.removed.set(index, true);
-                    result.push(RemovedByUserLog {
-                        package_name: md.package_record.name.as_source(),
-                        filename: md.filename,
-                    });
-                }
+    /// Remove packages attributed to a channel which matches any of the glob patterns, either as
+    /// a whole or by its last path segment, i.e. `conda-forge` for
+    /// `https://conda.anaconda.org/conda-forge/`. Packages without an attribution are kept.
+    pub fn apply_origin_channel_ban(
+        &mut self,
+        patterns: &'a [glob::Pattern],
+        origins: &[&'a OriginChannels],
+    ) -> Vec<RemovedByOriginChannelLog<'a>> {
+        let mut result = Vec::new();
+        if patterns.is_empty() {
+            return result;
+        }
+        for (filename, channel) in origins.iter().flat_map(|origins| origins.iter()) {
+            let Some(index) = self.filename_to_metadata.get(filename) else {
+                continue;
+            };
+            if self.removed[index.index()] || self.locked[index.index()] {
+                continue;
             }
+            let channel_name = channel.trim_end_matches('/').rsplit('/').next().unwrap();
+            let Some(pattern) = patterns
+                .iter()
+                .find(|pattern| pattern.matches(channel) || pattern.matches(channel_name))
+            else {
+                continue;
+            };
+            self.removed.set(index.index(), true);
+            let package = &self.package_metadatas[index.index()];
+            result.push(RemovedByOriginChannelLog {
+                filename: package.filename,
+                package_name: package.package_record.name.as_source(),
+                channel,
+                pattern: pattern.as_str(),
+            });
         }
         result
     }
 
-    fn get_dependencies(
-        &self,
-        index: usize,
-    ) -> impl Iterator<Item = (&'a str, &PackageDependency<'a>)> {
-        self.package_metadatas[index]
-            .package_record
-            .depends
-            .iter()
-            .map(|depend| {
-                let (dependency_name, dependency_spec) = dependsstr_to_name_and_spec(depend);
-                (
-                    dependency_name,
-                    &self.package_dependencies[dependency_name][dependency_spec],
-                )
+    /// The number of packages which have no signature in any of the `signatures`.
+    #[must_use]
+    pub fn unsigned_count(&self, signatures: &[&Signatures]) -> usize {
+        self.package_metadatas
+            .par_iter()
+            .filter(|package| {
+                !signatures
+                    .iter()
+                    .any(|signatures| signatures.contains(package.filename))
             })
+            .count()
     }
 
-    pub fn apply_must_compatible(
+    /// Remove packages which have no signature in any of the `signatures`.
+    pub fn apply_signature_requirement(
         &mut self,
-        package_name: &'a str,
-    ) -> Vec<RemovedBecauseIncompatibleLog<'a>> {
-        let mut result = Vec::new();
-
-        let mut range = self
-            .mkrange(package_name)
-            .filter(|index| !self.removed[*index]);
-
-        let mut relevant_packages = HashSet::new();
-        let mut relevant_matchspecs = HashMap::new();
-        let index = range.next();
-        if index.is_none() {
+        enabled: bool,
+        signatures: &[&Signatures],
+    ) -> Vec<RemovedUnsignedLog<'a>> {
+        if !enabled {
+            let result = Vec::with_capacity(0);
             return result;
         }
-        let index = index.unwrap();
-        for (name, dependency) in self.get_dependencies(index) {
-            relevant_packages.insert(name);
-            relevant_matchspecs.insert(name, HashSet::from([dependency.matchspec]));
-        }
+        let mut result: Vec<RemovedUnsignedLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter(|package| {
+                !signatures
+                    .iter()
+                    .any(|signatures| signatures.contains(package.filename))
+            })
+            .map(|package| RemovedUnsignedLog {
+                filename: package.filename,
+                package_name: package.package_record.name.as_source(),
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
 
-        for index in range {
-            let mut local_relevant_packages = HashSet::new();
-            for (name, dependency) in self.get_dependencies(index) {
-                if let Some(specs) = relevant_matchspecs.get_mut(name) {
-                    specs.insert(dependency.matchspec);
-                    local_relevant_packages.insert(name);
-                }
-            }
-            relevant_packages = &relevant_packages & &local_relevant_packages;
-            if relevant_packages.is_empty() {
-                break;
+    /// Remove the packages which the channel itself lists as removed.
+    pub fn apply_channel_removed<'r>(
+        &mut self,
+        filenames: impl IntoIterator<Item = &'r str>,
+    ) -> Vec<RemovedByChannelLog<'a>> {
+        let mut result = Vec::new();
+        for filename in filenames {
+            let Some(index) = self.filename_to_metadata.get(filename) else {
+                continue;
+            };
+            if self.removed[index.index()] || self.locked[index.index()] {
+                continue;
             }
+            self.removed.set(index.index(), true);
+            let package = &self.package_metadatas[index.index()];
+            result.push(RemovedByChannelLog {
+                filename: package.filename,
+                package_name: package.package_record.name.as_source(),
+            });
         }
+        result
+    }
 
-        for package in &relevant_packages {
-            let specs = relevant_matchspecs.remove(package).unwrap();
-            for item in self.apply_matchspecs(
-                package,
-                &specs.into_iter().collect::<Vec<&NamelessMatchSpec>>(),
-            ) {
-                result.push(RemovedBecauseIncompatibleLog {
-                    package_name: item.package_name,
-                    filename: item.filename,
-                    incompatible_with: package_name,
-                });
+    /// Remove the packages an earlier run removed, before any other rule, so that what depends on
+    /// them is removed in turn. Filenames which are not present are ignored.
+    pub fn apply_pre_removal<'r>(
+        &mut self,
+        filenames: impl IntoIterator<Item = &'r str>,
+    ) -> Vec<RemovedByPreRemovalLog<'a>> {
+        let mut result = Vec::new();
+        for filename in filenames {
+            let Some(index) = self.filename_to_metadata.get(filename) else {
+                continue;
+            };
+            if self.removed[index.index()] || self.locked[index.index()] {
+                continue;
             }
-        }
-
-        for package in relevant_packages {
-            let mut sub_results = self.apply_must_compatible(package);
-            result.append(&mut sub_results);
+            self.removed.set(index.index(), true);
+            let package = &self.package_metadatas[index.index()];
+            result.push(RemovedByPreRemovalLog {
+                filename: package.filename,
+                package_name: package.package_record.name.as_source(),
+            });
         }
         result
     }
 
-    fn mkrange(&self, package_name: &str) -> Range<usize> {
-        match self.package_name_to_providers.get(package_name) {
-            Some((start, offset)) => start.range_to(*offset),
-            None => 0..0,
+    /// Remove every build of the packages whose name matches a banned pattern, before any other
+    /// rule, so that what depends on them is removed in turn.
+    pub fn apply_name_ban(&mut self, banned: &'a [glob::Pattern]) -> Vec<RemovedByNameBanLog<'a>> {
+        if banned.is_empty() {
+            let result = Vec::with_capacity(0);
+            return result;
         }
+        self.remove_by_name(
+            |package_name| {
+                banned
+                    .iter()
+                    .find(|pattern| pattern.matches(package_name))
+                    .map(glob::Pattern::as_str)
+            },
+            |filename, package_name, pattern| RemovedByNameBanLog {
+                filename,
+                package_name,
+                pattern,
+            },
+        )
     }
 
-    pub fn find_all_unresolveables(&mut self) -> Vec<RemovedUnsatisfiableLog<'a>> {
-        return self.find_unresolveables(
-            self.package_dependencies
-                .keys()
-                .copied()
-                .filter(|d| !d.starts_with("__"))
-                .collect(),
-        );
+    /// Remove every package whose name matches a pattern of the denylist, or, if an allowlist is
+    /// given, does not match any of its patterns. Patterns are only matched once per package name.
+    pub fn apply_name_lists(
+        &mut self,
+        denied: &'a [glob::Pattern],
+        allowed: Option<&[glob::Pattern]>,
+    ) -> Vec<RemovedByNameListLog<'a>> {
+        if denied.is_empty() && allowed.is_none() {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        self.remove_by_name(
+            |package_name| {
+                let denied_by = denied
+                    .iter()
+                    .find(|pattern| pattern.matches(package_name))
+                    .map(glob::Pattern::as_str);
+                let allowed = allowed.map_or(true, |allowed| {
+                    allowed.iter().any(|pattern| pattern.matches(package_name))
+                });
+                (denied_by.is_some() || !allowed).then_some(denied_by)
+            },
+            |filename, package_name, denied_by| RemovedByNameListLog {
+                filename,
+                package_name,
+                denied_by,
+            },
+        )
     }
-    pub fn find_unresolveables(
+
+    /// Remove every remaining package of the names `reason` gives a reason to remove, logged by
+    /// `log` from the filename, the package name and that reason. The reason is only looked for
+    /// once per package name.
+    fn remove_by_name<R, L>(
         &mut self,
-        depending_ons: Vec<&'a str>,
-    ) -> Vec<RemovedUnsatisfiableLog<'a>> {
-        let updates: Vec<Evaluation> = depending_ons
-            .into_par_iter()
-            .filter_map(|depending_on| {
-                self.package_dependencies
-                    .get(depending_on)
-                    .map(|d| (depending_on, d))
+        reason: impl Fn(&str) -> Option<R> + Sync,
+        log: impl Fn(&'a str, &'a str, R) -> L + Sync,
+    ) -> Vec<L>
+    where
+        R: Copy + Send,
+        L: Log<'a> + Send,
+    {
+        let removed = &self.removed;
+        let package_metadatas = &self.package_metadatas;
+        let log = &log;
+        let mut result: Vec<L> = self
+            .package_name_to_providers
+            .par_iter()
+            .filter_map(|(package_name, (start, offset))| {
+                reason(package_name).map(|reason| (reason, start.range_to(*offset)))
             })
-            .flat_map(|(dependency_name, dependencies)| {
-                dependencies
-                    .par_iter()
-                    .filter_map(|(matchspec_str, dependency)| {
-                        if dependency.unsatisfiable {
-                            None
-                        } else {
-                            self.evaluate(
-                                DependencyKey {
-                                    name: dependency_name,
-                                    matchspec: matchspec_str,
-                                },
-                                dependency,
-                            )
-                        }
-                    })
+            .flat_map_iter(|(reason, range)| {
+                range.filter(|index| !removed[*index]).map(move |index| {
+                    let package = &package_metadatas[index];
+                    log(
+                        package.filename,
+                        package.package_record.name.as_source(),
+                        reason,
+                    )
+                })
             })
             .collect();
-        let mut result = Vec::with_capacity(updates.len());
-        for evaluation in updates {
-            match evaluation {
-                Evaluation::UpdateSolution(dep_key, offset) => {
-                    self.package_dependencies
-                        .get_mut(dep_key.name)
-                        .unwrap()
-                        .get_mut(dep_key.matchspec)
-                        .unwrap()
-                        .last_successful_resolution = Some(offset);
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages whose version has a local version segment, i.e. `1.2.3+local`. Only the
+    /// version is inspected, so a `+` in the build string has no effect.
+    pub fn apply_local_version_ban(&mut self, enabled: bool) -> Vec<RemovedByLocalVersionLog<'a>> {
+        if !enabled {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedByLocalVersionLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                if package.package_record.version.has_local() {
+                    Some(RemovedByLocalVersionLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        version: &package.package_record.version,
+                    })
+                } else {
+                    None
                 }
-                Evaluation::RemoveAndLog(dep_key, offset) => {
-                    let dependency = self
-                        .package_dependencies
-                        .get_mut(dep_key.name)
-                        .unwrap()
-                        .get_mut(dep_key.matchspec)
-                        .unwrap();
-                    dependency.unsatisfiable = true;
-                    for index in &dependency.dependers {
-                        let package = self.package_metadatas.get_mut(index.index()).unwrap();
-                        self.removed.set(index.index(), true);
-                        result.push(RemovedUnsatisfiableLog {
-                            dependency_package_name: dep_key.name,
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages whose version has a nonzero epoch, i.e. `1!2.0`, unless the package name
+    /// is in `allowed`.
+    pub fn apply_epoch_ban(
+        &mut self,
+        enabled: bool,
+        allowed: &HashSet<String>,
+    ) -> Vec<RemovedByEpochLog<'a>> {
+        if !enabled {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedByEpochLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let package_name = package.package_record.name.as_normalized();
+                if package.package_record.version.epoch() == 0 || allowed.contains(package_name) {
+                    return None;
+                }
+                Some(RemovedByEpochLog {
+                    filename: package.filename,
+                    package_name: package.package_record.name.as_source(),
+                    version: &package.package_record.version,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// The names depended on which no package in the channel provides, with the number of
+    /// packages depending on each, sorted by name. Virtual packages are not included.
+    #[must_use]
+    pub fn dangling_dependencies(&self) -> Vec<(&'a str, usize)> {
+        let mut result: Vec<(&'a str, usize)> = self
+            .package_dependencies
+            .iter()
+            .filter(|(package_name, _)| {
+                !package_name.starts_with("__")
+                    && !self.package_name_to_providers.contains_key(*package_name)
+            })
+            .map(|(package_name, dependencies)| {
+                let depender_count = dependencies
+                    .values()
+                    .flat_map(|dependency| &dependency.dependers)
+                    .map(|index| index.index())
+                    .unique()
+                    .count();
+                (*package_name, depender_count)
+            })
+            .filter(|(_, depender_count)| *depender_count > 0)
+            .collect();
+        result.sort_unstable();
+        result
+    }
+
+    /// Remove packages which depend on a name that no package in the channel provides.
+    pub fn apply_dangling_depends_removal(
+        &mut self,
+        enabled: bool,
+    ) -> Vec<RemovedByDanglingDependLog<'a>> {
+        let mut result = Vec::new();
+        if !enabled {
+            return result;
+        }
+        for (package_name, _) in self.dangling_dependencies() {
+            let dependers = self.package_dependencies[package_name]
+                .values()
+                .flat_map(|dependency| dependency.dependers.iter().copied())
+                .collect::<Vec<PkgIdx>>();
+            for index in dependers {
+                if self.removed[index.index()] || self.locked[index.index()] {
+                    continue;
+                }
+                self.removed.set(index.index(), true);
+                let package = &self.package_metadatas[index.index()];
+                result.push(RemovedByDanglingDependLog {
+                    filename: package.filename,
+                    package_name: package.package_record.name.as_source(),
+                    dependency_name: package_name,
+                });
+            }
+        }
+        result
+    }
+
+    /// The number of packages which do not declare a license.
+    #[must_use]
+    pub fn missing_license_count(&self) -> usize {
+        self.package_metadatas
+            .par_iter()
+            .filter(|package| lacks_license(package.package_record))
+            .count()
+    }
+
+    /// Remove packages which do not declare a license, except those of the allowed names.
+    pub fn apply_license_requirement(
+        &mut self,
+        enabled: bool,
+        allowed: &HashSet<String>,
+    ) -> Vec<RemovedByMissingLicenseLog<'a>> {
+        if !enabled {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedByMissingLicenseLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let package_name = package.package_record.name.as_normalized();
+                if !lacks_license(package.package_record) || allowed.contains(package_name) {
+                    return None;
+                }
+                Some(RemovedByMissingLicenseLog {
+                    filename: package.filename,
+                    package_name: package.package_record.name.as_source(),
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages whose license matches any of the provided glob patterns. Packages which
+    /// do not declare a license are not affected.
+    pub fn apply_license_ban(
+        &mut self,
+        patterns: &[glob::Pattern],
+    ) -> Vec<RemovedByLicenseLog<'a>> {
+        if patterns.is_empty() {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedByLicenseLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let license = package.package_record.license.as_deref()?;
+                if patterns.iter().any(|pattern| pattern.matches(license)) {
+                    Some(RemovedByLicenseLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        license,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages which were uploaded after `max_timestamp`, producing a view of the channel
+    /// as it was at that time. Packages without a timestamp are removed only if `remove_missing`.
+    pub fn apply_timestamp_cutoff(
+        &mut self,
+        max_timestamp: Option<DateTime<Utc>>,
+        remove_missing: bool,
+    ) -> Vec<RemovedByTimestampLog<'a>> {
+        let Some(max_timestamp) = max_timestamp else {
+            let result = Vec::with_capacity(0);
+            return result;
+        };
+        let mut result: Vec<RemovedByTimestampLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let timestamp = package.package_record.timestamp;
+                let remove = match timestamp {
+                    Some(timestamp) => timestamp > max_timestamp,
+                    None => remove_missing,
+                };
+                if remove {
+                    Some(RemovedByTimestampLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        timestamp,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages whose timestamp is older than `window` before `now`, except that every
+    /// build of the newest remaining version of each package name is kept, so that every package
+    /// name remains installable. `remove_missing` controls what happens to packages without a
+    /// timestamp.
+    pub fn apply_recency_window(
+        &mut self,
+        window: Option<TimeDelta>,
+        now: DateTime<Utc>,
+        remove_missing: bool,
+    ) -> Vec<RemovedByRecencyWindowLog<'a>> {
+        let Some(window) = window else {
+            let result = Vec::with_capacity(0);
+            return result;
+        };
+        // First, find the newest remaining version of each package name.
+        let newest_versions: HashMap<&str, &Version> = self
+            .package_name_to_providers
+            .par_iter()
+            .filter_map(|(package_name, (start, offset))| {
+                let newest = start
+                    .range_to(*offset)
+                    .filter(|index| !self.removed[*index])
+                    .map(|index| &*self.package_metadatas[index].package_record.version)
+                    .max()?;
+                Some((*package_name, newest))
+            })
+            .collect();
+        // Then, apply the window to everything else.
+        let cutoff = now - window;
+        let mut result: Vec<RemovedByRecencyWindowLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .enumerate()
+            .filter_map(|(index, package)| {
+                let record = package.package_record;
+                if self.removed[index]
+                    || newest_versions
+                        .get(record.name.as_source())
+                        .is_some_and(|newest| **newest == *record.version)
+                {
+                    return None;
+                }
+                let remove = match record.timestamp {
+                    Some(timestamp) => timestamp < cutoff,
+                    None => remove_missing,
+                };
+                if remove {
+                    Some(RemovedByRecencyWindowLog {
+                        filename: package.filename,
+                        package_name: record.name.as_source(),
+                        age: record.timestamp.map(|timestamp| now - timestamp),
+                        window,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove every package which does not belong to one of the `keep_versions` newest remaining
+    /// versions of its package name. `overrides` sets a different limit for specific names.
+    pub fn apply_version_prune(
+        &mut self,
+        keep_versions: Option<usize>,
+        overrides: &HashMap<String, usize>,
+    ) -> Vec<RemovedByVersionPruneLog<'a>> {
+        if keep_versions.is_none() && overrides.is_empty() {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedByVersionPruneLog<'a>> = self
+            .package_name_to_providers
+            .par_iter()
+            .flat_map_iter(|(package_name, (start, offset))| {
+                let mut result = Vec::new();
+                let Some(keep) = overrides.get(*package_name).copied().or(keep_versions) else {
+                    return result;
+                };
+                // Packages are sorted by version, so walk backwards from the newest.
+                let mut distinct_versions = 0;
+                let mut oldest_kept_version: Option<&'a VersionWithSource> = None;
+                for index in start.range_to(*offset).rev() {
+                    if self.removed[index] {
+                        continue;
+                    }
+                    let package = &self.package_metadatas[index];
+                    let version = &package.package_record.version;
+                    if distinct_versions < keep
+                        && oldest_kept_version.map_or(true, |kept| **kept != **version)
+                    {
+                        distinct_versions += 1;
+                        oldest_kept_version = Some(version);
+                    } else if oldest_kept_version.is_some_and(|kept| **kept != **version) {
+                        result.push(RemovedByVersionPruneLog {
                             filename: package.filename,
                             package_name: package.package_record.name.as_source(),
-                            matchspec: dependency.matchspec,
-                            cause_filename: offset
-                                .map(|index| self.package_metadatas[index.index as usize].filename),
+                            keep_versions: keep,
+                            oldest_kept_version: oldest_kept_version.unwrap(),
                         });
                     }
                 }
+                result
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove every package which does not belong to one of the newest versions of one of the
+    /// newest version series of its package name, as configured per package name. A series is a
+    /// version's leading segments, and versions with fewer segments are each a series of their
+    /// own.
+    pub fn apply_series_prune(
+        &mut self,
+        policies: &HashMap<String, SeriesPrune>,
+    ) -> Vec<RemovedBySeriesPruneLog<'a>> {
+        let mut result = Vec::new();
+        for (package_name, policy) in policies {
+            // Series and versions in the order they are seen, newest first.
+            let mut kept_series: Vec<Version> = Vec::new();
+            let mut kept_versions: HashMap<Version, Vec<&Version>> = HashMap::new();
+            for index in self.mkrange(package_name).rev() {
+                if self.removed[index] {
+                    continue;
+                }
+                let package = &self.package_metadatas[index];
+                let version = &*package.package_record.version;
+                let series = if version.segment_count() >= policy.depth {
+                    version.with_segments(..policy.depth)
+                } else {
+                    None
+                }
+                .unwrap_or_else(|| version.clone());
+                if !kept_series.contains(&series) && kept_series.len() < policy.keep_series {
+                    kept_series.push(series.clone());
+                }
+                let series_kept = kept_series.contains(&series);
+                let versions = kept_versions.entry(series.clone()).or_default();
+                if series_kept
+                    && !versions.contains(&version)
+                    && versions.len() < policy.keep_per_series
+                {
+                    versions.push(version);
+                }
+                let version_kept = series_kept && versions.contains(&version);
+                if !version_kept {
+                    result.push(RemovedBySeriesPruneLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        series,
+                        series_kept,
+                        keep_series: policy.keep_series,
+                        keep_per_series: policy.keep_per_series,
+                    });
+                }
             }
         }
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages larger than `max_size` bytes. Packages which do not declare a size are
+    /// kept.
+    pub fn apply_size_limit(&mut self, max_size: Option<u64>) -> Vec<RemovedBySizeLog<'a>> {
+        let Some(max_size) = max_size else {
+            let result = Vec::with_capacity(0);
+            return result;
+        };
+        let mut result: Vec<RemovedBySizeLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let size = package.package_record.size?;
+                if size > max_size {
+                    Some(RemovedBySizeLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        size,
+                        max_size,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages which lack the integrity hashes required by policy. `require_sha256`
+    /// demands a sha256 digest, `require_any_digest` demands either a sha256 or md5 digest.
+    pub fn apply_checksum_policy(
+        &mut self,
+        require_sha256: bool,
+        require_any_digest: bool,
+    ) -> Vec<RemovedByChecksumPolicyLog<'a>> {
+        if !(require_sha256 || require_any_digest) {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedByChecksumPolicyLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let record = package.package_record;
+                let missing = if require_sha256 && record.sha256.is_none() {
+                    "sha256"
+                } else if require_any_digest && record.sha256.is_none() && record.md5.is_none() {
+                    "sha256 or md5"
+                } else {
+                    return None;
+                };
+                Some(RemovedByChecksumPolicyLog {
+                    filename: package.filename,
+                    package_name: record.name.as_source(),
+                    missing,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove builds made for a CUDA version other than `cuda_version`, as indicated by their
+    /// depends on CUDA packages or a `cuda<version>` fragment in their build string. Builds which
+    /// don't involve CUDA are kept.
+    pub fn apply_cuda_version(
+        &mut self,
+        cuda_version: Option<&'a Version>,
+    ) -> Vec<RemovedByCudaVersionLog<'a>> {
+        let Some(cuda_version) = cuda_version else {
+            let result = Vec::with_capacity(0);
+            return result;
+        };
+        let pattern = regex::Regex::new(r"cuda(\d+)").unwrap();
+        let cuda_version_str = cuda_version.to_string();
+        let mut parts = cuda_version_str.split('.');
+        let major = parts.next().unwrap_or_default();
+        let minor = parts.next();
+        let mut result: Vec<RemovedByCudaVersionLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let record = package.package_record;
+                let conflicting_depend = record.depends.iter().find(|depend| {
+                    self.get_dependency(depend)
+                        .is_some_and(|(dependency_name, dependency)| {
+                            CUDA_PACKAGES.contains(&dependency_name)
+                                && dependency
+                                    .matchspec
+                                    .version
+                                    .as_ref()
+                                    .is_some_and(|spec| !spec.matches(cuda_version))
+                        })
+                });
+                let constraint = if let Some(depend) = conflicting_depend {
+                    depend.as_str()
+                } else {
+                    let captures = pattern.captures(&record.build)?;
+                    if cuda_build_string_compatible(&captures[1], major, minor) {
+                        return None;
+                    }
+                    record.build.as_str()
+                };
+                Some(RemovedByCudaVersionLog {
+                    filename: package.filename,
+                    package_name: record.name.as_source(),
+                    constraint,
+                    cuda_version,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove every BLAS implementation other than `blas_impl`: the implementation packages
+    /// themselves, blas mutex builds which select them, and any package tracking their
+    /// `blas_<implementation>` feature. Packages built against the removed implementations are
+    /// left for the unresolveable analysis to remove.
+    pub fn apply_blas_policy(
+        &mut self,
+        blas_impl: Option<&str>,
+    ) -> Vec<RemovedByBlasPolicyLog<'a>> {
+        let Some(blas_impl) = blas_impl else {
+            let result = Vec::with_capacity(0);
+            return result;
+        };
+        let banned: Vec<(&'static str, &'static [&'static str])> = BLAS_IMPLEMENTATIONS
+            .iter()
+            .copied()
+            .filter(|(implementation, _)| *implementation != blas_impl)
+            .collect();
+        let mut result: Vec<RemovedByBlasPolicyLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let record = package.package_record;
+                let package_name = record.name.as_source();
+                let is_mutex = BLAS_MUTEX_PACKAGES.contains(&package_name);
+                let implementation = banned.iter().find_map(|(implementation, providers)| {
+                    let selected = providers.contains(&package_name)
+                        || (is_mutex
+                            && (record.build == *implementation
+                                || record
+                                    .build
+                                    .strip_suffix(implementation)
+                                    .is_some_and(|prefix| prefix.ends_with('_'))))
+                        || record
+                            .track_features
+                            .iter()
+                            .any(|feature| feature.strip_prefix("blas_") == Some(implementation));
+                    selected.then_some(*implementation)
+                })?;
+                Some(RemovedByBlasPolicyLog {
+                    filename: package.filename,
+                    package_name,
+                    implementation,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove the builds of mutex packages, i.e. `_openmp_mutex`, whose build string and track
+    /// features all fail to match the variant selected for the mutex. Mutexes with no matching
+    /// build are left alone, since removing every build would take everything depending on them.
+    pub fn apply_mutex_selection(
+        &mut self,
+        mutex: &'a HashMap<String, glob::Pattern>,
+    ) -> Vec<RemovedByMutexLog<'a>> {
+        let mut result = Vec::new();
+        for (package_name, selection) in mutex {
+            let selected = |record: &PackageRecord| {
+                selection.matches(&record.build)
+                    || record
+                        .track_features
+                        .iter()
+                        .any(|feature| selection.matches(feature))
+            };
+            let remaining: Vec<&PackageMetadata<'a>> = self
+                .mkrange(package_name)
+                .filter(|index| !self.removed[*index])
+                .map(|index| &self.package_metadatas[index])
+                .collect();
+            let Some(winner) = remaining
+                .iter()
+                .find(|package| selected(package.package_record))
+            else {
+                if !remaining.is_empty() {
+                    warn!(
+                        "mutex {package_name} ignored: no build matches {}",
+                        selection.as_str()
+                    );
+                }
+                continue;
+            };
+            result.extend(
+                remaining
+                    .iter()
+                    .filter(|package| !selected(package.package_record))
+                    .map(|package| RemovedByMutexLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        build: &package.package_record.build,
+                        winner: &winner.package_record.build,
+                        selection: selection.as_str(),
+                    }),
+            );
+        }
+        self.mark_removed(&mut result);
         result
     }
 
-    fn evaluate(
-        &self,
-        dependency_key: DependencyKey<'a>,
-        dependency: &PackageDependency<'a>,
-    ) -> Option<Evaluation<'a>> {
-        let (candidates_start, candidates_end_offset) = {
-            if let Some(result) = self.package_name_to_providers.get(dependency_key.name) {
-                *result
-            } else {
-                (PkgIdx { index: u32::MAX }, PkgIdxOffset { offset: 0 })
-            }
-        };
-        // Does this dependency have the same solution as before?
-        let last_successful_resolution = dependency.last_successful_resolution;
-        if let Some(offset) = last_successful_resolution {
-            let last_solution_index = candidates_start.index() + offset.offset();
-            if !self.removed[last_solution_index] {
-                return None; // Yes, it does.
-            }
+    /// Remove every build of a pinned package except the pinned artifact. A package whose pinned
+    /// artifact is not in this subdir is left alone, since it is likely pinned for another
+    /// architecture.
+    pub fn apply_exact_pins(
+        &mut self,
+        pin_exact: &'a HashMap<String, ExactPin>,
+    ) -> Vec<RemovedByExactPinLog<'a>> {
+        let mut result = Vec::new();
+        for (package_name, pin) in pin_exact {
+            let is_pinned =
+                |package: &PackageMetadata| pin.matches(package.filename, package.package_record);
+            if !self
+                .mkrange(package_name)
+                .any(|index| is_pinned(&self.package_metadatas[index]))
+            {
+                continue;
+            }
+            result.extend(
+                self.mkrange(package_name)
+                    .filter(|index| !self.removed[*index])
+                    .map(|index| &self.package_metadatas[index])
+                    .filter(|package| !is_pinned(package))
+                    .map(|package| RemovedByExactPinLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        pin,
+                    }),
+            );
+        }
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove builds of the other compute flavor than `preferred` where a package version has
+    /// builds of both flavors. Versions with builds of only one flavor are left alone, as are
+    /// builds of neither flavor.
+    pub fn apply_compute_flavor(
+        &mut self,
+        preferred: Option<ComputeFlavor>,
+    ) -> Vec<RemovedByComputeFlavorLog<'a>> {
+        let mut result = Vec::new();
+        let Some(preferred) = preferred else {
+            return result;
+        };
+        for (_, packages) in &self.package_metadatas[..]
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !self.removed[*index])
+            .map(|(_, pkg)| pkg)
+            .chunk_by(|pkg| {
+                let r = &pkg.package_record;
+                (r.name.as_source(), &r.version)
+            })
+        {
+            let flavored: Vec<(&PackageMetadata, ComputeFlavor)> = packages
+                .filter_map(|pkg| Some((pkg, compute_flavor(pkg.package_record)?)))
+                .collect();
+            let Some(superseding) = flavored
+                .iter()
+                .filter(|(_, flavor)| *flavor == preferred)
+                .map(|(pkg, _)| pkg)
+                .max_by_key(|pkg| pkg.package_record.build_number)
+            else {
+                continue;
+            };
+            for (pkg, flavor) in &flavored {
+                if *flavor != preferred {
+                    result.push(RemovedByComputeFlavorLog {
+                        filename: pkg.filename,
+                        package_name: pkg.package_record.name.as_source(),
+                        preferred: preferred.as_str(),
+                        superseded_by: superseding.filename,
+                    });
+                }
+            }
+        }
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove architecture-specific builds which target a python minor version other than
+    /// `python_versions`, as indicated by their `python`/`python_abi` depends or a `py311`-style
+    /// build string fragment. Noarch packages are never removed by this rule.
+    pub fn apply_python_versions(
+        &mut self,
+        python_versions: &[Version],
+    ) -> Vec<RemovedByPythonVersionLog<'a>> {
+        if python_versions.is_empty() {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        // A depends entry targets a selected python if it admits any release of that minor.
+        let candidates: Vec<Version> = python_versions
+            .iter()
+            .filter_map(Version::as_major_minor)
+            .flat_map(|(major, minor)| [format!("{major}.{minor}"), format!("{major}.{minor}.99")])
+            .map(|version| Version::from_str(&version).unwrap())
+            .collect();
+        let build_fragments: Vec<String> = python_versions
+            .iter()
+            .filter_map(Version::as_major_minor)
+            .map(|(major, minor)| format!("{major}{minor}"))
+            .collect();
+        let pattern = regex::Regex::new(r"(?:^|[^a-z])py([23]\d{1,2})").unwrap();
+        let mut result: Vec<RemovedByPythonVersionLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let record = package.package_record;
+                if !record.noarch.is_none() {
+                    return None;
+                }
+                let python_depends: Vec<(&String, &NamelessMatchSpec)> = record
+                    .depends
+                    .iter()
+                    .filter_map(|depend| {
+                        let (dependency_name, dependency) = self.get_dependency(depend)?;
+                        if dependency_name == "python" || dependency_name == "python_abi" {
+                            Some((depend, dependency.matchspec))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                let target = if python_depends.is_empty() {
+                    let captures = pattern.captures(&record.build)?;
+                    if build_fragments
+                        .iter()
+                        .any(|fragment| *fragment == captures[1])
+                    {
+                        return None;
+                    }
+                    record.build.as_str()
+                } else {
+                    let (depend, _) = python_depends.iter().find(|(_, matchspec)| {
+                        matchspec.version.as_ref().is_some_and(|spec| {
+                            !candidates.iter().any(|candidate| spec.matches(candidate))
+                        })
+                    })?;
+                    depend.as_str()
+                };
+                Some(RemovedByPythonVersionLog {
+                    filename: package.filename,
+                    package_name: record.name.as_source(),
+                    target,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove `PyPy` and every build made for it: packages named `pypy`/`pypy3.*`, builds whose
+    /// build string mentions pypy, and builds whose `python`/`python_abi` depends select a pypy
+    /// build.
+    pub fn apply_pypy_ban(&mut self, ban_pypy: bool) -> Vec<RemovedByPypyPolicyLog<'a>> {
+        if !ban_pypy {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedByPypyPolicyLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let record = package.package_record;
+                let package_name = record.name.as_source();
+                let evidence = if package_name == "pypy" || package_name.starts_with("pypy3.") {
+                    package_name
+                } else if record.build.contains("pypy") {
+                    record.build.as_str()
+                } else {
+                    record
+                        .depends
+                        .iter()
+                        .find(|depend| {
+                            let (dependency_name, dependency_spec) =
+                                dependsstr_to_name_and_spec(depend);
+                            (dependency_name == "python_abi" || dependency_name == "python")
+                                && dependency_spec.contains("_pypy")
+                        })?
+                        .as_str()
+                };
+                Some(RemovedByPypyPolicyLog {
+                    filename: package.filename,
+                    package_name,
+                    evidence,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove the `python_abi` builds for python minor versions of which no `python` build
+    /// remains, and the `python` builds for minor versions of which no `python_abi` build
+    /// remains, in one pass rather than leaving them to the unresolveable analysis. `CPython` and
+    /// `PyPy` are considered separately, and an implementation which the channel has no builds of
+    /// for the other package at all is left alone.
+    pub fn apply_python_abi_coupling(&mut self) -> Vec<RemovedByPythonAbiLog<'a>> {
+        const COUPLED: [(&str, &str); 2] = [("python", "python_abi"), ("python_abi", "python")];
+        // Per package and implementation: whether the channel has any builds, and the minor
+        // versions which remain.
+        let mut minors: HashMap<(&str, &str), HashSet<Version>> = HashMap::new();
+        for (package_name, _) in COUPLED {
+            for index in self.mkrange(package_name) {
+                let record = self.package_metadatas[index].package_record;
+                let remaining = minors
+                    .entry((package_name, python_implementation(record)))
+                    .or_default();
+                if !self.removed[index] {
+                    remaining.insert(minor_version(&record.version));
+                }
+            }
+        }
+        let mut result = Vec::new();
+        for (package_name, counterpart) in COUPLED {
+            for index in self.mkrange(package_name) {
+                if self.removed[index] {
+                    continue;
+                }
+                let package = &self.package_metadatas[index];
+                let implementation = python_implementation(package.package_record);
+                let Some(counterpart_minors) = minors.get(&(counterpart, implementation)) else {
+                    continue;
+                };
+                let minor = minor_version(&package.package_record.version);
+                if !counterpart_minors.contains(&minor) {
+                    result.push(RemovedByPythonAbiLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        counterpart,
+                        implementation,
+                        minor,
+                    });
+                }
+            }
+        }
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages whose build string matches any of the `patterns`. A pattern which is
+    /// paired with a package name only applies to packages of that name.
+    pub fn apply_build_string_ban(
+        &mut self,
+        patterns: &'a [(Option<String>, regex::Regex)],
+    ) -> Vec<RemovedByBuildStringLog<'a>> {
+        if patterns.is_empty() {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedByBuildStringLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let record = package.package_record;
+                let package_name = record.name.as_source();
+                let (_, pattern) = patterns.iter().find(|(scope, pattern)| {
+                    scope.as_ref().map_or(true, |scope| scope == package_name)
+                        && pattern.is_match(&record.build)
+                })?;
+                Some(RemovedByBuildStringLog {
+                    filename: package.filename,
+                    package_name,
+                    pattern: pattern.as_str(),
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages whose weak or strong run exports would inject a banned pin into the
+    /// packages built against them. A run export matches a banned matchspec if it names the same
+    /// package, and the version it pins (its lower bound) matches the banned version. A banned
+    /// matchspec without a version matches every run export of that package. Does nothing if no
+    /// run exports are available.
+    pub fn apply_run_exports_ban(
+        &mut self,
+        run_exports: Option<&HashMap<&'a str, &'a RunExportsJson>>,
+        banned: &'a [String],
+    ) -> Vec<RemovedByRunExportLog<'a>> {
+        if banned.is_empty() {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let Some(run_exports) = run_exports else {
+            warn!("warning: no run_exports.json available, run exports ban skipped");
+            let result = Vec::with_capacity(0);
+            return result;
+        };
+        let banned: Vec<(&'a str, String, NamelessMatchSpec)> = banned
+            .iter()
+            .filter_map(|matchspec| {
+                let (package_name, spec) =
+                    parse_named_matchspec(matchspec, ParseStrictness::Lenient).ok()?;
+                Some((matchspec.as_str(), package_name, spec))
+            })
+            .collect();
+        let mut result: Vec<RemovedByRunExportLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .filter_map(|package| {
+                let exports = run_exports.get(package.filename)?;
+                let (run_export, banned_matchspec) = exports
+                    .weak
+                    .iter()
+                    .chain(&exports.strong)
+                    .find_map(|run_export| {
+                        let (package_name, spec) =
+                            parse_named_matchspec(run_export, ParseStrictness::Lenient).ok()?;
+                        let (banned_matchspec, _, _) =
+                            banned.iter().find(|(_, banned_name, banned_spec)| {
+                                *banned_name == package_name
+                                    && banned_spec.version.as_ref().map_or(true, |banned| {
+                                        spec.version
+                                            .as_ref()
+                                            .and_then(pinned_version)
+                                            .is_some_and(|pin| banned.matches(pin))
+                                    })
+                            })?;
+                        Some((run_export.as_str(), *banned_matchspec))
+                    })?;
+                Some(RemovedByRunExportLog {
+                    filename: package.filename,
+                    package_name: package.package_record.name.as_source(),
+                    run_export,
+                    banned_matchspec,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove every package which depends on any of `package_names`, regardless of whether the
+    /// dependency could otherwise be satisfied.
+    pub fn apply_depender_ban(
+        &mut self,
+        package_names: &[String],
+    ) -> Vec<RemovedByDependerBanLog<'a>> {
+        let mut result = Vec::new();
+        for package_name in package_names {
+            let Some((dependency_package_name, matchspec_map)) = self
+                .package_dependencies
+                .get_key_value(package_name.as_str())
+            else {
+                continue;
+            };
+            for dependency in matchspec_map.values() {
+                for index in &dependency.dependers {
+                    let package = &self.package_metadatas[index.index()];
+                    result.push(RemovedByDependerBanLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        dependency_package_name,
+                    });
+                }
+            }
+        }
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove `.tar.bz2` packages for which an equivalent `.conda` package (same name, version,
+    /// build and build number) remains.
+    pub fn apply_format_dedupe(&mut self, enabled: bool) -> Vec<RemovedAsDuplicateFormatLog<'a>> {
+        if !enabled {
+            let result = Vec::with_capacity(0);
+            return result;
+        }
+        let mut result: Vec<RemovedAsDuplicateFormatLog<'a>> = self
+            .package_metadatas
+            .par_iter()
+            .enumerate()
+            .filter_map(|(index, package)| {
+                if self.removed[index] {
+                    return None;
+                }
+                let stem = package.filename.strip_suffix(".tar.bz2")?;
+                let twin_index = self
+                    .filename_to_metadata
+                    .get(format!("{stem}.conda").as_str())?;
+                let twin = &self.package_metadatas[twin_index.index()];
+                let (record, twin_record) = (package.package_record, twin.package_record);
+                if self.removed[twin_index.index()]
+                    || record.name != twin_record.name
+                    || record.version != twin_record.version
+                    || record.build != twin_record.build
+                    || record.build_number != twin_record.build_number
+                {
+                    return None;
+                }
+                Some(RemovedAsDuplicateFormatLog {
+                    filename: package.filename,
+                    package_name: record.name.as_source(),
+                    conda_filename: twin.filename,
+                })
+            })
+            .collect();
+        self.mark_removed(&mut result);
+        for res in &result {
+            self.duplicates
+                .set(self.filename_to_metadata[res.filename].index(), true);
+        }
+        result
+    }
+
+    pub fn apply_incompatible_architecture(
+        &mut self,
+        architecture: &'a str,
+    ) -> Vec<RemovedIncompatibleArchitectureLog<'a>> {
+        let mut result: Vec<RemovedIncompatibleArchitectureLog<'a>> =
+            (*get_virtual_package_bans(architecture, self.enforce_constrains))
+                .into_par_iter()
+                .copied()
+                .filter_map(|depending_on| {
+                    self.package_dependencies
+                        .get(depending_on)
+                        .map(|d| (depending_on, d))
+                })
+                .flat_map(|(dependency_name, dependencies)| {
+                    dependencies
+                        .par_iter()
+                        .flat_map(|(_, dependency)| {
+                            dependency
+                                .dependers
+                                .par_iter()
+                                .chain(dependency.weak_dependers.par_iter())
+                        })
+                        .map(|pkgindex| {
+                            let package = &self.package_metadatas[pkgindex.index()];
+                            RemovedIncompatibleArchitectureLog {
+                                filename: package.filename,
+                                package_name: package.package_record.name.as_source(),
+                                virtual_package: dependency_name,
+                                actual_architecture: architecture,
+                            }
+                        })
+                })
+                .collect();
+        // Mark the packages as removed
+        self.mark_removed(&mut result);
+        // Mark the dependencies as unresolveable
+        for virtual_package_name in get_virtual_package_bans(architecture, self.enforce_constrains)
+        {
+            if let Some(matchspec_map) = self.package_dependencies.get_mut(virtual_package_name) {
+                for dependency in matchspec_map.values_mut() {
+                    dependency.unsatisfiable = true;
+                }
+            }
+        }
+        result
+    }
+
+    pub fn apply_user_matchspecs(
+        &mut self,
+        user_matchspecs: &HashMap<String, Vec<NamelessMatchSpec>>,
+    ) -> Vec<RemovedByUserLog<'a>> {
+        let mut result = Vec::new();
+        for (package_name, specs) in user_matchspecs {
+            let spec_arg: Vec<&NamelessMatchSpec> = specs.iter().collect();
+            result.append(&mut (self.apply_matchspecs(package_name, &spec_arg)));
+        }
+        result
+    }
+
+    /// Remove packages whose version is below the floor configured for their package name.
+    pub fn apply_minimum_versions(
+        &mut self,
+        minimum_versions: &'a HashMap<String, NamelessMatchSpec>,
+    ) -> Vec<RemovedByMinimumVersionLog<'a>> {
+        let mut result = Vec::new();
+        for (package_name, minimum_version) in minimum_versions {
+            if !self
+                .package_name_to_providers
+                .contains_key(package_name.as_str())
+            {
+                warn!("minimum version for {package_name} ignored: no such package");
+                continue;
+            }
+            for item in self.apply_matchspecs(package_name, &[minimum_version]) {
+                result.push(RemovedByMinimumVersionLog {
+                    filename: item.filename,
+                    package_name: item.package_name,
+                    minimum_version,
+                });
+            }
+        }
+        result
+    }
+
+    fn apply_matchspecs(
+        &mut self,
+        package_name: &str,
+        specs: &[&NamelessMatchSpec],
+    ) -> Vec<RemovedByUserLog<'a>> {
+        let mut result = Vec::new();
+        if let Some((start, offset)) = self.package_name_to_providers.get(package_name) {
+            let matchspecs = specs
+                .iter()
+                .map(|spec| format_user_matchspec(spec))
+                .join(" or ");
+            for index in start.range_to(*offset) {
+                if self.removed[index] {
+                    continue;
+                }
+                let md = &mut self.package_metadatas[index];
+                let mut passes = false;
+
+                // Determine if this package should no longer be here
+                for spec in specs {
+                    if spec.matches(md.package_record) {
+                        passes = true;
+                        break;
+                    }
+                }
+
+                if !passes && !self.locked[index] {
+                    self.removed.set(index, true);
+                    result.push(RemovedByUserLog {
+                        package_name: md.package_record.name.as_source(),
+                        filename: md.filename,
+                        matchspecs: matchspecs.clone(),
+                    });
+                }
+            }
+        }
+        result
+    }
+
+    /// Look up the dependency for one of a package's depends. Depends which could not be parsed
+    /// have no dependency.
+    fn get_dependency(&self, depend: &'a str) -> Option<(&'a str, &PackageDependency<'a>)> {
+        let (dependency_name, dependency_spec) = dependsstr_to_name_and_spec(depend);
+        let dependency = self
+            .package_dependencies
+            .get(dependency_name)?
+            .get(dependency_spec)?;
+        Some((dependency_name, dependency))
+    }
+
+    fn get_dependencies(
+        &self,
+        index: usize,
+    ) -> impl Iterator<Item = (&'a str, &PackageDependency<'a>)> {
+        self.package_metadatas[index]
+            .package_record
+            .depends
+            .iter()
+            .filter_map(|depend| self.get_dependency(depend))
+    }
+
+    /// The package names of `user_matchspecs` whose packages the matchspecs all removed. Names
+    /// which no package has at all are left to [`unknown_user_spec_names`], which looks at every
+    /// subdir.
+    #[must_use]
+    pub fn unmatched_user_matchspecs<'b>(
+        &self,
+        user_matchspecs: &'b HashMap<String, Vec<NamelessMatchSpec>>,
+    ) -> Vec<UnmatchedUserSpec<'a, 'b>> {
+        let mut result: Vec<UnmatchedUserSpec<'a, 'b>> = user_matchspecs
+            .keys()
+            .filter(|package_name| {
+                self.package_name_to_providers
+                    .contains_key(package_name.as_str())
+                    && self.mkrange(package_name).all(|index| self.removed[index])
+            })
+            .map(|package_name| UnmatchedUserSpec::NoneRemaining { package_name })
+            .collect();
+        result.sort_unstable_by_key(|unmatched| match unmatched {
+            UnmatchedUserSpec::UnknownName { package_name, .. }
+            | UnmatchedUserSpec::NoneRemaining { package_name } => *package_name,
+        });
+        result
+    }
+
+    /// Whether any remaining package of this name matches the spec.
+    #[must_use]
+    pub fn matches_any(&self, package_name: &str, spec: &NamelessMatchSpec) -> bool {
+        self.mkrange(package_name).any(|index| {
+            !self.removed[index] && spec.matches(self.package_metadatas[index].package_record)
+        })
+    }
+
+    /// The matchspecs which the remaining variants of `package_name` (only those matching
+    /// `spec`, if given) have on the packages every one of those variants depends on.
+    #[allow(clippy::mutable_key_type)]
+    fn relevant_matchspecs(
+        &self,
+        package_name: &str,
+        spec: Option<&NamelessMatchSpec>,
+    ) -> HashMap<&'a str, HashSet<&'a NamelessMatchSpec>> {
+        let mut range = self.mkrange(package_name).filter(|index| {
+            !self.removed[*index]
+                && spec.map_or(true, |spec| {
+                    spec.matches(self.package_metadatas[*index].package_record)
+                })
+        });
+
+        let mut relevant_packages = HashSet::new();
+        let mut relevant_matchspecs = HashMap::new();
+        let Some(index) = range.next() else {
+            return relevant_matchspecs;
+        };
+        for (name, dependency) in self.get_dependencies(index) {
+            relevant_packages.insert(name);
+            relevant_matchspecs.insert(name, HashSet::from([dependency.matchspec]));
+        }
+
+        for index in range {
+            let mut local_relevant_packages = HashSet::new();
+            for (name, dependency) in self.get_dependencies(index) {
+                if let Some(specs) = relevant_matchspecs.get_mut(name) {
+                    specs.insert(dependency.matchspec);
+                    local_relevant_packages.insert(name);
+                }
+            }
+            relevant_packages = &relevant_packages & &local_relevant_packages;
+            if relevant_packages.is_empty() {
+                break;
+            }
+        }
+        relevant_matchspecs.retain(|name, _| relevant_packages.contains(name));
+        relevant_matchspecs
+    }
+
+    /// Remove packages which are not compatible with any remaining variant of `package_name`,
+    /// or only with those variants matching `spec` if one is given. The packages every variant
+    /// depends on are then considered in the same way, up to `max_depth` dependencies away.
+    #[allow(clippy::mutable_key_type)]
+    pub fn apply_must_compatible(
+        &mut self,
+        package_name: &'a str,
+        spec: Option<&NamelessMatchSpec>,
+        max_depth: usize,
+    ) -> Vec<RemovedBecauseIncompatibleLog<'a>> {
+        let mut result = Vec::new();
+        // The number of remaining variants of each package name when its relevant matchspecs
+        // were last computed. Variants are only ever removed, so while that number is unchanged
+        // computing and applying them again would remove nothing. This also ends dependency
+        // cycles. The queue is breadth-first, so every name is first considered at the
+        // shallowest depth it is reachable from, and never cut off by a longer path.
+        let mut considered: HashMap<&'a str, usize> = HashMap::new();
+        let mut depth_capped = false;
+        let mut queue = VecDeque::from([(package_name, spec, 0)]);
+        while let Some((current, spec, depth)) = queue.pop_front() {
+            let remaining = self
+                .mkrange(current)
+                .filter(|index| !self.removed[*index])
+                .count();
+            if spec.is_none() && considered.insert(current, remaining) == Some(remaining) {
+                continue;
+            }
+            let relevant_matchspecs = self.relevant_matchspecs(current, spec);
+            for (package, specs) in &relevant_matchspecs {
+                for item in self.apply_matchspecs(
+                    package,
+                    &specs.iter().copied().collect::<Vec<&NamelessMatchSpec>>(),
+                ) {
+                    result.push(RemovedBecauseIncompatibleLog {
+                        package_name: item.package_name,
+                        filename: item.filename,
+                        incompatible_with: current,
+                    });
+                }
+            }
+            if depth == max_depth {
+                depth_capped |= !relevant_matchspecs.is_empty();
+                continue;
+            }
+            queue.extend(
+                relevant_matchspecs
+                    .into_keys()
+                    .map(|package| (package, None, depth + 1)),
+            );
+        }
+        if depth_capped {
+            warn!(
+                "warning: compatibility with {package_name} only checked {max_depth} dependencies deep"
+            );
+        }
+        result
+    }
+
+    /// Remove packages which depend on a pinned package with a matchspec no version allowed by
+    /// the pin can satisfy, i.e. `openssl <3` when `openssl >=3` is pinned.
+    pub fn apply_pin_conflicts(
+        &mut self,
+        pins: &'a HashMap<String, NamelessMatchSpec>,
+    ) -> Vec<RemovedByPinConflictLog<'a>> {
+        let mut result = Vec::new();
+        for (package_name, pin) in pins {
+            let (Some(dependencies), Some(pin_version)) = (
+                self.package_dependencies.get(package_name.as_str()),
+                &pin.version,
+            ) else {
+                continue;
+            };
+            for dependency in dependencies.values() {
+                let Some(version) = &dependency.matchspec.version else {
+                    continue;
+                };
+                if !version_specs_disjoint(version, pin_version) {
+                    continue;
+                }
+                for index in &dependency.dependers {
+                    if self.removed[index.index()] {
+                        continue;
+                    }
+                    let package = &self.package_metadatas[index.index()];
+                    result.push(RemovedByPinConflictLog {
+                        filename: package.filename,
+                        package_name: package.package_record.name.as_source(),
+                        pinned_package_name: package_name,
+                        matchspec: dependency.matchspec,
+                        pin,
+                    });
+                }
+            }
+        }
+        // A package may conflict with several pins.
+        result.sort_unstable_by_key(|log| log.filename);
+        result.dedup_by_key(|log| log.filename);
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// Remove packages which are not compatible with all of the `anchors` at the same time. Each
+    /// anchor is a package name and optionally a spec its variants must match. A package which
+    /// several anchors depend on must satisfy the dependencies of every one of them, and the
+    /// packages depended on are then considered jointly in the same way.
+    #[allow(clippy::mutable_key_type)]
+    pub fn apply_must_compatible_all(
+        &mut self,
+        anchors: &[(&'a str, Option<&NamelessMatchSpec>)],
+        incompatible_with: &'a str,
+    ) -> Vec<RemovedBecauseIncompatibleLog<'a>> {
+        let mut result = Vec::new();
+        let mut visited: HashSet<&str> = HashSet::new();
+        let mut anchors = anchors.to_vec();
+        while !anchors.is_empty() {
+            visited.extend(anchors.iter().map(|(package_name, _)| *package_name));
+            let mut joint_matchspecs: HashMap<&'a str, Vec<HashSet<&'a NamelessMatchSpec>>> =
+                HashMap::new();
+            for (package_name, spec) in &anchors {
+                for (name, specs) in self.relevant_matchspecs(package_name, *spec) {
+                    joint_matchspecs.entry(name).or_default().push(specs);
+                }
+            }
+            for (package, all_specs) in &joint_matchspecs {
+                for specs in all_specs {
+                    for item in self.apply_matchspecs(
+                        package,
+                        &specs.iter().copied().collect::<Vec<&NamelessMatchSpec>>(),
+                    ) {
+                        result.push(RemovedBecauseIncompatibleLog {
+                            package_name: item.package_name,
+                            filename: item.filename,
+                            incompatible_with,
+                        });
+                    }
+                }
+            }
+            anchors = joint_matchspecs
+                .into_keys()
+                .filter(|package| !visited.contains(package))
+                .map(|package| (package, None))
+                .collect();
+        }
+        result
+    }
+
+    /// Remove every package which is not reachable from the `roots` through the depends of the
+    /// remaining packages. A dependency reaches every remaining package which satisfies it, since
+    /// any of them could be chosen by a solver. Virtual packages are ignored.
+    pub fn apply_closure(
+        &mut self,
+        roots: Option<&'a HashMap<String, Vec<NamelessMatchSpec>>>,
+    ) -> Vec<RemovedOutsideClosureLog<'a>> {
+        let Some(roots) = roots else {
+            let result = Vec::with_capacity(0);
+            return result;
+        };
+        let mut reached = BitVec::<usize>::repeat(false, self.package_metadatas.len());
+        let mut queue = Vec::new();
+        for (package_name, specs) in roots {
+            for index in self.mkrange(package_name) {
+                let record = self.package_metadatas[index].package_record;
+                if !self.removed[index]
+                    && (specs.is_empty() || specs.iter().any(|spec| spec.matches(record)))
+                {
+                    reached.set(index, true);
+                    queue.push(index);
+                }
+            }
+        }
+        // Many packages share depends, and a dependency only needs to be expanded once.
+        let mut expanded: HashSet<(&'a str, &'a str)> = HashSet::new();
+        while let Some(index) = queue.pop() {
+            for depend in &self.package_metadatas[index].package_record.depends {
+                let (dependency_name, dependency_spec) = dependsstr_to_name_and_spec(depend);
+                if dependency_name.starts_with("__")
+                    || !expanded.insert((dependency_name, dependency_spec))
+                {
+                    continue;
+                }
+                let Some((_, dependency)) = self.get_dependency(depend) else {
+                    continue;
+                };
+                let matchspec = dependency.matchspec;
+                for candidate in self.mkrange(dependency_name) {
+                    if !self.removed[candidate]
+                        && !reached[candidate]
+                        && matchspec.matches(self.package_metadatas[candidate].package_record)
+                    {
+                        reached.set(candidate, true);
+                        queue.push(candidate);
+                    }
+                }
+            }
+        }
+        let mut result = Vec::new();
+        for (index, package) in self.package_metadatas.iter().enumerate() {
+            if !self.removed[index] && !reached[index] {
+                result.push(RemovedOutsideClosureLog {
+                    filename: package.filename,
+                    package_name: package.package_record.name.as_source(),
+                    roots,
+                });
+            }
+        }
+        self.mark_removed(&mut result);
+        result
+    }
+
+    /// The filenames of the remaining packages which belong in `current_repodata.json`, as
+    /// conda-index selects them: every build of the newest version of each name, and then, for
+    /// each depend none of those satisfy, every build of the newest version which does.
+    #[must_use]
+    pub fn current_filenames(&self) -> HashSet<&'a str> {
+        let mut current = BitVec::<usize>::repeat(false, self.package_metadatas.len());
+        let mut queue = Vec::new();
+        for package_name in self.package_name_to_providers.keys() {
+            queue.extend(self.newest_builds(self.mkrange(package_name), |_| true));
+        }
+        for &index in &queue {
+            current.set(index, true);
+        }
+        let mut expanded: HashSet<(&'a str, &'a str)> = HashSet::new();
+        while let Some(index) = queue.pop() {
+            for depend in &self.package_metadatas[index].package_record.depends {
+                let (dependency_name, dependency_spec) = dependsstr_to_name_and_spec(depend);
+                if dependency_name.starts_with("__")
+                    || !expanded.insert((dependency_name, dependency_spec))
+                {
+                    continue;
+                }
+                let Some((_, dependency)) = self.get_dependency(depend) else {
+                    continue;
+                };
+                let matchspec = dependency.matchspec;
+                let range = self.mkrange(dependency_name);
+                if range.clone().any(|candidate| {
+                    current[candidate]
+                        && matchspec.matches(self.package_metadatas[candidate].package_record)
+                }) {
+                    continue;
+                }
+                for candidate in self.newest_builds(range, |record| matchspec.matches(record)) {
+                    if !current[candidate] {
+                        current.set(candidate, true);
+                        queue.push(candidate);
+                    }
+                }
+            }
+        }
+        current
+            .iter_ones()
+            .map(|index| self.package_metadatas[index].filename)
+            .collect()
+    }
+
+    /// The remaining packages of `range` which have the newest version of those `accept`ed.
+    fn newest_builds(
+        &self,
+        range: Range<usize>,
+        accept: impl Fn(&PackageRecord) -> bool,
+    ) -> Vec<usize> {
+        let candidates: Vec<usize> = range
+            .filter(|&index| {
+                !self.removed[index] && accept(self.package_metadatas[index].package_record)
+            })
+            .collect();
+        let Some(newest) = candidates
+            .iter()
+            .map(|&index| &self.package_metadatas[index].package_record.version)
+            .max()
+        else {
+            return candidates;
+        };
+        candidates
+            .iter()
+            .copied()
+            .filter(|&index| self.package_metadatas[index].package_record.version == *newest)
+            .collect()
+    }
+
+    /// Remove packages which no remaining package depends upon, unless they match one of the
+    /// `requested` matchspecs or their name matches one of the `protected` patterns. This is
+    /// repeated until no more orphans are found.
+    pub fn apply_orphan_removal(
+        &mut self,
+        enabled: bool,
+        requested: &HashMap<String, Vec<NamelessMatchSpec>>,
+        protected: &[glob::Pattern],
+    ) -> Vec<RemovedOrphanLog<'a>> {
+        let mut result = Vec::new();
+        if !enabled {
+            return result;
+        }
+        for _ in 0..ORPHAN_ROUND_LIMIT {
+            let mut depended_upon = BitVec::<usize>::repeat(false, self.package_metadatas.len());
+            for (dependency_name, matchspec_map) in &self.package_dependencies {
+                let range = self.mkrange(dependency_name);
+                for dependency in matchspec_map.values() {
+                    if dependency
+                        .dependers
+                        .iter()
+                        .all(|idx| self.removed[idx.index()])
+                    {
+                        continue;
+                    }
+                    for index in range.clone() {
+                        if !depended_upon[index]
+                            && dependency
+                                .matchspec
+                                .matches(self.package_metadatas[index].package_record)
+                        {
+                            depended_upon.set(index, true);
+                        }
+                    }
+                }
+            }
+            let mut orphans: Vec<RemovedOrphanLog<'a>> = self
+                .package_metadatas
+                .iter()
+                .enumerate()
+                .filter(|(index, package)| {
+                    let record = package.package_record;
+                    let package_name = record.name.as_source();
+                    !self.removed[*index]
+                        && !depended_upon[*index]
+                        && !requested
+                            .get(package_name)
+                            .is_some_and(|specs| specs.iter().any(|spec| spec.matches(record)))
+                        && !protected
+                            .iter()
+                            .any(|pattern| pattern.matches(package_name))
+                })
+                .map(|(_, package)| RemovedOrphanLog {
+                    filename: package.filename,
+                    package_name: package.package_record.name.as_source(),
+                })
+                .collect();
+            self.mark_removed(&mut orphans);
+            if orphans.is_empty() {
+                return result;
+            }
+            result.extend(orphans);
+        }
+        warn!("orphan removal stopped after {ORPHAN_ROUND_LIMIT} rounds");
+        result
+    }
+
+    fn mkrange(&self, package_name: &str) -> Range<usize> {
+        match self.package_name_to_providers.get(package_name) {
+            Some((start, offset)) => start.range_to(*offset),
+            None => 0..0,
+        }
+    }
+
+    pub fn find_all_unresolveables(&mut self) -> Vec<RemovedUnsatisfiableLog<'a>> {
+        self.find_unresolveables(
+            self.package_dependencies
+                .keys()
+                .copied()
+                .filter(|d| !d.starts_with("__") || self.virtual_packages.contains_key(d))
+                .collect(),
+        )
+    }
+    pub fn find_unresolveables(
+        &mut self,
+        depending_ons: Vec<&'a str>,
+    ) -> Vec<RemovedUnsatisfiableLog<'a>> {
+        let updates: Vec<Evaluation> = depending_ons
+            .into_par_iter()
+            .filter_map(|depending_on| {
+                self.package_dependencies
+                    .get(depending_on)
+                    .map(|d| (depending_on, d))
+            })
+            .flat_map(|(dependency_name, dependencies)| {
+                dependencies
+                    .par_iter()
+                    .filter_map(|(matchspec_str, dependency)| {
+                        if dependency.unsatisfiable {
+                            None
+                        } else {
+                            self.evaluate(
+                                DependencyKey {
+                                    name: dependency_name,
+                                    matchspec: matchspec_str,
+                                },
+                                dependency,
+                            )
+                        }
+                    })
+            })
+            .collect();
+        let mut result = Vec::with_capacity(updates.len());
+        for evaluation in updates {
+            match evaluation {
+                Evaluation::UpdateSolution(dep_key, offset) => {
+                    self.package_dependencies
+                        .get_mut(dep_key.name)
+                        .unwrap()
+                        .get_mut(dep_key.matchspec)
+                        .unwrap()
+                        .last_successful_resolution = Some(offset);
+                }
+                Evaluation::RemoveAndLog(dep_key, offset, remove_weak_dependers) => {
+                    let dependency = self
+                        .package_dependencies
+                        .get_mut(dep_key.name)
+                        .unwrap()
+                        .get_mut(dep_key.matchspec)
+                        .unwrap();
+                    dependency.unsatisfiable = true;
+                    let weak_dependers: &[PkgIdx] = if remove_weak_dependers {
+                        &dependency.weak_dependers
+                    } else {
+                        &[]
+                    };
+                    let dependers = dependency.dependers.iter().map(|index| (index, false));
+                    let weak_dependers = weak_dependers.iter().map(|index| (index, true));
+                    for (index, constraint) in dependers.chain(weak_dependers) {
+                        if self.locked[index.index()] {
+                            continue;
+                        }
+                        let package = self.package_metadatas.get_mut(index.index()).unwrap();
+                        self.removed.set(index.index(), true);
+                        result.push(RemovedUnsatisfiableLog {
+                            dependency_package_name: dep_key.name,
+                            filename: package.filename,
+                            package_name: package.package_record.name.as_source(),
+                            matchspec: dependency.matchspec,
+                            cause_filename: offset
+                                .map(|index| self.package_metadatas[index.index as usize].filename),
+                            constraint,
+                            virtual_package: self.virtual_packages.get(dep_key.name).copied(),
+                        });
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn evaluate(
+        &self,
+        dependency_key: DependencyKey<'a>,
+        dependency: &PackageDependency<'a>,
+    ) -> Option<Evaluation<'a>> {
+        if let Some(record) = self.virtual_packages.get(dependency_key.name) {
+            if dependency.matchspec.matches(*record) {
+                return None;
+            }
+            // The declared virtual package exists, so constraints on it are enforced too.
+            let remove_weak_dependers = !dependency.weak_dependers.is_empty();
+            return Some(Evaluation::RemoveAndLog(
+                dependency_key,
+                None,
+                remove_weak_dependers,
+            ));
+        }
+        if dependency_key.name.starts_with("__") {
+            // No channel provides virtual packages. Those impossible on the architecture are
+            // already unsatisfiable, and any other may be present on the user's machine.
+            return None;
+        }
+        let (candidates_start, candidates_end_offset) = {
+            if let Some(result) = self.package_name_to_providers.get(dependency_key.name) {
+                *result
+            } else if self.external_providers {
+                return None;
+            } else {
+                (PkgIdx { index: u32::MAX }, PkgIdxOffset { offset: 0 })
+            }
+        };
+        // Does this dependency have the same solution as before?
+        let last_successful_resolution = dependency.last_successful_resolution;
+        if let Some(offset) = last_successful_resolution {
+            let last_solution_index = candidates_start.index() + offset.offset();
+            if !self.removed[last_solution_index] {
+                return None; // Yes, it does.
+            }
+        }
+        // Does the dependency have a solution?
+        let new_solution_index = wrap_range_from_middle(
+            candidates_start,
+            candidates_end_offset,
+            last_successful_resolution,
+        )
+        .find(|index| {
+            !self.removed[*index]
+                && dependency
+                    .matchspec
+                    .matches(self.package_metadatas[*index].package_record)
+        });
+        if let Some(new_solution_index) = new_solution_index {
+            // Yes, there is a solution. Save the solution in
+            // case we need to return to this dependency later.
+            return Some(Evaluation::UpdateSolution(
+                dependency_key,
+                PkgIdxOffset::from_difference(candidates_start, new_solution_index),
+            ));
+        }
+
+        // There is no solution.
+        // Try to determine the reason for unresolveable.
+        let cause_of_removal_index = match last_successful_resolution {
+            // We already know what package previously satisified
+            Some(offset) => Some(candidates_start.index() + offset.offset()),
+            // We need to find the previous package that satisfied
+            None => wrap_range_from_middle(candidates_start, candidates_end_offset, None).find(
+                |index| {
+                    self.removed[*index]
+                        && !self.duplicates[*index]
+                        && dependency
+                            .matchspec
+                            .matches(self.package_metadatas[*index].package_record)
+                },
+            ),
+        };
+
+        // A constraint only excludes a package when something could still be installed
+        // alongside it, but the constraint forbids all of it.
+        let remove_weak_dependers = !dependency.weak_dependers.is_empty()
+            && wrap_range_from_middle(candidates_start, candidates_end_offset, None)
+                .any(|index| !self.removed[index]);
+
+        Some(Evaluation::RemoveAndLog(
+            dependency_key,
+            cause_of_removal_index.map(PkgIdx::from_usize),
+            remove_weak_dependers,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packagerelations::{
+        unknown_user_spec_names, MatchspecCache, PackageRelations, UnmatchedUserSpec,
+    };
+    use rattler_conda_types::{PackageName, PackageRecord, RepoData, VersionWithSource};
+    use std::iter::zip;
+    use std::str::FromStr;
+
+    fn mkpkg(name: &str, version: &str, build: &str, build_number: u64) -> PackageRecord {
+        let mut pkgrecord = PackageRecord::new(
+            PackageName::try_from(name).unwrap(),
+            VersionWithSource::from_str(version).unwrap(),
+            build.to_string(),
+        );
+        pkgrecord.build_number = build_number;
+        pkgrecord
+    }
+
+    #[test]
+    fn test_apply_build_prune_happy_path() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("arrow-cpp", "1.5.1", "asdf_h1234567_1", 1),
+            mkpkg("arrow-cpp", "1.5.1", "asdf_h1234567_2", 2),
+            mkpkg("arrow-cpp", "1.5.1", "asdf_h1234567_3", 3),
+            mkpkg("arrow-cpp", "1.5.1", "asdf_h1234567_4", 4),
+        ];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let results = pr.apply_build_prune();
+        assert!(results.len() == 3);
+    }
+
+    #[test]
+    fn test_apply_build_prune_no_remove_hashfree_builds() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("arrow-cpp", "1.5.1", "1", 1),
+            mkpkg("arrow-cpp", "1.5.1", "2", 2),
+            mkpkg("arrow-cpp", "1.5.1", "3", 3),
+            mkpkg("arrow-cpp", "1.5.1", "4", 4),
+        ];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_build_prune().is_empty());
+    }
+
+    #[test]
+    fn test_apply_license_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("readline", "8.2", "h8228510_1", 1),
+            mkpkg("readline", "8.2", "h8228510_2", 2),
+            mkpkg("zlib", "1.3.1", "h4ab18f5_1", 1),
+        ];
+        records[0].license = Some("GPL-3.0-only".to_string());
+        records[2].license = Some("Zlib".to_string());
+        let names = ["1", "2", "3"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let patterns = [glob::Pattern::new("GPL*").unwrap()];
+        let results = pr.apply_license_ban(&patterns);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "1");
+        assert_eq!(results[0].license, "GPL-3.0-only");
+    }
+
+    #[test]
+    fn test_apply_timestamp_cutoff() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("zlib", "1.2.13", "h4ab18f5_0", 0),
+            mkpkg("zlib", "1.3.1", "h4ab18f5_0", 0),
+            mkpkg("zlib", "1.3.1", "h4ab18f5_1", 1),
+        ];
+        records[0].timestamp = chrono::DateTime::from_timestamp(1_600_000_000, 0);
+        records[1].timestamp = chrono::DateTime::from_timestamp(1_700_000_000, 0);
+        let names = ["1", "2", "3"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let cutoff = chrono::DateTime::from_timestamp(1_650_000_000, 0);
+        assert!(pr.apply_timestamp_cutoff(None, true).is_empty());
+        let results = pr.apply_timestamp_cutoff(cutoff, false);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "2");
+        let results = pr.apply_timestamp_cutoff(cutoff, true);
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn test_apply_version_prune() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("zlib", "1.2.11", "h4ab18f5_0", 0),
+            mkpkg("zlib", "1.2.13", "h4ab18f5_0", 0),
+            mkpkg("zlib", "1.3.1", "h4ab18f5_0", 0),
+            mkpkg("zlib", "1.3.1", "hb9d3cd8_2", 2),
+            mkpkg("zstd", "1.5.5", "hfc55251_0", 0),
+            mkpkg("zstd", "1.5.6", "ha6fb4c9_0", 0),
+        ];
+        let names = ["1", "2", "3", "4", "5", "6"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let overrides = std::collections::HashMap::from([("zstd".to_string(), 2)]);
+        let mut results: Vec<&str> = pr
+            .apply_version_prune(Some(2), &overrides)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["1"]);
+
+        let results = pr.apply_version_prune(Some(1), &overrides);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "2");
+    }
+
+    #[test]
+    fn test_cuda_build_string_compatible() {
+        use crate::packagerelations::cuda_build_string_compatible;
+        assert!(cuda_build_string_compatible("120", "12", Some("0")));
+        assert!(cuda_build_string_compatible("12", "12", Some("2")));
+        assert!(cuda_build_string_compatible("120", "12", Some("2")));
+        assert!(!cuda_build_string_compatible("126", "12", Some("2")));
+        assert!(!cuda_build_string_compatible("118", "12", Some("0")));
+        assert!(!cuda_build_string_compatible("92", "12", None));
+    }
+
+    #[test]
+    fn test_apply_cuda_version() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("cupy", "13.0.0", "py311cuda118h1234567_0", 0),
+            mkpkg("cupy", "13.0.0", "py311cuda120h1234567_0", 0),
+            mkpkg("cupy", "13.0.0", "py311h1234567_0", 0),
+            mkpkg("jaxlib", "0.4.23", "cpu_py311h1234567_0", 0),
+        ];
+        records[0].depends = vec!["cuda-version >=11.8,<12".to_string()];
+        records[1].depends = vec!["cuda-version >=12.0,<13".to_string()];
+        records[2].depends = vec!["cudatoolkit >=11.2,<12".to_string()];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let cuda_version = rattler_conda_types::Version::from_str("12.0").unwrap();
+        let mut results: Vec<&str> = pr
+            .apply_cuda_version(Some(&cuda_version))
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["1", "3"]);
+    }
+
+    #[test]
+    fn test_apply_blas_policy() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("blas", "2.120", "mkl", 0),
+            mkpkg("blas", "2.120", "openblas", 0),
+            mkpkg("libblas", "3.9.0", "20_linux64_mkl", 20),
+            mkpkg("libblas", "3.9.0", "20_linux64_openblas", 20),
+            mkpkg("libopenblas", "0.3.25", "pthreads_h413a1c8_0", 0),
+            mkpkg("mkl", "2023.2.0", "h84fe81f_50496", 50496),
+            mkpkg("numpy", "1.26.4", "py311h64a7726_0", 0),
+        ];
+        records[0].track_features = vec!["blas_mkl".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6", "7"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mut results: Vec<&str> = pr
+            .apply_blas_policy(Some("openblas"))
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["1", "3", "6"]);
+    }
+
+    #[test]
+    fn test_apply_python_versions() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("numpy", "1.26.4", "py310hb13e2d6_0", 0),
+            mkpkg("numpy", "1.26.4", "py311h64a7726_0", 0),
+            mkpkg("numpy", "1.26.4", "py39h474f0d3_0", 0),
+            mkpkg("requests", "2.31.0", "pyhd8ed1ab_0", 0),
+            mkpkg("zlib", "1.3.1", "h4ab18f5_1", 1),
+        ];
+        records[0].depends = vec![
+            "python >=3.10,<3.11.0a0".to_string(),
+            "python_abi 3.10.* *_cp310".to_string(),
+        ];
+        records[1].depends = vec![
+            "python >=3.11,<3.12.0a0".to_string(),
+            "python_abi 3.11.* *_cp311".to_string(),
+        ];
+        records[3].depends = vec!["python >=3.7".to_string()];
+        records[3].noarch = rattler_conda_types::NoArchType::python();
+        let names = ["1", "2", "3", "4", "5"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let versions = [
+            rattler_conda_types::Version::from_str("3.11").unwrap(),
+            rattler_conda_types::Version::from_str("3.12").unwrap(),
+        ];
+        let mut results: Vec<&str> = pr
+            .apply_python_versions(&versions)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["1", "3"]);
+    }
+
+    #[test]
+    fn test_apply_pypy_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("numpy", "1.26.4", "py39h474f0d3_0", 0),
+            mkpkg("numpy", "1.26.4", "py39h6dedee3_0", 0),
+            mkpkg("pypy3.9", "7.3.15", "h9557127_0", 0),
+            mkpkg("python", "3.9.18", "0_73_pypy", 0),
+        ];
+        records[0].depends = vec!["python_abi 3.9.* *_cp39".to_string()];
+        records[1].depends = vec!["python_abi 3.9 *_pypy39_pp73".to_string()];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_pypy_ban(false).is_empty());
+        let mut results: Vec<&str> = pr
+            .apply_pypy_ban(true)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["2", "3", "4"]);
+    }
+
+    #[test]
+    fn test_apply_build_string_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("numpy", "1.26.4", "py311_debug_h64a7726_0", 0),
+            mkpkg("numpy", "1.26.4", "py311h64a7726_0", 0),
+            mkpkg("scipy", "1.11.4", "py311_debug_h64a7726_0", 0),
+            mkpkg("scipy", "1.11.4", "py311cuda92h64a7726_0", 0),
+        ];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let patterns = [
+            (
+                Some("numpy".to_string()),
+                regex::Regex::new(".*_debug.*").unwrap(),
+            ),
+            (None, regex::Regex::new("cuda92").unwrap()),
+        ];
+        let mut results: Vec<&str> = pr
+            .apply_build_string_ban(&patterns)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["1", "4"]);
+    }
+
+    #[test]
+    fn test_apply_depender_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("pyqt", "4.11.4", "py27_4", 4),
+            mkpkg("qt", "4.8.7", "8", 8),
+            mkpkg("qt", "5.15.8", "h5810be5_19", 19),
+            mkpkg("spyder", "3.2.8", "py27_0", 0),
+        ];
+        records[0].depends = vec!["qt 4.8.*".to_string()];
+        records[3].depends = vec!["pyqt >=4".to_string(), "qt".to_string()];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mut results: Vec<&str> = pr
+            .apply_depender_ban(&["qt".to_string(), "missing".to_string()])
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["1", "4"]);
+    }
+
+    #[test]
+    fn test_apply_closure() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("libzlib", "1.3.1", "h4ab18f5_1", 1),
+            mkpkg("pip", "24.0", "pyhd8ed1ab_0", 0),
+            mkpkg("python", "3.11.8", "hab00c5b_0", 0),
+            mkpkg("python", "3.12.2", "hab00c5b_0", 0),
+            mkpkg("readline", "8.2", "h8228510_1", 1),
+            mkpkg("zlib", "1.3.1", "h4ab18f5_1", 1),
+        ];
+        records[1].depends = vec!["python >=3.7".to_string()];
+        records[2].depends = vec![
+            "libzlib >=1.2.13".to_string(),
+            "pip".to_string(),
+            "__glibc >=2.17".to_string(),
+        ];
+        records[3].depends = vec!["readline >=8.2".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let roots = std::collections::HashMap::from([(
+            "python".to_string(),
+            vec![rattler_conda_types::NamelessMatchSpec::from_str(
+                "3.11.*",
+                rattler_conda_types::ParseStrictness::Lenient,
+            )
+            .unwrap()],
+        )]);
+        assert!(pr.apply_closure(None).is_empty());
+        let mut results: Vec<&str> = pr
+            .apply_closure(Some(&roots))
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        // python 3.12 is reachable through the cycle with pip, and brings readline along with it.
+        assert_eq!(results, ["6"]);
+    }
+
+    #[test]
+    fn test_current_filenames() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("libzlib", "1.2.11", "h4ab18f5_0", 0),
+            mkpkg("libzlib", "1.2.13", "h4ab18f5_0", 1),
+            mkpkg("libzlib", "1.3.1", "h4ab18f5_1", 1),
+            mkpkg("python", "3.10.14", "hab00c5b_0", 0),
+            mkpkg("python", "3.11.8", "hab00c5b_0", 0),
+            mkpkg("python", "3.12.2", "hab00c5b_0", 0),
+            mkpkg("python", "3.12.2", "hab00c5b_1", 0),
+            mkpkg("six", "1.16.0", "pyhd8ed1ab_0", 0),
+            mkpkg("six", "1.16.0", "pyhd8ed1ab_1", 0),
+        ];
+        records[5].depends = vec!["libzlib >=1.3".to_string()];
+        records[6].depends = vec!["libzlib <1.3".to_string(), "__glibc >=2.17".to_string()];
+        records[8].depends = vec!["python <3.12".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mut results: Vec<&str> = pr.current_filenames().into_iter().collect();
+        results.sort_unstable();
+        // Both builds of python 3.12.2 and six 1.16.0, and the older libzlib and python which
+        // one of their builds needs.
+        assert_eq!(results, ["2", "3", "5", "6", "7", "8", "9"]);
+    }
+
+    #[test]
+    fn test_apply_orphan_removal() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("git", "2.44.0", "pl5321h709897a_0", 0),
+            mkpkg("libzlib", "1.3.1", "h4ab18f5_1", 1),
+            mkpkg("openssl", "3.2.1", "hd590300_1", 1),
+            mkpkg("perl", "5.32.1", "7_hd590300_perl5", 7),
+            mkpkg("python", "3.12.2", "hab00c5b_0", 0),
+            mkpkg("tzdata", "2024a", "h0c530f3_0", 0),
+        ];
+        records[0].depends = vec!["perl 5.*".to_string()];
+        records[4].depends = vec!["libzlib >=1.2.13".to_string(), "openssl >=3".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let requested = std::collections::HashMap::from([(
+            "python".to_string(),
+            vec![rattler_conda_types::NamelessMatchSpec::from_str(
+                ">=3.12",
+                rattler_conda_types::ParseStrictness::Lenient,
+            )
+            .unwrap()],
+        )]);
+        let protected = [glob::Pattern::new("tz*").unwrap()];
+        assert!(pr
+            .apply_orphan_removal(false, &requested, &protected)
+            .is_empty());
+        let mut results: Vec<&str> = pr
+            .apply_orphan_removal(true, &requested, &protected)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        // perl is only orphaned once git has been removed.
+        assert_eq!(results, ["1", "4"]);
+    }
+
+    #[test]
+    fn test_apply_format_dedupe() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("numpy", "1.26.4", "py311h64a7726_0", 0),
+            mkpkg("numpy", "1.26.4", "py311h64a7726_0", 0),
+            mkpkg("numpy", "1.26.3", "py311h64a7726_0", 0),
+            mkpkg("scipy", "1.11.4", "py311h64a7726_0", 0),
+        ];
+        records[3].depends = vec!["numpy >=1.26.4".to_string()];
+        let names = [
+            "numpy-1.26.4-py311h64a7726_0.conda",
+            "numpy-1.26.4-py311h64a7726_0.tar.bz2",
+            "numpy-1.26.3-py311h64a7726_0.tar.bz2",
+            "scipy-1.11.4-py311h64a7726_0.conda",
+        ];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let results = pr.apply_format_dedupe(true);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "numpy-1.26.4-py311h64a7726_0.tar.bz2");
+        assert_eq!(
+            results[0].conda_filename,
+            "numpy-1.26.4-py311h64a7726_0.conda"
+        );
+
+        // When the .conda is removed too, it is the one to blame.
+        let spec = rattler_conda_types::NamelessMatchSpec::from_str(
+            "<1.26.4",
+            rattler_conda_types::ParseStrictness::Lenient,
+        )
+        .unwrap();
+        pr.apply_matchspecs("numpy", &[&spec]);
+        let results = pr.find_all_unresolveables();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].cause_filename,
+            Some("numpy-1.26.4-py311h64a7726_0.conda")
+        );
+    }
+
+    #[test]
+    fn test_external_providers() {
+        let cache = MatchspecCache::with_capacity(8);
+        let mut six = mkpkg("six", "1.16.0", "pyhd8ed1ab_0", 0);
+        six.depends = vec!["python >=3.8".to_string(), "wheel".to_string()];
+        let wheel = mkpkg("wheel", "0.42.0", "pyhd8ed1ab_0", 0);
+        let spec = rattler_conda_types::NamelessMatchSpec::from_str(
+            "<0.42",
+            rattler_conda_types::ParseStrictness::Lenient,
+        )
+        .unwrap();
+        for external_providers in [false, true] {
+            let mut pr = PackageRelations::new().with_external_providers(external_providers);
+            pr.insert(&cache, "six-1.16.0-pyhd8ed1ab_0.conda", &six);
+            pr.insert(&cache, "wheel-0.42.0-pyhd8ed1ab_0.conda", &wheel);
+            let results = pr.find_all_unresolveables();
+            // Without python, six is only satisfiable when python is provided elsewhere.
+            assert_eq!(results.len(), usize::from(!external_providers));
+            if external_providers {
+                // A name which is inserted is still unsatisfiable once its providers are gone.
+                pr.apply_matchspecs("wheel", &[&spec]);
+                assert_eq!(pr.find_all_unresolveables().len(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_prereleases() {
+        use crate::packagerelations::{prereleases, Prerelease};
+        let classify = |version: &str| -> Vec<Prerelease> {
+            prereleases(&rattler_conda_types::Version::from_str(version).unwrap()).collect()
+        };
+        assert_eq!(classify("2.0.0.dev0"), [Prerelease::Dev]);
+        assert_eq!(classify("2.0.0rc1"), [Prerelease::Rc]);
+        assert_eq!(classify("1.0a1"), [Prerelease::Alpha]);
+        assert_eq!(classify("1.0.0b2"), [Prerelease::Beta]);
+        assert_eq!(classify("3.0.0alpha"), [Prerelease::Alpha]);
+        assert_eq!(classify("3.0.0.beta1"), [Prerelease::Beta]);
+        assert_eq!(classify("0.4.0preview"), [Prerelease::Beta]);
+        assert_eq!(classify("1.0a1.dev0"), [Prerelease::Alpha, Prerelease::Dev]);
+        // Not prereleases
+        assert!(classify("2024a").is_empty());
+        assert!(classify("1.0.2a").is_empty());
+        assert!(classify("1.1.1w").is_empty());
+        assert!(classify("1.0.6bzip").is_empty());
+        assert!(classify("1.26.4").is_empty());
+    }
+
+    #[test]
+    fn test_apply_local_version_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("libfoo", "1.2.3", "h1234567_0+cuda", 0),
+            mkpkg("libfoo", "1.2.3+hotfix1", "h1234567_0", 0),
+            mkpkg("libfoo", "1.2.4", "h1234567_0", 0),
+            mkpkg("usesfoo", "1.0", "h1234567_0", 0),
+        ];
+        records[3].depends = vec!["libfoo 1.2.3+hotfix1".to_string()];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_local_version_ban(false).is_empty());
+        let results: Vec<&str> = pr
+            .apply_local_version_ban(true)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["2"]);
+        let unresolveables: Vec<&str> = pr
+            .find_all_unresolveables()
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(unresolveables, ["4"]);
+    }
+
+    #[test]
+    fn test_apply_epoch_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("libfoo", "1!2.0", "h1234567_0", 0),
+            mkpkg("openssl", "1!3.2.1", "hd590300_1", 1),
+            mkpkg("usesfoo", "1.0", "h1234567_0", 0),
+            mkpkg("zlib", "0!1.3.1", "h4ab18f5_1", 1),
+        ];
+        records[2].depends = vec!["libfoo".to_string()];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let allowed = std::collections::HashSet::from(["openssl".to_string()]);
+        assert!(pr.apply_epoch_ban(false, &allowed).is_empty());
+        let results: Vec<&str> = pr
+            .apply_epoch_ban(true, &allowed)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["1"]);
+        let unresolveables: Vec<&str> = pr
+            .find_all_unresolveables()
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(unresolveables, ["3"]);
+    }
+
+    fn mkcudaversion(version: &str, cudatoolkit: &str, cuda: &str) -> PackageRecord {
+        let mut record = mkpkg("cuda-version", version, "h7b1a6b4_3", 3);
+        record.constrains = vec![
+            format!("cudatoolkit {cudatoolkit}"),
+            format!("__cuda >={cuda}"),
+            "cudnn >=8".to_string(),
+        ];
+        record
+    }
+
+    #[test]
+    fn test_enforce_constrains_virtual_package() {
+        let records = [
+            mkcudaversion("12.0", "12.0|12.0.*", "12"),
+            mkpkg("zlib", "1.3.1", "h4ab18f5_1", 1),
+        ];
+        let names = ["1", "2"];
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_incompatible_architecture("osx-64").is_empty());
+
+        let mut pr = PackageRelations::new().with_enforced_constrains(true);
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_incompatible_architecture("linux-64").is_empty());
+        let results: Vec<&str> = pr
+            .apply_incompatible_architecture("osx-64")
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["1"]);
+    }
+
+    #[test]
+    fn test_enforce_constrains_providers() {
+        let records = [
+            mkcudaversion("11.2", "11.2|11.2.*", "11"),
+            mkcudaversion("11.8", "11.8|11.8.*", "11.8"),
+            mkpkg("cudatoolkit", "11.2.2", "hc23eb0c_13", 13),
+            mkpkg("cudatoolkit", "11.8.0", "h4ba93d1_13", 13),
+        ];
+        let names = ["1", "2", "3", "4"];
+        let cache = MatchspecCache::with_capacity(8);
+        let user_matchspecs = std::collections::HashMap::from([(
+            "cudatoolkit".to_string(),
+            vec![rattler_conda_types::NamelessMatchSpec::from_str(
+                ">=11.8",
+                rattler_conda_types::ParseStrictness::Lenient,
+            )
+            .unwrap()],
+        )]);
+
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert_eq!(pr.apply_user_matchspecs(&user_matchspecs).len(), 1);
+        assert!(pr.find_all_unresolveables().is_empty());
+
+        let mut pr = PackageRelations::new().with_enforced_constrains(true);
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        // cudnn has no providers at all, so constraining it never removes anything.
+        assert!(pr.find_all_unresolveables().is_empty());
+        assert_eq!(pr.apply_user_matchspecs(&user_matchspecs).len(), 1);
+        let results = pr.find_unresolveables(vec!["cudatoolkit"]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "1");
+        assert!(results[0].constraint);
+    }
+
+    #[test]
+    fn test_virtual_packages() {
+        let mut records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("b", "1.0", "h1234567_0", 0),
+            mkpkg("c", "1.0", "h1234567_0", 0),
+            mkpkg("d", "1.0", "h1234567_0", 0),
+            mkpkg("e", "1.0", "h1234567_0", 0),
+        ];
+        records[0].depends = vec!["__glibc >=2.17,<3.0.a0".to_string(), "__cuda".to_string()];
+        records[1].depends = vec!["__glibc >=2.35,<3.0.a0".to_string()];
+        records[2].depends = vec!["__cuda >=12.4".to_string()];
+        records[3].depends = vec!["__osx >=11".to_string()];
+        records[4].depends = vec!["b".to_string()];
+        let virtual_packages = [
+            mkpkg("__cuda", "12.2", "0", 0),
+            mkpkg("__glibc", "2.28", "0", 0),
+        ];
+        let names = ["1", "2", "3", "4", "5"];
+        let cache = MatchspecCache::with_capacity(8);
+        let mut pr = PackageRelations::new().with_virtual_packages(&virtual_packages);
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mut results: Vec<&str> = pr
+            .find_all_unresolveables()
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["2", "3"]);
+        let results: Vec<&str> = pr
+            .find_unresolveables(vec!["b"])
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["5"]);
+    }
+
+    #[test]
+    fn test_variant_hashes() {
+        use crate::packagerelations::variant_hashes;
+        assert_eq!(variant_hashes("py39h0f8d45d_100"), ["h0f8d45d"]);
+        assert_eq!(variant_hashes("h2bc3f7f_2"), ["h2bc3f7f"]);
+        assert_eq!(variant_hashes("cuda112py39h3e2d4f1_0"), ["h3e2d4f1"]);
+        assert_eq!(
+            variant_hashes("cuda120_py310h8b5b4e4_hcb1b4e8_201"),
+            ["h8b5b4e4", "hcb1b4e8"]
+        );
+        assert_eq!(variant_hashes("pyhd8ed1ab_0"), ["hd8ed1ab"]);
+        assert!(variant_hashes("hashfree_1").is_empty());
+        assert!(variant_hashes("3").is_empty());
+    }
+
+    #[test]
+    fn test_apply_build_prune_variants() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("cupy", "12.3.0", "py39h3e2d4f1_0", 0),
+            mkpkg("cupy", "12.3.0", "py39h3e2d4f1_1", 1),
+            mkpkg("cupy", "12.3.0", "py39h3e2d4f1_2", 2),
+            mkpkg("cupy", "12.3.0", "py39h7a1b2c3_0", 0),
+            mkpkg("numpy", "1.26.4", "py39h0f8d45d_100", 100),
+            mkpkg("numpy", "1.26.4", "py39h0f8d45d_101", 101),
+            mkpkg("numpy", "1.26.4", "py310h1f2e3d4_0", 0),
+            mkpkg("zlib", "1.3.1", "h2bc3f7f_1", 1),
+            mkpkg("zlib", "1.3.1", "h2bc3f7f_2", 2),
+        ];
+        // Builds 1 and 2 of the first cupy variant were made for different CUDA versions.
+        records[0].depends = vec!["cudatoolkit >=11.2,<12".to_string()];
+        records[1].depends = vec!["cudatoolkit >=11.2,<12".to_string()];
+        records[2].depends = vec!["cuda-version >=12,<13".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mut results: Vec<&str> = pr
+            .apply_build_prune()
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["1", "5", "8"]);
+    }
+
+    #[test]
+    fn test_apply_name_lists() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("numpy", "1.26.4", "py311h64a7726_0", 0),
+            mkpkg("pyqt", "5.15.9", "py311hf0fb5b6_5", 5),
+            mkpkg("python", "3.11.8", "hab00c5b_0", 0),
+            mkpkg("qt-main", "5.15.8", "h112747c_20", 20),
+            mkpkg("qt-main", "5.15.8", "h112747c_21", 21),
+        ];
+        let names = ["1", "2", "3", "4", "5"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let denied = [glob::Pattern::new("qt*").unwrap()];
+        let allowed = [
+            glob::Pattern::new("py*").unwrap(),
+            glob::Pattern::new("qt-main").unwrap(),
+        ];
+        assert!(pr.apply_name_lists(&[], None).is_empty());
+        let mut results: Vec<(&str, Option<&str>)> = pr
+            .apply_name_lists(&denied, Some(&allowed))
+            .iter()
+            .map(|log| (log.filename, log.denied_by))
+            .collect();
+        results.sort_unstable();
+        assert_eq!(
+            results,
+            [("1", None), ("4", Some("qt*")), ("5", Some("qt*"))]
+        );
+    }
+
+    #[test]
+    fn test_apply_filename_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("numpy", "1.26.4", "py311h64a7726_0", 0),
+            mkpkg("pandas", "2.2.1", "py311h320fe9a_0", 0),
+        ];
+        records[1].depends = vec!["numpy >=1.26".to_string()];
+        let names = [
+            "numpy-1.26.4-py311h64a7726_0.conda",
+            "pandas-2.2.1-py311h320fe9a_0.conda",
+        ];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let banned = [
+            "numpy-1.26.4-py311h64a7726_0.conda".to_string(),
+            "nmupy-1.26.4-py311h64a7726_0.conda".to_string(),
+        ];
+        let results: Vec<&str> = pr
+            .apply_filename_ban(&banned)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["numpy-1.26.4-py311h64a7726_0.conda"]);
+        let unresolveables: Vec<&str> = pr
+            .find_unresolveables(vec!["numpy"])
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(unresolveables, ["pandas-2.2.1-py311h320fe9a_0.conda"]);
+    }
+
+    #[test]
+    fn test_bad_metadata() {
+        use crate::packagerelations::MetadataProblem;
+
+        let mut records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("b", "1.0", "h1234567_0", 0),
+            mkpkg("c", "1.0", "h1234567_0", 0),
+            mkpkg("d", "1.0", "h1234567_0", 0),
+        ];
+        records[0].depends = vec!["b >=1.0".to_string()];
+        records[1].depends = vec!["c >=>=1.0".to_string(), "d".to_string()];
+        records[2].depends = vec![String::new()];
+        records[3].constrains = vec!["a 1.0 [build_number=x]".to_string()];
+        let names = ["1", "2", "3", "4"];
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut pr = PackageRelations::new().with_enforced_constrains(true);
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert_eq!(pr.bad_metadata_count(), 3);
+        // Keeping the packages ignores the broken entries, and everything else still works.
+        assert!(pr.apply_bad_metadata_removal(false, true).is_empty());
+        assert!(pr.find_all_unresolveables().is_empty());
+        assert!(pr.apply_closure(None).is_empty());
+        let results: Vec<(&str, MetadataProblem)> = pr
+            .apply_bad_metadata_removal(true, true)
+            .iter()
+            .map(|log| (log.filename, log.problem))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("2", MetadataProblem::UnparseableMatchspec("c >=>=1.0")),
+                ("3", MetadataProblem::UnparseableMatchspec("")),
+                (
+                    "4",
+                    MetadataProblem::UnparseableMatchspec("a 1.0 [build_number=x]")
+                )
+            ]
+        );
+        let unresolveables: Vec<&str> = pr
+            .find_unresolveables(vec!["b"])
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(unresolveables, ["1"]);
+    }
+
+    #[test]
+    fn test_apply_run_exports_ban() {
+        use rattler_conda_types::package::RunExportsJson;
+
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("openssl", "1.1.1w", "hd590300_0", 0),
+            mkpkg("openssl", "3.2.1", "hd590300_1", 1),
+            mkpkg("zlib", "1.3.1", "h4ab18f5_1", 1),
+        ];
+        let names = ["1", "2", "3"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let old_openssl = RunExportsJson {
+            weak: vec!["openssl >=1.1.1w,<1.1.2a".to_string()],
+            ..RunExportsJson::default()
+        };
+        let new_openssl = RunExportsJson {
+            weak: vec!["openssl >=3.2.1,<4.0a0".to_string()],
+            ..RunExportsJson::default()
+        };
+        let zlib = RunExportsJson {
+            strong: vec!["libzlib 1.3.*".to_string()],
+            ..RunExportsJson::default()
+        };
+        let run_exports = std::collections::HashMap::from([
+            ("1", &old_openssl),
+            ("2", &new_openssl),
+            ("3", &zlib),
+        ]);
+        let banned = ["openssl <3".to_string(), "libzlib".to_string()];
+        assert!(pr.apply_run_exports_ban(None, &banned).is_empty());
+        let mut results: Vec<(&str, &str)> = pr
+            .apply_run_exports_ban(Some(&run_exports), &banned)
+            .iter()
+            .map(|log| (log.filename, log.run_export))
+            .collect();
+        results.sort_unstable();
+        assert_eq!(
+            results,
+            [("1", "openssl >=1.1.1w,<1.1.2a"), ("3", "libzlib 1.3.*")]
+        );
+    }
+
+    #[test]
+    fn test_apply_track_features_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("blas", "1.0", "mkl", 0),
+            mkpkg("blas", "1.0", "openblas", 0),
+            mkpkg("numpy", "1.16.6", "py37hbc911f0_0", 0),
+        ];
+        records[0].track_features = vec!["blas_mkl".to_string()];
+        records[2].depends = vec!["blas 1.0 mkl".to_string()];
+        let names = ["1", "2", "3"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_track_features_ban(false).is_empty());
+        let results = pr.apply_track_features_ban(true);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "1");
+        assert_eq!(
+            results[0].to_string(),
+            "1 removed: tracks features blas_mkl"
+        );
+        let unresolveables: Vec<&str> = pr
+            .find_unresolveables(vec!["blas"])
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(unresolveables, ["3"]);
+    }
+
+    #[test]
+    fn test_apply_plain_rebuild_prune() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("parquet-cpp", "1.5.1", "0", 0),
+            mkpkg("parquet-cpp", "1.5.1", "1", 1),
+            mkpkg("parquet-cpp", "1.5.1", "2", 2),
+            mkpkg("parquet-cpp", "1.5.1", "2", 2),
+            mkpkg("parquet-cpp", "1.5.1", "3", 3),
+            mkpkg("parquet-cpp", "1.5.1", "h1234567_4", 4),
+            mkpkg("parquet-cpp", "1.5.2", "0", 0),
+        ];
+        for record in &mut records[..4] {
+            record.depends = vec!["arrow-cpp >=0.8".to_string(), "libgcc-ng".to_string()];
+        }
+        // Build 1 lists its depends in another order, build 3 is pinned differently.
+        records[1].depends.reverse();
+        records[4].depends = vec!["arrow-cpp >=0.9".to_string()];
+        let names = ["1", "2", "3.conda", "3.tar.bz2", "4", "5", "6"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_plain_rebuild_prune(false).is_empty());
+        let mut results: Vec<&str> = pr
+            .apply_plain_rebuild_prune(true)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, ["1", "2"]);
+    }
+
+    #[test]
+    fn test_apply_recency_window() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let now = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let days_ago = |days: i64| Some(now - chrono::TimeDelta::days(days));
+        let mut records = [
+            mkpkg("libfoo", "1.0", "h1234567_0", 0),
+            mkpkg("libfoo", "1.1", "h1234567_0", 0),
+            mkpkg("libfoo", "1.2", "h1234567_0", 0),
+            mkpkg("libfoo", "1.2", "h7654321_0", 0),
+            mkpkg("stale", "0.1", "h1234567_0", 0),
+            mkpkg("stale", "0.2", "h1234567_0", 0),
+        ];
+        records[0].timestamp = days_ago(400);
+        records[1].timestamp = days_ago(10);
+        records[2].timestamp = days_ago(200);
+        records[3].timestamp = days_ago(5);
+        records[4].timestamp = None;
+        records[5].timestamp = days_ago(1000);
+        let names = ["1", "2", "3", "4", "5", "6"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let window = chrono::TimeDelta::days(90);
+        assert!(pr.apply_recency_window(None, now, true).is_empty());
+        assert_eq!(pr.apply_recency_window(Some(window), now, false).len(), 1);
+        let results = pr.apply_recency_window(Some(window), now, true);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "5");
+        assert_eq!(results[0].age, None);
+    }
+
+    #[test]
+    fn test_apply_noarch_type_ban() {
+        use rattler_conda_types::{NoArchKind, NoArchType};
+
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("ca-certificates", "2024.2.2", "hbcca054_0", 0),
+            mkpkg("requests", "2.31.0", "pyhd8ed1ab_0", 0),
+            mkpkg("tzdata", "2024a", "h0c530f3_0", 0),
+        ];
+        records[1].noarch = NoArchType::python();
+        records[2].noarch = NoArchType::generic();
+        let names = ["1", "2", "3"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_noarch_type_ban(&[]).is_empty());
+        let results = pr.apply_noarch_type_ban(&[NoArchKind::Generic]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "3");
+        assert_eq!(
+            results[0].to_string(),
+            "3 removed: banned noarch type generic"
+        );
+    }
+
+    #[test]
+    fn test_apply_subdir_consistency() {
+        use rattler_conda_types::NoArchType;
+
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("b", "1.0", "h1234567_0", 0),
+            mkpkg("c", "1.0", "h1234567_0", 0),
+            mkpkg("d", "1.0", "h1234567_0", 0),
+            mkpkg("e", "1.0", "pyhd8ed1ab_0", 0),
+            mkpkg("f", "1.0", "h1234567_0", 0),
+        ];
+        records[0].subdir = "linux-64".to_string();
+        records[0].platform = Some("linux".to_string());
+        records[0].arch = Some("x86_64".to_string());
+        records[1].subdir = "linux-64".to_string();
+        records[1].platform = Some("osx".to_string());
+        records[2].subdir = "osx-arm64".to_string();
+        records[3].arch = Some("aarch64".to_string());
+        records[4].subdir = "noarch".to_string();
+        records[4].noarch = NoArchType::python();
+        // A linux-64 record published under noarch, and a noarch one where it belongs.
+        let mut published_noarch = [
+            mkpkg("g", "1.0", "h1234567_0", 0),
+            mkpkg("h", "1.0", "pyhd8ed1ab_0", 0),
+        ];
+        published_noarch[0].subdir = "linux-64".to_string();
+        published_noarch[1].subdir = "noarch".to_string();
+        let repodata = |names: &[&str], records: &[PackageRecord]| -> RepoData {
+            let mut repodata: RepoData = serde_json::from_str("{}").unwrap();
+            for (name, record) in zip(names, records) {
+                repodata
+                    .conda_packages
+                    .insert((*name).to_string(), record.clone());
+            }
+            repodata
+        };
+        let names = ["1", "2", "3", "4", "5", "6"];
+        let linux = repodata(&names, &records);
+        let noarch = repodata(&["7", "8"], &published_noarch);
+        for (name, record) in zip(names, &records).chain(zip(["7", "8"], &published_noarch)) {
+            pr.insert(&cache, name, record);
+        }
+        let directories = [("linux-64", &linux), ("noarch", &noarch)];
+        assert_eq!(pr.subdir_mismatch_count(&directories), 4);
+        assert!(pr
+            .apply_subdir_consistency(&directories, false, false)
+            .is_empty());
+        let mut results = pr.apply_subdir_consistency(&directories, true, false);
+        results.sort_by_key(|log| log.filename);
+        assert_eq!(
+            results
+                .iter()
+                .map(|log| (log.filename, log.field))
+                .collect::<Vec<_>>(),
+            [("3", "subdir"), ("7", "subdir")]
+        );
+        assert_eq!(
+            results[1].to_string(),
+            "7 removed: subdir linux-64 contradicts subdir noarch"
+        );
+        let mut results = pr.apply_subdir_consistency(&directories, true, true);
+        results.sort_by_key(|log| log.filename);
+        let removed: Vec<(&str, &str)> = results
+            .iter()
+            .map(|log| (log.filename, log.field))
+            .collect();
+        assert_eq!(removed, [("2", "platform"), ("4", "arch")]);
+        assert_eq!(
+            results[0].to_string(),
+            "2 removed: platform osx contradicts subdir linux-64"
+        );
+    }
+
+    #[test]
+    fn test_apply_must_compatible() {
+        let cache = MatchspecCache::with_capacity(8);
+        let mut records = [
+            mkpkg("numpy", "1.26.4", "py310h1234567_0", 0),
+            mkpkg("numpy", "2.1.0", "py311h1234567_0", 0),
+            mkpkg("python", "3.10.14", "h1234567_0", 0),
+            mkpkg("python", "3.11.9", "h1234567_0", 0),
+            mkpkg("python", "3.12.4", "h1234567_0", 0),
+        ];
+        records[0].depends = vec!["python >=3.10,<3.11.0a0".to_string()];
+        records[1].depends = vec!["python >=3.11,<3.12.0a0".to_string()];
+        let names = ["1", "2", "3", "4", "5"];
+
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let results = pr.apply_must_compatible("numpy", None, 64);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["5"]);
+
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let spec = rattler_conda_types::NamelessMatchSpec::from_str(
+            ">=2",
+            rattler_conda_types::ParseStrictness::Lenient,
+        )
+        .unwrap();
+        assert!(pr.matches_any("numpy", &spec));
+        let mut results = pr.apply_must_compatible("numpy", Some(&spec), 64);
+        results.sort_by_key(|log| log.filename);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["3", "5"]);
+
+        let spec = rattler_conda_types::NamelessMatchSpec::from_str(
+            ">=3",
+            rattler_conda_types::ParseStrictness::Lenient,
+        )
+        .unwrap();
+        assert!(!pr.matches_any("numpy", &spec));
+    }
+
+    #[test]
+    fn test_apply_must_compatible_all() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+        let mut records = [
+            mkpkg("numpy", "1.26.4", "py310h1234567_0", 0),
+            mkpkg("numpy", "1.26.4", "py311h1234567_0", 0),
+            mkpkg("python", "3.10.14", "h1234567_0", 0),
+            mkpkg("python", "3.11.9", "h1234567_0", 0),
+            mkpkg("python", "3.12.4", "h1234567_0", 0),
+            mkpkg("scipy", "1.14.0", "py311h1234567_0", 0),
+            mkpkg("scipy", "1.14.0", "py312h1234567_0", 0),
+        ];
+        records[0].depends = vec!["python >=3.10,<3.11.0a0".to_string()];
+        records[1].depends = vec!["python >=3.11,<3.12.0a0".to_string()];
+        records[5].depends = vec!["python >=3.11,<3.12.0a0".to_string()];
+        records[6].depends = vec!["python >=3.12,<3.13.0a0".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6", "7"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mut results =
+            pr.apply_must_compatible_all(&[("numpy", None), ("scipy", None)], "numpy and scipy");
+        results.sort_by_key(|log| log.filename);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["3", "5"]);
+        assert_eq!(
+            results[0].to_string(),
+            "3 removed: incompatible with numpy and scipy"
+        );
+    }
+
+    #[test]
+    fn test_apply_license_requirement() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("gcc_impl_linux-64", "13.2.0", "h9eb54c0_7", 7),
+            mkpkg("openssl", "3.3.1", "h4bc722e_2", 2),
+            mkpkg("sloppy", "1.0", "h1234567_0", 0),
+            mkpkg("unlicensed", "1.0", "h1234567_0", 0),
+        ];
+        records[1].license = Some("Apache-2.0".to_string());
+        records[2].license = Some("  ".to_string());
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert_eq!(pr.missing_license_count(), 3);
+        let allowed = std::collections::HashSet::from(["gcc_impl_linux-64".to_string()]);
+        assert!(pr.apply_license_requirement(false, &allowed).is_empty());
+        let mut results = pr.apply_license_requirement(true, &allowed);
+        results.sort_by_key(|log| log.filename);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["3", "4"]);
+        assert_eq!(results[0].to_string(), "3 removed: no license declared");
+    }
+
+    #[test]
+    fn test_detect_cycles() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("b", "1.0", "h1234567_0", 0),
+            mkpkg("c", "1.0", "h1234567_0", 0),
+            mkpkg("d", "1.0", "h1234567_0", 0),
+            mkpkg("e", "1.0", "h1234567_0", 0),
+        ];
+        records[0].depends = vec!["b".to_string()];
+        records[1].depends = vec!["c".to_string()];
+        records[2].depends = vec!["a".to_string(), "d".to_string()];
+        records[3].depends = vec!["d".to_string()];
+        records[4].depends = vec!["e >=2".to_string()];
+        let names = ["a-1", "b-1", "c-1", "d-1", "e-1"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let cycles = pr.detect_cycles();
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(
+            cycles[0].to_string(),
+            "cycle of 3 names: a (a-1) b (b-1) c (c-1)"
+        );
+
+        assert!(pr.apply_self_cycle_removal(false).is_empty());
+        let results = pr.apply_self_cycle_removal(true);
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].to_string(),
+            "e-1 removed: depends on its own name with unsatisfiable e >=2"
+        );
+    }
+
+    #[test]
+    fn test_apply_series_prune() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("python", "3", "h1234567_0", 0),
+            mkpkg("python", "3.10.13", "h1234567_0", 0),
+            mkpkg("python", "3.10.14", "h1234567_0", 0),
+            mkpkg("python", "3.11.8", "h1234567_0", 0),
+            mkpkg("python", "3.11.9", "h1234567_0", 0),
+            mkpkg("python", "3.11.9", "h1234567_1", 1),
+            mkpkg("python", "3.12.4", "h1234567_0", 0),
+        ];
+        let names = ["1", "2", "3", "4", "5", "6", "7"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let policies = std::collections::HashMap::from([(
+            "python".to_string(),
+            crate::matchspecyaml::SeriesPrune {
+                depth: 2,
+                keep_series: 2,
+                keep_per_series: 1,
+            },
+        )]);
+        let mut results = pr.apply_series_prune(&policies);
+        results.sort_by_key(|log| log.filename);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["1", "2", "3", "4"]);
+        assert_eq!(
+            results[0].to_string(),
+            "1 removed: series 3 is not among the 2 newest series"
+        );
+        assert_eq!(
+            results[3].to_string(),
+            "4 removed: not among the 1 newest versions of series 3.11"
+        );
+    }
+
+    #[test]
+    fn test_apply_dangling_depends_removal() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("b", "1.0", "h1234567_0", 0),
+            mkpkg("c", "1.0", "h1234567_0", 0),
+        ];
+        records[0].depends = vec!["b".to_string(), "__glibc >=2.17".to_string()];
+        records[1].depends = vec!["missing >=1".to_string()];
+        records[2].depends = vec!["missing".to_string(), "gone".to_string()];
+        let names = ["1", "2", "3"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert_eq!(pr.dangling_dependencies(), [("gone", 1), ("missing", 2)]);
+        assert!(pr.apply_dangling_depends_removal(false).is_empty());
+        let results = pr.apply_dangling_depends_removal(true);
+        let removed: Vec<(&str, &str)> = results
+            .iter()
+            .map(|log| (log.filename, log.dependency_name))
+            .collect();
+        assert_eq!(removed, [("3", "gone"), ("2", "missing")]);
+        assert_eq!(
+            results[0].to_string(),
+            "3 removed: depends on gone which does not exist in this channel"
+        );
+    }
+
+    #[test]
+    fn test_apply_channel_removed() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("b", "1.0", "h1234567_0", 0),
+        ];
+        let names = ["a-1.0-h1234567_0.conda", "b-1.0-h1234567_0.conda"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let results =
+            pr.apply_channel_removed(["b-1.0-h1234567_0.conda", "c-1.0-h1234567_0.conda"]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].to_string(),
+            "b-1.0-h1234567_0.conda removed: listed in channel removed set"
+        );
+        assert!(pr
+            .apply_channel_removed(["b-1.0-h1234567_0.conda"])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_apply_pre_removal() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("b", "1.0", "h1234567_0", 0),
+        ];
+        let names = ["a-1.0-h1234567_0.conda", "b-1.0-h1234567_0.conda"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        pr.lock("a", "1.0", "h1234567_0");
+        let results = pr.apply_pre_removal([
+            "a-1.0-h1234567_0.conda",
+            "b-1.0-h1234567_0.conda",
+            "c-1.0-h1234567_0.conda",
+        ]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].to_string(),
+            "b-1.0-h1234567_0.conda removed: removed by an earlier run"
+        );
+    }
+
+    #[test]
+    fn test_apply_name_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("qt-main", "5.15", "h1234567_0", 0),
+            mkpkg("qt-main", "6.7", "h1234567_0", 0),
+            mkpkg("qtpy", "2.4", "h1234567_0", 0),
+            mkpkg("zlib", "1.3", "h1234567_0", 0),
+        ];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        pr.lock("qt-main", "6.7", "h1234567_0");
+        let banned = [
+            glob::Pattern::new("qt-*").unwrap(),
+            glob::Pattern::new("zlib").unwrap(),
+        ];
+        let mut results = pr.apply_name_ban(&banned);
+        results.sort_unstable_by_key(|log| log.filename);
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[0].to_string(),
+            "1 removed: package qt-main banned by qt-*"
+        );
+        assert_eq!(results[1].filename, "4");
+        assert!(pr.apply_name_ban(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_apply_vulnerability_denylist() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("openssl", "3.0.0", "h1234567_0", 0),
+            mkpkg("openssl", "3.0.7", "h1234567_0", 0),
+            mkpkg("zlib", "1.2.11", "h1234567_0", 0),
+        ];
+        let names = ["1", "2", "3"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let denylist = crate::listfile::parse_denylist(
+            "openssl ==3.0.0\tCVE-2022-3602\nzlib==1.2.11\n",
+            rattler_conda_types::ParseStrictness::Strict,
+        )
+        .unwrap();
+        let results = pr.apply_vulnerability_denylist(&denylist);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["1", "3"]);
+        assert_eq!(
+            results[0].to_string(),
+            "1 removed: vulnerable (CVE-2022-3602), denied by openssl ==3.0.0"
+        );
+        assert_eq!(
+            results[1].to_string(),
+            "3 removed: vulnerable, denied by zlib==1.2.11"
+        );
+    }
+
+    #[test]
+    fn test_apply_signature_requirement() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("b", "1.0", "h1234567_0", 0),
+        ];
+        let names = ["a-1.0-h1234567_0.conda", "b-1.0-h1234567_0.conda"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let signatures: crate::rawrepodata::Signatures = serde_json::from_str(
+            r#"{"signatures": {"a-1.0-h1234567_0.conda": {"key": {"signature": "00"}}}}"#,
+        )
+        .unwrap();
+        assert_eq!(pr.unsigned_count(&[&signatures]), 1);
+        assert!(pr
+            .apply_signature_requirement(false, &[&signatures])
+            .is_empty());
+        let results = pr.apply_signature_requirement(true, &[&signatures]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].to_string(),
+            "b-1.0-h1234567_0.conda removed: no package signature"
+        );
+    }
+
+    #[test]
+    fn test_apply_origin_channel_ban() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("b", "1.0", "h1234567_0", 0),
+            mkpkg("c", "1.0", "h1234567_0", 0),
+        ];
+        let names = ["1", "2", "3"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let origins: crate::rawrepodata::OriginChannels = serde_json::from_str(
+            r#"{"packages": {
+                "1": {"channel": "https://conda.anaconda.org/conda-forge/"},
+                "2": {"channel": "bioconda"},
+                "3": {}
+            }}"#,
+        )
+        .unwrap();
+        let patterns = [glob::Pattern::new("conda-*").unwrap()];
+        assert!(pr.apply_origin_channel_ban(&[], &[&origins]).is_empty());
+        let results = pr.apply_origin_channel_ban(&patterns, &[&origins]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].to_string(),
+            "1 removed: from channel https://conda.anaconda.org/conda-forge/, banned by conda-*"
+        );
+    }
+
+    /// A small channel whose dependency graph has shared chains and a diamond, but no cycles.
+    fn must_compatible_fixture() -> Vec<PackageRecord> {
+        let mut records = vec![
+            mkpkg("app", "1.0", "py311_0", 0),
+            mkpkg("app", "2.0", "py311_0", 0),
+            mkpkg("numpy", "1.25.2", "py310_0", 0),
+            mkpkg("numpy", "1.26.4", "py311_0", 0),
+            mkpkg("numpy", "2.1.0", "py311_0", 0),
+            mkpkg("numpy", "2.1.0", "py312_0", 0),
+            mkpkg("python", "3.10.14", "h1234567_0", 0),
+            mkpkg("python", "3.11.9", "h1234567_0", 0),
+            mkpkg("python", "3.12.4", "h1234567_0", 0),
+            mkpkg("openssl", "1.1.1w", "h1234567_0", 0),
+            mkpkg("openssl", "3.3.1", "h1234567_0", 0),
+            mkpkg("libopenblas", "0.3.20", "h1234567_0", 0),
+            mkpkg("libopenblas", "0.3.27", "h1234567_0", 0),
+            mkpkg("libgfortran", "11.2.0", "h1234567_0", 0),
+            mkpkg("libgfortran", "14.1.0", "h1234567_0", 0),
+        ];
+        let depends: [&[&str]; 15] = [
+            &["numpy >=1.26", "python >=3.11,<3.12.0a0", "openssl >=3"],
+            &["numpy >=2", "python >=3.11,<3.12.0a0", "openssl >=3"],
+            &["python >=3.10,<3.11.0a0", "libopenblas >=0.3.20"],
+            &["python >=3.11,<3.12.0a0", "libopenblas >=0.3.20"],
+            &["python >=3.11,<3.12.0a0", "libopenblas >=0.3.27"],
+            &["python >=3.12,<3.13.0a0", "libopenblas >=0.3.27"],
+            &["openssl >=1.1.1,<1.1.2a"],
+            &["openssl >=3"],
+            &["openssl >=3"],
+            &[],
+            &[],
+            &["libgfortran >=11"],
+            &["libgfortran >=14"],
+            &[],
+            &[],
+        ];
+        for (record, depends) in zip(&mut records, depends) {
+            record.depends = depends.iter().map(ToString::to_string).collect();
+        }
+        records
+    }
+
+    #[test]
+    fn test_apply_must_compatible_acyclic_fixture() {
+        let cache = MatchspecCache::with_capacity(16);
+        let records = must_compatible_fixture();
+        let names: Vec<String> = (1..=records.len()).map(|n| format!("{n:02}")).collect();
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(&names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mut removed: Vec<&str> = pr
+            .apply_must_compatible("app", None, 64)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        removed.sort_unstable();
+        assert_eq!(removed, ["03", "07", "09", "10"]);
+    }
+
+    #[test]
+    fn test_apply_must_compatible_cycle_and_depth() {
+        let cache = MatchspecCache::with_capacity(8);
+        let mut records = [
+            mkpkg("pip", "23.3", "pyhd8ed1ab_0", 0),
+            mkpkg("pip", "24.2", "pyhd8ed1ab_0", 0),
+            mkpkg("python", "3.11.9", "h1234567_0", 0),
+            mkpkg("python", "3.12.4", "h1234567_0", 0),
+            mkpkg("setuptools", "69.0", "pyhd8ed1ab_0", 0),
+            mkpkg("setuptools", "72.1", "pyhd8ed1ab_0", 0),
+        ];
+        records[0].depends = vec!["python >=3.7".to_string(), "setuptools >=69".to_string()];
+        records[1].depends = vec!["python >=3.8".to_string(), "setuptools >=70".to_string()];
+        records[2].depends = vec!["pip >=24".to_string()];
+        records[3].depends = vec!["pip >=24".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6"];
+
+        // python -> pip -> python terminates, and pip is then considered for setuptools.
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mut results = pr.apply_must_compatible("python", None, 64);
+        results.sort_by_key(|log| log.filename);
+        let removed: Vec<(&str, &str)> = results
+            .iter()
+            .map(|log| (log.filename, log.incompatible_with))
+            .collect();
+        assert_eq!(removed, [("1", "python"), ("5", "pip")]);
+
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let results = pr.apply_must_compatible("python", None, 0);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["1"]);
+    }
+
+    #[test]
+    fn test_apply_must_compatible_shallowest_path() {
+        let cache = MatchspecCache::with_capacity(8);
+        let mut records = [
+            mkpkg("app", "1.0", "h1234567_0", 0),
+            mkpkg("plugin", "1.0", "h1234567_0", 0),
+            mkpkg("libcore", "1.0", "h1234567_0", 0),
+            mkpkg("libutil", "1.0", "h1234567_0", 0),
+            mkpkg("zlib", "1.2.13", "h1234567_0", 0),
+            mkpkg("zlib", "1.3.1", "h1234567_0", 0),
+        ];
+        records[0].depends = vec!["plugin".to_string(), "libcore".to_string()];
+        records[1].depends = vec!["libcore".to_string()];
+        records[2].depends = vec!["libutil".to_string()];
+        records[3].depends = vec!["zlib >=1.3".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6"];
+
+        // libcore is 2 dependencies deep through plugin, but only 1 directly, which leaves
+        // libutil within the limit whichever path is walked first.
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let results = pr.apply_must_compatible("app", None, 2);
+        let removed: Vec<(&str, &str)> = results
+            .iter()
+            .map(|log| (log.filename, log.incompatible_with))
+            .collect();
+        assert_eq!(removed, [("5", "libutil")]);
+    }
+
+    #[test]
+    fn test_unknown_virtual_packages_satisfiable() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("pytorch", "2.4.0", "cuda118_py311h1234567_0", 0),
+            mkpkg("torchvision", "0.19.0", "cuda118_py311h1234567_0", 0),
+            mkpkg("pywin32", "306", "py311h1234567_0", 0),
+            mkpkg("pywinpty", "2.0.13", "py311h1234567_0", 0),
+        ];
+        records[0].depends = vec!["__cuda >=11.8".to_string(), "__unix".to_string()];
+        records[1].depends = vec!["pytorch 2.4.0".to_string()];
+        records[2].depends = vec!["__win".to_string()];
+        records[3].depends = vec!["pywin32".to_string()];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let results = pr.apply_incompatible_architecture("linux-64");
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["3"]);
+        let results: Vec<&str> = pr
+            .find_unresolveables(vec!["__cuda", "__unix", "__win", "pywin32"])
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["4"]);
+        assert!(pr.find_all_unresolveables().is_empty());
+    }
+
+    #[test]
+    fn test_apply_pin_conflicts() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(16);
+
+        let depends = [
+            "openssl >=1.1.1,<1.1.2a",
+            "openssl 1.1.1.*",
+            "openssl ==1.1.1w",
+            "openssl >=3.1",
+            "openssl",
+            "openssl 3.*",
+            "openssl >=1.1.1,<4",
+            "libstdcxx-ng >=9",
+            "libstdcxx-ng <12",
+        ];
+        let mut records: Vec<PackageRecord> = (0..depends.len())
+            .map(|n| mkpkg(&format!("pkg{n}"), "1.0", "h1234567_0", 0))
+            .collect();
+        for (record, depend) in zip(&mut records, depends) {
+            record.depends = vec![depend.to_string()];
         }
-        // Does the dependency have a solution?
-        let new_solution_index = wrap_range_from_middle(
-            candidates_start,
-            candidates_end_offset,
-            last_successful_resolution,
+        let names = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let pins = crate::matchspecyaml::parse_user_matchspecs(
+            "pins: [openssl >=3, libstdcxx-ng >=12]",
+            rattler_conda_types::ParseStrictness::Lenient,
         )
-        .find(|index| {
-            !self.removed[*index]
-                && dependency
-                    .matchspec
-                    .matches(self.package_metadatas[*index].package_record)
-        });
-        if let Some(new_solution_index) = new_solution_index {
-            // Yes, there is a solution. Save the solution in
-            // case we need to return to this dependency later.
-            return Some(Evaluation::UpdateSolution(
-                dependency_key,
-                PkgIdxOffset::from_difference(candidates_start, new_solution_index),
-            ));
+        .unwrap()
+        .pins;
+        let results = pr.apply_pin_conflicts(&pins);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["1", "2", "3", "9"]);
+        assert_eq!(
+            results[3].to_string(),
+            "9 removed: dependency libstdcxx-ng <12 conflicts with pin libstdcxx-ng >=12"
+        );
+    }
+
+    #[test]
+    fn test_apply_mutex_selection() {
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("_openmp_mutex", "4.5", "2_gnu", 2),
+            mkpkg("_openmp_mutex", "4.5", "2_kmp_llvm", 2),
+            mkpkg("libgomp", "14.1.0", "h77fa898_0", 0),
+            mkpkg("llvm-openmp", "18.1.8", "hf5423f3_0", 0),
+        ];
+        records[1].track_features = vec!["openmp_impl_llvm".to_string()];
+        records[2].depends = vec!["_openmp_mutex >=4.5 *_gnu".to_string()];
+        records[3].depends = vec!["_openmp_mutex >=4.5 *_llvm".to_string()];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
         }
+        let nothing = std::collections::HashMap::from([(
+            "_openmp_mutex".to_string(),
+            glob::Pattern::new("*intel*").unwrap(),
+        )]);
+        assert!(pr.apply_mutex_selection(&nothing).is_empty());
+        let mutex = std::collections::HashMap::from([(
+            "_openmp_mutex".to_string(),
+            glob::Pattern::new("*gnu*").unwrap(),
+        )]);
+        let results = pr.apply_mutex_selection(&mutex);
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].to_string(),
+            "2 removed: mutex _openmp_mutex variant 2_kmp_llvm lost to 2_gnu selected by *gnu*"
+        );
+        let cascade: Vec<&str> = pr
+            .find_unresolveables(vec!["_openmp_mutex"])
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(cascade, ["4"]);
 
-        // There is no solution.
-        // Try to determine the reason for unresolveable.
-        let cause_of_removal_index = match last_successful_resolution {
-            // We already know what package previously satisified
-            Some(offset) => Some(candidates_start.index() + offset.offset()),
-            // We need to find the previous package that satisfied
-            None => wrap_range_from_middle(candidates_start, candidates_end_offset, None).find(
-                |index| {
-                    self.removed[*index]
-                        && dependency
-                            .matchspec
-                            .matches(self.package_metadatas[*index].package_record)
-                },
-            ),
-        };
+        // Selecting by track feature.
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mutex = std::collections::HashMap::from([(
+            "_openmp_mutex".to_string(),
+            glob::Pattern::new("openmp_impl_llvm").unwrap(),
+        )]);
+        let results = pr.apply_mutex_selection(&mutex);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["1"]);
+    }
 
-        return Some(Evaluation::RemoveAndLog(
-            dependency_key,
-            cause_of_removal_index.map(PkgIdx::from_usize),
-        ));
+    #[test]
+    fn test_locked_packages_are_kept() {
+        use crate::packagerelations::Prerelease;
+
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("libfoo", "1.0", "h1234567_0", 0),
+            mkpkg("libfoo", "1.0", "h1234567_1", 1),
+            mkpkg("libfoo", "2.0rc1", "h1234567_0", 0),
+            mkpkg("app", "1.0", "h1234567_0", 0),
+        ];
+        records[3].depends = vec!["libfoo 1.0 h1234567_0".to_string()];
+        let names = ["1", "2", "3", "4"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert_eq!(pr.lock("libfoo", "1.0", "h1234567_0"), 1);
+        assert_eq!(pr.lock("libfoo", "2.0rc1", "h1234567_0"), 1);
+        assert_eq!(pr.lock("app", "1.0", "h7654321_0"), 0);
+        assert!(pr.apply_build_prune().is_empty());
+        assert!(pr.apply_prerelease_ban(&[Prerelease::Rc], &[]).is_empty());
+        assert!(pr.find_all_unresolveables().is_empty());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::packagerelations::{MatchspecCache, PackageRelations};
-    use rattler_conda_types::{PackageName, PackageRecord, VersionWithSource};
-    use std::iter::zip;
-    use std::str::FromStr;
+    #[test]
+    fn test_apply_compute_flavor() {
+        use crate::packagerelations::ComputeFlavor;
 
-    fn mkpkg(name: &str, version: &str, build: &str, build_number: u64) -> PackageRecord {
-        let mut pkgrecord = PackageRecord::new(
-            PackageName::try_from(name).unwrap(),
-            VersionWithSource::from_str(version).unwrap(),
-            build.to_string(),
+        let mut pr = PackageRelations::new();
+        let cache = MatchspecCache::with_capacity(8);
+
+        let mut records = [
+            mkpkg("jaxlib", "0.4.23", "cpu_py311h1234567_0", 0),
+            mkpkg("jaxlib", "0.4.23", "cuda120py311h7654321_200", 200),
+            mkpkg("jaxlib", "0.4.31", "cpu_py311h1234567_0", 0),
+            mkpkg("pytorch", "2.4.0", "cpu_mkl_py311h1234567_100", 100),
+            mkpkg("pytorch", "2.4.0", "cuda120_py311h7654321_300", 300),
+            mkpkg("pytorch", "2.4.0", "cuda120_py311h7654321_301", 301),
+            mkpkg("numpy", "1.26.4", "py311h1234567_0", 0),
+            mkpkg("tensorflow", "2.16.1", "py311h1234567_0", 0),
+            mkpkg("tensorflow", "2.16.1", "py311h7654321_0", 0),
+        ];
+        // jaxlib's GPU build is only recognisable by its depends.
+        records[1].depends = vec!["cuda-version >=12.0,<13".to_string()];
+        records[7].depends = vec!["cudnn >=8.9".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_compute_flavor(None).is_empty());
+        let mut results = pr.apply_compute_flavor(Some(ComputeFlavor::Gpu));
+        results.sort_by_key(|log| log.filename);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["1", "4"]);
+        assert_eq!(
+            results[1].to_string(),
+            "4 removed: gpu builds preferred, superseded by 6"
         );
-        pkgrecord.build_number = build_number;
-        pkgrecord
+
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let mut results = pr.apply_compute_flavor(Some(ComputeFlavor::Cpu));
+        results.sort_by_key(|log| log.filename);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["2", "5", "6"]);
     }
 
     #[test]
-    fn test_apply_build_prune_happy_path() {
+    fn test_apply_python_abi_coupling() {
         let mut pr = PackageRelations::new();
         let cache = MatchspecCache::with_capacity(8);
 
         let records = [
-            mkpkg("arrow-cpp", "1.5.1", "asdf_h1234567_1", 1),
-            mkpkg("arrow-cpp", "1.5.1", "asdf_h1234567_2", 2),
-            mkpkg("arrow-cpp", "1.5.1", "asdf_h1234567_3", 3),
-            mkpkg("arrow-cpp", "1.5.1", "asdf_h1234567_4", 4),
+            mkpkg("python", "3.9.19", "h0755675_0_cpython", 0),
+            mkpkg("python", "3.9.18", "0_73_pypy", 0),
+            mkpkg("python", "3.11.9", "hb806964_0_cpython", 0),
+            mkpkg("python", "3.13.0", "h9ebbce0_100_cp313", 100),
+            mkpkg("python_abi", "3.9", "4_cp39", 4),
+            mkpkg("python_abi", "3.9", "4_pypy39_pp73", 4),
+            mkpkg("python_abi", "3.11", "4_cp311", 4),
+            mkpkg("python_abi", "3.12", "4_cp312", 4),
+        ];
+        let names = ["1", "2", "3", "4", "5", "6", "7", "8"];
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert_eq!(pr.apply_filename_ban(&["1".to_string()]).len(), 1);
+        let mut results = pr.apply_python_abi_coupling();
+        results.sort_by_key(|log| log.filename);
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["4", "5", "8"]);
+        assert_eq!(
+            results[1].to_string(),
+            "5 removed: no python remains for cpython 3.9"
+        );
+
+        // PyPy's python_abi goes once PyPy itself is filtered.
+        assert_eq!(pr.apply_filename_ban(&["2".to_string()]).len(), 1);
+        let results = pr.apply_python_abi_coupling();
+        let removed: Vec<&str> = results.iter().map(|log| log.filename).collect();
+        assert_eq!(removed, ["6"]);
+    }
+
+    #[test]
+    fn test_user_matchspec_build_number() {
+        let mut records = [
+            mkpkg("libfoo", "1.2.0", "h1234567_1", 1),
+            mkpkg("libfoo", "1.2.0", "h1234567_2", 2),
+            mkpkg("libfoo", "1.2.0", "h1234567_3", 3),
+            mkpkg("libfoo", "1.3.0", "h1234567_0", 0),
+            mkpkg("bar", "1.0", "h1234567_0", 0),
+            mkpkg("baz", "1.0", "h1234567_0", 0),
+        ];
+        records[4].depends = vec!["libfoo 1.2.*[build_number='>=3']".to_string()];
+        records[5].depends = vec!["libfoo 1.2.0 h1234567_2".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6"];
+        let cache = MatchspecCache::with_capacity(8);
+        let user_matchspecs = std::collections::HashMap::from([(
+            "libfoo".to_string(),
+            vec![
+                crate::matchspecyaml::parse_user_matchspec(
+                    "1.2.* build_number>=3",
+                    rattler_conda_types::ParseStrictness::Lenient,
+                )
+                .unwrap(),
+                crate::matchspecyaml::parse_user_matchspec(
+                    ">=1.3",
+                    rattler_conda_types::ParseStrictness::Lenient,
+                )
+                .unwrap(),
+            ],
+        )]);
+
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.find_all_unresolveables().is_empty());
+        let removed = pr.apply_user_matchspecs(&user_matchspecs);
+        let results: Vec<&str> = removed.iter().map(|log| log.filename).collect();
+        assert_eq!(results, ["1", "2"]);
+        assert_eq!(
+            removed[0].to_string(),
+            "1 removed: failed user matchspec 1.2.* build_number>=3 or >=1.3"
+        );
+        // bar still finds a late enough build, but baz needed build 2.
+        let results: Vec<&str> = pr
+            .find_all_unresolveables()
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["6"]);
+    }
+
+    #[test]
+    fn test_unmatched_user_matchspecs() {
+        let records = [
+            mkpkg("pytorch", "2.1.0", "h1234567_0", 0),
+            mkpkg("pytorch", "2.2.0", "h1234567_0", 0),
+            mkpkg("pytorch-cpu", "2.2.0", "h1234567_0", 0),
+            mkpkg("numpy", "1.26.0", "h1234567_0", 0),
         ];
         let names = ["1", "2", "3", "4"];
+        let cache = MatchspecCache::with_capacity(8);
+        let spec = |matchspec| {
+            vec![crate::matchspecyaml::parse_user_matchspec(
+                matchspec,
+                rattler_conda_types::ParseStrictness::Lenient,
+            )
+            .unwrap()]
+        };
+        let user_matchspecs = std::collections::HashMap::from([
+            ("pytorhc".to_string(), spec(">=2")),
+            ("numpy".to_string(), spec(">=2")),
+            ("pytorch".to_string(), spec(">=2.2")),
+            ("tensorflow".to_string(), spec(">=2")),
+        ]);
+
+        let mut pr = PackageRelations::new();
         for (name, record) in zip(names, &records) {
             pr.insert(&cache, name, record);
         }
-        let results = pr.apply_build_prune();
-        assert!(results.len() == 3);
+        pr.apply_user_matchspecs(&user_matchspecs);
+        let unmatched = pr.unmatched_user_matchspecs(&user_matchspecs);
+        assert_eq!(
+            unmatched,
+            [UnmatchedUserSpec::NoneRemaining {
+                package_name: "numpy"
+            }]
+        );
+        assert_eq!(
+            unmatched[0].to_string(),
+            "numpy: no package matches the user matchspecs"
+        );
     }
 
     #[test]
-    fn test_apply_build_prune_no_remove_hashfree_builds() {
+    fn test_unknown_user_spec_names() {
+        let repodata = |records: &str| -> RepoData {
+            serde_json::from_str(&format!(r#"{{"packages.conda": {{{records}}}}}"#)).unwrap()
+        };
+        let linux_64 = repodata(
+            r#""pytorch-2.2.0-0.conda": {"name": "pytorch", "version": "2.2.0", "build": "0", "build_number": 0},
+               "cuda-12.4-0.conda": {"name": "cuda", "version": "12.4", "build": "0", "build_number": 0}"#,
+        );
+        let osx_arm64 = repodata(
+            r#""pytorch-2.2.0-0.conda": {"name": "pytorch", "version": "2.2.0", "build": "0", "build_number": 0}"#,
+        );
+        let noarch = repodata(
+            r#""tzdata-2024a-0.conda": {"name": "tzdata", "version": "2024a", "build": "0", "build_number": 0}"#,
+        );
+        let unknown = unknown_user_spec_names(
+            ["pytorhc", "cuda", "tzdata", "tensorflow", "pytorhc"],
+            [&linux_64, &osx_arm64, &noarch],
+        );
+        assert_eq!(
+            unknown,
+            [
+                UnmatchedUserSpec::UnknownName {
+                    package_name: "pytorhc",
+                    suggestions: vec!["pytorch"],
+                },
+                UnmatchedUserSpec::UnknownName {
+                    package_name: "tensorflow",
+                    suggestions: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            unknown[0].to_string(),
+            "pytorhc: no such package, did you mean pytorch?"
+        );
+    }
+
+    #[test]
+    fn test_apply_exact_pins() {
+        let mut records = [
+            mkpkg("openssl", "3.1.0", "hd590300_0", 0),
+            mkpkg("openssl", "3.2.1", "hd590300_0", 0),
+            mkpkg("openssl", "3.2.1", "hd590300_0", 0),
+            mkpkg("openssl", "3.2.1", "hd590300_1", 1),
+            mkpkg("curl", "8.0", "h1234567_0", 0),
+            mkpkg("python", "3.12.0", "h1234567_0", 0),
+            mkpkg("zlib", "1.3", "h1234567_0", 0),
+        ];
+        records[4].depends = vec!["openssl >=3.2.1,<3.3 *_0".to_string()];
+        records[5].depends = vec!["openssl 3.1.*".to_string()];
+        let names = [
+            "openssl-3.1.0-hd590300_0.conda",
+            "openssl-3.2.1-hd590300_0.conda",
+            "openssl-3.2.1-hd590300_0.tar.bz2",
+            "openssl-3.2.1-hd590300_1.conda",
+            "curl-8.0-h1234567_0.conda",
+            "python-3.12.0-h1234567_0.conda",
+            "zlib-1.3-h1234567_0.conda",
+        ];
+        let cache = MatchspecCache::with_capacity(8);
         let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let pins = std::collections::HashMap::from([
+            (
+                "openssl".to_string(),
+                crate::matchspecyaml::ExactPin::Filename(
+                    "openssl-3.2.1-hd590300_0.conda".to_string(),
+                ),
+            ),
+            // Pinned for another architecture, so zlib is left alone.
+            (
+                "zlib".to_string(),
+                crate::matchspecyaml::ExactPin::Build {
+                    version: "1.3".to_string(),
+                    build: "h7654321_0".to_string(),
+                },
+            ),
+        ]);
+        let removed = pr.apply_exact_pins(&pins);
+        let mut results: Vec<&str> = removed.iter().map(|log| log.filename).collect();
+        results.sort_unstable();
+        assert_eq!(
+            results,
+            [
+                "openssl-3.1.0-hd590300_0.conda",
+                "openssl-3.2.1-hd590300_0.tar.bz2",
+                "openssl-3.2.1-hd590300_1.conda"
+            ]
+        );
+        assert_eq!(
+            removed[0].to_string(),
+            format!(
+                "{} removed: collapsed by exact pin openssl openssl-3.2.1-hd590300_0.conda",
+                removed[0].filename
+            )
+        );
+        let results: Vec<&str> = pr
+            .find_all_unresolveables()
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["python-3.12.0-h1234567_0.conda"]);
+    }
+
+    #[test]
+    fn test_apply_variant_preference() {
+        let records = [
+            mkpkg("libfoo", "1.0", "openblas_h1234567_0", 0),
+            mkpkg("libfoo", "1.0", "mkl_h1234567_0", 0),
+            mkpkg("libfoo", "1.0", "gnu_h1234567_0", 0),
+            mkpkg("libfoo", "2.0", "mkl_h1234567_0", 0),
+            mkpkg("libfoo", "2.0", "gnu_h1234567_0", 0),
+            mkpkg("libfoo", "3.0", "mkl_h1234567_0", 0),
+            mkpkg("libfoo", "3.0", "llvm_h1234567_0", 0),
+            mkpkg("libbar", "1.0", "openblas_h1234567_0", 0),
+            mkpkg("libbar", "1.0", "openblas_h7654321_0", 0),
+        ];
+        let names = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
         let cache = MatchspecCache::with_capacity(8);
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert!(pr.apply_variant_preference(&[]).is_empty());
+        let preferences = ["openblas".to_string(), "gnu".to_string()];
+        let removed = pr.apply_variant_preference(&preferences);
+        let results: Vec<(&str, usize, &str, usize)> = removed
+            .iter()
+            .map(|log| (log.filename, log.score, log.winner, log.winner_score))
+            .collect();
+        // libfoo 3.0 and libbar cannot be told apart by the preferences.
+        assert_eq!(
+            results,
+            [("2", 0, "1", 2), ("3", 1, "1", 2), ("4", 0, "5", 1)]
+        );
+        assert_eq!(
+            removed[1].to_string(),
+            "3 removed: variant preference score 1 is below 1 with score 2"
+        );
+    }
+
+    #[test]
+    fn test_prerelease_exceptions_with_user_matchspecs() {
+        use crate::packagerelations::Prerelease;
 
         let records = [
-            mkpkg("arrow-cpp", "1.5.1", "1", 1),
-            mkpkg("arrow-cpp", "1.5.1", "2", 2),
-            mkpkg("arrow-cpp", "1.5.1", "3", 3),
-            mkpkg("arrow-cpp", "1.5.1", "4", 4),
+            mkpkg("black", "23.0rc1", "pyhd8ed1ab_0", 0),
+            mkpkg("black", "24.1.0rc1", "pyhd8ed1ab_0", 0),
+            mkpkg("black", "24.1.0", "pyhd8ed1ab_0", 0),
+            mkpkg("apache-beam", "2.60.0rc1", "py312h1234567_0", 0),
+            mkpkg("flake8", "7.0.0rc1", "pyhd8ed1ab_0", 0),
+            mkpkg("flake8", "7.0.0", "pyhd8ed1ab_0", 0),
         ];
-        let names = ["1", "2", "3", "4"];
+        let names = ["1", "2", "3", "4", "5", "6"];
+        let cache = MatchspecCache::with_capacity(8);
+        let mut pr = PackageRelations::new();
         for (name, record) in zip(names, &records) {
             pr.insert(&cache, name, record);
         }
-        assert!(pr.apply_build_prune().is_empty());
+        let user_matchspecs = std::collections::HashMap::from([(
+            "black".to_string(),
+            vec![rattler_conda_types::NamelessMatchSpec::from_str(
+                ">=24.1.0rc1",
+                rattler_conda_types::ParseStrictness::Lenient,
+            )
+            .unwrap()],
+        )]);
+        let exceptions = [
+            glob::Pattern::new("black").unwrap(),
+            glob::Pattern::new("apache-*").unwrap(),
+        ];
+        // The exception only exempts black from the ban, its user matchspec still applies.
+        let results: Vec<&str> = pr
+            .apply_user_matchspecs(&user_matchspecs)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["1"]);
+        let results: Vec<&str> = pr
+            .apply_prerelease_ban(&[Prerelease::Rc], &exceptions)
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["5"]);
+    }
+
+    #[test]
+    fn test_apply_build_prune_keeps_pinned_builds() {
+        let mut records = [
+            mkpkg("libfoo", "1.2.3", "h1234567_0", 0),
+            mkpkg("libfoo", "1.2.3", "h1234567_1", 1),
+            mkpkg("libbar", "1.0", "h1234567_0", 0),
+            mkpkg("libbar", "1.0", "h1234567_1", 1),
+            mkpkg("app", "1.0", "h7654321_0", 0),
+        ];
+        records[4].depends = vec![
+            "libfoo 1.2.3 h1234567_0".to_string(),
+            "libbar >=1.0".to_string(),
+        ];
+        let names = ["1", "2", "3", "4", "5"];
+        let cache = MatchspecCache::with_capacity(8);
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let results: Vec<&str> = pr
+            .apply_build_prune()
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["3"]);
+        assert!(pr.find_all_unresolveables().is_empty());
+    }
+
+    #[test]
+    fn test_apply_build_prune_timestamp_ties() {
+        let mut records = [
+            mkpkg("libfoo", "1.0", "cpu_h1234567_1", 1),
+            mkpkg("libfoo", "1.0", "h1234567_1", 1),
+            mkpkg("libfoo", "1.0", "h1234567_1", 1),
+            mkpkg("libfoo", "1.0", "h1234567_0", 0),
+            mkpkg("libbar", "1.0", "a_h1234567_1", 1),
+            mkpkg("libbar", "1.0", "b_h1234567_1", 1),
+        ];
+        records[0].timestamp = chrono::DateTime::from_timestamp(1_700_000_000, 0);
+        records[1].timestamp = chrono::DateTime::from_timestamp(1_600_000_000, 0);
+        records[2].timestamp = chrono::DateTime::from_timestamp(1_600_000_000, 0);
+        let names = [
+            "libfoo-1.0-cpu_h1234567_1.conda",
+            "libfoo-1.0-h1234567_1.conda",
+            "libfoo-1.0-h1234567_1.tar.bz2",
+            "libfoo-1.0-h1234567_0.conda",
+            "libbar-1.0-a_h1234567_1.conda",
+            "libbar-1.0-b_h1234567_1.conda",
+        ];
+        let cache = MatchspecCache::with_capacity(8);
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        let removed = pr.apply_build_prune();
+        let mut results: Vec<(&str, &str)> = removed
+            .iter()
+            .map(|log| (log.filename, log.kept_filename))
+            .collect();
+        results.sort_unstable();
+        // Without timestamps, the filename decides.
+        assert_eq!(
+            results,
+            [
+                (
+                    "libbar-1.0-a_h1234567_1.conda",
+                    "libbar-1.0-b_h1234567_1.conda"
+                ),
+                (
+                    "libfoo-1.0-h1234567_0.conda",
+                    "libfoo-1.0-cpu_h1234567_1.conda"
+                ),
+                (
+                    "libfoo-1.0-h1234567_1.conda",
+                    "libfoo-1.0-cpu_h1234567_1.conda"
+                ),
+                (
+                    "libfoo-1.0-h1234567_1.tar.bz2",
+                    "libfoo-1.0-cpu_h1234567_1.conda"
+                ),
+            ]
+        );
+        let log = removed
+            .iter()
+            .find(|log| log.filename == "libfoo-1.0-h1234567_0.conda")
+            .unwrap();
+        assert_eq!(
+            log.to_string(),
+            "libfoo-1.0-h1234567_0.conda removed: superceded by build 1, \
+             libfoo-1.0-cpu_h1234567_1.conda"
+        );
+    }
+
+    #[test]
+    fn test_malformed_records() {
+        let mut records = [
+            mkpkg("a", "1.0", "h1234567_0", 0),
+            mkpkg("a", "1.0-", "h1234567_0", 0),
+            mkpkg("a", "2.0_", "h1234567_0", 0),
+            mkpkg("a", "latest", "h1234567_0", 0),
+            mkpkg("a", "1!2.0", "h1234567_0", 0),
+            mkpkg("b", "2.0", "", 0),
+            mkpkg("c", "1.0", "h1234567_0", 0),
+        ];
+        records[6].depends = vec!["b".to_string()];
+        let names = ["1", "2", "3", "4", "5", "6", "7"];
+        let cache = MatchspecCache::with_capacity(8);
+        let mut pr = PackageRelations::new();
+        for (name, record) in zip(names, &records) {
+            pr.insert(&cache, name, record);
+        }
+        assert_eq!(pr.bad_metadata_count(), 4);
+        // Unparseable depends and malformed records are removed independently.
+        assert!(pr.apply_bad_metadata_removal(true, false).is_empty());
+        let removed = pr.apply_bad_metadata_removal(false, true);
+        let results: Vec<(&str, String)> = removed
+            .iter()
+            .map(|log| (log.filename, log.to_string()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("2", "2 removed: malformed version \"1.0-\"".to_string()),
+                ("3", "3 removed: malformed version \"2.0_\"".to_string()),
+                ("4", "4 removed: malformed version \"latest\"".to_string()),
+                ("6", "6 removed: empty build string".to_string()),
+            ]
+        );
+        let results: Vec<&str> = pr
+            .find_all_unresolveables()
+            .iter()
+            .map(|log| log.filename)
+            .collect();
+        assert_eq!(results, ["7"]);
     }
 }
@@ -0,0 +1,121 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rattler_repodata_gateway::Reporter;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+/// Progress bars drawn on stderr, or none at all when stdout is not a terminal or with
+/// `--quiet`. While bars are drawn, output must go through `println` or `eprintln`, as the
+/// logging does, so that it is printed above them instead of being drawn over.
+#[derive(Clone, Default)]
+pub struct Progress {
+    bars: Option<MultiProgress>,
+}
+
+impl Progress {
+    #[must_use]
+    pub fn new(quiet: bool) -> Self {
+        Progress {
+            bars: (!quiet && std::io::stdout().is_terminal())
+                .then(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr())),
+        }
+    }
+
+    /// Print a line of output, above the progress bars if there are any.
+    pub fn println(&self, line: impl AsRef<str>) {
+        match &self.bars {
+            Some(bars) => {
+                // Only fails if the terminal went away, when there is nowhere to print to anyway.
+                let _ = bars.println(line);
+            }
+            None => println!("{}", line.as_ref()),
+        }
+    }
+
+    /// Print a line of diagnostics, above the progress bars if there are any.
+    pub fn eprintln(&self, line: impl AsRef<str>) {
+        match &self.bars {
+            Some(bars) => {
+                let _ = bars.println(line);
+            }
+            None => eprintln!("{}", line.as_ref()),
+        }
+    }
+
+    /// A spinner for work of unknown length, ticking on its own until finished.
+    #[must_use]
+    pub fn spinner(&self, message: impl Into<String>) -> Option<ProgressBar> {
+        let bar = self.bars.as_ref()?.add(ProgressBar::new_spinner());
+        bar.set_style(ProgressStyle::with_template("{spinner} {msg} ({elapsed})").unwrap());
+        bar.set_message(message.into());
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Some(bar)
+    }
+
+    /// A bar counting up to `length` items, labelled by `prefix`.
+    #[must_use]
+    pub fn counter(&self, prefix: impl Into<String>, length: u64) -> Option<ProgressBar> {
+        let bar = self.bars.as_ref()?.add(ProgressBar::new(length));
+        bar.set_style(
+            ProgressStyle::with_template("{prefix:>12} [{bar:30}] {pos:>7}/{len:7} {msg}")
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        bar.set_prefix(prefix.into());
+        Some(bar)
+    }
+
+    /// A reporter of the downloads of repodata, with a bar for each download.
+    #[must_use]
+    pub fn download_reporter(&self) -> Option<Arc<dyn Reporter>> {
+        let bars = self.bars.clone()?;
+        Some(Arc::new(DownloadReporter {
+            bars,
+            downloads: Mutex::default(),
+        }))
+    }
+}
+
+/// Draws the progress of each download, in bytes if the server gives the length and as a spinner
+/// otherwise.
+struct DownloadReporter {
+    bars: MultiProgress,
+    downloads: Mutex<Vec<ProgressBar>>,
+}
+
+impl Reporter for DownloadReporter {
+    fn on_download_start(&self, url: &Url) -> usize {
+        let bar = self.bars.add(ProgressBar::no_length());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner} [{bar:30}] {bytes:>10}/{total_bytes:10} {bytes_per_sec:>12} {msg}",
+            )
+            .unwrap()
+            .progress_chars("=> "),
+        );
+        bar.set_message(url.to_string());
+        let mut downloads = self.downloads.lock().unwrap();
+        downloads.push(bar);
+        downloads.len() - 1
+    }
+
+    fn on_download_progress(
+        &self,
+        _url: &Url,
+        index: usize,
+        bytes_downloaded: usize,
+        total_bytes: Option<usize>,
+    ) {
+        let downloads = self.downloads.lock().unwrap();
+        let bar = &downloads[index];
+        if let Some(total_bytes) = total_bytes {
+            bar.set_length(total_bytes as u64);
+        }
+        bar.set_position(bytes_downloaded as u64);
+    }
+
+    fn on_download_complete(&self, _url: &Url, index: usize) {
+        self.downloads.lock().unwrap()[index].finish_and_clear();
+    }
+}
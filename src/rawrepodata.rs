@@ -1,99 +1,895 @@
-use futures::{StreamExt, TryStreamExt};
+use crate::progress::Progress;
+use crate::shardedrepodata::fetch_sharded_subdir;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use fxhash::{FxHashMap, FxHashSet};
 use rattler::default_cache_dir;
-use rattler_conda_types::{ChannelInfo, PackageRecord, RepoData};
+use rattler_conda_types::package::RunExportsJson;
+use rattler_conda_types::{ChannelInfo, PackageRecord, Platform, RepoData};
 use rattler_repodata_gateway::fetch;
 use rattler_repodata_gateway::fetch::CacheResult;
+use rattler_repodata_gateway::Reporter;
 use reqwest::Client;
 use reqwest_middleware::ClientWithMiddleware;
-use serde::Serialize;
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
 use url::Url;
 
 pub struct RepodataFilenames {
+    /// The requested architectures the channel publishes, in the order requested. The other
+    /// fields are aligned with them.
+    pub architectures: Vec<String>,
     pub noarch: PathBuf,
     pub arches: Vec<PathBuf>,
+    /// The `run_exports.json` of noarch, if requested and available
+    pub noarch_run_exports: Option<PathBuf>,
+    /// The `run_exports.json` of each architecture, if requested and available
+    pub arches_run_exports: Vec<Option<PathBuf>>,
+    /// Where the repodata of each architecture, then noarch, came from, and how old it was.
+    pub caches: Vec<SubdirCache>,
 }
 
+/// How the cached repodata is used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Use the cache while the caching headers of the channel say it is fresh, and fetch otherwise.
+    Normal,
+    /// Only use the cache, however old.
+    Offline,
+    /// Fetch again, even if the cache is fresh.
+    Refresh,
+}
+
+/// Whether the repodata of a subdir was taken from the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheState {
+    /// Taken from the cache without asking the channel.
+    Cached,
+    /// Taken from the cache once the channel confirmed it is fresh.
+    Confirmed,
+    /// Downloaded during this run.
+    Fetched,
+    /// Brought up to date during this run by the JLAP patches of the channel.
+    Patched,
+    /// Read in place from a channel on disk.
+    Local,
+}
+
+/// The repodata of a subdir as fetched. Its age is zero if it was fetched or confirmed fresh by the
+/// channel during this run, else the time since it was last downloaded.
+#[derive(Clone, Debug)]
+pub struct SubdirCache {
+    pub subdir: String,
+    pub url: String,
+    pub state: CacheState,
+    pub age: Duration,
+}
+
+/// The `run_exports.json` of a subdir, which lists the run exports of each package.
+#[derive(Deserialize)]
+pub struct RunExportsIndex {
+    #[serde(default)]
+    packages: HashMap<String, RunExportsEntry>,
+    #[serde(default, rename = "packages.conda")]
+    conda_packages: HashMap<String, RunExportsEntry>,
+}
+
+#[derive(Deserialize)]
+struct RunExportsEntry {
+    #[serde(default)]
+    run_exports: RunExportsJson,
+}
+
+impl RunExportsIndex {
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// The run exports of each package, by package filename.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &RunExportsJson)> {
+        self.packages
+            .iter()
+            .chain(self.conda_packages.iter())
+            .map(|(filename, entry)| (filename.as_str(), &entry.run_exports))
+    }
+
+    /// Add the run exports of the packages of `other` which this has none of.
+    pub fn merge(&mut self, other: RunExportsIndex) {
+        for (filename, entry) in other.packages {
+            self.packages.entry(filename).or_insert(entry);
+        }
+        for (filename, entry) in other.conda_packages {
+            self.conda_packages.entry(filename).or_insert(entry);
+        }
+    }
+}
+
+/// The package signatures a repodata publishes for conda content trust, in its top level
+/// `signatures` section, by package filename. Rattler does not model these, so they are captured
+/// by [`read_repodata`] and written back verbatim.
+#[derive(Default, Deserialize)]
+pub struct Signatures {
+    #[serde(default)]
+    signatures: HashMap<String, serde_json::Value>,
+}
+
+/// The fields of a repodata.json which [`RepoData`] models, and its signatures.
+#[derive(Deserialize)]
+struct SignedRepoData {
+    info: Option<ChannelInfo>,
+    #[serde(default)]
+    packages: FxHashMap<String, PackageRecord>,
+    #[serde(default, rename = "packages.conda")]
+    conda_packages: FxHashMap<String, PackageRecord>,
+    #[serde(default)]
+    removed: FxHashSet<String>,
+    #[serde(rename = "repodata_version")]
+    version: Option<u64>,
+    #[serde(default)]
+    signatures: HashMap<String, serde_json::Value>,
+}
+
+/// Read a repodata.json along with the package signatures it publishes, in a single parse.
+pub fn read_repodata(path: &Path) -> Result<(RepoData, Signatures), std::io::Error> {
+    let contents = fs::read_to_string(path)?;
+    let signed: SignedRepoData = serde_json::from_str(&contents)?;
+    let repodata = RepoData {
+        info: signed.info,
+        packages: signed.packages,
+        conda_packages: signed.conda_packages,
+        removed: signed.removed,
+        version: signed.version,
+    };
+    let signatures = Signatures {
+        signatures: signed.signatures,
+    };
+    Ok((repodata, signatures))
+}
+
+impl Signatures {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+
+    #[must_use]
+    pub fn contains(&self, filename: &str) -> bool {
+        self.signatures.contains_key(filename)
+    }
+
+    /// Add the signatures of `other` for the packages `keep` accepts, unless they have one
+    /// already.
+    pub fn merge(&mut self, other: Signatures, keep: impl Fn(&str) -> bool) {
+        for (filename, signature) in other.signatures {
+            if keep(&filename) {
+                self.signatures.entry(filename).or_insert(signature);
+            }
+        }
+    }
+}
+
+/// The channel each package of a repodata aggregated from several channels is attributed to, in
+/// the `channel` field of its record. Rattler does not model this, so it is read separately.
+#[derive(Default, Deserialize)]
+pub struct OriginChannels {
+    #[serde(default)]
+    packages: HashMap<String, OriginChannel>,
+    #[serde(default, rename = "packages.conda")]
+    conda_packages: HashMap<String, OriginChannel>,
+}
+
+#[derive(Deserialize)]
+struct OriginChannel {
+    channel: Option<String>,
+}
+
+impl OriginChannels {
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)?;
+        if !contents.contains("\"channel\"") {
+            return Ok(OriginChannels::default());
+        }
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// The channel of each package which is attributed to one, by package filename.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.packages
+            .iter()
+            .chain(self.conda_packages.iter())
+            .filter_map(|(filename, origin)| Some((filename.as_str(), origin.channel.as_deref()?)))
+    }
+
+    /// The channel a package is attributed to, if any.
+    #[must_use]
+    pub fn get(&self, filename: &str) -> Option<&str> {
+        self.packages
+            .get(filename)
+            .or_else(|| self.conda_packages.get(filename))?
+            .channel
+            .as_deref()
+    }
+}
+
+impl FromIterator<(String, String)> for OriginChannels {
+    /// Attribute each package filename to a channel.
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        OriginChannels {
+            packages: iter
+                .into_iter()
+                .map(|(filename, channel)| {
+                    let origin = OriginChannel {
+                        channel: Some(channel),
+                    };
+                    (filename, origin)
+                })
+                .collect(),
+            conda_packages: HashMap::new(),
+        }
+    }
+}
+
+/// Fetch the `run_exports.json` of a subdir into the cache, or only look it up in the cache when
+/// offline. That of a channel on disk is read in place.
+async fn fetch_run_exports(
+    subdir_url: &Url,
+    cache: &Path,
+    is_offline: bool,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if subdir_url.scheme() == "file" {
+        let path = subdir_url
+            .join("run_exports.json")?
+            .to_file_path()
+            .map_err(|()| "not a local path")?;
+        if !path.is_file() {
+            return Err(format!("{} does not exist", path.display()).into());
+        }
+        return Ok(path);
+    }
+    let mut path = cache.join("run_exports");
+    fs::create_dir_all(&path)?;
+    path.push(format!(
+        "{}.json",
+        subdir_url
+            .as_str()
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    ));
+    if is_offline {
+        if path.exists() {
+            return Ok(path);
+        }
+        return Err("not in cache".into());
+    }
+    let run_exports_url = subdir_url.join("run_exports.json")?;
+    let body = http_client()
+        .get(run_exports_url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    fs::write(&path, body)?;
+    info!("fetched {run_exports_url}");
+    Ok(path)
+}
+
+/// The client of every request to the channel.
+pub(crate) fn http_client() -> Client {
+    Client::new()
+}
+
+/// The base URL each subdir is fetched from and published under: the channel alias, unless
+/// another URL is mapped to the subdir.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelAliases {
+    default: String,
+    subdirs: HashMap<String, String>,
+}
+
+impl ChannelAliases {
+    /// Parse the values of `--channel-alias`: URLs mapped to a subdir as `SUBDIR=URL`, and at most
+    /// one bare URL for the other subdirs, which otherwise use `default`. A directory on disk may
+    /// be given as a path instead of a URL.
+    pub fn parse(values: &[String], default: &str) -> Result<Self, String> {
+        let mut bare = None;
+        let mut subdirs = HashMap::new();
+        for value in values {
+            let mapping = value
+                .split_once('=')
+                .filter(|(subdir, _)| !subdir.contains([':', '/']));
+            let Some((subdir, url)) = mapping else {
+                if bare.replace(channel_url(value)?).is_some() {
+                    return Err("only one channel alias may be given without a subdir".into());
+                }
+                continue;
+            };
+            Platform::from_str(subdir).map_err(|err| format!("{value}: {err}"))?;
+            if subdirs
+                .insert(subdir.to_string(), channel_url(url)?)
+                .is_some()
+            {
+                return Err(format!("{subdir} is given more than one channel alias"));
+            }
+        }
+        Ok(ChannelAliases {
+            default: match bare {
+                Some(bare) => bare,
+                None => channel_url(default)?,
+            },
+            subdirs,
+        })
+    }
+
+    /// The base URL of the subdir, ending in a slash.
+    #[must_use]
+    pub fn for_subdir(&self, subdir: &str) -> &str {
+        self.subdirs.get(subdir).unwrap_or(&self.default)
+    }
+
+    /// The base URL of the subdirs which are not given a URL of their own, ending in a slash.
+    #[must_use]
+    pub fn default_url(&self) -> &str {
+        &self.default
+    }
+
+    /// The subdirs which are given a URL of their own.
+    pub fn mapped_subdirs(&self) -> impl Iterator<Item = &str> {
+        self.subdirs.keys().map(String::as_str)
+    }
+}
+
+/// The URL of a channel given as a URL, or as a path to a directory on disk, ending in a slash.
+fn channel_url(value: &str) -> Result<String, String> {
+    if value.contains("://") {
+        Url::parse(value).map_err(|err| format!("{value}: {err}"))?;
+        return Ok(with_trailing_slash(value));
+    }
+    let path = std::path::absolute(value).map_err(|err| format!("{value}: {err}"))?;
+    let url = Url::from_directory_path(&path)
+        .map_err(|()| format!("{value} is not a usable directory path"))?;
+    Ok(url.to_string())
+}
+
+pub(crate) fn with_trailing_slash(url: &str) -> String {
+    if url.ends_with('/') {
+        url.to_string()
+    } else {
+        format!("{url}/")
+    }
+}
+
+/// Where the repodata is fetched from, and how it is cached.
+#[derive(Clone, Copy)]
+pub struct RepodataSource<'a> {
+    pub channel_aliases: &'a ChannelAliases,
+    /// The cache, or else the default cache of rattler.
+    pub cache_dir: Option<&'a Path>,
+    pub cache_mode: CacheMode,
+    /// Prefer the sharded repodata of CEP-16 where the channel publishes it.
+    pub use_shards: bool,
+    /// Update a cached repodata.json with the JLAP patches of the channel, where it publishes them,
+    /// rather than downloading it again. This is the default of rattler.
+    pub use_jlap: bool,
+}
+
+/// Fetch the repodata of a subdir, logging whether it came from the cache. Its sharded repodata
+/// is preferred with `use_shards`, falling back to its repodata.json when it has none or the
+/// shards are unusable. With `use_jlap`, a cached repodata.json is patched where the channel
+/// publishes JLAP patches, falling back to downloading it in full when the patches do not apply.
+async fn fetch_subdir(
+    subdir: String,
+    repodata_url: Url,
+    cache: PathBuf,
+    cache_mode: CacheMode,
+    use_shards: bool,
+    use_jlap: bool,
+    reporter: Option<Arc<dyn Reporter>>,
+) -> Result<(PathBuf, SubdirCache), fetch::FetchRepoDataError> {
+    if repodata_url.scheme() == "file" {
+        return local_subdir(subdir, &repodata_url);
+    }
+    if use_shards {
+        match fetch_sharded_subdir(&subdir, &repodata_url, &cache, cache_mode).await {
+            Ok(Some(fetched)) => return Ok(fetched),
+            Ok(None) => debug!("no sharded repodata for {repodata_url}"),
+            Err(err) => warn!(
+                "warning: the sharded repodata of {subdir} is unusable, fetching its \
+                repodata.json instead: {err}"
+            ),
+        }
+    }
+    let client = ClientWithMiddleware::from(http_client());
+    let opts = fetch::FetchRepoDataOptions {
+        cache_action: match cache_mode {
+            CacheMode::Normal => fetch::CacheAction::CacheOrFetch,
+            CacheMode::Offline => fetch::CacheAction::ForceCacheOnly,
+            CacheMode::Refresh => fetch::CacheAction::NoCache,
+        },
+        jlap_enabled: use_jlap,
+        ..Default::default()
+    };
+    let started = SystemTime::now();
+    let result =
+        fetch::fetch_repo_data(repodata_url.clone(), client, cache, opts, reporter).await?;
+    // Patching rewrites the cached repodata.json without updating the time recorded for it.
+    let modified = fs::metadata(&result.repo_data_json_path)
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or(result.cache_state.cache_last_modified);
+    let jlap_tried = use_jlap
+        && result
+            .cache_state
+            .has_jlap
+            .as_ref()
+            .is_some_and(|has_jlap| has_jlap.value);
+    let (state, age) = cache_state(
+        &repodata_url,
+        result.cache_result,
+        result.cache_state.jlap.is_some(),
+        jlap_tried,
+        modified,
+        started,
+    );
+    let cache = SubdirCache {
+        subdir,
+        url: repodata_url.to_string(),
+        state,
+        age,
+    };
+    Ok((result.repo_data_json_path, cache))
+}
+
+/// How the cached repodata.json of `repodata_url` was brought up to date by a fetch begun at
+/// `started`, logging it. `patchable` is whether the cache records where the JLAP patches of the
+/// channel left off, and `jlap_tried` whether the channel publishes patches and they were enabled.
+fn cache_state(
+    repodata_url: &Url,
+    cache_result: CacheResult,
+    patchable: bool,
+    jlap_tried: bool,
+    modified: SystemTime,
+    started: SystemTime,
+) -> (CacheState, Duration) {
+    match cache_result {
+        CacheResult::CacheHit => {
+            debug!("cached {repodata_url}");
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            (CacheState::Cached, age)
+        }
+        // The patches were applied to the cache, unless there were none yet to apply.
+        CacheResult::CacheOutdated if patchable => {
+            if modified >= started {
+                info!("patched {repodata_url}");
+                (CacheState::Patched, Duration::ZERO)
+            } else {
+                debug!("cached {repodata_url}, confirmed fresh by its patches");
+                (CacheState::Confirmed, Duration::ZERO)
+            }
+        }
+        CacheResult::CacheHitAfterFetch => {
+            debug!("cached {repodata_url}, confirmed fresh");
+            (CacheState::Confirmed, Duration::ZERO)
+        }
+        CacheResult::CacheOutdated if jlap_tried => {
+            // The patches were tried against the cache, and rattler fell back to the full download.
+            warn!("warning: the JLAP patches of {repodata_url} did not apply, fetched it in full");
+            (CacheState::Fetched, Duration::ZERO)
+        }
+        CacheResult::CacheOutdated | CacheResult::CacheNotPresent => {
+            info!("fetched {repodata_url}");
+            (CacheState::Fetched, Duration::ZERO)
+        }
+    }
+}
+
+/// The repodata of a subdir of a channel on disk, which is read in place rather than copied into
+/// the cache, offline too.
+fn local_subdir(
+    subdir: String,
+    subdir_url: &Url,
+) -> Result<(PathBuf, SubdirCache), fetch::FetchRepoDataError> {
+    let not_found = |message: String| {
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, message);
+        fetch::FetchRepoDataError::NotFound(err.into())
+    };
+    let directory = subdir_url
+        .to_file_path()
+        .map_err(|()| not_found(format!("{subdir_url} is not a local path")))?;
+    let path = directory.join("repodata.json");
+    if !path.is_file() {
+        return Err(not_found(format!("{} does not exist", path.display())));
+    }
+    debug!("local {}", path.display());
+    let cache = SubdirCache {
+        subdir,
+        url: subdir_url.to_string(),
+        state: CacheState::Local,
+        age: Duration::ZERO,
+    };
+    Ok((path, cache))
+}
+
+/// Fetch the repodata of every architecture and noarch, along with their `run_exports.json` if
+/// `with_run_exports`, into the cache of the source. An architecture the channel does not publish
+/// is left out of the run with a warning, unless `strict_architectures`, when it fails the fetch
+/// like any other error.
 pub async fn fetch_repodata(
-    channel_alias: &str,
+    source: &RepodataSource<'_>,
     architectures: &[String],
-    is_offline: bool,
+    with_run_exports: bool,
+    strict_architectures: bool,
+    progress: &Progress,
 ) -> Result<RepodataFilenames, Box<dyn std::error::Error>> {
-    let cache = &default_cache_dir()?;
+    let RepodataSource {
+        channel_aliases,
+        cache_dir,
+        cache_mode,
+        use_shards,
+        use_jlap,
+    } = *source;
+    let cache = &match cache_dir {
+        Some(cache_dir) => cache_dir.to_path_buf(),
+        None => default_cache_dir()?,
+    };
     let all_architectures = architectures.iter().map(String::as_str).chain(["noarch"]);
     let repodata_urls: Vec<Url> = all_architectures
-        .map(|architecture| Url::parse(&(format!("{channel_alias}{architecture}/"))))
+        .clone()
+        .map(|architecture| {
+            let channel_alias = channel_aliases.for_subdir(architecture);
+            Url::parse(&(format!("{channel_alias}{architecture}/")))
+        })
         .collect::<Result<Vec<Url>, _>>()?;
-    let mut repodata_fns: Vec<PathBuf> = futures::stream::iter(repodata_urls)
-        .map(|repodata_url| {
-            let client = ClientWithMiddleware::from(Client::new());
-            let mut opts = fetch::FetchRepoDataOptions {
-                ..Default::default()
-            };
-            if is_offline {
-                opts.cache_action = fetch::CacheAction::ForceCacheOnly;
+    let reporter = progress.download_reporter();
+    let mut fetched: Vec<Result<(PathBuf, SubdirCache), fetch::FetchRepoDataError>> =
+        futures::stream::iter(all_architectures.zip(repodata_urls.iter().cloned()))
+            .map(|(subdir, repodata_url)| {
+                fetch_subdir(
+                    subdir.to_string(),
+                    repodata_url,
+                    cache.clone(),
+                    cache_mode,
+                    use_shards,
+                    use_jlap,
+                    reporter.clone(),
+                )
+            })
+            .buffered(20)
+            .collect()
+            .await;
+
+    let (noarch, noarch_cache) = fetched.pop().unwrap().map_err(|err| match err {
+        fetch::FetchRepoDataError::NotFound(err) => {
+            format!("the channel does not publish noarch: {err}")
+        }
+        err => err.to_string(),
+    })?;
+    let mut caches = Vec::with_capacity(repodata_urls.len());
+    let mut fetched_architectures = Vec::with_capacity(architectures.len());
+    let mut repodata_fns = Vec::with_capacity(architectures.len());
+    let mut subdir_urls = Vec::with_capacity(repodata_urls.len());
+    for ((architecture, subdir_url), result) in
+        architectures.iter().zip(&repodata_urls).zip(fetched)
+    {
+        match result {
+            Ok((path, cache)) => {
+                caches.push(cache);
+                fetched_architectures.push(architecture.clone());
+                repodata_fns.push(path);
+                subdir_urls.push(subdir_url);
             }
-            async move {
-                let result =
-                    fetch::fetch_repo_data(repodata_url.clone(), client, cache.clone(), opts, None)
-                        .await;
-                result.map(|result| {
-                    match &result.cache_result {
-                        CacheResult::CacheHit | CacheResult::CacheHitAfterFetch => {}
-                        CacheResult::CacheOutdated | CacheResult::CacheNotPresent => {
-                            println!("fetched {repodata_url}");
-                        }
-                    }
-
-                    result.repo_data_json_path
-                })
+            Err(fetch::FetchRepoDataError::NotFound(err)) => {
+                if strict_architectures {
+                    return Err(
+                        format!("the channel does not publish {architecture}: {err}").into(),
+                    );
+                }
+                warn!("warning: {architecture} skipped, the channel does not publish it: {err}");
             }
-        })
-        .buffered(20)
-        .try_collect()
-        .await?;
+            Err(err) => return Err(err.into()),
+        }
+    }
+    subdir_urls.push(repodata_urls.last().unwrap());
+    caches.push(noarch_cache);
 
-    let noarch = repodata_fns.pop().unwrap();
+    let mut run_exports_fns: Vec<Option<PathBuf>> = Vec::with_capacity(subdir_urls.len());
+    for subdir_url in subdir_urls {
+        if !with_run_exports {
+            run_exports_fns.push(None);
+            continue;
+        }
+        match fetch_run_exports(subdir_url, cache, cache_mode == CacheMode::Offline).await {
+            Ok(path) => run_exports_fns.push(Some(path)),
+            Err(err) => {
+                warn!("warning: run_exports.json unavailable for {subdir_url}: {err}");
+                run_exports_fns.push(None);
+            }
+        }
+    }
+    let noarch_run_exports = run_exports_fns.pop().unwrap();
 
     Ok(RepodataFilenames {
+        architectures: fetched_architectures,
         noarch,
         arches: repodata_fns,
+        noarch_run_exports,
+        arches_run_exports: run_exports_fns,
+        caches,
     })
 }
 
+/// A subdir the channel publishes.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PublishedSubdir {
+    pub subdir: String,
+    /// The URL of its repodata.json.
+    pub url: String,
+    /// The size of its repodata.json, when known.
+    pub size: Option<u64>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// The `.info.json` rattler keeps alongside each cached repodata.json.
+#[derive(Deserialize)]
+struct CachedRepodataInfo {
+    url: String,
+    size: u64,
+    mtime_ns: u64,
+    #[serde(rename = "mod")]
+    last_modified: Option<String>,
+}
+
+/// The `subdirs` of the `channeldata.json` of a channel.
+#[derive(Deserialize)]
+struct ChannelData {
+    #[serde(default)]
+    subdirs: Vec<String>,
+}
+
+/// Find which of `subdirs` the channel publishes, along with those its `channeldata.json` lists,
+/// if it has one. Offline, the cache in `cache_dir`, or else the default cache of rattler, is
+/// inspected instead, giving the subdirs fetched before.
+pub async fn list_subdirs(
+    channel_aliases: &ChannelAliases,
+    subdirs: &[String],
+    is_offline: bool,
+    cache_dir: Option<&Path>,
+) -> Result<Vec<PublishedSubdir>, Box<dyn std::error::Error>> {
+    let mut subdirs: BTreeSet<String> = subdirs.iter().cloned().collect();
+    let repodata_url = |subdir: &str| {
+        Url::parse(&format!(
+            "{}{subdir}/repodata.json",
+            channel_aliases.for_subdir(subdir)
+        ))
+    };
+    let published = if is_offline {
+        let cache = match cache_dir {
+            Some(cache_dir) => cache_dir.to_path_buf(),
+            None => default_cache_dir()?,
+        };
+        let cached = read_cached_repodata_infos(&cache)?;
+        subdirs
+            .iter()
+            .filter_map(|subdir| {
+                let url = repodata_url(subdir).ok()?;
+                cached
+                    .iter()
+                    .filter(|info| info.url.starts_with(url.as_str()))
+                    .max_by_key(|info| info.mtime_ns)
+                    .map(|info| PublishedSubdir {
+                        subdir: subdir.clone(),
+                        url: url.to_string(),
+                        size: Some(info.size),
+                        last_modified: info
+                            .last_modified
+                            .as_deref()
+                            .and_then(parse_http_date)
+                            .or_else(|| {
+                                let mtime_ns = i64::try_from(info.mtime_ns).ok()?;
+                                Some(DateTime::from_timestamp_nanos(mtime_ns))
+                            }),
+                    })
+            })
+            .collect()
+    } else {
+        let channeldata_url =
+            Url::parse(channel_aliases.default_url())?.join("channeldata.json")?;
+        match fetch_channeldata(&channeldata_url).await {
+            Ok(channeldata) => subdirs.extend(channeldata.subdirs),
+            Err(err) => debug!("no channeldata.json at {channeldata_url}: {err}"),
+        }
+        let probed: Vec<Result<Option<PublishedSubdir>, Box<dyn std::error::Error>>> =
+            futures::stream::iter(&subdirs)
+                .map(|subdir| async move { probe_subdir(subdir, repodata_url(subdir)?).await })
+                .buffered(20)
+                .collect()
+                .await;
+        probed
+            .into_iter()
+            .filter_map(Result::transpose)
+            .collect::<Result<_, _>>()?
+    };
+    Ok(published)
+}
+
+fn read_cached_repodata_infos(
+    cache: &Path,
+) -> Result<Vec<CachedRepodataInfo>, Box<dyn std::error::Error>> {
+    let mut infos = Vec::new();
+    let entries = match fs::read_dir(cache) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(infos),
+        entries => entries.map_err(|err| format!("{}: {err}", cache.display()))?,
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with(".info.json") {
+            continue;
+        }
+        match serde_json::from_str::<CachedRepodataInfo>(&fs::read_to_string(&path)?) {
+            Ok(mut info) => {
+                // JLAP patches rewrite the repodata.json without updating the size recorded for it.
+                let json_path = path.to_string_lossy().replace(".info.json", ".json");
+                if let Ok(metadata) = fs::metadata(json_path) {
+                    info.size = metadata.len();
+                }
+                infos.push(info);
+            }
+            Err(err) => debug!("{} skipped: {err}", path.display()),
+        }
+    }
+    Ok(infos)
+}
+
+async fn fetch_channeldata(url: &Url) -> Result<ChannelData, Box<dyn std::error::Error>> {
+    let body = if url.scheme() == "file" {
+        fs::read(url.to_file_path().map_err(|()| "not a local path")?)?
+    } else {
+        http_client()
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec()
+    };
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Ask the channel for the repodata.json of a subdir, or None if it does not publish the subdir.
+/// Channels which refuse HEAD requests are sent a GET, whose body is left unread.
+async fn probe_subdir(
+    subdir: &str,
+    url: Url,
+) -> Result<Option<PublishedSubdir>, Box<dyn std::error::Error>> {
+    let (size, last_modified) = if url.scheme() == "file" {
+        let path = url.to_file_path().map_err(|()| "not a local path")?;
+        let metadata = match fs::metadata(path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            metadata => metadata?,
+        };
+        (
+            Some(metadata.len()),
+            Some(DateTime::<Utc>::from(metadata.modified()?)),
+        )
+    } else {
+        let client = http_client();
+        let mut response = client.head(url.clone()).send().await?;
+        if response.status() == reqwest::StatusCode::METHOD_NOT_ALLOWED {
+            response = client.get(url.clone()).send().await?;
+        }
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        // The length of the body of a HEAD response is zero, so read the header instead.
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        (
+            header(reqwest::header::CONTENT_LENGTH).and_then(|length| length.parse().ok()),
+            header(reqwest::header::LAST_MODIFIED).and_then(parse_http_date),
+        )
+    };
+    Ok(Some(PublishedSubdir {
+        subdir: subdir.to_string(),
+        url: url.to_string(),
+        size,
+        last_modified,
+    }))
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// How the output repodata is written.
+#[derive(Clone, Copy, Default)]
+pub struct OutputFormat<'a> {
+    /// The `curation` marker of `--stamp-info`, added to the `info` of the repodata.
+    pub stamp: Option<&'a serde_json::Value>,
+    /// The zstd level of the `repodata.json.zst` written alongside the `repodata.json`, if any.
+    pub zst_level: Option<i32>,
+    /// Whether to write a `repodata.json.bz2` alongside the `repodata.json`.
+    pub bz2: bool,
+    /// Whether to write `current_repodata.json`, and its variants, instead of `repodata.json`.
+    pub current: bool,
+}
+
+/// The sizes of the files of a repodata as written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WrittenRepodata {
+    pub json_size: u64,
+    pub zst_size: Option<u64>,
+    pub bz2_size: Option<u64>,
+}
+
+/// Write the repodata of a subdir without the packages `predicate` rejects, along with its
+/// `repodata.json.zst` and `repodata.json.bz2`, compressed from the same bytes, when the format
+/// asks for them.
 pub fn filtered_repodata_to_file<'a>(
     initial: &'a RepoData,
     output_dir: &std::path::Path,
     mut predicate: impl FnMut(&'a str) -> bool,
     subdir: &str,
     possible_replacement_base_url: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // This is like the RepoData from Rattler, except is built out of references.
+    signatures: &'a Signatures,
+    format: OutputFormat,
+) -> Result<WrittenRepodata, Box<dyn std::error::Error>> {
+    // This is like the RepoData from Rattler, except is built out of references. It is sorted so
+    // that the same packages always give the same file.
     #[derive(Debug, Serialize)]
     struct RefRepoData<'a> {
-        info: Option<ChannelInfo>,
-        packages: HashMap<&'a str, &'a PackageRecord>,
+        info: Option<RefChannelInfo>,
+        packages: BTreeMap<&'a str, &'a PackageRecord>,
         #[serde(rename = "packages.conda")]
-        conda_packages: HashMap<&'a str, &'a PackageRecord>,
-        removed: HashSet<&'a str>,
+        conda_packages: BTreeMap<&'a str, &'a PackageRecord>,
+        removed: BTreeSet<&'a str>,
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        signatures: BTreeMap<&'a str, &'a serde_json::Value>,
         #[serde(rename = "repodata_version")]
         version: Option<u64>,
     }
 
+    // The `info` of the repodata, with the `curation` marker of `--stamp-info`.
+    #[derive(Debug, Serialize)]
+    struct RefChannelInfo {
+        #[serde(flatten)]
+        info: ChannelInfo,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        curation: Option<serde_json::Value>,
+    }
+
     let mut filepath = output_dir.to_path_buf();
     filepath.push(subdir);
     fs::create_dir_all(&filepath).expect("Failed to create directory for arch");
-    filepath.push("repodata.json");
+    filepath.push(if format.current {
+        "current_repodata.json"
+    } else {
+        "repodata.json"
+    });
     let filename = filepath;
 
     let mut out = RefRepoData {
-        info: initial.info.clone(),
+        info: None,
         removed: initial.removed.iter().map(String::as_str).collect(),
         version: initial.version,
-        packages: HashMap::with_capacity(initial.packages.len()),
-        conda_packages: HashMap::with_capacity(initial.conda_packages.len()),
+        packages: BTreeMap::new(),
+        conda_packages: BTreeMap::new(),
+        signatures: BTreeMap::new(),
     };
 
     out.packages.extend(
@@ -111,12 +907,28 @@ pub fn filtered_repodata_to_file<'a>(
             .filter(|(package_filename, _)| predicate(package_filename)),
     );
 
+    out.signatures.extend(
+        signatures
+            .signatures
+            .iter()
+            .map(|(pkfn, signature)| (pkfn.as_str(), signature))
+            .filter(|(pkfn, _)| {
+                out.packages.contains_key(pkfn) || out.conda_packages.contains_key(pkfn)
+            }),
+    );
+
+    // Packages which are kept after all are no longer removed.
+    out.removed.retain(|filename| {
+        !out.packages.contains_key(filename) && !out.conda_packages.contains_key(filename)
+    });
+
+    let mut info = initial.info.clone();
     if initial.base_url().is_none() {
         // In conda's unit tests, they did not include a trailing slash on base_url.
         let url = Some(format!("{possible_replacement_base_url}{subdir}"));
-        match out.info {
+        match info {
             None => {
-                out.info = Some(ChannelInfo {
+                info = Some(ChannelInfo {
                     subdir: subdir.to_string(),
                     base_url: url,
                 });
@@ -124,14 +936,69 @@ pub fn filtered_repodata_to_file<'a>(
             Some(ref mut info) => info.base_url = url,
         }
     }
+    out.info = info.map(|info| RefChannelInfo {
+        info,
+        curation: format.stamp.cloned(),
+    });
     out.version = Some(2);
 
-    {
-        let repodata = serde_json::to_string(&out)?;
-        fs::write(filename, repodata)?;
+    let repodata = serde_json::to_vec(&out)?;
+    let (zst, bz2) = rayon::join(
+        || {
+            format
+                .zst_level
+                .map(|level| zstd::encode_all(repodata.as_slice(), level))
+                .transpose()
+        },
+        || format.bz2.then(|| compress_bz2(&repodata)).transpose(),
+    );
+    write_if_changed(&filename, &repodata)?;
+    Ok(WrittenRepodata {
+        json_size: repodata.len() as u64,
+        zst_size: write_compressed(&filename.with_extension("json.zst"), zst?)?,
+        bz2_size: write_compressed(&filename.with_extension("json.bz2"), bz2?)?,
+    })
+}
+
+fn compress_bz2(contents: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+/// Write a compressed variant of a repodata.json, returning its size, or remove the one an earlier
+/// run left if there is none: clients prefer the variants, so it would shadow the repodata.json.
+fn write_compressed(
+    filename: &Path,
+    compressed: Option<Vec<u8>>,
+) -> Result<Option<u64>, std::io::Error> {
+    let Some(compressed) = compressed else {
+        return match fs::remove_file(filename) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(None),
+        };
+    };
+    write_if_changed(filename, &compressed)?;
+    Ok(Some(compressed.len() as u64))
+}
+
+/// Write a file, unless it already has these contents, so that its mirrors and caches are left
+/// alone too.
+fn write_if_changed(filename: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    if fs::read(filename).is_ok_and(|existing| existing == contents) {
+        debug!("unchanged {}", filename.display());
+        return Ok(());
     }
+    write_atomically(filename, contents)
+}
 
-    Ok(())
+/// Write a file through a sibling file, so that it is never seen incomplete.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let mut partial_name = path.as_os_str().to_owned();
+    partial_name.push(".partial");
+    let partial = PathBuf::from(partial_name);
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, path)
 }
 
 #[must_use]
@@ -153,3 +1020,379 @@ pub fn sorted_iter<'a>(repodatas: &[&'a RepoData]) -> Vec<(&'a String, &'a Packa
     });
     everything
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::progress::Progress;
+    use crate::rawrepodata::{
+        cache_state, fetch_repodata, fetch_subdir, filtered_repodata_to_file, list_subdirs,
+        read_repodata, CacheMode, CacheState, ChannelAliases, OutputFormat, RepodataSource,
+        Signatures,
+    };
+    use rattler_conda_types::RepoData;
+    use rattler_repodata_gateway::fetch::CacheResult;
+    use std::io::Read;
+    use std::time::{Duration, SystemTime};
+    use url::Url;
+
+    #[test]
+    fn channel_aliases_by_subdir() {
+        let default = "https://conda.anaconda.org/conda-forge/";
+        let aliases = ChannelAliases::parse(
+            &[
+                "linux-64=https://linux.example.com/conda-forge".to_string(),
+                "https://mirror.example.com/conda-forge?token=a=b".to_string(),
+                "noarch=https://noarch.example.com/conda-forge/".to_string(),
+            ],
+            default,
+        )
+        .unwrap();
+        assert_eq!(
+            aliases.for_subdir("linux-64"),
+            "https://linux.example.com/conda-forge/"
+        );
+        assert_eq!(
+            aliases.for_subdir("noarch"),
+            "https://noarch.example.com/conda-forge/"
+        );
+        assert_eq!(
+            aliases.for_subdir("win-64"),
+            "https://mirror.example.com/conda-forge?token=a=b/"
+        );
+        let mut mapped: Vec<&str> = aliases.mapped_subdirs().collect();
+        mapped.sort_unstable();
+        assert_eq!(mapped, ["linux-64", "noarch"]);
+
+        let aliases =
+            ChannelAliases::parse(&["win-64=https://win.example.com/".to_string()], default)
+                .unwrap();
+        assert_eq!(aliases.for_subdir("osx-arm64"), default);
+        assert!(ChannelAliases::parse(&["linx-64=https://a/".to_string()], default).is_err());
+        assert!(ChannelAliases::parse(
+            &["https://a/".to_string(), "https://b/".to_string()],
+            default
+        )
+        .is_err());
+        assert!(ChannelAliases::parse(&["https://a b/".to_string()], default).is_err());
+
+        let aliases = ChannelAliases::parse(
+            &[
+                "/srv/channels/conda-forge".to_string(),
+                "noarch=file:///srv/channels/noarch/".to_string(),
+            ],
+            default,
+        )
+        .unwrap();
+        assert_eq!(
+            aliases.for_subdir("linux-64"),
+            "file:///srv/channels/conda-forge/"
+        );
+        assert_eq!(aliases.for_subdir("noarch"), "file:///srv/channels/noarch/");
+    }
+
+    #[test]
+    fn local_channels_are_read_in_place() {
+        let root = std::env::temp_dir().join(format!("localchannel-{}", std::process::id()));
+        let channel = root.join("channel");
+        for subdir in ["linux-64", "noarch"] {
+            std::fs::create_dir_all(channel.join(subdir)).unwrap();
+            std::fs::write(channel.join(subdir).join("repodata.json"), "{}").unwrap();
+        }
+        let aliases = ChannelAliases::parse(&[channel.display().to_string()], "").unwrap();
+        let fetch = |architectures: &[&str], strict_architectures| {
+            let source = RepodataSource {
+                channel_aliases: &aliases,
+                cache_dir: Some(&root.join("cache")),
+                cache_mode: CacheMode::Offline,
+                use_shards: true,
+                use_jlap: false,
+            };
+            let architectures: Vec<String> =
+                architectures.iter().map(ToString::to_string).collect();
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(fetch_repodata(
+                &source,
+                &architectures,
+                true,
+                strict_architectures,
+                &Progress::new(true),
+            ))
+        };
+        let fetched = fetch(&["linux-64", "osx-64"], false).unwrap();
+        let strict = fetch(&["linux-64", "osx-64"], true).err().unwrap();
+        std::fs::remove_dir_all(channel.join("noarch")).unwrap();
+        let without_noarch = fetch(&["linux-64"], false).err().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(fetched.architectures, ["linux-64"]);
+        assert_eq!(fetched.noarch, channel.join("noarch").join("repodata.json"));
+        assert_eq!(fetched.arches_run_exports, [None]);
+        assert!(fetched
+            .caches
+            .iter()
+            .all(|cache| cache.state == CacheState::Local));
+        assert!(!root.join("cache").exists());
+        assert_eq!(
+            strict.to_string(),
+            format!(
+                "the channel does not publish osx-64: {} does not exist",
+                channel.join("osx-64").join("repodata.json").display()
+            )
+        );
+        assert!(without_noarch
+            .to_string()
+            .starts_with("the channel does not publish noarch: "));
+    }
+
+    #[test]
+    fn patched_or_confirmed_by_the_time_of_the_cache() {
+        let url = Url::parse("https://conda.example.com/channel/linux-64/").unwrap();
+        let started = SystemTime::now();
+        let state = |modified| {
+            cache_state(
+                &url,
+                CacheResult::CacheOutdated,
+                true,
+                true,
+                modified,
+                started,
+            )
+        };
+        assert_eq!(
+            state(started + Duration::from_secs(1)).0,
+            CacheState::Patched
+        );
+        assert_eq!(state(started).0, CacheState::Patched);
+        assert_eq!(
+            state(started - Duration::from_secs(60)).0,
+            CacheState::Confirmed
+        );
+        assert_eq!(
+            cache_state(&url, CacheResult::CacheHit, true, true, started, started).0,
+            CacheState::Cached
+        );
+    }
+
+    #[test]
+    fn unapplied_patches_warn_of_the_full_download() {
+        let log = std::env::temp_dir().join(format!("jlapfallback-{}.log", std::process::id()));
+        std::fs::write(&log, "").unwrap();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || {
+                std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&writer)
+                    .unwrap()
+            })
+            .finish();
+        let url = Url::parse("https://conda.example.com/channel/linux-64/").unwrap();
+        let now = SystemTime::now();
+        let (tried, untried) = tracing::subscriber::with_default(subscriber, || {
+            let tried = cache_state(&url, CacheResult::CacheOutdated, false, true, now, now);
+            let untried = cache_state(&url, CacheResult::CacheOutdated, false, false, now, now);
+            (tried, untried)
+        });
+        let logged = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_file(&log).unwrap();
+
+        assert_eq!(tried.0, CacheState::Fetched);
+        assert_eq!(untried.0, CacheState::Fetched);
+        let warnings: Vec<&str> = logged
+            .lines()
+            .filter(|line| line.contains("WARN"))
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].ends_with(
+            "warning: the JLAP patches of https://conda.example.com/channel/linux-64/ did not \
+            apply, fetched it in full"
+        ));
+    }
+
+    #[test]
+    fn offline_reads_a_patched_cache() {
+        let cache = std::env::temp_dir().join(format!("jlapcache-{}", std::process::id()));
+        std::fs::create_dir_all(&cache).unwrap();
+        // The cache key of rattler, the start of the MD5 digest of the subdir URL.
+        let key = format!(
+            "{:x}",
+            rattler_digest::compute_bytes_digest::<rattler_digest::Md5>(
+                "https://conda.example.com/channel/linux-64"
+            )
+        );
+        let json = cache.join(format!("{}.json", &key[..8]));
+        let repodata = r#"{"info": {"subdir": "linux-64"}, "packages": {}, "packages.conda": {}}"#;
+        std::fs::write(&json, repodata).unwrap();
+        let modified = std::fs::metadata(&json).unwrap().modified().unwrap();
+        // As patching leaves it: the digest of the rewritten file, the size from before, and where
+        // the patches left off.
+        let digest = rattler_digest::compute_bytes_digest::<rattler_digest::Blake2b256>(repodata);
+        let info = serde_json::json!({
+            "url": "https://conda.example.com/channel/linux-64/repodata.json",
+            "mtime_ns": modified.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() * 1_000_000_000,
+            "size": 1,
+            "blake2_hash": format!("{digest:x}"),
+            "blake2_hash_nominal": "0".repeat(64),
+            "has_jlap": {"value": true, "last_checked": "2026-10-01T00:00:00Z"},
+            "jlap": {
+                "iv": "0".repeat(64),
+                "pos": 4096,
+                "footer": {"url": "repodata.json", "latest": "0".repeat(64)},
+            },
+        });
+        std::fs::write(
+            cache.join(format!("{}.info.json", &key[..8])),
+            info.to_string(),
+        )
+        .unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let fetched = runtime.block_on(fetch_subdir(
+            "linux-64".to_string(),
+            Url::parse("https://conda.example.com/channel/linux-64/").unwrap(),
+            cache.clone(),
+            CacheMode::Offline,
+            false,
+            true,
+            None,
+        ));
+        std::fs::remove_dir_all(&cache).unwrap();
+
+        let (path, subdir_cache) = fetched.unwrap();
+        assert_eq!(path, json);
+        assert_eq!(subdir_cache.state, CacheState::Cached);
+    }
+
+    #[test]
+    fn subdirs_of_a_channel() {
+        let root = std::env::temp_dir().join(format!("listsubdirs-{}", std::process::id()));
+        let channel = root.join("channel");
+        let cache = root.join("cache");
+        for subdir in ["linux-64", "noarch", "unlisted-64"] {
+            std::fs::create_dir_all(channel.join(subdir)).unwrap();
+            std::fs::write(channel.join(subdir).join("repodata.json"), "{}").unwrap();
+        }
+        std::fs::write(
+            channel.join("channeldata.json"),
+            r#"{"subdirs": ["linux-64", "noarch", "zos-z"]}"#,
+        )
+        .unwrap();
+        std::fs::create_dir_all(&cache).unwrap();
+        let channel_url = Url::from_directory_path(&channel).unwrap();
+        std::fs::write(
+            cache.join("0123abcd.info.json"),
+            format!(
+                r#"{{"url": "{channel_url}linux-64/repodata.json", "mod": "Tue, 01 Oct 2024 12:00:00 GMT", "mtime_ns": 1727784000000000000, "size": 2048}}"#
+            ),
+        )
+        .unwrap();
+        let aliases = ChannelAliases::parse(&[channel_url.to_string()], "").unwrap();
+        let list = |is_offline| {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let subdirs = ["linux-64".to_string(), "osx-64".to_string()];
+            runtime
+                .block_on(list_subdirs(&aliases, &subdirs, is_offline, Some(&cache)))
+                .unwrap()
+        };
+        let online = list(false);
+        let offline = list(true);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let subdirs: Vec<&str> = online.iter().map(|subdir| subdir.subdir.as_str()).collect();
+        assert_eq!(subdirs, ["linux-64", "noarch"]);
+        assert_eq!(online[0].size, Some(2));
+        assert_eq!(online[1].url, format!("{channel_url}noarch/repodata.json"));
+        assert_eq!(offline.len(), 1);
+        assert_eq!(offline[0].size, Some(2048));
+        assert_eq!(
+            offline[0].last_modified.unwrap().to_rfc3339(),
+            "2024-10-01T12:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn signatures_are_read_with_the_repodata() {
+        let filename = std::env::temp_dir().join(format!("signed-{}.json", std::process::id()));
+        std::fs::write(
+            &filename,
+            r#"{"info": {"subdir": "linux-64"}, "packages.conda": {
+                "a-1.0-0.conda": {"name": "a", "version": "1.0", "build": "0", "build_number": 0},
+                "b-1.0-0.conda": {"name": "b", "version": "1.0", "build": "0", "build_number": 0}
+            }, "removed": ["c-1.0-0.conda"], "repodata_version": 1,
+            "signatures": {"a-1.0-0.conda": {"abcd": {"signature": "ef01"}}}}"#,
+        )
+        .unwrap();
+        let (repodata, signatures) = read_repodata(&filename).unwrap();
+        let unsigned = RepoData::from_path(&filename).unwrap();
+        std::fs::write(&filename, r#"{"packages": {}}"#).unwrap();
+        let (_, none) = read_repodata(&filename).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+
+        assert_eq!(repodata, unsigned);
+        assert!(signatures.contains("a-1.0-0.conda"));
+        assert!(!signatures.contains("b-1.0-0.conda"));
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn variants_are_compressed_from_the_json() {
+        let directory = std::env::temp_dir().join(format!("zst-{}", std::process::id()));
+        let repodata: RepoData = serde_json::from_str(
+            r#"{"packages.conda": {
+                "a-1.0-0.conda": {"name": "a", "version": "1.0", "build": "0", "build_number": 0},
+                "b-1.0-0.conda": {"name": "b", "version": "1.0", "build": "0", "build_number": 0}
+            }}"#,
+        )
+        .unwrap();
+        let write = |zst_level| {
+            filtered_repodata_to_file(
+                &repodata,
+                &directory,
+                |filename| filename != "b-1.0-0.conda",
+                "linux-64",
+                "https://example.com/",
+                &Signatures::default(),
+                OutputFormat {
+                    stamp: None,
+                    zst_level,
+                    bz2: zst_level.is_some(),
+                    current: false,
+                },
+            )
+            .unwrap()
+        };
+        let json_filename = directory.join("linux-64").join("repodata.json");
+        let zst_filename = directory.join("linux-64").join("repodata.json.zst");
+
+        let written = write(Some(3));
+        let json = std::fs::read(&json_filename).unwrap();
+        let zst = std::fs::read(&zst_filename).unwrap();
+        assert_eq!(zstd::decode_all(zst.as_slice()).unwrap(), json);
+        let mut bz2 = Vec::new();
+        bzip2::read::BzDecoder::new(
+            std::fs::read(directory.join("linux-64").join("repodata.json.bz2"))
+                .unwrap()
+                .as_slice(),
+        )
+        .read_to_end(&mut bz2)
+        .unwrap();
+        assert_eq!(bz2, json);
+        assert!(written.bz2_size.is_some());
+        assert_eq!(written.json_size, json.len() as u64);
+        assert_eq!(written.zst_size, Some(zst.len() as u64));
+        assert!(!String::from_utf8(json).unwrap().contains("b-1.0-0.conda"));
+
+        let written = write(None);
+        let zst_exists = zst_filename.exists();
+        let remaining: Vec<_> = std::fs::read_dir(directory.join("linux-64"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(written.zst_size, None);
+        assert_eq!(written.bz2_size, None);
+        assert!(!zst_exists);
+        assert_eq!(remaining, ["repodata.json"]);
+    }
+}
@@ -0,0 +1,575 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// The version of the schema of the report, increased whenever a field is removed or changes
+/// meaning.
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+/// A machine readable account of a whole run, as written by `--report-json`.
+#[derive(Serialize)]
+pub struct RunReport {
+    pub schema_version: u32,
+    pub architectures: Vec<ArchitectureReport>,
+    pub resources: ResourceReport,
+}
+
+impl RunReport {
+    #[must_use]
+    pub fn new(architectures: Vec<ArchitectureReport>, resources: ResourceReport) -> Self {
+        RunReport {
+            schema_version: REPORT_SCHEMA_VERSION,
+            architectures,
+            resources,
+        }
+    }
+
+    pub fn write(&self, filename: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        std::fs::write(filename, json)?;
+        Ok(())
+    }
+}
+
+/// The outcome of filtering one architecture, together with the noarch packages.
+#[derive(Serialize)]
+pub struct ArchitectureReport {
+    pub architecture: String,
+    pub packages_before: usize,
+    pub packages_after: usize,
+    /// Every round in the order it was performed. Each round applies a single rule, though a
+    /// rule may be applied by several rounds.
+    pub rounds: Vec<RoundReport>,
+    /// The package names with the most removed packages, most removed first.
+    pub most_removed: Vec<NameRemovals>,
+    /// The statistics of `--stats-only`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<RemovalStatistics>,
+}
+
+impl ArchitectureReport {
+    /// The share of the packages which was removed, between 0 and 1.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn removed_share(&self) -> f64 {
+        if self.packages_before == 0 {
+            return 0.0;
+        }
+        (self.packages_before - self.packages_after) as f64 / self.packages_before as f64
+    }
+
+    /// The packages removed by each rule, most first, the unresolveable rounds counted together.
+    #[must_use]
+    pub fn removed_by_rule(&self) -> Vec<RuleRemovals> {
+        let mut by_rule: Vec<RuleRemovals> = Vec::new();
+        for round in &self.rounds {
+            add_removals(&mut by_rule, rule_of_round(&round.label), round.removed);
+        }
+        by_rule.sort_by_key(|rule| std::cmp::Reverse(rule.removed));
+        by_rule
+    }
+}
+
+/// The rule a round applies, the unresolveable rounds being numbered.
+fn rule_of_round(label: &str) -> &str {
+    if label.starts_with("No Sln Round") {
+        "unresolveable"
+    } else {
+        label
+    }
+}
+
+fn add_removals(by_rule: &mut Vec<RuleRemovals>, rule: &str, removed: usize) {
+    match by_rule.iter_mut().find(|other| other.rule == rule) {
+        Some(other) => other.removed += removed,
+        None => by_rule.push(RuleRemovals {
+            rule: rule.to_string(),
+            removed,
+        }),
+    }
+}
+
+/// What an architecture would lose, for planning without writing anything.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct RemovalStatistics {
+    pub removed_by_rule: Vec<RuleRemovals>,
+    /// The ten package names with the most removed packages, most removed first.
+    pub most_removed: Vec<NameRemovals>,
+    pub removed_tar_bz2: usize,
+    pub removed_conda: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RuleRemovals {
+    pub rule: String,
+    pub removed: usize,
+}
+
+#[derive(Serialize)]
+pub struct RoundReport {
+    pub label: String,
+    pub removed: usize,
+    pub seconds: f64,
+}
+
+/// One round of one architecture, as printed on a line of its own by `--round-format json`.
+#[derive(Serialize)]
+pub struct RoundRecord<'a> {
+    pub architecture: &'a str,
+    #[serde(flatten)]
+    pub round: &'a RoundReport,
+}
+
+/// One removal of one architecture, as written on a line of its own by `--explain-to`.
+#[derive(Serialize)]
+pub struct ExplanationRecord<'a, L> {
+    pub architecture: &'a str,
+    pub rule: &'a str,
+    #[serde(flatten)]
+    pub removal: &'a L,
+}
+
+/// The wall time of each phase of a run, and the most memory resident at the end of any phase.
+#[derive(Serialize, Default)]
+pub struct ResourceReport {
+    /// The phases in the order they were first performed.
+    pub phases: Vec<PhaseReport>,
+    pub peak_resident_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct PhaseReport {
+    pub phase: String,
+    pub seconds: f64,
+}
+
+impl ResourceReport {
+    /// Add `elapsed` to the time of `phase`, which may be performed more than once, along with
+    /// the memory resident at its end if known.
+    pub fn record(&mut self, phase: &str, elapsed: Duration, resident_bytes: Option<u64>) {
+        match self.phases.iter_mut().find(|report| report.phase == phase) {
+            Some(report) => report.seconds += elapsed.as_secs_f64(),
+            None => self.phases.push(PhaseReport {
+                phase: phase.to_string(),
+                seconds: elapsed.as_secs_f64(),
+            }),
+        }
+        self.peak_resident_bytes = self.peak_resident_bytes.max(resident_bytes);
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct NameRemovals {
+    pub name: String,
+    pub removed: usize,
+    /// The packages of the name removed by each rule, most first, when known.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub by_rule: Vec<RuleRemovals>,
+}
+
+/// Every package removed from an architecture by name, with the rules which removed them, as the
+/// rounds report them.
+#[derive(Default)]
+pub struct RemovalTally {
+    by_name: HashMap<String, Vec<RuleRemovals>>,
+    total: usize,
+}
+
+impl RemovalTally {
+    /// Count a package removed by the round labelled `label`.
+    pub fn add(&mut self, name: &str, label: &str) {
+        if !self.by_name.contains_key(name) {
+            self.by_name.insert(name.to_string(), Vec::new());
+        }
+        add_removals(self.by_name.get_mut(name).unwrap(), rule_of_round(label), 1);
+        self.total += 1;
+    }
+
+    /// The number of packages removed.
+    #[must_use]
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// The `count` names with the most removed packages, most removed first and ties broken by
+    /// name, each with its removals by rule.
+    #[must_use]
+    pub fn most_removed(&self, count: usize) -> Vec<NameRemovals> {
+        let removed = |rules: &[RuleRemovals]| rules.iter().map(|rule| rule.removed).sum();
+        let mut names: Vec<(&String, usize)> = self
+            .by_name
+            .iter()
+            .map(|(name, rules)| (name, removed(rules)))
+            .collect();
+        names.sort_unstable_by(|(a_name, a_count), (b_name, b_count)| {
+            b_count.cmp(a_count).then(a_name.cmp(b_name))
+        });
+        names
+            .into_iter()
+            .take(count)
+            .map(|(name, removed)| {
+                let mut by_rule = self.by_name[name].clone();
+                by_rule.sort_by(|a, b| b.removed.cmp(&a.removed).then(a.rule.cmp(&b.rule)));
+                NameRemovals {
+                    name: name.clone(),
+                    removed,
+                    by_rule,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The `count` names which occur most often among the names of the removed packages, ties
+/// broken by name.
+pub fn most_removed<'a>(
+    removed_names: impl IntoIterator<Item = &'a str>,
+    count: usize,
+) -> Vec<NameRemovals> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for name in removed_names {
+        *counts.entry(name).or_default() += 1;
+    }
+    let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+    counts.sort_unstable_by(|(a_name, a_count), (b_name, b_count)| {
+        b_count.cmp(a_count).then(a_name.cmp(b_name))
+    });
+    counts
+        .into_iter()
+        .take(count)
+        .map(|(name, removed)| NameRemovals {
+            name: name.to_string(),
+            removed,
+            by_rule: Vec::new(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logs::{
+        RemovedByMinimumVersionLog, RemovedByNoarchTypeLog, RemovedByRecencyWindowLog,
+        RemovedOutsideClosureLog, RemovedUnsatisfiableLog,
+    };
+    use crate::report::{
+        most_removed, ArchitectureReport, ExplanationRecord, NameRemovals, RemovalStatistics,
+        RemovalTally, ResourceReport, RoundRecord, RoundReport, RuleRemovals, RunReport,
+    };
+    use chrono::TimeDelta;
+    use rattler_conda_types::{
+        NamelessMatchSpec, NoArchKind, PackageName, PackageRecord, ParseStrictness, Version,
+    };
+    use serde::Serialize;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    #[test]
+    fn most_removed_names() {
+        let names = ["numpy", "scipy", "numpy", "arrow", "scipy", "numpy", "zlib"];
+        assert_eq!(
+            most_removed(names, 3),
+            [
+                NameRemovals {
+                    name: "numpy".to_string(),
+                    removed: 3,
+                    by_rule: Vec::new(),
+                },
+                NameRemovals {
+                    name: "scipy".to_string(),
+                    removed: 2,
+                    by_rule: Vec::new(),
+                },
+                NameRemovals {
+                    name: "arrow".to_string(),
+                    removed: 1,
+                    by_rule: Vec::new(),
+                },
+            ]
+        );
+        assert!(most_removed(names, 0).is_empty());
+    }
+
+    #[test]
+    fn report_schema() {
+        let mut resources = ResourceReport::default();
+        resources.record("fetch", Duration::from_millis(1500), Some(1024));
+        resources.record("write", Duration::from_millis(250), None);
+        resources.record("write", Duration::from_millis(500), Some(4096));
+        let report = RunReport::new(
+            vec![ArchitectureReport {
+                architecture: "linux-64".to_string(),
+                packages_before: 100,
+                packages_after: 90,
+                rounds: vec![
+                    RoundReport {
+                        label: "dev".to_string(),
+                        removed: 7,
+                        seconds: 0.5,
+                    },
+                    RoundReport {
+                        label: "No Sln Round 1".to_string(),
+                        removed: 3,
+                        seconds: 0.25,
+                    },
+                ],
+                most_removed: most_removed(["numpy", "numpy", "scipy"], 10),
+                statistics: None,
+            }],
+            resources,
+        );
+        assert!((report.architectures[0].removed_share() - 0.1).abs() < f64::EPSILON);
+        assert_eq!(
+            serde_json::to_string_pretty(&report).unwrap(),
+            r#"{
+  "schema_version": 1,
+  "architectures": [
+    {
+      "architecture": "linux-64",
+      "packages_before": 100,
+      "packages_after": 90,
+      "rounds": [
+        {
+          "label": "dev",
+          "removed": 7,
+          "seconds": 0.5
+        },
+        {
+          "label": "No Sln Round 1",
+          "removed": 3,
+          "seconds": 0.25
+        }
+      ],
+      "most_removed": [
+        {
+          "name": "numpy",
+          "removed": 2
+        },
+        {
+          "name": "scipy",
+          "removed": 1
+        }
+      ]
+    }
+  ],
+  "resources": {
+    "phases": [
+      {
+        "phase": "fetch",
+        "seconds": 1.5
+      },
+      {
+        "phase": "write",
+        "seconds": 0.75
+      }
+    ],
+    "peak_resident_bytes": 4096
+  }
+}"#
+        );
+    }
+
+    #[test]
+    fn removals_by_rule() {
+        let round = |label: &str, removed| RoundReport {
+            label: label.to_string(),
+            removed,
+            seconds: 0.0,
+        };
+        let report = ArchitectureReport {
+            architecture: "linux-64".to_string(),
+            packages_before: 100,
+            packages_after: 80,
+            rounds: vec![
+                round("dev", 3),
+                round("No Sln Round 1", 6),
+                round("rc", 5),
+                round("No Sln Round 2", 2),
+                round("dev", 4),
+            ],
+            most_removed: Vec::new(),
+            statistics: Some(RemovalStatistics {
+                removed_by_rule: Vec::new(),
+                most_removed: Vec::new(),
+                removed_tar_bz2: 12,
+                removed_conda: 8,
+            }),
+        };
+        let removals = |rule: &str, removed| RuleRemovals {
+            rule: rule.to_string(),
+            removed,
+        };
+        assert_eq!(
+            report.removed_by_rule(),
+            [
+                removals("unresolveable", 8),
+                removals("dev", 7),
+                removals("rc", 5)
+            ]
+        );
+        assert!(serde_json::to_string(&report)
+            .unwrap()
+            .ends_with(r#""statistics":{"removed_by_rule":[],"most_removed":[],"removed_tar_bz2":12,"removed_conda":8}}"#));
+    }
+
+    #[test]
+    fn tally_by_name_and_rule() {
+        let mut tally = RemovalTally::default();
+        for (name, label) in [
+            ("numpy", "dev"),
+            ("scipy", "No Sln Round 1"),
+            ("numpy", "No Sln Round 2"),
+            ("numpy", "dev"),
+            ("arrow", "rc"),
+            ("scipy", "No Sln Round 3"),
+            ("numpy", "No Sln Round 1"),
+        ] {
+            tally.add(name, label);
+        }
+        let removals = |rule: &str, removed| RuleRemovals {
+            rule: rule.to_string(),
+            removed,
+        };
+        assert_eq!(tally.total(), 7);
+        assert_eq!(
+            tally.most_removed(2),
+            [
+                NameRemovals {
+                    name: "numpy".to_string(),
+                    removed: 4,
+                    by_rule: vec![removals("dev", 2), removals("unresolveable", 2)],
+                },
+                NameRemovals {
+                    name: "scipy".to_string(),
+                    removed: 2,
+                    by_rule: vec![removals("unresolveable", 2)],
+                },
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&tally.most_removed(3)[2]).unwrap(),
+            r#"{"name":"arrow","removed":1,"by_rule":[{"rule":"rc","removed":1}]}"#
+        );
+    }
+
+    #[test]
+    fn round_record_line() {
+        let round = RoundReport {
+            label: "dev".to_string(),
+            removed: 7,
+            seconds: 0.5,
+        };
+        let record = RoundRecord {
+            architecture: "linux-64",
+            round: &round,
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"architecture":"linux-64","label":"dev","removed":7,"seconds":0.5}"#
+        );
+    }
+
+    #[test]
+    fn explanation_record_line() {
+        let removal = NameRemovals {
+            name: "numpy".to_string(),
+            removed: 1,
+            by_rule: Vec::new(),
+        };
+        let record = ExplanationRecord {
+            architecture: "linux-64",
+            rule: "dev",
+            removal: &removal,
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"architecture":"linux-64","rule":"dev","name":"numpy","removed":1}"#
+        );
+    }
+
+    #[test]
+    fn explanation_record_logs() {
+        fn line(rule: &str, log: &impl Serialize) -> String {
+            serde_json::to_string(&ExplanationRecord {
+                architecture: "linux-64",
+                rule,
+                removal: log,
+            })
+            .unwrap()
+        }
+        let matchspec = |spec| NamelessMatchSpec::from_str(spec, ParseStrictness::Lenient).unwrap();
+        let glibc = PackageRecord::new(
+            PackageName::from_str("__glibc").unwrap(),
+            Version::from_str("2.17").unwrap(),
+            "0".to_string(),
+        );
+        let at_least_2_28 = matchspec(">=2.28");
+        assert_eq!(
+            line(
+                "unresolveable",
+                &RemovedUnsatisfiableLog {
+                    filename: "numpy-2.0.0-0.conda",
+                    package_name: "numpy",
+                    dependency_package_name: "__glibc",
+                    matchspec: &at_least_2_28,
+                    cause_filename: None,
+                    constraint: false,
+                    virtual_package: Some(&glibc),
+                }
+            ),
+            r#"{"architecture":"linux-64","rule":"unresolveable","filename":"numpy-2.0.0-0.conda","package_name":"numpy","dependency_package_name":"__glibc","matchspec":">=2.28","cause_filename":null,"constraint":false,"virtual_package":"__glibc=2.17"}"#
+        );
+        let minimum = matchspec(">=1.26");
+        assert_eq!(
+            line(
+                "min versions",
+                &RemovedByMinimumVersionLog {
+                    filename: "numpy-1.24.0-0.conda",
+                    package_name: "numpy",
+                    minimum_version: &minimum,
+                }
+            ),
+            r#"{"architecture":"linux-64","rule":"min versions","filename":"numpy-1.24.0-0.conda","package_name":"numpy","minimum_version":">=1.26"}"#
+        );
+        assert_eq!(
+            line(
+                "noarch types",
+                &RemovedByNoarchTypeLog {
+                    filename: "six-1.16.0-pyhd8ed1ab_0.conda",
+                    package_name: "six",
+                    kind: NoArchKind::Python,
+                }
+            ),
+            r#"{"architecture":"linux-64","rule":"noarch types","filename":"six-1.16.0-pyhd8ed1ab_0.conda","package_name":"six","kind":"python"}"#
+        );
+        assert_eq!(
+            line(
+                "recency",
+                &RemovedByRecencyWindowLog {
+                    filename: "numpy-2.1.0-0.conda",
+                    package_name: "numpy",
+                    age: Some(TimeDelta::hours(2)),
+                    window: TimeDelta::days(1),
+                }
+            ),
+            r#"{"architecture":"linux-64","rule":"recency","filename":"numpy-2.1.0-0.conda","package_name":"numpy","age_seconds":7200,"window_seconds":86400}"#
+        );
+        let roots = HashMap::from([
+            ("scipy".to_string(), vec![matchspec(">=1.13")]),
+            ("numpy".to_string(), Vec::new()),
+        ]);
+        assert_eq!(
+            line(
+                "closure",
+                &RemovedOutsideClosureLog {
+                    filename: "zlib-1.3.1-0.conda",
+                    package_name: "zlib",
+                    roots: &roots,
+                }
+            ),
+            r#"{"architecture":"linux-64","rule":"closure","filename":"zlib-1.3.1-0.conda","package_name":"zlib","roots":["numpy","scipy"]}"#
+        );
+    }
+}
@@ -0,0 +1,496 @@
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::Path;
+
+/// A configuration file for a whole run: a value for any command line option, keyed by the name
+/// of its field (i.e. `channel_alias`) or by its long flag (i.e. `channel-alias`), and optionally
+/// the matchspecs YAML inline. The options may also be given in a `base:` section, which named
+/// `profiles:` override.
+#[derive(Deserialize)]
+pub struct RunConfig {
+    /// The contents of a matchspecs YAML, instead of a path to one.
+    pub matchspecs: Option<Value>,
+    #[serde(default)]
+    base: Mapping,
+    /// Sets of options by name, one of which `--profile` selects.
+    #[serde(default)]
+    profiles: BTreeMap<String, Mapping>,
+    #[serde(flatten)]
+    options: Mapping,
+    /// The options of the selected profile, which replace those of the base.
+    #[serde(skip)]
+    profile: Mapping,
+}
+
+impl RunConfig {
+    /// Select a profile, whose options, including the inline matchspecs, replace those given at
+    /// the top level or in `base:`.
+    pub fn select_profile(&mut self, name: &str) -> Result<(), String> {
+        let Some(mut profile) = self.profiles.remove(name) else {
+            let mut available = self.profiles.keys().map(String::as_str).peekable();
+            if available.peek().is_none() {
+                return Err(format!(
+                    "unknown profile {name}, the config defines no profiles"
+                ));
+            }
+            return Err(format!(
+                "unknown profile {name}, expected one of {}",
+                available.collect::<Vec<_>>().join(", ")
+            ));
+        };
+        if let Some(matchspecs) = profile.remove("matchspecs") {
+            self.matchspecs = Some(matchspecs);
+            self.options.remove("matchspecs_yaml");
+            self.options.remove("matchspecs-yaml");
+        } else if profile.contains_key("matchspecs_yaml") || profile.contains_key("matchspecs-yaml")
+        {
+            self.matchspecs = None;
+        }
+        self.profile = profile;
+        Ok(())
+    }
+}
+
+pub fn parse_run_config(yaml: &str) -> Result<RunConfig, Box<dyn std::error::Error>> {
+    let mut config: RunConfig = serde_yaml::from_str(yaml)?;
+    for (key, value) in std::mem::take(&mut config.base) {
+        if key.as_str() == Some("matchspecs") {
+            if config.matchspecs.is_some() {
+                return Err("matchspecs is given both in base and at the top level".into());
+            }
+            config.matchspecs = Some(value);
+        } else if config.options.contains_key(&key) {
+            return Err(format!("{key:?} is given both in base and at the top level").into());
+        } else {
+            config.options.insert(key, value);
+        }
+    }
+    Ok(config)
+}
+
+pub fn read_run_config(filename: &Path) -> Result<RunConfig, Box<dyn std::error::Error>> {
+    parse_run_config(&std::fs::read_to_string(filename)?)
+}
+
+fn is_flag(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse)
+}
+
+fn is_configurable(arg: &Arg) -> bool {
+    !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version)
+}
+
+/// The number of single character edits between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn unknown_key_error(command: &Command, key: &str) -> String {
+    let closest = command
+        .get_arguments()
+        .filter(|arg| is_configurable(arg))
+        .map(|arg| arg.get_id().as_str())
+        .min_by_key(|id| edit_distance(key, id));
+    match closest {
+        Some(id) if edit_distance(key, id) <= 3 => {
+            format!("unknown key {key}, did you mean {id}?")
+        }
+        _ => format!("unknown key {key}"),
+    }
+}
+
+fn scalar_to_string(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Bool(value) => Ok(value.to_string()),
+        _ => Err(format!("{key} must be a string, number or list of those")),
+    }
+}
+
+/// The command line tokens which give an option the values it has in the config file.
+fn config_tokens(arg: &Arg, key: &str, value: &Value) -> Result<Vec<OsString>, String> {
+    let long = arg.get_long().map(|long| format!("--{long}"));
+    if matches!(arg.get_action(), ArgAction::Count) {
+        let Some(count) = value.as_u64() else {
+            return Err(format!("{key} must be a number of times"));
+        };
+        return Ok(long
+            .into_iter()
+            .map(OsString::from)
+            .cycle()
+            .take(usize::try_from(count).map_err(|err| format!("{key}: {err}"))?)
+            .collect());
+    }
+    if is_flag(arg) {
+        let Value::Bool(value) = value else {
+            return Err(format!("{key} must be true or false"));
+        };
+        // A flag given by its field name holds the field's value, which a `--keep-*` flag
+        // clears, while a flag given by its long name is whether to pass the flag.
+        let by_long_name = arg.get_long() == Some(key);
+        let set_true = matches!(arg.get_action(), ArgAction::SetTrue);
+        let given = if by_long_name {
+            *value
+        } else {
+            *value == set_true
+        };
+        return Ok(if given {
+            long.into_iter().map(OsString::from).collect()
+        } else {
+            Vec::new()
+        });
+    }
+    let values = match value {
+        Value::Null => Vec::new(),
+        Value::Sequence(values) => values
+            .iter()
+            .map(|value| scalar_to_string(key, value))
+            .collect::<Result<_, _>>()?,
+        value => vec![scalar_to_string(key, value)?],
+    };
+    if values.len() > 1 && matches!(arg.get_action(), ArgAction::Set) {
+        return Err(format!("{key} takes a single value"));
+    }
+    Ok(values
+        .into_iter()
+        .map(|value| match &long {
+            Some(long) => OsString::from(format!("{long}={value}")),
+            None => OsString::from(value),
+        })
+        .collect())
+}
+
+/// The command line tokens which repeat what was given for an option on the command line.
+fn command_line_tokens(arg: &Arg, matches: &ArgMatches) -> Vec<OsString> {
+    let long = arg.get_long().map(|long| format!("--{long}"));
+    if matches!(arg.get_action(), ArgAction::Count) {
+        let count = matches.get_count(arg.get_id().as_str());
+        return long
+            .into_iter()
+            .map(OsString::from)
+            .cycle()
+            .take(usize::from(count))
+            .collect();
+    }
+    if is_flag(arg) {
+        return long.into_iter().map(OsString::from).collect();
+    }
+    let Some(values) = matches.get_raw(arg.get_id().as_str()) else {
+        return Vec::new();
+    };
+    values
+        .map(|value| match &long {
+            Some(long) => {
+                let mut token = OsString::from(format!("{long}="));
+                token.push(value);
+                token
+            }
+            None => value.to_os_string(),
+        })
+        .collect()
+}
+
+/// Build the command line which combines the options of `matches`, as parsed from the actual
+/// command line, with those of the config file. Options given on the command line take
+/// precedence over the config file entirely, so a list given on the command line replaces that
+/// of the config file rather than adding to it.
+pub fn merge_arguments(
+    command: &Command,
+    matches: &ArgMatches,
+    config: &RunConfig,
+) -> Result<Vec<OsString>, Box<dyn std::error::Error>> {
+    let mut from_config = options_tokens(command, &config.options)?;
+    for (arg, tokens) in options_tokens(command, &config.profile)? {
+        from_config.retain(|(other, _)| other.get_id() != arg.get_id());
+        from_config.push((arg, tokens));
+    }
+
+    let mut result = vec![OsString::from(command.get_name())];
+    let mut positionals = Vec::new();
+    for arg in command.get_arguments().filter(|arg| is_configurable(arg)) {
+        let id = arg.get_id().as_str();
+        let tokens = if matches.value_source(id) == Some(ValueSource::CommandLine) {
+            command_line_tokens(arg, matches)
+        } else if let Some((_, tokens)) = from_config.iter().find(|(other, _)| other.get_id() == id)
+        {
+            tokens.clone()
+        } else {
+            continue;
+        };
+        if arg.is_positional() {
+            positionals.extend(tokens);
+        } else {
+            result.extend(tokens);
+        }
+    }
+    if !positionals.is_empty() {
+        result.push(OsString::from("--"));
+        result.extend(positionals);
+    }
+    Ok(result)
+}
+
+/// An option set by the config file, with the command line tokens of its value.
+type ConfigTokens<'c> = (&'c Arg, Vec<OsString>);
+
+/// The command line tokens of every option of a section of the config file, by option.
+fn options_tokens<'c>(
+    command: &'c Command,
+    options: &Mapping,
+) -> Result<Vec<ConfigTokens<'c>>, Box<dyn std::error::Error>> {
+    let mut result: Vec<ConfigTokens> = Vec::with_capacity(options.len());
+    for (key, value) in options {
+        let Value::String(key) = key else {
+            return Err(format!("keys must be option names, not {key:?}").into());
+        };
+        let arg = command
+            .get_arguments()
+            .filter(|arg| is_configurable(arg))
+            .find(|arg| arg.get_id() == key.as_str() || arg.get_long() == Some(key.as_str()))
+            .ok_or_else(|| unknown_key_error(command, key))?;
+        if arg.get_id() == "profile" {
+            return Err("profile can only be selected on the command line".into());
+        }
+        if result
+            .iter()
+            .any(|(other, _)| other.get_id() == arg.get_id())
+        {
+            return Err(format!("{} is given more than once", arg.get_id()).into());
+        }
+        result.push((arg, config_tokens(arg, key, value)?));
+    }
+    Ok(result)
+}
+
+/// Every option of the command with the value it has in `matches`, keyed by field name, in the
+/// format of a config file.
+#[must_use]
+pub fn effective_config(command: &Command, matches: &ArgMatches) -> Mapping {
+    let mut result = Mapping::new();
+    for arg in command.get_arguments().filter(|arg| is_configurable(arg)) {
+        let id = arg.get_id().as_str();
+        let value = if matches!(arg.get_action(), ArgAction::Count) {
+            Value::Number(matches.get_count(id).into())
+        } else if is_flag(arg) {
+            Value::Bool(matches.get_flag(id))
+        } else {
+            let mut values = matches
+                .get_raw(id)
+                .into_iter()
+                .flatten()
+                .map(|value| Value::String(value.to_string_lossy().into_owned()));
+            match arg.get_action() {
+                ArgAction::Append => Value::Sequence(values.collect()),
+                _ => values.next().unwrap_or(Value::Null),
+            }
+        };
+        result.insert(Value::String(id.to_string()), value);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runconfig::{effective_config, merge_arguments, parse_run_config};
+    use clap::{CommandFactory, FromArgMatches, Parser};
+
+    #[derive(Parser, Debug, PartialEq)]
+    struct TestCli {
+        #[arg(long = "channel-alias", default_value = "https://example.com/")]
+        channel_alias: String,
+        #[arg(short = 'a', long = "architecture")]
+        architectures: Vec<String>,
+        #[arg(long = "keep-dev", action = clap::ArgAction::SetFalse)]
+        ban_dev: bool,
+        #[arg(long = "offline")]
+        is_offline: bool,
+        #[arg(long)]
+        keep_versions: Option<usize>,
+        #[arg(short = 'v', long, action = clap::ArgAction::Count)]
+        verbose: u8,
+        #[arg(long)]
+        config: Option<std::path::PathBuf>,
+        matchspecs_yaml: Option<std::path::PathBuf>,
+    }
+
+    fn merged(command_line: &[&str], yaml: &str) -> Result<TestCli, Box<dyn std::error::Error>> {
+        let command = TestCli::command();
+        let matches = command.clone().try_get_matches_from(command_line)?;
+        let config = parse_run_config(yaml)?;
+        let arguments = merge_arguments(&command, &matches, &config)?;
+        let matches = command.clone().try_get_matches_from(arguments)?;
+        Ok(TestCli::from_arg_matches(&matches)?)
+    }
+
+    #[test]
+    fn config_values_apply() {
+        let cli = merged(
+            &["prog"],
+            r"
+channel_alias: https://mirror.example.com/
+architecture: [linux-64, osx-arm64]
+keep-dev: true
+is_offline: true
+keep_versions: 3
+verbose: 2
+matchspecs_yaml: matchspecs.yaml
+",
+        )
+        .unwrap();
+        assert_eq!(
+            cli,
+            TestCli {
+                channel_alias: "https://mirror.example.com/".to_string(),
+                architectures: vec!["linux-64".to_string(), "osx-arm64".to_string()],
+                ban_dev: false,
+                is_offline: true,
+                keep_versions: Some(3),
+                verbose: 2,
+                config: None,
+                matchspecs_yaml: Some("matchspecs.yaml".into()),
+            }
+        );
+        // A flag given by its field name takes the value of the field.
+        assert!(!merged(&["prog"], "ban_dev: false").unwrap().ban_dev);
+        assert!(merged(&["prog"], "ban_dev: true").unwrap().ban_dev);
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let cli = merged(
+            &[
+                "prog",
+                "-a",
+                "win-64",
+                "--keep-versions=5",
+                "-v",
+                "other.yaml",
+            ],
+            r"
+architectures: [linux-64, osx-arm64]
+keep_versions: 3
+verbose: 2
+channel_alias: https://mirror.example.com/
+matchspecs_yaml: matchspecs.yaml
+",
+        )
+        .unwrap();
+        assert_eq!(cli.architectures, ["win-64"]);
+        assert_eq!(cli.keep_versions, Some(5));
+        assert_eq!(cli.verbose, 1);
+        assert_eq!(cli.channel_alias, "https://mirror.example.com/");
+        assert_eq!(cli.matchspecs_yaml, Some("other.yaml".into()));
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let error = merged(&["prog"], "chanel_alias: x").err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "unknown key chanel_alias, did you mean channel_alias?"
+        );
+        assert_eq!(
+            merged(&["prog"], "nonsense: x").err().unwrap().to_string(),
+            "unknown key nonsense"
+        );
+        assert!(merged(&["prog"], "is_offline: yes please").is_err());
+        assert!(merged(&["prog"], "keep_versions: [1, 2]").is_err());
+        assert!(merged(&["prog"], "keep_versions: many").is_err());
+        assert!(merged(&["prog"], "verbose: true").is_err());
+        assert!(merged(&["prog"], "offline: true\nis_offline: true").is_err());
+    }
+
+    #[test]
+    fn profiles_override_the_base() {
+        let yaml = r"
+matchspecs_yaml: default.yaml
+base:
+  keep_versions: 3
+  architecture: [linux-64]
+profiles:
+  strict:
+    keep-versions: 1
+    matchspecs_yaml: strict.yaml
+  research:
+    keep-dev: true
+    matchspecs: {numpy: []}
+";
+        let profile = |command_line: &[&str], name: &str| {
+            let command = TestCli::command();
+            let matches = command.clone().try_get_matches_from(command_line)?;
+            let mut config = parse_run_config(yaml)?;
+            config.select_profile(name)?;
+            let arguments = merge_arguments(&command, &matches, &config)?;
+            let matches = command.clone().try_get_matches_from(arguments)?;
+            Ok::<_, Box<dyn std::error::Error>>((TestCli::from_arg_matches(&matches)?, config))
+        };
+        let base = merged(&["prog"], yaml).unwrap();
+        assert_eq!(base.keep_versions, Some(3));
+        assert_eq!(base.matchspecs_yaml, Some("default.yaml".into()));
+        let (strict, _) = profile(&["prog"], "strict").unwrap();
+        assert_eq!(strict.keep_versions, Some(1));
+        assert_eq!(strict.architectures, ["linux-64"]);
+        assert_eq!(strict.matchspecs_yaml, Some("strict.yaml".into()));
+        assert!(strict.ban_dev);
+        let (strict, _) = profile(&["prog", "--keep-versions", "7"], "strict").unwrap();
+        assert_eq!(strict.keep_versions, Some(7));
+        let (research, config) = profile(&["prog"], "research").unwrap();
+        assert!(!research.ban_dev);
+        assert_eq!(research.keep_versions, Some(3));
+        assert!(config.matchspecs.is_some());
+        assert_eq!(research.matchspecs_yaml, None);
+        assert_eq!(
+            profile(&["prog"], "lenient").err().unwrap().to_string(),
+            "unknown profile lenient, expected one of research, strict"
+        );
+        assert!(merged(&["prog"], "keep_versions: 1\nbase: {keep_versions: 2}").is_err());
+    }
+
+    #[test]
+    fn effective_config_round_trips() {
+        let command = TestCli::command();
+        let matches = command
+            .clone()
+            .try_get_matches_from([
+                "prog",
+                "-a",
+                "linux-64",
+                "--keep-dev",
+                "-vv",
+                "matchspecs.yaml",
+            ])
+            .unwrap();
+        let config = effective_config(&command, &matches);
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert_eq!(
+            yaml,
+            "channel_alias: https://example.com/
+architectures:
+- linux-64
+ban_dev: false
+is_offline: false
+keep_versions: null
+verbose: 2
+config: null
+matchspecs_yaml: matchspecs.yaml
+"
+        );
+        let original = TestCli::from_arg_matches(&matches).unwrap();
+        assert_eq!(merged(&["prog"], &yaml).unwrap(), original);
+    }
+}
@@ -0,0 +1,357 @@
+use crate::rawrepodata::{
+    http_client, with_trailing_slash, write_atomically, CacheMode, CacheState, SubdirCache,
+};
+use futures::StreamExt;
+use rattler_conda_types::{ChannelInfo, RepoData, Shard, ShardedRepodata};
+use rattler_digest::Sha256Hash;
+use reqwest::{header, StatusCode};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info};
+use url::Url;
+
+/// The index of the sharded repodata of CEP-16, in each subdir of a channel which publishes it.
+const INDEX_FILENAME: &str = "repodata_shards.msgpack.zst";
+
+/// How many shards are downloaded at once.
+const CONCURRENT_SHARDS: usize = 50;
+
+/// Fetch the sharded repodata of a subdir, and reassemble it into a repodata.json in the cache.
+/// Shards are cached by their hash, so that only those which changed since the last run are
+/// downloaded. None if the channel does not publish sharded repodata for the subdir, or, offline,
+/// if it is not in the cache.
+pub async fn fetch_sharded_subdir(
+    subdir: &str,
+    subdir_url: &Url,
+    cache: &Path,
+    cache_mode: CacheMode,
+) -> Result<Option<(PathBuf, SubdirCache)>, Box<dyn std::error::Error>> {
+    let index_url = subdir_url.join(INDEX_FILENAME)?;
+    let directory = cache.join("shards");
+    fs::create_dir_all(&directory)?;
+    let key = index_url
+        .as_str()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    let index_path = directory.join(format!("{key}.msgpack.zst"));
+    let repodata_path = directory.join(format!("{key}.repodata.json"));
+    let Some(state) = fetch_index(&index_url, &index_path, &repodata_path, cache_mode).await?
+    else {
+        return Ok(None);
+    };
+    let age = match state {
+        CacheState::Cached => SystemTime::now()
+            .duration_since(fs::metadata(&index_path)?.modified()?)
+            .unwrap_or_default(),
+        CacheState::Confirmed | CacheState::Fetched | CacheState::Patched | CacheState::Local => {
+            Duration::ZERO
+        }
+    };
+    let subdir_cache = SubdirCache {
+        subdir: subdir.to_string(),
+        url: index_url.to_string(),
+        state,
+        age,
+    };
+    // An unchanged index names the same shards, so the repodata assembled from them still holds.
+    if state != CacheState::Fetched && repodata_path.exists() {
+        return Ok(Some((repodata_path, subdir_cache)));
+    }
+
+    let index: ShardedRepodata =
+        rmp_serde::from_slice(&zstd::decode_all(fs::read(&index_path)?.as_slice())?)?;
+    let shards_base_url = index_url.join(&with_trailing_slash(&index.info.shards_base_url))?;
+    let shards = fetch_shards(
+        &index,
+        &shards_base_url,
+        &directory,
+        cache_mode == CacheMode::Offline,
+    )
+    .await?;
+    let base_url = index_url.join(&with_trailing_slash(&index.info.base_url))?;
+    let repodata = assemble_repodata(
+        subdir,
+        (base_url != *subdir_url).then_some(&base_url),
+        shards,
+    );
+    write_atomically(&repodata_path, &serde_json::to_vec(&repodata)?)?;
+    Ok(Some((repodata_path, subdir_cache)))
+}
+
+/// Bring the cached index up to date, as the cache mode allows. None if there is no index. The
+/// repodata assembled from the cached index is deleted before a new index is written, so that
+/// a run interrupted before the shards are reassembled does not leave it behind as current.
+async fn fetch_index(
+    index_url: &Url,
+    index_path: &Path,
+    repodata_path: &Path,
+    cache_mode: CacheMode,
+) -> Result<Option<CacheState>, Box<dyn std::error::Error>> {
+    let etag_path = index_path.with_extension("etag");
+    if cache_mode == CacheMode::Offline {
+        return Ok(index_path.exists().then_some(CacheState::Cached));
+    }
+    if index_url.scheme() == "file" {
+        let path = index_url.to_file_path().map_err(|()| "not a local path")?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        remove_stale(repodata_path)?;
+        write_atomically(index_path, &fs::read(path)?)?;
+        return Ok(Some(CacheState::Fetched));
+    }
+    let mut request = http_client().get(index_url.clone());
+    if cache_mode == CacheMode::Normal && index_path.exists() {
+        if let Ok(etag) = fs::read_to_string(&etag_path) {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+    }
+    let response = request.send().await?;
+    match response.status() {
+        StatusCode::NOT_FOUND => return Ok(None),
+        StatusCode::NOT_MODIFIED => {
+            debug!("cached {index_url}, confirmed fresh");
+            return Ok(Some(CacheState::Confirmed));
+        }
+        _ => {}
+    }
+    let response = response.error_for_status()?;
+    let etag = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string);
+    let bytes = response.bytes().await?;
+    remove_stale(repodata_path)?;
+    write_atomically(index_path, &bytes)?;
+    match etag {
+        Some(etag) => fs::write(&etag_path, etag)?,
+        None if etag_path.exists() => fs::remove_file(&etag_path)?,
+        None => {}
+    }
+    info!("fetched {index_url}");
+    Ok(Some(CacheState::Fetched))
+}
+
+/// Delete a file, if there is one.
+fn remove_stale(path: &Path) -> std::io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// The shard of every package name of the index, from the cache when it holds one of the same
+/// hash, and otherwise downloaded into it.
+async fn fetch_shards(
+    index: &ShardedRepodata,
+    shards_base_url: &Url,
+    directory: &Path,
+    is_offline: bool,
+) -> Result<Vec<Shard>, Box<dyn std::error::Error>> {
+    let client = http_client();
+    let fetched: Vec<Result<(Shard, bool), Box<dyn std::error::Error>>> =
+        futures::stream::iter(&index.shards)
+            .map(|(name, hash)| {
+                let client = &client;
+                async move {
+                    let hex = format!("{hash:x}");
+                    let path = directory.join(format!("{hex}.msgpack.zst"));
+                    let (compressed, downloaded) = if path.exists() {
+                        (fs::read(&path)?, false)
+                    } else if is_offline {
+                        return Err(format!("the shard of {name} is not in the cache").into());
+                    } else {
+                        let url = shards_base_url.join(&format!("{hex}.msgpack.zst"))?;
+                        let compressed = download_shard(client, &url, hash).await?;
+                        write_atomically(&path, &compressed)?;
+                        (compressed, true)
+                    };
+                    let shard: Shard =
+                        rmp_serde::from_slice(&zstd::decode_all(compressed.as_slice())?)
+                            .map_err(|err| format!("the shard of {name}: {err}"))?;
+                    Ok((shard, downloaded))
+                }
+            })
+            .buffer_unordered(CONCURRENT_SHARDS)
+            .collect()
+            .await;
+    let mut shards = Vec::with_capacity(fetched.len());
+    let mut downloaded_count = 0;
+    for result in fetched {
+        let (shard, downloaded) = result?;
+        shards.push(shard);
+        downloaded_count += usize::from(downloaded);
+    }
+    if downloaded_count > 0 {
+        info!(
+            "fetched {downloaded_count} of {} shards from {shards_base_url}",
+            shards.len()
+        );
+    }
+    Ok(shards)
+}
+
+/// Download a shard, checking it has the hash the index gives it.
+async fn download_shard(
+    client: &reqwest::Client,
+    url: &Url,
+    hash: &Sha256Hash,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let compressed = if url.scheme() == "file" {
+        fs::read(url.to_file_path().map_err(|()| "not a local path")?)?
+    } else {
+        client
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec()
+    };
+    let digest = rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(&compressed);
+    if digest != *hash {
+        return Err(format!("{url} does not match its hash in the index").into());
+    }
+    Ok(compressed)
+}
+
+/// The repodata of the records of every shard. Its `base_url` is only given when the packages
+/// are elsewhere than the subdir, as in the repodata.json of most channels.
+fn assemble_repodata(subdir: &str, base_url: Option<&Url>, shards: Vec<Shard>) -> RepoData {
+    let mut repodata = RepoData {
+        info: Some(ChannelInfo {
+            subdir: subdir.to_string(),
+            base_url: base_url.map(Url::to_string),
+        }),
+        packages: fxhash::FxHashMap::default(),
+        conda_packages: fxhash::FxHashMap::default(),
+        removed: fxhash::FxHashSet::default(),
+        version: Some(if base_url.is_some() { 2 } else { 1 }),
+    };
+    for shard in shards {
+        repodata.packages.extend(shard.packages);
+        repodata.conda_packages.extend(shard.conda_packages);
+        repodata.removed.extend(shard.removed);
+    }
+    repodata
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rawrepodata::{CacheMode, CacheState};
+    use crate::shardedrepodata::fetch_sharded_subdir;
+    use rattler_conda_types::{
+        PackageName, PackageRecord, RepoData, Shard, ShardedRepodata, ShardedSubdirInfo, Version,
+    };
+    use std::path::Path;
+    use std::str::FromStr;
+    use url::Url;
+
+    fn write_shard(subdir: &Path, name: &str, versions: &[&str]) -> rattler_digest::Sha256Hash {
+        let mut shard = Shard {
+            packages: fxhash::FxHashMap::default(),
+            conda_packages: fxhash::FxHashMap::default(),
+            removed: fxhash::FxHashSet::default(),
+        };
+        for version in versions {
+            shard.conda_packages.insert(
+                format!("{name}-{version}-0.conda"),
+                PackageRecord::new(
+                    PackageName::from_str(name).unwrap(),
+                    Version::from_str(version).unwrap(),
+                    "0".to_string(),
+                ),
+            );
+        }
+        let compressed =
+            zstd::encode_all(rmp_serde::to_vec_named(&shard).unwrap().as_slice(), 0).unwrap();
+        let hash = rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(&compressed);
+        std::fs::create_dir_all(subdir.join("shards")).unwrap();
+        std::fs::write(
+            subdir.join("shards").join(format!("{hash:x}.msgpack.zst")),
+            compressed,
+        )
+        .unwrap();
+        hash
+    }
+
+    fn write_index(subdir: &Path, shards: &[(&str, rattler_digest::Sha256Hash)]) {
+        let index = ShardedRepodata {
+            info: ShardedSubdirInfo {
+                subdir: "linux-64".to_string(),
+                base_url: "./".to_string(),
+                shards_base_url: "./shards/".to_string(),
+            },
+            shards: shards
+                .iter()
+                .map(|(name, hash)| ((*name).to_string(), *hash))
+                .collect(),
+        };
+        let compressed =
+            zstd::encode_all(rmp_serde::to_vec_named(&index).unwrap().as_slice(), 0).unwrap();
+        std::fs::write(subdir.join("repodata_shards.msgpack.zst"), compressed).unwrap();
+    }
+
+    #[test]
+    fn shards_are_reassembled_and_cached() {
+        let root = std::env::temp_dir().join(format!("shards-{}", std::process::id()));
+        let subdir = root.join("channel").join("linux-64");
+        let cache = root.join("cache");
+        let subdir_url = Url::from_directory_path(&subdir).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let fetch = |cache_mode| {
+            runtime
+                .block_on(fetch_sharded_subdir(
+                    "linux-64",
+                    &subdir_url,
+                    &cache,
+                    cache_mode,
+                ))
+                .unwrap()
+        };
+        let read = |path: &Path| -> RepoData {
+            serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+        };
+
+        std::fs::create_dir_all(&subdir).unwrap();
+        assert!(fetch(CacheMode::Normal).is_none());
+        let numpy = write_shard(&subdir, "numpy", &["1.26.4", "2.0.0"]);
+        let scipy = write_shard(&subdir, "scipy", &["1.13.0"]);
+        write_index(&subdir, &[("numpy", numpy), ("scipy", scipy)]);
+        let (path, cache_state) = fetch(CacheMode::Normal).unwrap();
+        let repodata = read(&path);
+        assert_eq!(cache_state.state, CacheState::Fetched);
+        assert_eq!(repodata.conda_packages.len(), 3);
+        assert!(repodata.conda_packages.contains_key("scipy-1.13.0-0.conda"));
+        assert_eq!(repodata.info.unwrap().base_url, None);
+
+        // Only the changed shard is downloaded: the channel no longer holds the unchanged one.
+        std::fs::remove_dir_all(subdir.join("shards")).unwrap();
+        let scipy = write_shard(&subdir, "scipy", &["1.13.0", "1.14.0"]);
+        write_index(&subdir, &[("numpy", numpy), ("scipy", scipy)]);
+        let (path, _) = fetch(CacheMode::Normal).unwrap();
+        assert_eq!(read(&path).conda_packages.len(), 4);
+
+        std::fs::remove_dir_all(root.join("channel")).unwrap();
+        let (path, cache_state) = fetch(CacheMode::Offline).unwrap();
+        assert_eq!(cache_state.state, CacheState::Cached);
+        assert_eq!(read(&path).conda_packages.len(), 4);
+
+        // A new index whose shards cannot be fetched leaves no stale repodata behind.
+        let scipy = write_shard(&subdir, "scipy", &["1.14.1"]);
+        std::fs::remove_dir_all(subdir.join("shards")).unwrap();
+        write_index(&subdir, &[("numpy", numpy), ("scipy", scipy)]);
+        assert!(runtime
+            .block_on(fetch_sharded_subdir(
+                "linux-64",
+                &subdir_url,
+                &cache,
+                CacheMode::Normal
+            ))
+            .is_err());
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}